@@ -1,8 +1,21 @@
 use crate::{Fallible, Label, StoiError};
-use std::collections::HashSet;
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use std::convert::{From, TryFrom};
 use std::fmt;
 
+/// The conventional name of an axis of measures, for quilts with several values in each cell
+///
+/// Labels of this axis have names in the catalog, like "value" or "flag", so pipelines don't
+/// need their own constants. See StorageTransaction::declare_measures() and Patch::slice_label().
+pub const MEASURE_AXIS: &str = "measure";
+
+/// The storage index of each label of an axis, see Axis::label_index()
+pub type LabelIndex = HashMap<Label, usize>;
+
+/// The labels of an axis sorted by value, each with its storage index, see Axis::sorted_index()
+pub type SortedLabelIndex = Vec<(Label, usize)>;
+
 /// A sequence of distinct signed integer labels uniquely mapping to indices of an axis
 ///
 ///  - In a dense patch, it represents the storage order along one dimension
@@ -15,13 +28,25 @@ pub struct Axis {
 }
 impl fmt::Debug for Axis {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        // Axes can be huge, so only show the first few labels
         f.debug_struct("Axis")
             .field("name", &self.name)
-            .field("labels", &&self.labels[..3])
+            .field("len", &self.len())
+            .field("labels", &&self.labels[..self.len().min(3)])
             .finish()?;
         Ok(())
     }
 }
+/// A short summary for logs, like `Axis(itm: 3 labels, 1 to 9)`, with the least and greatest label
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Axis({}: {} labels", self.name, self.len())?;
+        if let (Some(min), Some(max)) = (self.labels.iter().min(), self.labels.iter().max()) {
+            write!(f, ", {} to {}", min, max)?;
+        }
+        write!(f, ")")
+    }
+}
 impl Axis {
     /// Create a new named axis with a set of labels
     pub fn new<T: ToString>(name: T, labels: Vec<Label>) -> Fallible<Axis> {
@@ -84,11 +109,96 @@ impl Axis {
         self.labels.iter().copied().collect()
     }
 
+    /// Map each label to its storage index, which is O(n) to construct
+    ///
+    /// For finding many labels, this is much faster than searching the labels for each.
+    /// Transactions keep one for each axis, see StorageTransaction::get_label_index().
+    pub fn label_index(&self) -> LabelIndex {
+        self.labels
+            .iter()
+            .enumerate()
+            .map(|(ix, &label)| (label, ix))
+            .collect()
+    }
+
+    /// Sort the labels by value, with their storage indices, which is O(n log n) to construct
+    ///
+    /// Labels are stored in the order they were added, so a range of values, like all the days
+    /// in March, could be anywhere in the axis. This finds them with a binary search instead.
+    /// Transactions keep one for each axis, see StorageTransaction::get_sorted_label_index().
+    pub fn sorted_index(&self) -> SortedLabelIndex {
+        self.labels
+            .iter()
+            .enumerate()
+            .map(|(ix, &label)| (label, ix))
+            .sorted()
+            .collect()
+    }
+
     /// Get the number of selected labels in this axis (in most cases this is not all possible labels)
     pub fn len(&self) -> usize {
         self.labels.len()
     }
 
+    /// Find the storage indices of an inclusive slice of labels
+    ///
+    /// Both labels must be in the axis, and the end must not come before the start in storage
+    /// order, since labels are not sorted. A slice from a label to itself has one element.
+    ///
+    /// Returns (start, end) where end is exclusive.
+    pub fn label_slice(&self, start: Label, end: Label) -> Fallible<(usize, usize)> {
+        Axis::find_label_slice(&self.name, start, end, |label| {
+            self.labels.iter().position(|&x| x == label)
+        })
+    }
+
+    /// Find the storage indices of an inclusive slice of labels, like label_slice()
+    ///
+    /// This uses `find` to get the storage index of a label, like from a LabelIndex.
+    pub(crate) fn find_label_slice(
+        axis_name: &str,
+        start: Label,
+        end: Label,
+        find: impl Fn(Label) -> Option<usize>,
+    ) -> Fallible<(usize, usize)> {
+        let find =
+            |label| find(label).ok_or_else(|| StoiError::LabelNotFound(axis_name.into(), label));
+        let start_ix = find(start)?;
+        let end_ix = find(end)?;
+        if end_ix < start_ix {
+            return Err(StoiError::ReversedLabelSlice(axis_name.into(), start, end));
+        }
+        Ok((start_ix, end_ix + 1))
+    }
+
+    /// Find the storage indices of every label from start through end by value, inclusive
+    ///
+    /// Unlike label_slice(), neither label needs to be in the axis, and the range may be empty.
+    /// The indices are in storage order.
+    pub fn label_range(&self, start: Label, end: Label) -> Vec<usize> {
+        Axis::find_label_range(&self.sorted_index(), start, end)
+    }
+
+    /// Find the storage indices of an inclusive range of labels, like label_range()
+    pub(crate) fn find_label_range(
+        index: &SortedLabelIndex,
+        start: Label,
+        end: Label,
+    ) -> Vec<usize> {
+        if end < start {
+            return vec![];
+        }
+        // Labels are unique, so a label found is the only one with that value
+        let lo = index
+            .binary_search_by_key(&start, |&(label, _)| label)
+            .unwrap_or_else(|ix| ix);
+        let hi = match index.binary_search_by_key(&end, |&(label, _)| label) {
+            Ok(ix) => ix + 1,
+            Err(ix) => ix,
+        };
+        index[lo..hi].iter().map(|&(_, ix)| ix).sorted().collect()
+    }
+
     /// Merge the labels of two axes, removing duplicates and appending new elements
     ///
     /// This will not change labels in self, because downstream that means patches would need to
@@ -116,6 +226,30 @@ impl Axis {
         mutated
     }
 
+    /// Choose up to `n` distinct labels at random, in storage order
+    ///
+    /// This is handy to explore an unfamiliar axis, without looking at all of it.
+    ///
+    ///     use stoicheia::Axis;
+    ///     let axis = Axis::range("a", 0..100);
+    ///     let sample = axis.sample(10);
+    ///     assert_eq!(sample.len(), 10);
+    ///     assert!(sample.windows(2).all(|w| w[0] < w[1]));
+    pub fn sample(&self, n: usize) -> Vec<Label> {
+        Axis::sample_indices(self.len(), n)
+            .into_iter()
+            .map(|ix| self.labels[ix])
+            .collect()
+    }
+
+    /// Choose up to `n` distinct indices less than `len` at random, in increasing order
+    pub(crate) fn sample_indices(len: usize, n: usize) -> Vec<usize> {
+        let mut indices =
+            rand::seq::index::sample(&mut rand::thread_rng(), len, n.min(len)).into_vec();
+        indices.sort();
+        indices
+    }
+
     /// Find the smallest aligned power-of-two block enclosing an interval.
     ///
     /// Accepts:
@@ -155,7 +289,7 @@ impl<L: IntoIterator<Item = Label>> TryFrom<(&str, L)> for Axis {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Axis, Catalog, Label, StorageTransaction};
+    use crate::{Axis, Catalog, Label, StoiError, StorageTransaction};
 
     #[test]
     fn test_create_axis() {
@@ -195,6 +329,54 @@ mod tests {
         assert_eq!(ax.labels(), &[1, 5, 0]);
     }
 
+    #[test]
+    fn test_label_range() {
+        let ax = Axis::new(
+            "day",
+            vec![20200302, 20200228, 20200401, 20200301, 20200331],
+        )
+        .unwrap();
+        assert_eq!(ax.label_range(20200301, 20200331), vec![0, 3, 4]);
+        // The labels don't need to exist
+        assert_eq!(ax.label_range(20200300, 20200332), vec![0, 3, 4]);
+        assert_eq!(ax.label_range(20200303, 20200330), Vec::<usize>::new());
+        assert_eq!(ax.label_range(20200331, 20200301), Vec::<usize>::new());
+        assert_eq!(ax.label_range(0, 1 << 40), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_label_slice() {
+        let ax = Axis::new("itm", vec![5, 3, 9, 1]).unwrap();
+        assert_eq!(ax.label_slice(3, 1).unwrap(), (1, 4));
+        assert_eq!(ax.label_slice(9, 9).unwrap(), (2, 3));
+        match ax.label_slice(9, 3) {
+            Err(StoiError::ReversedLabelSlice(name, 9, 3)) => assert_eq!(name, "itm"),
+            other => panic!("expected a reversed slice, got {:?}", other),
+        }
+        match ax.label_slice(5, 4) {
+            Err(StoiError::LabelNotFound(_, 4)) => (),
+            other => panic!("expected a missing label, got {:?}", other),
+        }
+        // The index finds the same slices
+        let index = ax.label_index();
+        assert_eq!(index[&9], 2);
+        let find = |label| index.get(&label).copied();
+        assert_eq!(Axis::find_label_slice("itm", 3, 1, find).unwrap(), (1, 4));
+        assert!(Axis::find_label_slice("itm", 5, 4, find).is_err());
+    }
+
+    #[test]
+    fn test_format_axis() {
+        let ax = Axis::new("itm", vec![5, 3, 9, 1]).unwrap();
+        assert_eq!(ax.to_string(), "Axis(itm: 4 labels, 1 to 9)");
+        // Short axes used to panic
+        assert_eq!(
+            format!("{:?}", Axis::new("itm", vec![5]).unwrap()),
+            "Axis { name: \"itm\", len: 1, labels: [5] }"
+        );
+        assert_eq!(Axis::empty("itm").to_string(), "Axis(itm: 0 labels)");
+    }
+
     #[test]
     fn test_split_patch() {
         assert_eq!(Axis::get_block(8, 10), (8, 11));
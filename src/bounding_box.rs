@@ -0,0 +1,325 @@
+//! Boxes of storage indices, which is how patches are found without reading them
+use crate::{AxisSegment, Fallible, StoiError};
+use std::convert::TryFrom;
+use std::ops::Index;
+
+/// A 4-dimensional box referencing a contiguous region of multiple axes.
+///
+/// Remember that in these boxes, storage indices (usize) are always consecutive,
+/// but labels (i64) may not be. See StorageTransaction::get_bounding_box()
+///
+/// Each segment is the first and last storage index on that axis, inclusive. Axes the box
+/// doesn't limit, including the axes a quilt doesn't have, end at BoundingBox::UNBOUNDED.
+/// The constructors check that every segment starts before it ends and fits in SQLite's
+/// integers, so boxes can't silently wrap around when they're stored.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(try_from = "[AxisSegment; 4]", into = "[AxisSegment; 4]")]
+pub struct BoundingBox([AxisSegment; 4]);
+
+impl BoundingBox {
+    /// The end of a segment that isn't limited, past any storage index an axis could have
+    pub const UNBOUNDED: usize = 1 << 60;
+
+    /// A box with exactly these segments, if they're all valid
+    pub fn new(segments: [AxisSegment; 4]) -> Fallible<Self> {
+        for &(start, end) in &segments {
+            if start > end {
+                return Err(StoiError::InvalidBoundingBox(format!(
+                    "the segment ({}, {}) ends before it starts",
+                    start, end
+                )));
+            }
+            if end > Self::UNBOUNDED {
+                return Err(StoiError::InvalidBoundingBox(format!(
+                    "the segment ({}, {}) ends past BoundingBox::UNBOUNDED",
+                    start, end
+                )));
+            }
+        }
+        Ok(BoundingBox(segments))
+    }
+
+    /// A box that doesn't limit any axis
+    pub fn everywhere() -> Self {
+        BoundingBox([(0, Self::UNBOUNDED); 4])
+    }
+
+    /// A box limiting the first axes to these segments, and leaving the rest unbounded
+    pub fn from_segments<I: IntoIterator<Item = AxisSegment>>(segments: I) -> Fallible<Self> {
+        let mut all = [(0, Self::UNBOUNDED); 4];
+        for (ax_ix, segment) in segments.into_iter().enumerate() {
+            match all.get_mut(ax_ix) {
+                Some(slot) => *slot = segment,
+                None => {
+                    return Err(StoiError::InvalidBoundingBox(
+                        "bounding boxes have at most 4 axes".into(),
+                    ))
+                }
+            }
+        }
+        Self::new(all)
+    }
+
+    /// The same box, but with another segment on one axis
+    pub fn with_segment(self, ax_ix: usize, segment: AxisSegment) -> Fallible<Self> {
+        let mut segments = self.0;
+        match segments.get_mut(ax_ix) {
+            Some(slot) => *slot = segment,
+            None => {
+                return Err(StoiError::InvalidBoundingBox(format!(
+                    "there is no axis {} in a bounding box",
+                    ax_ix
+                )))
+            }
+        }
+        Self::new(segments)
+    }
+
+    /// The segments of each axis, in order
+    pub fn iter(&self) -> std::slice::Iter<AxisSegment> {
+        self.0.iter()
+    }
+
+    /// Whether this box doesn't limit an axis
+    pub fn is_unbounded(&self, ax_ix: usize) -> bool {
+        self.0[ax_ix] == (0, Self::UNBOUNDED)
+    }
+
+    /// Whether every storage index of `inner` is also inside this box
+    pub fn contains(&self, inner: &BoundingBox) -> bool {
+        self.iter()
+            .zip(inner.iter())
+            .all(|(outer, inner)| outer.0 <= inner.0 && inner.1 <= outer.1)
+    }
+
+    /// The smallest box containing both boxes
+    pub fn hull(&self, other: &BoundingBox) -> BoundingBox {
+        let mut segments = self.0;
+        for (segment, other) in segments.iter_mut().zip(other.iter()) {
+            *segment = (segment.0.min(other.0), segment.1.max(other.1));
+        }
+        BoundingBox(segments)
+    }
+
+    /// The box as SQL integers, in the order of the dim_N_min, dim_N_max columns of Patch
+    ///
+    /// This is the only way boxes are written to SQL, so the conversion is checked once here.
+    pub(crate) fn to_sql_bounds(&self) -> Fallible<[i64; 8]> {
+        let mut values = [0i64; 8];
+        for (ax_ix, &(start, end)) in self.iter().enumerate() {
+            values[2 * ax_ix] = Self::index_to_sql(start)?;
+            values[2 * ax_ix + 1] = Self::index_to_sql(end)?;
+        }
+        Ok(values)
+    }
+
+    /// Read a box from eight columns of a row starting at `first`, ordered like to_sql_bounds()
+    pub(crate) fn from_sql_row(row: &rusqlite::Row, first: usize) -> Fallible<Self> {
+        let mut segments = [(0, 0); 4];
+        for (ax_ix, segment) in segments.iter_mut().enumerate() {
+            *segment = (
+                Self::index_from_sql(row.get(first + 2 * ax_ix)?)?,
+                Self::index_from_sql(row.get(first + 2 * ax_ix + 1)?)?,
+            );
+        }
+        Self::new(segments)
+    }
+
+    fn index_to_sql(index: usize) -> Fallible<i64> {
+        i64::try_from(index).map_err(|_| {
+            StoiError::InvalidBoundingBox(format!("storage index {} is too large for SQL", index))
+        })
+    }
+
+    fn index_from_sql(value: i64) -> Fallible<usize> {
+        usize::try_from(value).map_err(|_| {
+            StoiError::InvalidBoundingBox(format!("storage index {} from SQL is invalid", value))
+        })
+    }
+}
+
+/// A few tight intervals of storage indices on each axis of a patch, inside its bounding box
+///
+/// A patch whose labels are scattered along a very long axis has a bounding box covering
+/// most of the axis, so it would be found by nearly every search. These intervals keep up to
+/// `max_intervals` runs of storage indices per axis, merging the runs with the smallest gaps
+/// between them when there are more, so searches can skip patches that only span the box.
+/// Like bounding boxes, they cover every index of the patch, so they never reject too much.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct PatchIntervals(Vec<Vec<AxisSegment>>);
+
+impl PatchIntervals {
+    /// The most intervals kept per axis of a patch
+    pub const MAX_INTERVALS: usize = 16;
+
+    /// Intervals for the storage indices of each axis of a patch, in any order
+    pub fn from_indices(axes: Vec<Vec<usize>>, max_intervals: usize) -> Self {
+        let max_intervals = max_intervals.max(1);
+        PatchIntervals(
+            axes.into_iter()
+                .map(|mut indices| {
+                    indices.sort_unstable();
+                    indices.dedup();
+                    let mut runs: Vec<AxisSegment> = vec![];
+                    for ix in indices {
+                        match runs.last_mut() {
+                            Some(run) if run.1 + 1 == ix => run.1 = ix,
+                            _ => runs.push((ix, ix)),
+                        }
+                    }
+                    if runs.len() <= max_intervals {
+                        return runs;
+                    }
+                    // Close the smallest gaps, which widens the intervals the least, and keep
+                    // the rest. Ties close the leftmost gap first.
+                    let mut gaps: Vec<(usize, usize)> = (1..runs.len())
+                        .map(|i| (runs[i].0 - runs[i - 1].1, i))
+                        .collect();
+                    gaps.sort_unstable();
+                    let mut splits: Vec<usize> = gaps[runs.len() - max_intervals..]
+                        .iter()
+                        .map(|&(_, i)| i)
+                        .collect();
+                    splits.sort_unstable();
+                    splits.push(runs.len());
+                    let mut start = 0;
+                    splits
+                        .into_iter()
+                        .map(|end| {
+                            let merged = (runs[start].0, runs[end - 1].1);
+                            start = end;
+                            merged
+                        })
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    /// The intervals of each axis, in order
+    pub fn axes(&self) -> &[Vec<AxisSegment>] {
+        &self.0
+    }
+
+    /// Whether these intervals are any tighter than the bounding box of the patch
+    pub fn is_fragmented(&self) -> bool {
+        self.0.iter().any(|runs| runs.len() > 1)
+    }
+
+    /// Whether any index of the patch could be in this box
+    ///
+    /// Axes without intervals, or that the box doesn't have, always intersect.
+    pub fn intersects(&self, bounding_box: &BoundingBox) -> bool {
+        self.0
+            .iter()
+            .zip(bounding_box.iter())
+            .all(|(runs, &(start, end))| {
+                runs.is_empty() || runs.iter().any(|run| run.1 >= start && run.0 <= end)
+            })
+    }
+}
+
+impl Index<usize> for BoundingBox {
+    type Output = AxisSegment;
+    fn index(&self, ax_ix: usize) -> &AxisSegment {
+        &self.0[ax_ix]
+    }
+}
+
+impl TryFrom<[AxisSegment; 4]> for BoundingBox {
+    type Error = StoiError;
+    fn try_from(segments: [AxisSegment; 4]) -> Fallible<Self> {
+        Self::new(segments)
+    }
+}
+
+impl From<BoundingBox> for [AxisSegment; 4] {
+    fn from(bounding_box: BoundingBox) -> Self {
+        bounding_box.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AxisSegment, BoundingBox, PatchIntervals};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn bounding_box_checks() {
+        let unbounded = BoundingBox::UNBOUNDED;
+        let bx = BoundingBox::from_segments(vec![(0, 9), (5, 5)]).unwrap();
+        assert_eq!(bx[1], (5, 5));
+        assert!(!bx.is_unbounded(1));
+        assert!(bx.is_unbounded(2));
+        assert!(BoundingBox::everywhere().contains(&bx));
+        assert!(!bx.contains(&BoundingBox::everywhere()));
+
+        // Segments must run forward, and stay within SQLite's integers
+        assert!(BoundingBox::new([(3, 2), (0, 0), (0, 0), (0, 0)]).is_err());
+        assert!(bx.with_segment(0, (0, unbounded + 1)).is_err());
+        assert!(bx.with_segment(4, (0, 1)).is_err());
+        assert!(BoundingBox::from_segments(vec![(0, 1); 5]).is_err());
+        let hull = bx.hull(&bx.with_segment(0, (20, 29)).unwrap());
+        assert_eq!(hull[0], (0, 29));
+
+        // Boxes are stored as arrays, and checked when they're read back
+        let json = serde_json::to_string(&bx).unwrap();
+        assert_eq!(
+            json,
+            format!("[[0,9],[5,5],[0,{}],[0,{}]]", unbounded, unbounded)
+        );
+        assert_eq!(serde_json::from_str::<BoundingBox>(&json).unwrap(), bx);
+        assert!(serde_json::from_str::<BoundingBox>("[[9,0],[0,0],[0,0],[0,0]]").is_err());
+    }
+
+    #[test]
+    fn patch_intervals() {
+        let intervals = PatchIntervals::from_indices(vec![vec![9, 0, 1, 2, 5, 9], vec![3]], 16);
+        assert_eq!(
+            intervals.axes(),
+            &[vec![(0, 2), (5, 5), (9, 9)], vec![(3, 3)]][..]
+        );
+        assert!(intervals.is_fragmented());
+        let bx = |a, b| BoundingBox::from_segments(vec![(a, b)]).unwrap();
+        assert!(intervals.intersects(&bx(1, 1)));
+        assert!(!intervals.intersects(&bx(3, 4)));
+        assert!(!intervals.intersects(&bx(6, 8)));
+        assert!(!intervals.intersects(&bx(0, 9).with_segment(1, (4, 9)).unwrap()));
+
+        // Too many runs merge across the smallest gaps first
+        let merged = PatchIntervals::from_indices(vec![vec![0, 2, 10, 30]], 2);
+        assert_eq!(merged.axes(), &[vec![(0, 10), (30, 30)]][..]);
+        assert!(!PatchIntervals::from_indices(vec![vec![4, 5, 6]], 2).is_fragmented());
+    }
+
+    /// Merging many scattered runs at once gives what merging one gap at a time would
+    #[test]
+    fn patch_intervals_scattered() {
+        let mut rng = SmallRng::seed_from_u64(3219);
+        for &max_intervals in &[1, 2, 16, 100] {
+            let mut indices: Vec<usize> = (0..5000).map(|_| rng.gen_range(0, 1 << 20)).collect();
+            // Equal gaps too, to check ties
+            indices.extend((0..50).map(|i| (1 << 21) + i * 10));
+            let intervals = PatchIntervals::from_indices(vec![indices.clone()], max_intervals);
+
+            indices.sort_unstable();
+            indices.dedup();
+            let mut runs: Vec<AxisSegment> = vec![];
+            for ix in indices {
+                match runs.last_mut() {
+                    Some(run) if run.1 + 1 == ix => run.1 = ix,
+                    _ => runs.push((ix, ix)),
+                }
+            }
+            while runs.len() > max_intervals {
+                let narrowest = (1..runs.len())
+                    .min_by_key(|&i| runs[i].0 - runs[i - 1].1)
+                    .unwrap();
+                runs[narrowest - 1].1 = runs[narrowest].1;
+                runs.remove(narrowest);
+            }
+            assert_eq!(intervals.axes(), &[runs][..]);
+        }
+    }
+}
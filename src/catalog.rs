@@ -1,629 +1,7820 @@
-use crate::sqlite::{SQLiteConnection, SQLiteTransaction};
+use crate::patch::GlobalBounds;
+use crate::sqlite::{replica_lag, SQLiteConnection};
 use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
-use std::convert::{TryFrom, TryInto};
-use std::sync::Arc;
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use enum_map::EnumMap;
 
 use crate::{
-    Axis, AxisSegment, AxisSelection, BoundingBox, Counter, Fallible, Label, Patch, PatchID,
-    PatchRef, StoiError,
+    ApplyStats, Axis, AxisSegment, AxisSelection, BoundingBox, Coarsening, Counter, Fallible,
+    Label, LabelIndex, Patch, PatchCompressionType, PatchID, PatchIntervals, PatchQuantization,
+    PatchRef, ReduceOp, SortedLabelIndex, StoiError, Transaction,
 };
 
+/// Every bounding box of the cartesian product of the segments of each axis, see resolve_request()
+fn bounding_boxes_of(segments_by_axis: &[Vec<AxisSegment>]) -> Fallible<Vec<BoundingBox>> {
+    // If there are more than 1000 bounding boxes, collapse them, to protect the R*tree (or whatever index) from DOS
+    let total_bounding_boxes: usize = segments_by_axis.iter().map(|s| s.len()).product();
+    if total_bounding_boxes > 1000 {
+        Ok(vec![BoundingBox::everywhere()])
+    } else {
+        segments_by_axis
+            .iter()
+            .multi_cartesian_product()
+            .map(|segments_group| BoundingBox::from_segments(segments_group.into_iter().copied()))
+            .collect()
+    }
+}
+
+/// Catalog::connect() opens a replica for URLs starting with this
+const REPLICA_PREFIX: &str = "replica:";
+
+/// Catalog::connect() opens a SQLite file for URLs starting with this, or with none
+const SQLITE_PREFIX: &str = "sqlite:";
+
+/// Which storage a catalog URL is for, see Catalog::connect()
+///
+/// The backend is chosen here at runtime from the URL, so connecting to storage that isn't
+/// supported fails with StoiError::UnsupportedBackend, rather than creating a strange file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CatalogUrl {
+    /// A new catalog in memory, for the URL ""
+    Memory,
+    /// A SQLite file, for a path, a "sqlite:" path, or a SQLite "file:" URI
+    SQLite(PathBuf),
+    /// A read only replica of a SQLite file, for a "replica:" path
+    Replica(PathBuf),
+}
+impl CatalogUrl {
+    /// Find out which storage a URL is for
+    pub fn parse(url: &str) -> Fallible<Self> {
+        if url == "" {
+            Ok(CatalogUrl::Memory)
+        } else if url.starts_with(REPLICA_PREFIX) {
+            Ok(CatalogUrl::Replica(url[REPLICA_PREFIX.len()..].into()))
+        } else if url.starts_with(SQLITE_PREFIX) {
+            Ok(CatalogUrl::SQLite(url[SQLITE_PREFIX.len()..].into()))
+        } else {
+            // Anything like "postgres://" is for a backend, but not Windows paths like "C:/"
+            match url.find("://") {
+                Some(end) if end > 1 && url[..end].chars().all(|c| c.is_ascii_alphanumeric()) => {
+                    Err(StoiError::UnsupportedBackend(url[..end + 3].to_string()))
+                }
+                _ => Ok(CatalogUrl::SQLite(url.into())),
+            }
+        }
+    }
+}
+
+/// The default for patch_compression()
+pub(crate) const DEFAULT_PATCH_COMPRESSION: PatchCompressionType =
+    PatchCompressionType::LZ4 { quality: 0 };
+
+/// The default for target_patch_bytes()
+pub(crate) const DEFAULT_TARGET_PATCH_BYTES: usize = 4 << 20;
+
+/// tune_target_patch_bytes() never tunes a quilt's target_patch_bytes() outside this range
+const TUNED_PATCH_BYTES: (usize, usize) = (64 << 10, 256 << 20);
+
+/// tune_target_patch_bytes() waits for at least this many fetches before changing anything
+const MIN_TUNING_FETCHES: usize = 10;
+
+/// Fetches with more elements than this are rejected, as a safety valve (1 GB of f32)
+pub(crate) const MAX_FETCH_ELEMENTS: usize = 256 << 20;
+
+/// Patches with more elements than this are always split, to limit memory use
+const MAX_PATCH_ELEMENTS: usize = 16 << 20;
+
+/// LabelRange selections stored in more separate runs than this are searched as one segment
+const MAX_RANGE_SEGMENTS: usize = 100;
+
+/// Patches are only merged on commit if their overlap_ratio() is at least this much
+pub(crate) const MIN_MERGE_OVERLAP: f64 = 0.1;
+
+/// analyze_layout() reads at most this many patches to see how densely axes are stored
+const LAYOUT_SAMPLE_PATCHES: usize = 64;
+
+/// analyze_layout() suggests reordering an axis if patches use less of it than this
+const MIN_AXIS_FILL: f64 = 0.5;
+
+/// analyze_layout() suggests compacting if the median patch is smaller than this fraction
+/// of target_patch_bytes()
+const MIN_PATCH_FILL: f64 = 0.25;
+
+/// analyze_layout() suggests compacting if more than this fraction of bytes are hidden
+const MAX_OCCLUDED_FRACTION: f64 = 0.1;
+
+/// create_commit_points() makes one patch per block of this many storage indices on each axis
+const SCATTER_BLOCK_WIDTH: u64 = 256;
+
+/// create_commit_points() splits the points of a block until their patch has at most this
+/// many cells for each point, since it has every combination of their labels
+const SCATTER_CELLS_PER_POINT: usize = 4;
+
+/// Appends are split into blocks of this many storage indices on each axis but the append axis
+pub(crate) const APPEND_BLOCK_WIDTH: usize = 256;
+
+/// Split a patch in half, recursively, until each part is small enough after compression
+///
+/// This is StorageTransaction::maybe_split() without the transaction, so it can run outside
+/// the storage lock. `global_axis` gets an axis of the catalog by name, which must already
+/// have all the labels of the patch. Returns the parts and how many times it split.
+pub(crate) fn split_patch(
+    original: Patch,
+    global_axis: &mut dyn FnMut(&str) -> Fallible<Axis>,
+    target_patch_bytes: usize,
+    compression: PatchCompressionType,
+) -> Fallible<(Vec<Patch>, usize)> {
+    let longest_axis_len = original.axes().iter().map(|a| a.len()).max().unwrap_or(0);
+    match original.content().len() {
+        0 => Ok((vec![], 0)), // Take out the trash
+        _ if longest_axis_len < 2 => Ok((vec![original], 0)), // Can't split anyway
+        // Cap memory at 64 MB no matter how well it compresses
+        1..=MAX_PATCH_ELEMENTS
+            if original.estimate_serialized_size(Some(compression))? <= target_patch_bytes =>
+        {
+            Ok((vec![original], 0))
+        }
+        _ => {
+            // Split everything else
+            let mut splits = 1;
+
+            // Split it along it's longest axis
+            let (long_ax_ix, long_axis) = original
+                .axes()
+                .iter()
+                .enumerate()
+                .max_by_key(|(_ax_ix, ax)| ax.labels().len())
+                .unwrap(); // <- Patch::new() checks for at least one axis
+                           // Replace the patch axis for the global axis by that name
+
+            let global_long_axis = global_axis(&long_axis.name)?;
+            // This is a heuristic and it could use more serious study
+            let long_axis_labelset: HashMap<Label, usize> = long_axis
+                .labels()
+                .iter()
+                .copied()
+                .enumerate()
+                .map(|(a, b)| (b, a))
+                .collect();
+
+            let global_locations = global_long_axis
+                .labels()
+                .iter()
+                .filter_map(|global_label| long_axis_labelset.get(global_label))
+                .copied()
+                .collect_vec();
+
+            if global_locations.len() < long_axis_labelset.len() {
+                return Err(StoiError::MisalignedAxes(
+                    "Patch contains labels not present in the global axis. 
+                    Always union global axes against patch axes before splitting a patch,
+                    because otherwise it's not clear what the Patch's bounding box would be."
+                        .into(),
+                ));
+            }
+
+            // The important part - split the long axis in half according to the global axis order
+            let (left_patch_indices, right_patch_indices) =
+                global_locations.split_at(global_locations.len() / 2);
+
+            let mut patches = vec![];
+            for indices in &[left_patch_indices, right_patch_indices] {
+                let mut axes = original.axes().to_vec();
+                // Replace the long axis
+                axes[long_ax_ix] = Axis::new_unchecked(
+                    &long_axis.name,
+                    indices
+                        .iter()
+                        .map(|ix| long_axis.labels()[*ix])
+                        .collect_vec(),
+                );
+                // Slice the patch
+                let sliced_patch = Patch::new(
+                    axes.to_vec(),
+                    Some(original.content().select(nd::Axis(long_ax_ix), indices)),
+                )
+                .unwrap()
+                .compact()
+                .into_owned();
+                let (parts, part_splits) =
+                    split_patch(sliced_patch, global_axis, target_patch_bytes, compression)?;
+                patches.extend(parts);
+                splits += part_splits;
+            }
+            Ok((patches, splits))
+        }
+    }
+}
+
+/// Split a patch into blocks aligned on the storage indices of every axis but one
+///
+/// `global_indices` has the storage index of each label of each axis of the patch. Labels in
+/// the same block of `block_width` storage indices stay together, and `unsplit_axis` stays
+/// whole, so every part fits in one block of the other axes. Parts that are all NAN are left out.
+pub(crate) fn split_aligned(
+    original: &Patch,
+    global_indices: &[Vec<usize>],
+    unsplit_axis: &str,
+    block_width: usize,
+) -> Fallible<Vec<Patch>> {
+    // The indices of the patch along each axis, grouped by block
+    let mut groups_by_axis = vec![];
+    for (axis, indices) in original.axes().iter().zip(global_indices) {
+        let mut blocks: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (ix, &global_ix) in indices.iter().enumerate() {
+            let block = if axis.name == unsplit_axis {
+                0
+            } else {
+                global_ix / block_width
+            };
+            blocks.entry(block).or_default().push(ix);
+        }
+        groups_by_axis.push(blocks.into_iter().map(|(_, ixs)| ixs).collect_vec());
+    }
+
+    let mut parts = vec![];
+    for group in groups_by_axis.iter().multi_cartesian_product() {
+        // Selecting from a view copies only the part, and each axis after that makes it smaller
+        let mut content = original.content().select(nd::Axis(0), group[0]);
+        let mut axes = vec![];
+        for (ax_ix, (axis, ixs)) in original.axes().iter().zip(group).enumerate() {
+            if ax_ix > 0 {
+                content = content.select(nd::Axis(ax_ix), ixs);
+            }
+            axes.push(Axis::new_unchecked(
+                &axis.name,
+                ixs.iter().map(|&ix| axis.labels()[ix]).collect(),
+            ));
+        }
+        let part = Patch::new(axes, Some(content))?.compact().into_owned();
+        if part.len() > 0 {
+            parts.push(part);
+        }
+    }
+    Ok(parts)
+}
+
+/// How much two bounding boxes overlap, relative to the smallest box containing both
+///
+/// This is 1.0 for identical boxes and 0.0 for disjoint boxes. Boxes that are close but
+/// don't overlap still score 0, and boxes that overlap only at a corner score near 0,
+/// since merging either of those would make a much larger box than either patch.
+pub(crate) fn overlap_ratio(left: &BoundingBox, right: &BoundingBox) -> f64 {
+    left.iter()
+        .zip(right.iter())
+        .map(|(&(left_lo, left_hi), &(right_lo, right_hi))| {
+            // Bounding boxes are inclusive on both ends
+            let overlap = (left_hi.min(right_hi) + 1).saturating_sub(left_lo.max(right_lo));
+            let hull = left_hi.max(right_hi) + 1 - left_lo.min(right_lo);
+            overlap as f64 / hull as f64
+        })
+        .product()
+}
+
+/// Whether two bounding boxes share any storage index on every axis
+pub(crate) fn boxes_overlap(left: &BoundingBox, right: &BoundingBox) -> bool {
+    // Bounding boxes are inclusive on both ends
+    left.iter()
+        .zip(right.iter())
+        .all(|(left, right)| left.0 <= right.1 && right.0 <= left.1)
+}
+
+/// The storage behind a catalog, with a variant for each backend
+///
+/// Catalog::connect() chooses the variant at runtime from the URL, see CatalogUrl.
+#[derive(Clone)]
+enum Storage {
+    SQLite(Arc<SQLiteConnection>),
+}
+
+/// A connection to a Stoicheia catalog
+///
+/// Cloning a catalog is cheap, and the clones share the same connection and counters.
+#[derive(Clone)]
 pub struct Catalog {
-    storage: Arc<SQLiteConnection>,
+    storage: Storage,
+    counters: Arc<PerformanceCounters>,
 }
 impl Catalog {
     /// Connect to a Stoicheia catalog.
     ///
     /// If url is "", then an in-memory catalog will be created.
     /// If url is a file path, then a new SQLite-based catalog will be created.
-    /// Other URLs may be added to support other storage schemes.
+    /// The path can also start with "sqlite:", or be a SQLite "file:" URI.
+    /// If url is "replica:" and a file path, it's a read only replica, see sync_replica().
+    /// Other URLs may be added to support other storage schemes, see CatalogUrl. Until then,
+    /// URLs like "postgres://..." are a StoiError::UnsupportedBackend.
     pub fn connect(url: &str) -> Fallible<Self> {
-        Ok(if url == "" {
-            Catalog {
-                storage: SQLiteConnection::connect_in_memory()?,
-            }
-        } else {
-            Catalog {
-                storage: SQLiteConnection::connect(url.into())?,
+        Self::connect_with(url, CatalogOptions::default())
+    }
+
+    /// Connect to a Stoicheia catalog, with options for how to open it
+    ///
+    /// The url is the same as for connect().
+    pub fn connect_with(url: &str, options: CatalogOptions) -> Fallible<Self> {
+        let counters = Arc::new(PerformanceCounters::default());
+        let storage = match CatalogUrl::parse(url)? {
+            CatalogUrl::Memory => Storage::SQLite(SQLiteConnection::connect_in_memory(
+                counters.clone(),
+                &options,
+            )?),
+            CatalogUrl::SQLite(path) => {
+                Storage::SQLite(SQLiteConnection::connect(path, counters.clone(), &options)?)
             }
-        })
+            CatalogUrl::Replica(path) => Storage::SQLite(SQLiteConnection::connect_replica(
+                path,
+                counters.clone(),
+                &options,
+            )?),
+        };
+        Ok(Catalog { storage, counters })
     }
 
     /// Start a new transaction on the quilt
-    pub fn begin(&mut self) -> Fallible<SQLiteTransaction> {
-        self.storage.txn()
+    ///
+    /// This takes the write lock for the catalog right away, so no other transaction can
+    /// write until it ends, even in another process. If it can't get the lock within a few
+    /// seconds, it fails with StoiError::Busy and you can try again.
+    pub fn begin(&self) -> Fallible<Transaction> {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.txn(true).map(Transaction::SQLite),
+        }
     }
-}
 
-pub trait StorageConnection: Send + Sync {
-    type Transaction: StorageTransaction;
-    fn txn(self) -> Fallible<Self::Transaction>;
-}
+    /// Start a new transaction that only reads
+    ///
+    /// Read transactions don't wait for or block each other, but they can't write anything,
+    /// and trying to fails with StoiError::ReadOnly. On a read only catalog, begin() is the
+    /// same as this.
+    pub fn begin_read(&self) -> Fallible<Transaction> {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.txn(false).map(Transaction::SQLite),
+        }
+    }
 
-/// A connection to tensor storage
-pub trait StorageTransaction {
-    /// Increment a counter by name, used for performance statistics
-    fn trace(&mut self, ctr: Counter, increment: usize);
+    /// Give a fetched patch's memory to the buffer pool, when you're done with it
+    ///
+    /// The next fetch of the same size or smaller can use it rather than allocating.
+    /// Without a buffer pool (see CatalogOptions::buffer_pool_bytes) this just drops it.
+    pub fn recycle(&self, patch: Patch) {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.pool.give_floats(patch.into_buffer()),
+        }
+    }
 
-    /// Get only the metadata associated with a quilt by name
-    fn get_quilt_details(&mut self, quilt_name: &str) -> Fallible<QuiltDetails>;
+    /// Close the catalog, releasing the database file
+    ///
+    /// The file is otherwise closed when the last clone of the catalog is dropped, which can be
+    /// hard to control, and it matters on Windows and NFS. Afterward, beginning a transaction
+    /// on any clone is a StoiError::Closed error, and so is finishing one that was in progress
+    /// in another thread, which is rolled back. Closing twice is fine.
+    pub fn close(&self) -> Fallible<()> {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.close(),
+        }
+    }
 
-    /// Create a new quilt
-    fn create_quilt(
-        &mut self,
-        quilt_name: &str,
-        axes_names: &[&str],
-    ) -> Fallible<bool>;
+    /// Copy the catalog to a new file, as a consistent backup, while it's still in use
+    ///
+    /// The copy is of one moment, as if it were read in one transaction, so it has no half
+    /// finished commits. Other processes can keep reading meanwhile, and writing too if the
+    /// catalog is in WAL mode; otherwise their commits wait until the copy is done. Transactions
+    /// on this catalog wait as well, since they share its connection.
+    ///
+    /// The backup records which commit each tag pointed to, which is returned here and can be
+    /// read from the backup later with StorageTransaction::get_snapshot().
+    /// It's an error if `path` already exists, so old backups are never overwritten.
+    pub fn backup_to(&self, path: &str) -> Fallible<CatalogSnapshot> {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.backup_to(path),
+        }
+    }
 
-    /// List all the quilts in the catalog
-    fn list_quilts(&mut self) -> Fallible<HashMap<String, QuiltDetails>>;
+    /// Replace this replica with a fresh copy of its primary
+    ///
+    /// Replicas are read only copies of a catalog, for spreading out reads, and connecting
+    /// with "replica:" and the path opens one. The first copy is made with backup_to(), and
+    /// after that, call this now and then with the primary. It copies the whole primary, like
+    /// backup_to(), and then swaps the new copy in all at once, so transactions already in
+    /// progress finish on the old copy, and later ones see the new one. Other processes with
+    /// the replica open switch to the new copy on their next transaction too.
+    ///
+    /// See replica_lag() for how far behind it is, and CatalogOptions::max_replica_lag to
+    /// refuse to read from a replica that is too far behind.
+    pub fn sync_replica(&self, primary: &Catalog) -> Fallible<CatalogSnapshot> {
+        match (&self.storage, &primary.storage) {
+            (Storage::SQLite(replica), Storage::SQLite(primary)) => replica.sync_replica(primary),
+        }
+    }
 
-    /// List all the patches that intersect a bounding box
+    /// How long ago the copy of the primary in this replica was taken
     ///
-    /// There may be false positives; some patches may not actually overlap
-    /// There are no false negatives; all patches that overlap will be returned
+    /// Catalogs that aren't copies, from backup_to() or sync_replica(), have no lag at all,
+    /// so this is None.
+    pub fn replica_lag(&self) -> Fallible<Option<Duration>> {
+        let snapshot = self.begin_read()?.get_snapshot()?;
+        replica_lag(snapshot.as_ref().map(|snapshot| snapshot.taken_at.as_str()))
+    }
+
+    /// Make sure everything committed is in the database file itself
     ///
-    /// This method exists in case the database supports efficient multidimensional range queries
-    /// such as SQLite or Postgres/PostGIS
-    fn search(
-        &mut self,
-        quilt_name: &str,
+    /// Commits are durable as soon as they finish, but with SQLite in WAL mode they can stay
+    /// in a separate -wal file for a while. Flush before copying the file.
+    pub fn flush(&self) -> Fallible<()> {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.flush(),
+        }
+    }
+
+    /// Get the most recent entries of the audit log, newest first
+    ///
+    /// Only entries about `quilt_name` are included, if it's given.
+    /// See StorageTransaction::get_audit_log() for details.
+    pub fn audit_log(
+        &self,
+        quilt_name: Option<&str>,
+        limit: usize,
+    ) -> Fallible<Vec<AuditEntry>> {
+        self.begin_read()?.get_audit_log(quilt_name, limit)
+    }
+
+    /// Draw the commits of a quilt, how they branch, and where its tags point
+    ///
+    /// The DOT format is for Graphviz, like `dot -Tsvg`, and JSON is for anything else.
+    /// See StorageTransaction::get_history() for which commits are included.
+    ///
+    ///     use stoicheia::{Catalog, HistoryFormat, Patch, StorageTransaction};
+    ///     let cat = Catalog::connect("").unwrap();
+    ///     let mut txn = cat.begin().unwrap();
+    ///     txn.create_quilt("sales", &["itm"]).unwrap();
+    ///     let pat = Patch::build().axis("itm", &[1]).content_1d(&[5.]).unwrap();
+    ///     txn.create_commit("sales", "latest", "latest", "first", &[&pat]).unwrap();
+    ///     txn.finish().unwrap();
+    ///     let dot = cat.export_history("sales", HistoryFormat::Dot).unwrap();
+    ///     assert!(dot.starts_with("digraph \"sales\""));
+    pub fn export_history(&self, quilt_name: &str, format: HistoryFormat) -> Fallible<String> {
+        let history = self.begin_read()?.get_history(quilt_name)?;
+        render_history(quilt_name, &history, format)
+    }
+
+    /// The coldest patches of a quilt, until they add up to at least `bytes` of content
+    ///
+    /// See StorageTransaction::get_coldest_patches(). This uses a write transaction, so the
+    /// reads sampled by every transaction so far are saved and counted.
+    pub fn coldest_patches(&self, quilt_name: &str, bytes: u64) -> Fallible<Vec<PatchAccess>> {
+        let mut txn = self.begin()?;
+        let coldest = txn.get_coldest_patches(quilt_name, bytes)?;
+        txn.finish()?;
+        Ok(coldest)
+    }
+
+    /// Iterate over the commits to a quilt after `sequence`, oldest first, with their patches
+    ///
+    /// This is for keeping something outside the catalog up to date, like a cache or a search
+    /// index: remember the sequence of the last commit you processed, and start from there next
+    /// time, or from 0 to get everything. Commit ids come from clocks, so they aren't a safe
+    /// place to resume from. Commits are read a page at a time, each in its own read
+    /// transaction, so this doesn't hold anything open between pages.
+    ///
+    /// Only commits some tag of the quilt still leads to are included. Patches merged into a
+    /// later commit are listed with that commit instead, so the later commit's bounding boxes
+    /// still cover everything that changed.
+    pub fn changes_since(&self, quilt_name: &str, sequence: i64) -> Changes {
+        // Commits made before there were sequences are all 0, and only included from the start
+        let last = if sequence > 0 {
+            (sequence, std::i64::MAX)
+        } else {
+            (std::i64::MIN, std::i64::MIN)
+        };
+        Changes {
+            catalog: self.clone(),
+            quilt_name: quilt_name.into(),
+            last,
+            page: vec![].into_iter(),
+            done: false,
+        }
+    }
+
+    /// Do all the work queued for derived quilts, each item in its own transaction
+    ///
+    /// See StorageTransaction::run_derived_work(). This stops at the first item that fails,
+    /// which stays queued. Returns how many items were done.
+    pub fn run_derived_work(&self) -> Fallible<usize> {
+        let mut done = 0;
+        loop {
+            let mut txn = self.begin()?;
+            let count = txn.run_derived_work(1)?;
+            txn.finish()?;
+            if count == 0 {
+                return Ok(done);
+            }
+            done += count;
+        }
+    }
+
+    /// Derive a whole tag of a derived quilt again, and do any other queued work
+    ///
+    /// See StorageTransaction::queue_derived_refresh(). Returns how many items were done.
+    pub fn refresh_derived_quilt(
+        &self,
+        src_quilt: &str,
+        dst_quilt: &str,
         tag: &str,
-        deep: bool,
-        bounds: &[BoundingBox],
-    ) -> Fallible<Vec<PatchRef>>;
+    ) -> Fallible<usize> {
+        let mut txn = self.begin()?;
+        txn.queue_derived_refresh(src_quilt, dst_quilt, tag)?;
+        txn.finish()?;
+        self.run_derived_work()
+    }
 
-    /// Get a single patch by ID
-    fn get_patch(&mut self, id: PatchID) -> Fallible<Patch>;
+    /// Get the performance counters accumulated over all finished transactions
+    ///
+    /// Transactions only report their counters here once they end (committed, rolled back,
+    /// or dropped), so a transaction still in progress is not included.
+    pub fn performance_snapshot(&self) -> EnumMap<Counter, usize> {
+        self.counters.snapshot()
+    }
 
-    /// Get all the labels of an axis, in the order you would expect them to be stored.
+    /// Get the accumulated performance counters of each quilt
     ///
-    /// Returns an empty axis if this axis is missing.
-    fn get_axis(&mut self, name: &str) -> Fallible<&Axis>;
+    /// Like performance_snapshot(), this only includes finished transactions.
+    pub fn performance_snapshot_by_quilt(&self) -> CounterBreakdown {
+        self.counters.snapshot_by_quilt()
+    }
 
-    /// Commit a patch to a quilt.
+    /// Get the accumulated performance counters of each axis, for the axis counters
+    pub fn performance_snapshot_by_axis(&self) -> CounterBreakdown {
+        self.counters.snapshot_by_axis()
+    }
+
+    /// Copy a selection from one quilt and tag to another, in a new transaction
     ///
-    /// Commits are a pretty expensive operation - the system is designed for more reads than writes.
-    /// In specific, it will do at least all the following:
+    /// See StorageTransaction::copy() for details.
+    pub fn copy(
+        &self,
+        src_quilt: &str,
+        src_tag: &str,
+        request: Vec<AxisSelection>,
+        dst_quilt: &str,
+        dst_tag: &str,
+        message: &str,
+    ) -> Fallible<()> {
+        let mut txn = self.begin()?;
+        txn.copy(src_quilt, src_tag, request, dst_quilt, dst_tag, message)?;
+        txn.finish()
+    }
+
+    /// Commit patches to a quilt, doing as much of the work as possible outside the transaction
     ///
-    /// - Get quilt details, including full copies of all the axes
-    /// - Check, compact, and compress all the patches, splitting and balancing search indices
-    /// - Extend all the axes (if necessary) to include the area the patches cover
-    /// - Upload all the patches and their data
-    /// - Log the commit and change the tags to point to it
+    /// This is the same as create_commit() in a new transaction, but faster for large commits,
+    /// and especially for many threads committing at once. Only adding new labels to the axes
+    /// and the final inserts hold the storage lock. Splitting, compressing and serializing
+    /// the patches happen in between, without the lock, using all the cores.
     ///
-    fn create_commit(
-        &mut self,
+    /// Patches may be serialized again if they need to be merged with existing patches, which
+    /// is likely if they overlap patches of the last commit to the tag.
+    pub fn commit(
+        &self,
         quilt_name: &str,
         parent_tag: &str,
         new_tag: &str,
         message: &str,
         patches: &[&Patch],
     ) -> Fallible<()> {
-        self.trace(Counter::CreateCommit, 1);
-        // Check that the axes are consistent
-        let quilt_details = self.get_quilt_details(quilt_name)?;
-        for patch in patches {
-            if patch
-                .axes()
-                .iter()
-                .map(|a| &a.name)
-                .sorted()
-                .ne(quilt_details.axes.iter().sorted())
-            {
-                return Err(StoiError::MisalignedAxes(format!(
-                    "the quilt \"{}\" has axes [{}] but the patch has [{}], which doesn't match. Please note broadcasting is not (yet) supported. The patch axes should match exactly.",
-                    quilt_name, quilt_details.axes.iter().join(", "), patch.axes().iter().map(|a|&a.name).join(", ")
-                )));
-            }
+        let prepared = self.prepare_commit(quilt_name, patches)?;
+        let mut txn = self.begin()?;
+        txn.put_commit_prepared(quilt_name, parent_tag, new_tag, message, prepared)?;
+        txn.finish()
+    }
+
+    /// Commit patches to a quilt like commit(), at most once for each commit key
+    ///
+    /// Pipelines that retry after a timeout can't tell whether the first attempt committed.
+    /// If a commit was already made on this quilt with the same key, this returns its id
+    /// without writing anything, so retrying is safe. Otherwise it commits and returns the
+    /// id of the new commit.
+    pub fn commit_keyed(
+        &self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+        commit_key: &str,
+    ) -> Fallible<i64> {
+        // Skip preparing the patches if we already know they were committed, and looking
+        // only needs a read transaction, so retries don't wait for the write lock
+        if let Some(comm_id) = self
+            .begin_read()?
+            .get_keyed_commit(quilt_name, commit_key)?
+        {
+            return Ok(comm_id);
         }
+        let prepared = self.prepare_commit(quilt_name, patches)?;
+        let mut txn = self.begin()?;
+        // Another attempt may have finished while we were preparing or waiting for the lock
+        if let Some(comm_id) = txn.get_keyed_commit(quilt_name, commit_key)? {
+            return Ok(comm_id);
+        }
+        txn.put_commit_prepared(quilt_name, parent_tag, new_tag, message, prepared)?;
+        let comm_id = txn.put_commit_key(quilt_name, new_tag, commit_key)?;
+        txn.finish()?;
+        Ok(comm_id)
+    }
 
-        // Extend all axes as necessary to complete the patching
-        for axis_name in &quilt_details.axes {
-            let mut axis = self.get_axis(axis_name)?.clone();
-            let mut mutated = false;
-            for patch in patches {
-                // Linear search over max 4 elements so don't sweat it
-                mutated |= axis.union(&patch.axes().iter().find(|a| &a.name == axis_name).unwrap());
+    /// Split, compress and serialize patches for put_commit_prepared(), mostly without the lock
+    fn prepare_commit(&self, quilt_name: &str, patches: &[&Patch]) -> Fallible<Vec<PreparedPatch>> {
+        let mut txn = self.begin()?;
+        txn.trace_quilt(quilt_name);
+        txn.trace(Counter::CreateCommit, 1);
+        let quilt_details = txn.union_patch_axes(quilt_name, patches)?;
+        let global_axes = quilt_details
+            .axes
+            .iter()
+            .map(|name| Ok((name.clone(), txn.get_axis(name)?.clone())))
+            .collect::<Fallible<HashMap<String, Axis>>>()?;
+        let target_patch_bytes = txn.target_patch_bytes();
+        let compression = txn.patch_compression();
+        txn.finish()?;
+
+        // Labels are only ever appended to axes, so these stay correct after the lock
+        Ok(patches
+            .par_iter()
+            .map(|&patch| {
+                let mut patch = patch.to_owned();
+                quilt_details.to_stored(patch.content_mut())?;
+                PreparedPatch::prepare(
+                    patch,
+                    &global_axes,
+                    target_patch_bytes,
+                    compression,
+                    quilt_details.storage_quantization(),
+                )
+            })
+            .collect::<Fallible<Vec<Vec<PreparedPatch>>>>()?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Tune target_patch_bytes() for each quilt, from how it has been fetched since last time
+    ///
+    /// See StorageTransaction::tune_target_patch_bytes(). Quilts that haven't been fetched
+    /// enough yet keep their counters for the next call. Call this now and then, like once an
+    /// hour, in a long running service. Returns the new target of each quilt that was tuned.
+    pub fn auto_tune_patch_bytes(&self) -> Fallible<HashMap<String, usize>> {
+        let since_tuning = self.counters.snapshot_since_tuning();
+        let mut tuned = HashMap::new();
+        let mut txn = self.begin()?;
+        for (quilt_name, trace) in since_tuning.iter().sorted_by_key(|(name, _)| *name) {
+            match txn.tune_target_patch_bytes(quilt_name, trace) {
+                Ok(Some(target)) => {
+                    tuned.insert(quilt_name.clone(), target);
+                }
+                Ok(None) => continue,
+                // The quilt was deleted, so there is nothing to tune
+                Err(StoiError::NotFound(..)) => {}
+                Err(err) => return Err(err),
             }
-            if mutated {
-                // This is actually quite expensive so it's worth avoiding it where possible
-                self.union_axis(&axis)?;
+            self.counters.settle_tuning(quilt_name, trace);
+        }
+        txn.finish()?;
+        Ok(tuned)
+    }
+
+    /// Analyze the layout of a tag in a new transaction, like StorageTransaction::analyze_layout()
+    ///
+    /// This also measures how fetches of the quilt used what they read since maintenance last
+    /// compacted it, see ReadOcclusion, and advises compacting if newer patches hid much of it.
+    pub fn analyze_layout(&self, quilt_name: &str, tag: &str) -> Fallible<LayoutReport> {
+        let mut txn = self.begin_read()?;
+        let mut report = txn.analyze_layout(quilt_name, tag)?;
+        let tags = txn.list_tags(quilt_name)?.len();
+        drop(txn);
+        // Analyzing counts against the quilt too, but only fetches say anything about reads
+        let since_compaction = self.counters.snapshot_since_compaction();
+        if let Some(trace) = since_compaction.get(quilt_name) {
+            if trace[Counter::Fetch] > 0 {
+                report.observe_reads(ReadOcclusion::from_counters(trace, tags));
             }
         }
+        Ok(report)
+    }
+
+    /// Start measuring a quilt's reads from scratch, after maintenance compacted it
+    pub(crate) fn settle_compaction(&self, quilt_name: &str) {
+        self.counters.settle_compaction(quilt_name);
+    }
 
-        // Split the patches into reasonable sizes
-        let mut split_patches = vec![];
-        for &patch in patches {
-            // TODO: Extra clone here?
-            split_patches.extend(self.maybe_split(patch.to_owned())?);
+    /// Set the size patches should be in storage, after compression
+    ///
+    /// This affects transactions started after this call. The default is 4 MB.
+    /// Quilts with their own target, see set_quilt_target_patch_bytes(), ignore this.
+    pub fn set_target_patch_bytes(&self, bytes: usize) {
+        match &self.storage {
+            Storage::SQLite(conn) => conn.target_patch_bytes.store(bytes, Ordering::Relaxed),
         }
+    }
 
-        self.put_commit(
-            quilt_name,
-            parent_tag,
-            new_tag,
-            message,
-            &split_patches.iter().collect_vec(),
-        )?;
+    /// Set how much more than they were given commits may write, see WriteAmplificationLimit
+    ///
+    /// This affects transactions started after this call, unless they set their own with
+    /// StorageTransaction::set_write_amplification_limit(). The default is unlimited.
+    pub fn set_write_amplification_limit(&self, limit: WriteAmplificationLimit) -> Fallible<()> {
+        let setting = match &self.storage {
+            Storage::SQLite(conn) => &conn.write_amplification,
+        };
+        let mut setting = setting
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("write amplification setting was poisoned"))?;
+        *setting = limit;
         Ok(())
     }
 
-    /// Make changes to a tensor via a commit
+    /// Set the compression used for patches written to storage
     ///
-    /// This is only available together, so that the underlying storage media can do this
-    /// atomically without a complicated API
-    fn put_commit(
-        &mut self,
-        quilt_name: &str,
-        parent_tag: &str,
-        new_tag: &str,
-        message: &str,
-        patches: &[&Patch],
-    ) -> Fallible<()>;
+    /// This is how a custom PatchCodec is put to use, with PatchCompressionType::Custom.
+    /// With PatchCompressionType::Auto, each patch is compressed the way its content suits.
+    /// Patches already in storage are unaffected, and can still be read as long as their codec
+    /// is available. This affects transactions started after this call. The default is LZ4.
+    pub fn set_patch_compression(&self, compression: PatchCompressionType) -> Fallible<()> {
+        let setting = match &self.storage {
+            Storage::SQLite(conn) => &conn.patch_compression,
+        };
+        let mut setting = setting
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("patch compression setting was poisoned"))?;
+        *setting = compression;
+        Ok(())
+    }
 
-    /// Rollback the transaction
-    fn rollback(self) -> Fallible<()>;
+    /// Reset the accumulated performance counters to zero
+    pub fn reset_performance_counters(&self) {
+        self.counters.reset()
+    }
+}
 
-    /// Finish and commit the transaction successfully
-    fn finish(self) -> Fallible<()>;
+/// What a fetch would return and cost, from StorageTransaction::estimate_fetch()
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FetchEstimate {
+    /// The length of each axis of the result, in the order of the quilt's axes
+    pub shape: Vec<usize>,
+    /// The number of elements in the result
+    pub elements: usize,
+    /// The number of patches that would be read
+    pub patches: usize,
+    /// The size of the result in memory, in bytes
+    pub bytes: usize,
+    /// The total decompressed size of the patches that would be read, in bytes
+    pub read_bytes: u64,
+    /// Whether fetch() would reject it for being too large
+    pub too_large: bool,
+}
 
-    /// Use the actual axis values to resolve a request into specific labels
-    ///
-    /// This is necessary because we need to turn the axis labels into storage indices for range queries
-    fn resolve_selection(
-        &mut self,
-        name: &str,
-        sel: AxisSelection,
-    ) -> Fallible<(Axis, Vec<AxisSegment>)> {
-        self.trace(Counter::ResolveSelection, 1);
-        Ok(match sel {
-            AxisSelection::All => {
-                let axis = self.get_axis(&name)?;
-                let full_range = (0, axis.len());
-                (axis.clone(), vec![full_range])
+/// A patch a read skipped because its content couldn't be decoded, see CatalogOptions
+///
+/// Its region of the result was left as NANs, or whatever older patches had there.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SkippedPatch {
+    /// The patch that couldn't be read
+    pub patch_id: PatchID,
+    /// The region it covers, as label indices of each axis
+    pub bounding_box: BoundingBox,
+    /// Why it couldn't be read
+    pub reason: String,
+}
+impl std::fmt::Display for SkippedPatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "skipped patch {} covering {:?}: {}",
+            self.patch_id.0, self.bounding_box, self.reason
+        )
+    }
+}
+
+/// How much and how recently a patch was read, see StorageTransaction::get_coldest_patches()
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PatchAccess {
+    pub patch_id: PatchID,
+    /// The region it covers, as label indices of each axis
+    pub bounding_box: BoundingBox,
+    /// Bytes of (compressed) patch content
+    pub bytes: u64,
+    /// About how many times it was read. Reads are sampled, so this is an estimate.
+    pub reads: u64,
+    /// When a read of it was last sampled, in RFC 3339 format in UTC, or None if never
+    pub last_read: Option<String>,
+}
+
+/// How a tag of a quilt is laid out in storage, from StorageTransaction::analyze_layout()
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LayoutReport {
+    /// The number of patches visible from the tag
+    pub patches: usize,
+    /// Their total decompressed size, in bytes
+    pub bytes: u64,
+    /// The decompressed size of the median patch, in bytes
+    pub median_patch_bytes: u64,
+    /// Bytes of patches whose bounding box is inside a newer patch's, so they may be hidden.
+    /// This is an upper bound, since the newer patch may have gaps.
+    pub occluded_bytes: u64,
+    /// For each axis of the quilt, the fraction of each patch's span on that axis that the
+    /// patch actually has labels for, averaged over a sample of patches by size.
+    pub axis_fill: Vec<(String, f64)>,
+    /// How fetches of the quilt used what they read, if Catalog::analyze_layout() measured any.
+    /// This covers every tag of the quilt, since counters aren't broken down by tag, so it's
+    /// only used for advice when the quilt has one tag.
+    #[serde(default)]
+    pub reads: Option<ReadOcclusion>,
+    /// What could be done about it, most important first
+    pub advice: Vec<LayoutAdvice>,
+}
+impl LayoutReport {
+    /// Add what fetches measured to the report, and advise compacting if they read much that
+    /// newer patches hid
+    ///
+    /// Reads only show occlusion that fetches actually ran into, unlike occluded_bytes, which
+    /// only guesses from bounding boxes. A tag of a single patch has nothing to compact though.
+    /// Reads aren't counted by tag, so they only say something about this tag if the quilt has
+    /// no others.
+    pub fn observe_reads(&mut self, reads: ReadOcclusion) {
+        self.reads = Some(reads);
+        let fraction = reads.occluded_fraction();
+        if reads.fetches >= MIN_TUNING_FETCHES
+            && fraction > MAX_OCCLUDED_FRACTION
+            && reads.tags == 1
+            && self.patches > 1
+        {
+            // Measured waste matters more than a guess, but less than a bad axis order
+            let at = self
+                .advice
+                .iter()
+                .position(|advice| match advice {
+                    LayoutAdvice::Reorder { .. } => false,
+                    _ => true,
+                })
+                .unwrap_or_else(|| self.advice.len());
+            self.advice.insert(
+                at,
+                LayoutAdvice::OccludedReads {
+                    occluded_bytes: reads.occluded_bytes,
+                    fraction,
+                },
+            );
+        }
+    }
+}
+
+/// How fetches of a quilt used the bytes they copied, from its performance counters
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct ReadOcclusion {
+    /// The number of fetches counted
+    pub fetches: usize,
+    /// Bytes fetches copied that were still in their results, see Counter::FetchVisibleBytes
+    pub visible_bytes: u64,
+    /// Bytes fetches copied that newer patches overwrote, see Counter::FetchOccludedBytes
+    pub occluded_bytes: u64,
+    /// Patches fetches read for nothing, see Counter::FetchSkippedPatch
+    pub skipped_patches: usize,
+    /// How many tags the quilt has, any of which the fetches could have read from
+    #[serde(default)]
+    pub tags: usize,
+}
+impl ReadOcclusion {
+    /// Measure it from the counters of a quilt with this many tags, like
+    /// Catalog::performance_snapshot_by_quilt()
+    pub fn from_counters(trace: &EnumMap<Counter, usize>, tags: usize) -> Self {
+        ReadOcclusion {
+            fetches: trace[Counter::Fetch],
+            visible_bytes: trace[Counter::FetchVisibleBytes] as u64,
+            occluded_bytes: trace[Counter::FetchOccludedBytes] as u64,
+            skipped_patches: trace[Counter::FetchSkippedPatch],
+            tags,
+        }
+    }
+
+    /// The fraction of the bytes fetches copied that newer patches then overwrote
+    pub fn occluded_fraction(&self) -> f64 {
+        self.occluded_bytes as f64 / (self.visible_bytes + self.occluded_bytes).max(1) as f64
+    }
+}
+
+/// Something that would improve the layout of a quilt, with its estimated benefit
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum LayoutAdvice {
+    /// Labels stored together on this axis are rarely used together, so patches span much
+    /// more of the axis than they fill, and fetches read more patches than they need.
+    /// Storing the axis in the order it's used would fix it.
+    Reorder { axis: String, fill: f64 },
+    /// Many bytes are in patches hidden by newer ones, mostly in older commits, so only
+    /// squashing the history would drop them. Maintenance leaves this to you.
+    Squash { occluded_bytes: u64, fraction: f64 },
+    /// Fetches copied many bytes that newer patches then overwrote, so reading them was
+    /// wasted. Compacting the tag would drop them. See ReadOcclusion.
+    OccludedReads { occluded_bytes: u64, fraction: f64 },
+    /// Patches are much smaller than target_patch_bytes(), so there are more to search and
+    /// read than necessary. Compacting the tag would leave about `patches_after` patches.
+    Compact {
+        median_patch_bytes: u64,
+        patches_after: usize,
+    },
+}
+impl std::fmt::Display for LayoutAdvice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LayoutAdvice::Reorder { axis, fill } => write!(
+                f,
+                "axis {} is interleaved, patches fill {:.0}% of their span; reorder it",
+                axis,
+                fill * 100.
+            ),
+            LayoutAdvice::Squash {
+                occluded_bytes,
+                fraction,
+            } => write!(
+                f,
+                "{:.0}% of bytes ({}) are occluded; squash to save them",
+                fraction * 100.,
+                occluded_bytes
+            ),
+            LayoutAdvice::OccludedReads {
+                occluded_bytes,
+                fraction,
+            } => write!(
+                f,
+                "{:.0}% of bytes fetched ({}) were hidden by newer patches; compact to skip them",
+                fraction * 100.,
+                occluded_bytes
+            ),
+            LayoutAdvice::Compact {
+                median_patch_bytes,
+                patches_after,
+            } => write!(
+                f,
+                "median patch is {}KB; compact to about {} patches",
+                median_patch_bytes >> 10,
+                patches_after
+            ),
+        }
+    }
+}
+
+/// A patch that has been split and serialized for a commit, see Catalog::commit()
+pub struct PreparedPatch {
+    pub(crate) patch: Patch,
+    pub(crate) content: Vec<u8>,
+    pub(crate) compression: PatchCompressionType,
+    pub(crate) quantization: PatchQuantization,
+}
+impl PreparedPatch {
+    /// Split and serialize a patch that already has the stored units of its quilt
+    ///
+    /// This is CPU heavy but doesn't need storage, so it can run in parallel. The catalog's
+    /// axes must already have every label of the patch.
+    fn prepare(
+        patch: Patch,
+        global_axes: &HashMap<String, Axis>,
+        target_patch_bytes: usize,
+        compression: PatchCompressionType,
+        quantization: PatchQuantization,
+    ) -> Fallible<Vec<PreparedPatch>> {
+        let (parts, _splits) = split_patch(
+            patch,
+            &mut |name: &str| {
+                global_axes
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| StoiError::NotFound("axis", name.into()))
+            },
+            target_patch_bytes,
+            compression,
+        )?;
+        parts
+            .into_par_iter()
+            .map(|part| {
+                // Patches are compacted before they are stored, so serialize it that way
+                let part = part.compact().into_owned();
+                let mut content = vec![];
+                part.serialize_quantized_into(Some(compression), quantization, &mut content)?;
+                Ok(PreparedPatch {
+                    patch: part,
+                    content,
+                    compression,
+                    quantization,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Which tags a fetch reads from
+///
+/// Usually that's one tag, and plain tags convert into this, so fetch(quilt, "latest", ..)
+/// still works. Overlays read several tags as layers, without creating a merge commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr<'a> {
+    /// The state of one tag
+    Tag(&'a str),
+    /// Several tags, where earlier layers occlude later ones wherever they have data
+    ///
+    /// For example, Overlay(vec!["overrides", "latest"]) is "latest" except where "overrides"
+    /// has values. Cells that are NAN in a layer show the layers below.
+    Overlay(Vec<&'a str>),
+}
+impl<'a> TagExpr<'a> {
+    /// The tags to read, from the top layer to the bottom one
+    pub fn layers(&self) -> &[&'a str] {
+        match self {
+            TagExpr::Tag(tag) => std::slice::from_ref(tag),
+            TagExpr::Overlay(tags) => tags,
+        }
+    }
+}
+impl<'a> From<&'a str> for TagExpr<'a> {
+    fn from(tag: &'a str) -> Self {
+        TagExpr::Tag(tag)
+    }
+}
+impl<'a> From<&'a String> for TagExpr<'a> {
+    fn from(tag: &'a String) -> Self {
+        TagExpr::Tag(tag)
+    }
+}
+
+/// A fetch request that is resolved once and reused, such as for a dashboard
+///
+/// Resolving a request reads its axes and builds bounding boxes, which can cost more than
+/// the fetch itself for small selections. A plan keeps the result and can be used for any
+/// tag of the quilt, in any transaction, with StorageTransaction::fetch_planned().
+///
+/// The plan is resolved again only if the generation of an axis of the quilt changed since
+/// the last fetch, see StorageTransaction::get_axis_generation().
+#[derive(Debug, Clone)]
+pub struct FetchPlan {
+    quilt_name: String,
+    axis_names: Vec<String>,
+    request: Vec<AxisSelection>,
+    generations: Vec<u64>,
+    resolved: Option<(Vec<Axis>, Vec<BoundingBox>)>,
+}
+impl FetchPlan {
+    /// Plan a fetch of a quilt, to be resolved the first time it's used
+    pub fn new<T: StorageTransaction>(
+        txn: &mut T,
+        quilt_name: &str,
+        request: Vec<AxisSelection>,
+    ) -> Fallible<Self> {
+        Ok(FetchPlan {
+            quilt_name: quilt_name.into(),
+            axis_names: txn.get_quilt_details(quilt_name)?.axes,
+            request,
+            generations: vec![],
+            resolved: None,
+        })
+    }
+
+    /// The quilt this plan fetches from
+    pub fn quilt_name(&self) -> &str {
+        &self.quilt_name
+    }
+}
+
+/// A selection of one commit of a quilt, divided into a grid of chunks to fetch one by one
+///
+/// This is for clients that load a large selection progressively, like a heatmap that fills in
+/// as chunks arrive. The grid remembers the patches of the commit the tag pointed to when it was
+/// planned, so every chunk comes from that same commit, even if the tag moves in the meantime.
+/// Plan one with StorageTransaction::plan_chunks(), and fetch each chunk with fetch_chunk().
+///
+/// Commits that merge into the same commit, and compaction, rewrite its patches. Chunks can't be
+/// fetched consistently after that, so fetch_chunk() fails with StaleChunks instead.
+#[derive(Debug, Clone)]
+pub struct ChunkGrid {
+    quilt_name: String,
+    comm_id: Option<i64>,
+    axes: Vec<Axis>,
+    chunk_shape: Vec<usize>,
+    patch_refs: Vec<PatchRef>,
+}
+impl ChunkGrid {
+    /// The quilt the chunks are fetched from
+    pub fn quilt_name(&self) -> &str {
+        &self.quilt_name
+    }
+
+    /// The commit all the chunks are fetched from, or None if the tag didn't exist
+    pub fn comm_id(&self) -> Option<i64> {
+        self.comm_id
+    }
+
+    /// The axes of the whole selection, in the order of the quilt's axes
+    pub fn axes(&self) -> &[Axis] {
+        &self.axes
+    }
+
+    /// The most labels a chunk has along each axis. Chunks at the edges may have fewer.
+    pub fn chunk_shape(&self) -> &[usize] {
+        &self.chunk_shape
+    }
+
+    /// How many chunks there are along each axis
+    pub fn grid_shape(&self) -> Vec<usize> {
+        self.axes
+            .iter()
+            .zip(&self.chunk_shape)
+            .map(|(axis, &size)| (axis.len() + size - 1) / size)
+            .collect()
+    }
+
+    /// The keys of all the chunks, which are their positions in the grid, in row-major order
+    pub fn keys(&self) -> Vec<Vec<usize>> {
+        self.grid_shape()
+            .into_iter()
+            .map(|n| 0..n)
+            .multi_cartesian_product()
+            .collect()
+    }
+
+    /// The axes of one chunk, which are slices of the axes of the whole selection
+    pub fn chunk_axes(&self, key: &[usize]) -> Fallible<Vec<Axis>> {
+        let grid_shape = self.grid_shape();
+        if key.len() != grid_shape.len() || key.iter().zip(&grid_shape).any(|(k, n)| k >= n) {
+            return Err(StoiError::NotFound("chunk", format!("{:?}", key)));
+        }
+        Ok(self
+            .axes
+            .iter()
+            .zip(key.iter().zip(&self.chunk_shape))
+            .map(|(axis, (&k, &size))| {
+                let labels = axis.labels();
+                let end = labels.len().min((k + 1) * size);
+                Axis::new_unchecked(&axis.name, Vec::from(&labels[k * size..end]))
+            })
+            .collect())
+    }
+}
+
+/// A patch of a stream, decoded or not
+type StreamedPatch = (PatchRef, Fallible<Patch>);
+
+/// A patch of a stream as it was read, which is its content, or the result of reading it some
+/// other way if it has no content of its own
+type ReadPatch = (PatchRef, Result<Vec<u8>, Fallible<Patch>>);
+
+/// Decode a batch of patches of a stream
+fn decode_batch(batch: Vec<ReadPatch>) -> Vec<StreamedPatch> {
+    batch
+        .into_iter()
+        .map(|(patch_ref, content)| {
+            let patch = match content {
+                Ok(content) => Patch::deserialize_stored(&content[..]),
+                Err(read) => read,
+            };
+            (patch_ref, patch)
+        })
+        .collect()
+}
+
+/// The thread decoding the batches of one stream, in the order they're sent
+struct StreamDecoder {
+    batches: std::sync::mpsc::Sender<Vec<ReadPatch>>,
+    decoded: std::sync::mpsc::Receiver<Vec<StreamedPatch>>,
+}
+impl StreamDecoder {
+    /// Start a thread to decode batches, which stops when the decoder is dropped
+    ///
+    /// This is a thread of its own rather than rayon's, since a fetch could be waiting for it
+    /// in every one of rayon's threads.
+    fn spawn() -> Self {
+        let (batches, incoming) = std::sync::mpsc::channel();
+        let (sender, decoded) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for batch in incoming {
+                // If the stream was dropped, nobody needs the batch any more
+                if sender.send(decode_batch(batch)).is_err() {
+                    break;
+                }
             }
-            AxisSelection::Labels(labels) => {
-                // TODO: Profile this - it could be a performance issue
-                let axis = self.get_axis(&name)?;
-                let labelset = axis.labelset();
-                let start_ix = axis
-                    .labels()
-                    .iter()
-                    .position(|x| labelset.contains(&x))
-                    .unwrap_or(axis.len());
-                let end_ix = axis.labels()[start_ix..]
-                    .iter()
-                    .rposition(|x| labelset.contains(&x))
-                    .unwrap_or(0);
-                (Axis::new(name, labels)?, vec![(start_ix, end_ix)])
+        });
+        StreamDecoder { batches, decoded }
+    }
+}
+
+/// The patches a fetch applies, in the order to apply them, read a batch at a time
+///
+/// Start one with StorageTransaction::stream_patches(). Each batch holds patches up to about
+/// target_patch_bytes() decompressed. While you apply the patches of one batch, the next batch
+/// is already read and is decoding in the background, so decompression overlaps applying.
+/// A stream of only one batch has nothing to overlap with, so it's decoded right away.
+///
+/// Patches missing in recovery mode, or skipped because they're unreadable, are left out as
+/// get_patch_or_hole() would. Call applied() with each patch once it's applied.
+pub struct PatchStream<'t, T: StorageTransaction + ?Sized> {
+    txn: &'t mut T,
+    pending: std::vec::IntoIter<PatchRef>,
+    batch_bytes: u64,
+    ready: std::collections::VecDeque<StreamedPatch>,
+    /// The thread decoding batches, once there's more than one
+    decoder: Option<StreamDecoder>,
+    /// Whether a batch was sent to the decoder and hasn't come back yet
+    decoding: bool,
+    applied_stats: ApplyStats,
+}
+impl<'t, T: StorageTransaction + ?Sized> PatchStream<'t, T> {
+    /// Record that a patch from this stream was applied, and recycle its buffer
+    pub fn applied(&mut self, source_patch: Patch, stats: ApplyStats) {
+        self.txn.trace_apply(stats);
+        self.txn.recycle_patch(source_patch);
+        self.applied_stats += stats;
+    }
+
+    /// The stats of every patch applied so far, added up
+    pub fn applied_stats(&self) -> ApplyStats {
+        self.applied_stats
+    }
+
+    /// Read the next batch and start decoding it in the background, if there are more patches
+    ///
+    /// If it's the only batch of the stream, it's decoded right away instead.
+    fn prefetch(&mut self) {
+        let mut batch: Vec<ReadPatch> = vec![];
+        let mut batch_size = 0;
+        while batch_size < self.batch_bytes {
+            let patch_ref = match self.pending.next() {
+                Some(patch_ref) => patch_ref,
+                None => break,
+            };
+            batch_size += patch_ref.decompressed_size;
+            let content = match self.txn.get_patch_content(patch_ref.id) {
+                Ok(Some(content)) => Ok(content),
+                // Patches without content of their own, like members of patchsets
+                Ok(None) => Err(self.txn.get_patch(patch_ref.id)),
+                Err(err) => Err(Err(err)),
+            };
+            batch.push((patch_ref, content));
+        }
+        if batch.is_empty() {
+            return;
+        }
+        if self.decoder.is_none() && self.pending.as_slice().is_empty() {
+            self.ready.extend(decode_batch(batch));
+            return;
+        }
+        let decoder = self.decoder.get_or_insert_with(StreamDecoder::spawn);
+        // The thread only stops when the decoder is dropped, so this can't fail
+        let _ = decoder.batches.send(batch);
+        self.decoding = true;
+    }
+}
+impl<'t, T: StorageTransaction + ?Sized> Iterator for PatchStream<'t, T> {
+    type Item = Fallible<(PatchRef, Patch)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((patch_ref, result)) = self.ready.pop_front() {
+                match self.txn.patch_or_hole(&patch_ref, result) {
+                    Ok(Some(patch)) => return Some(Ok((patch_ref, patch))),
+                    Ok(None) => continue,
+                    Err(err) => return Some(Err(err)),
+                }
             }
-            AxisSelection::LabelSlice(start, end) => {
-                // Axis labels are not guaranteed to be sorted because it may be optimized for storage, not lookup
-                let axis = self.get_axis(&name)?;
-                let lab = axis.labels();
-                let start_ix = lab
-                    .iter()
-                    .position(|&x| x == start)
-                    // If we can't find that label we don't search anything
-                    .unwrap_or(axis.len());
-                let end_ix = lab[start_ix..].iter().position(|&x| x == end).unwrap_or(0);
-                let end_ix = (1 + start_ix + end_ix).min(axis.len());
-                (
-                    Axis::new(&axis.name, Vec::from(&lab[start_ix..end_ix]))?,
-                    vec![(start_ix, end_ix)],
-                )
+            if !self.decoding {
+                self.prefetch();
+                if !self.ready.is_empty() {
+                    continue;
+                }
             }
-            AxisSelection::StorageSlice(start_ix, end_ix) => {
-                let axis = self.get_axis(&name)?;
-                let lab = axis.labels();
-                (
-                    Axis::new(&axis.name, Vec::from(&lab[start_ix..end_ix]))?,
-                    vec![(start_ix, end_ix)],
-                )
+            if !self.decoding {
+                return None;
+            }
+            self.decoding = false;
+            let decoder = self.decoder.as_ref()?;
+            let batch = match decoder.decoded.recv() {
+                Ok(batch) => batch,
+                Err(_) => {
+                    return Some(Err(StoiError::RuntimeError(
+                        "decoding a batch of patches panicked",
+                    )))
+                }
+            };
+            self.ready.extend(batch);
+            // Read and decode the next batch while this one is applied
+            self.prefetch();
+        }
+    }
+}
+
+/// Options for opening a catalog, used with Catalog::connect_with()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogOptions {
+    /// Open the catalog only for reading. Any writes will fail.
+    pub read_only: bool,
+    /// Create the catalog if it doesn't exist yet. Otherwise, a missing catalog is an error.
+    pub create_if_missing: bool,
+    /// Tolerate missing patch content when reading, so a partially corrupted catalog can
+    /// still be read and exported. Missing patches leave holes (NANs) in fetches, and a
+    /// warning is recorded in the transaction (see StorageTransaction::take_warnings())
+    pub recovery: bool,
+    /// The most bytes of patches one fetch may read, decompressed. Fetches that would read more
+    /// fail with StoiError::TooLarge before reading anything. The default is no limit.
+    pub max_read_bytes: Option<u64>,
+    /// Skip patches whose content can't be decoded, like a blob that fails to decompress,
+    /// rather than failing the whole read. Their regions are left as NANs, and each one is
+    /// reported (see StorageTransaction::take_skipped_patches())
+    pub skip_unreadable: bool,
+    /// Who is making changes, recorded in the audit log. See Catalog::audit_log()
+    pub actor: Option<String>,
+    /// How many bytes of spare buffers to keep for reuse across fetches, see BufferPool.
+    /// The default of 0 keeps none.
+    pub buffer_pool_bytes: usize,
+    /// Pack patches smaller than this many bytes (before compression) into shared blobs when
+    /// committing, rather than storing each one on its own. Reading them is unchanged, but
+    /// thousands of tiny patches take much less space. The default of 0 never packs them.
+    pub patchset_bytes: usize,
+    /// How much more than they were given commits may write, by merging with existing patches.
+    /// The default is unlimited. See Catalog::set_write_amplification_limit()
+    pub write_amplification: WriteAmplificationLimit,
+    /// For replicas, how far behind the primary they may be before transactions fail with
+    /// StoiError::StaleReplica. The default is no limit. See Catalog::sync_replica()
+    pub max_replica_lag: Option<Duration>,
+}
+impl Default for CatalogOptions {
+    fn default() -> Self {
+        CatalogOptions {
+            read_only: false,
+            create_if_missing: true,
+            recovery: false,
+            max_read_bytes: None,
+            skip_unreadable: false,
+            actor: None,
+            buffer_pool_bytes: 0,
+            patchset_bytes: 0,
+            write_amplification: WriteAmplificationLimit::Unlimited,
+            max_replica_lag: None,
+        }
+    }
+}
+
+/// A safety valve for commits that would rewrite far more than they were given
+///
+/// Commits merge new patches with overlapping patches already in the tag, which can rewrite
+/// much more than the new patches themselves. The limit is a whole multiple of the bytes of the
+/// new patches, or of target_patch_bytes() if that's larger, since merging a small patch into
+/// one of the usual size is expected. Bytes are counted before compression.
+///
+/// Either way, reaching the limit is counted as Counter::WriteAmplificationLimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteAmplificationLimit {
+    /// Merge as much as the commit calls for
+    Unlimited,
+    /// Fail the commit with StoiError::WriteAmplification, leaving the transaction as it was
+    Abort(u32),
+    /// Stop merging, and append the rest of the new patches as they are
+    Append(u32),
+}
+impl WriteAmplificationLimit {
+    /// The most bytes a commit of `input_bytes` may write, if there is a limit
+    pub(crate) fn max_bytes(self, input_bytes: usize, target_patch_bytes: usize) -> Option<usize> {
+        match self {
+            WriteAmplificationLimit::Unlimited => None,
+            WriteAmplificationLimit::Abort(multiple)
+            | WriteAmplificationLimit::Append(multiple) => {
+                Some((multiple as usize).saturating_mul(input_bytes.max(target_patch_bytes)))
+            }
+        }
+    }
+}
+
+/// One change to the catalog, as recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// When it happened, in RFC 3339 format, in UTC
+    pub timestamp: String,
+    /// Who did it, from CatalogOptions::actor, if it was set
+    pub actor: Option<String>,
+    /// What was done: "create_quilt", "create_commit", "untag", "collect_garbage",
+    /// "collect_expired_commits", or "compact_region"
+    pub operation: String,
+    /// The quilt that was changed
+    pub quilt_name: String,
+    /// The arguments of the operation, which depend on the operation
+    pub parameters: serde_json::Value,
+    /// The commits that were created or changed
+    pub comm_ids: Vec<i64>,
+}
+
+/// A commit and the patches it has now, see Catalog::changes_since()
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommitChanges {
+    pub comm_id: i64,
+    /// Where the commit is in the order of commits to the quilt, which is later than any commit
+    /// before it in the iteration. Commits made before catalogs kept an order are all 0.
+    pub sequence: i64,
+    /// The commit message
+    pub message: String,
+    /// The patches of the commit
+    pub patch_ids: Vec<PatchID>,
+    /// The bounding box of each patch, in storage indices, see get_bounding_box()
+    pub bounding_boxes: Vec<BoundingBox>,
+}
+
+/// One commit in the history of a quilt, see StorageTransaction::get_history()
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryCommit {
+    pub comm_id: i64,
+    /// The commit it was made on top of, if any
+    pub parent_comm_id: Option<i64>,
+    pub message: String,
+    /// How many patches the commit has now, after any merging and compaction
+    pub patches: usize,
+    /// The tags pointing to this commit, sorted by name
+    pub tags: Vec<String>,
+    /// When the commit was made, in RFC 3339, unless it was made before catalogs kept track
+    #[serde(default)]
+    pub created_at: Option<String>,
+    /// If the quilt's retention has expired it, see QuiltRetention
+    #[serde(default)]
+    pub expired: bool,
+}
+
+/// How to write the history of a quilt, see Catalog::export_history()
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// A Graphviz digraph, with an edge from each commit to its parent, and one from each tag
+    Dot,
+    /// An object with the quilt name and a list of HistoryCommit, for a web UI
+    Json,
+}
+
+/// Write the history of a quilt in a format other tools can draw
+fn render_history(
+    quilt_name: &str,
+    history: &[HistoryCommit],
+    format: HistoryFormat,
+) -> Fallible<String> {
+    if format == HistoryFormat::Json {
+        let graph = serde_json::json!({ "quilt_name": quilt_name, "commits": history });
+        return Ok(serde_json::to_string_pretty(&graph)?);
+    }
+    // Debug formatting quotes strings and escapes their quotes and newlines, like DOT needs
+    let mut dot = format!("digraph {:?} {{\n", quilt_name);
+    for commit in history {
+        let summary = commit.message.lines().next().unwrap_or_default();
+        let label = format!("{}\n{} patches", summary, commit.patches);
+        let style = if commit.expired { ", style=dashed" } else { "" };
+        dot += &format!("    c{} [label={:?}{}];\n", commit.comm_id, label, style);
+        if let Some(parent_comm_id) = commit.parent_comm_id {
+            dot += &format!("    c{} -> c{};\n", commit.comm_id, parent_comm_id);
+        }
+        for tag in &commit.tags {
+            let node = format!("tag:{}", tag);
+            dot += &format!("    {:?} [label={:?}, shape=box];\n", node, tag);
+            dot += &format!("    {:?} -> c{};\n", node, commit.comm_id);
+        }
+    }
+    dot += "}\n";
+    Ok(dot)
+}
+
+/// Catalog::changes_since() reads this many commits per transaction
+const CHANGES_PAGE_SIZE: usize = 100;
+
+/// The commits to a quilt after some commit, oldest first, see Catalog::changes_since()
+pub struct Changes {
+    catalog: Catalog,
+    quilt_name: String,
+    /// The sequence and id of the last commit, since sequences alone can tie at 0
+    last: (i64, i64),
+    page: std::vec::IntoIter<CommitChanges>,
+    done: bool,
+}
+impl Iterator for Changes {
+    type Item = Fallible<CommitChanges>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.page.next() {
+                self.last = (change.sequence, change.comm_id);
+                return Some(Ok(change));
+            }
+            if self.done {
+                return None;
+            }
+            let (quilt_name, last) = (&self.quilt_name, self.last);
+            let page = self
+                .catalog
+                .begin_read()
+                .and_then(|mut txn| txn.get_changes_since(quilt_name, last, CHANGES_PAGE_SIZE));
+            match page {
+                Ok(page) => {
+                    self.done = page.len() < CHANGES_PAGE_SIZE;
+                    self.page = page.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Which patches were committed by StorageTransaction::create_commit_report(), and why not
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitReport {
+    /// The indices of the patches that were committed
+    pub committed: Vec<usize>,
+    /// The index of each patch that was rejected, and what was wrong with it
+    pub rejected: Vec<(usize, String)>,
+}
+
+/// What's wrong with a patch's axes for a quilt with these axes, if anything
+///
+/// Repeated labels are wrong for any quilt, since it's ambiguous which of their values to use.
+fn patch_axes_problem(quilt_axes: &[String], patch: &Patch) -> Option<String> {
+    let patch_axes = patch.axes().iter().map(|a| &a.name).collect_vec();
+    if patch_axes.iter().copied().sorted().ne(quilt_axes.iter().sorted()) {
+        let missing = quilt_axes
+            .iter()
+            .filter(|name| !patch_axes.contains(name))
+            .join(", ");
+        let extra = patch_axes
+            .iter()
+            .filter(|name| !quilt_axes.contains(**name))
+            .join(", ");
+        Some(format!(
+            "the quilt has axes [{}] but the patch has [{}] (missing [{}], extra [{}])",
+            quilt_axes.join(", "),
+            patch_axes.iter().join(", "),
+            missing,
+            extra
+        ))
+    } else {
+        patch.axes().iter().find_map(|axis| {
+            let repeats = axis.len() - axis.labels().iter().unique().count();
+            if repeats > 0 {
+                Some(format!(
+                    "axis {} repeats {} labels, see Patch::dedup_labels() to merge them",
+                    axis.name, repeats
+                ))
+            } else {
+                None
             }
         })
     }
+}
 
-    /// Replace the labels of an axis, in the order you would expect them to be stored.
+/// What a backup captured, see Catalog::backup_to()
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogSnapshot {
+    /// When the backup was taken, in RFC 3339 format, in UTC
+    pub taken_at: String,
+    /// The commit each tag pointed to, as (quilt_name, tag_name, comm_id)
+    pub tags: Vec<(String, String, i64)>,
+}
+
+/// How a derived quilt is computed from its source, see StorageTransaction::set_derived_quilt()
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Derivation {
+    /// The same values, such as to keep a copy in other units
+    Copy,
+    /// Aggregate labels of an axis into coarser labels, see Patch::coarsen()
+    Coarsen {
+        axis_name: String,
+        coarsening: Coarsening,
+        op: ReduceOp,
+    },
+    /// Aggregate a whole axis into one label, like the total over all stores
     ///
-    /// Returns true iff the axis was mutated in the process
-    fn union_axis(&mut self, new_axis: &Axis) -> Fallible<bool>;
+    /// Axes are shared by every quilt, so `label` is added to the source's axis too.
+    /// Choose one that can't be confused with a real label, like -1.
+    Aggregate {
+        axis_name: String,
+        op: ReduceOp,
+        label: Label,
+    },
+}
+impl Derivation {
+    /// Compute the derived quilt's patch from a patch of the source quilt
+    pub fn derive(&self, patch: Patch) -> Fallible<Patch> {
+        match self {
+            Derivation::Copy => Ok(patch),
+            Derivation::Coarsen {
+                axis_name,
+                coarsening,
+                op,
+            } => patch.coarsen(axis_name, coarsening, *op),
+            Derivation::Aggregate {
+                axis_name,
+                op,
+                label,
+            } => {
+                let all_labels = patch
+                    .axes()
+                    .iter()
+                    .find(|axis| &axis.name == axis_name)
+                    .map(|axis| axis.labels().to_vec())
+                    .unwrap_or_default();
+                let coarsening = Coarsening::Groups(vec![(*label, all_labels)]);
+                patch.coarsen(axis_name, &coarsening, *op)
+            }
+        }
+    }
 
-    /// Fetch a patch from a quilt.
+    /// What to fetch from an axis of the source, when a segment of it changed
     ///
-    /// - You can request any slice, and it will be assembled from the underlying commits.
-    ///   - How many patches it's assembled from depends on the storage order
-    ///     (which is the order the labels are specified in the axis, not in your request)
-    /// - You can request elements you haven't initialized yet, and you'll get NANs.
-    /// - You can only request patches up to 1 GB, as a safety valve
-    fn fetch(
-        &mut self,
-        quilt_name: &str,
-        tag: &str,
-        mut request: Vec<AxisSelection>,
-    ) -> Fallible<Patch> {
-        self.trace(Counter::Fetch, 1);
+    /// Coarsened axes need whole groups, not only the labels that changed. Factors count from
+    /// the start of the fetched patch, so the segment is widened to multiples of the factor.
+    fn selection(&self, axis_name: &str, segment: AxisSegment, axis_len: usize) -> AxisSelection {
+        match self {
+            Derivation::Coarsen {
+                axis_name: coarse_axis_name,
+                coarsening,
+                ..
+            } if coarse_axis_name == axis_name => match coarsening {
+                Coarsening::Factor(factor) => {
+                    let factor = (*factor).max(1);
+                    AxisSelection::StorageSlice(
+                        segment.0 / factor * factor,
+                        ((segment.1 / factor + 1) * factor).min(axis_len),
+                    )
+                }
+                Coarsening::Groups(_) => AxisSelection::All,
+            },
+            // Every label contributes to the aggregate, so they're all needed
+            Derivation::Aggregate {
+                axis_name: aggregate_axis_name,
+                ..
+            } if aggregate_axis_name == axis_name => AxisSelection::All,
+            _ => AxisSelection::StorageSlice(segment.0, (segment.1 + 1).min(axis_len)),
+        }
+    }
+}
 
-        //
-        // Find all the labels of the axes they are planning to use
-        //
-        let quilt_details = self.get_quilt_details(quilt_name)?;
+/// A rule deriving one quilt from another, and how up to date the derived quilt is
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedQuiltStatus {
+    pub src_quilt: String,
+    pub dst_quilt: String,
+    pub derivation: Derivation,
+    /// How many items of work are queued, which is 0 if it's up to date
+    pub pending: usize,
+    /// When the oldest queued work was queued, in RFC 3339 format, if any is queued
+    pub stale_since: Option<String>,
+    /// When work for it was last done, in RFC 3339 format, if ever
+    pub refreshed_at: Option<String>,
+}
 
-        // Names and all labels of all of the axes involved
-        let mut axes = vec![];
-        // Segments of each axis, which will be the edges of bounding boxes
-        let mut segments_by_axis = vec![];
+/// Work queued for a derived quilt, because a commit changed its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedWork {
+    pub work_id: i64,
+    pub src_quilt: String,
+    pub dst_quilt: String,
+    /// The tag that was committed to, which is also the tag of the derived quilt to update
+    pub tag: String,
+    /// The region of the source that changed, in the order of its axes
+    pub bounding_box: BoundingBox,
+}
 
-        // They can't possibly use more than ten axes - just a safety measure.
-        request.reverse(); // So we can iterate it and take ownership
-        for axis_name in &quilt_details.axes {
-            let (axis, segments) = match request.pop() {
-                Some(sel) => self.resolve_selection(axis_name, sel)?,
-                None => self.resolve_selection(axis_name, AxisSelection::All)?,
-            };
-            axes.push(axis);
-            segments_by_axis.push(segments);
+/// Performance counters accumulated across many transactions
+///
+/// These are shared by every transaction on a catalog, so long running services can report
+/// cumulative IO rather than only what one transaction did.
+#[derive(Debug, Default)]
+pub struct PerformanceCounters {
+    counts: EnumMap<Counter, AtomicUsize>,
+    by_quilt: Mutex<CounterBreakdown>,
+    by_axis: Mutex<CounterBreakdown>,
+    /// Like by_quilt, but only since each quilt was last auto-tuned
+    since_tuning: Mutex<CounterBreakdown>,
+    /// Like by_quilt, but only since maintenance last compacted each quilt
+    since_compaction: Mutex<CounterBreakdown>,
+}
+impl PerformanceCounters {
+    /// Add the counters of one transaction to the totals
+    pub fn accumulate(&self, trace: &EnumMap<Counter, usize>) {
+        for (ctr, &increment) in trace {
+            if increment > 0 {
+                self.counts[ctr].fetch_add(increment, Ordering::Relaxed);
+            }
         }
-        assert!(
-            axes.len() >= 1,
-            StoiError::MisalignedAxes("No axes for quilt in fetch()".into())
-        );
+    }
 
-        // At this point we know how big the output will be.
-        // The error here is early to avoid the IO
-        // and we don't construct the patch (which would have noticed and raised the same error)
-        // in order to avoid holding memory longer
-        if axes.iter().map(|a| a.len()).product::<usize>() > 256 << 20 {
-            return Err(StoiError::TooLarge(
-                "Patches must be 256 million elements or less (1GB of 32bit floats)",
-            ));
+    /// Add the counters of one transaction, broken down by quilt and by axis, to the totals
+    pub fn accumulate_breakdown(&self, by_quilt: &CounterBreakdown, by_axis: &CounterBreakdown) {
+        for (totals, breakdown) in &[
+            (&self.by_quilt, by_quilt),
+            (&self.since_tuning, by_quilt),
+            (&self.since_compaction, by_quilt),
+            (&self.by_axis, by_axis),
+        ] {
+            // A poisoned lock only means another thread panicked while counting
+            let mut totals = totals.lock().unwrap_or_else(|err| err.into_inner());
+            for (name, trace) in breakdown.iter() {
+                let total = totals.entry(name.clone()).or_insert_with(EnumMap::new);
+                for (ctr, &increment) in trace {
+                    total[ctr] += increment;
+                }
+            }
         }
+    }
 
-        //
-        // Find all bounding boxes we need to get the cartesian product of all the axis segments
-        //
+    /// Copy the current totals
+    pub fn snapshot(&self) -> EnumMap<Counter, usize> {
+        let mut snap = EnumMap::new();
+        for (ctr, count) in &self.counts {
+            snap[ctr] = count.load(Ordering::Relaxed);
+        }
+        snap
+    }
 
-        // If there are more than 1000 bounding boxes, collapse them, to protect the R*tree (or whatever index) from DOS
-        let total_bounding_boxes: usize = segments_by_axis.iter().map(|s| s.len()).product();
-        let bounding_boxes = if total_bounding_boxes > 1000 {
-            vec![[
-                (0usize, 1usize << 60),
-                (0usize, 1usize << 60),
-                (0usize, 1usize << 60),
-                (0usize, 1usize << 60),
-            ]]
-        } else {
-            segments_by_axis
-                .iter()
-                .multi_cartesian_product()
-                .map(|segments_group| {
-                    [
-                        **segments_group.get(0).unwrap_or(&&(0usize, 1usize << 60)),
-                        **segments_group.get(1).unwrap_or(&&(0usize, 1usize << 60)),
-                        **segments_group.get(2).unwrap_or(&&(0usize, 1usize << 60)),
-                        **segments_group.get(3).unwrap_or(&&(0usize, 1usize << 60)),
-                    ]
-                })
-                .collect::<Vec<BoundingBox>>()
-        };
+    /// Copy the current totals of each quilt
+    pub fn snapshot_by_quilt(&self) -> CounterBreakdown {
+        self.by_quilt
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Copy the current totals of each axis
+    pub fn snapshot_by_axis(&self) -> CounterBreakdown {
+        self.by_axis
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Copy the totals of each quilt since it was last tuned, see Catalog::auto_tune_patch_bytes()
+    pub fn snapshot_since_tuning(&self) -> CounterBreakdown {
+        self.since_tuning
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Forget counters of a quilt that it was tuned with, keeping any counted since the snapshot
+    pub fn settle_tuning(&self, quilt_name: &str, trace: &EnumMap<Counter, usize>) {
+        let mut since_tuning = self
+            .since_tuning
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(total) = since_tuning.get_mut(quilt_name) {
+            for (ctr, &used) in trace {
+                total[ctr] = total[ctr].saturating_sub(used);
+            }
+            if total.values().all(|&count| count == 0) {
+                since_tuning.remove(quilt_name);
+            }
+        }
+    }
+
+    /// Copy the totals of each quilt since maintenance last compacted it
+    pub fn snapshot_since_compaction(&self) -> CounterBreakdown {
+        self.since_compaction
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Forget the counters of a quilt that was just compacted, since they describe old patches
+    pub fn settle_compaction(&self, quilt_name: &str) {
+        self.since_compaction
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(quilt_name);
+    }
+
+    /// Set all the totals back to zero
+    pub fn reset(&self) {
+        for (_ctr, count) in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+        for breakdown in &[
+            &self.by_quilt,
+            &self.by_axis,
+            &self.since_tuning,
+            &self.since_compaction,
+        ] {
+            breakdown
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clear();
+        }
+    }
+}
+
+/// Commit patches to a quilt like create_commit(), without checking the CommitRules
+///
+/// This is for commits the catalog makes on its own, like updating derived quilts,
+/// whose messages are generated rather than written by whoever is committing.
+pub(crate) fn create_generated_commit<T: StorageTransaction + ?Sized>(
+    txn: &mut T,
+    quilt_name: &str,
+    parent_tag: &str,
+    new_tag: &str,
+    message: &str,
+    patches: &[&Patch],
+) -> Fallible<()> {
+    txn.trace_quilt(quilt_name);
+    txn.trace(Counter::CreateCommit, 1);
+    let quilt_details = txn.union_patch_axes(quilt_name, patches)?;
+
+    // Split the patches into reasonable sizes
+    let mut split_patches = vec![];
+    for &patch in patches {
+        // TODO: Extra clone here?
+        let mut patch = patch.to_owned();
+        quilt_details.to_stored(patch.content_mut())?;
+        split_patches.extend(txn.maybe_split(patch)?);
+    }
+
+    txn.put_commit(
+        quilt_name,
+        parent_tag,
+        new_tag,
+        message,
+        &split_patches.iter().collect_vec(),
+    )
+}
+
+/// Performance counters broken down by the name of a quilt or an axis
+pub type CounterBreakdown = HashMap<String, EnumMap<Counter, usize>>;
+
+pub trait StorageConnection: Send + Sync {
+    type Transaction: StorageTransaction;
+    /// Start a transaction, which can write only if `write` is set
+    fn txn(self, write: bool) -> Fallible<Self::Transaction>;
+}
+
+/// A connection to tensor storage
+pub trait StorageTransaction {
+    /// Increment a counter by name, used for performance statistics
+    fn trace(&mut self, ctr: Counter, increment: usize);
+
+    /// Count everything traced from now on against this quilt too, until another quilt is set
+    ///
+    /// Operations on a quilt call this as they start, so that the counters can be broken down
+    /// by quilt with get_performance_counters_by_quilt().
+    fn trace_quilt(&mut self, quilt_name: &str);
+
+    /// Count the work of applying one patch to another, see Patch::apply_counted()
+    fn trace_apply(&mut self, stats: ApplyStats) {
+        self.trace(Counter::ApplyShuffledBytes, stats.shuffled_bytes);
+        self.trace(Counter::ApplyCopiedBytes, stats.copied_bytes);
+        if stats.copied_bytes == 0 {
+            self.trace(Counter::FetchSkippedPatch, 1);
+        }
+    }
+
+    /// Count how much of what a fetch copied is still visible in its result
+    ///
+    /// `applied` adds up the stats of every patch applied to `content`, which started out NAN.
+    /// Patches never copy NANs, so whatever they copied that isn't in the result anymore was
+    /// overwritten by a newer patch.
+    fn trace_visible(&mut self, applied: ApplyStats, content: nd::ArrayViewD<f32>) {
+        let visible_bytes =
+            content.iter().filter(|x| !x.is_nan()).count() * std::mem::size_of::<f32>();
+        self.trace(Counter::FetchVisibleBytes, visible_bytes);
+        self.trace(
+            Counter::FetchOccludedBytes,
+            applied.copied_bytes.saturating_sub(visible_bytes),
+        );
+    }
+
+    /// Get only the metadata associated with a quilt by name
+    fn get_quilt_details(&mut self, quilt_name: &str) -> Fallible<QuiltDetails>;
+
+    /// Create a new quilt
+    ///
+    /// A name like "teamA/sales" creates the quilt in the namespace "teamA", which must
+    /// already exist, see create_namespace(). The quilt is always known by its full name.
+    fn create_quilt(
+        &mut self,
+        quilt_name: &str,
+        axes_names: &[&str],
+    ) -> Fallible<bool>;
+
+    /// Get the structure and settings of a quilt, to make more like it
+    ///
+    /// See create_quilts_from_template()
+    fn get_quilt_template(&mut self, quilt_name: &str) -> Fallible<QuiltTemplate> {
+        let details = self.get_quilt_details(quilt_name)?;
+        Ok(QuiltTemplate {
+            axes: details.axes,
+            units: details.units,
+            quantization: details.quantization,
+            quota: details.quota,
+            target_patch_bytes: self.get_quilt_target_patch_bytes(quilt_name)?,
+            append_axis: self.get_quilt_append_axis(quilt_name)?,
+            integers: details.integers,
+        })
+    }
+
+    /// Create many quilts with the same structure and settings at once
+    ///
+    /// Quilts that already exist with the template's axes are left as they are, but if one
+    /// has different axes it's an error, and none are created. Returns how many were created.
+    fn create_quilts_from_template(
+        &mut self,
+        template: &QuiltTemplate,
+        quilt_names: &[&str],
+    ) -> Fallible<usize> {
+        // Check them all first, so nothing is created if any conflict
+        for &quilt_name in quilt_names {
+            match self.get_quilt_details(quilt_name) {
+                Ok(existing) if existing.axes != template.axes => {
+                    return Err(StoiError::MisalignedAxes(format!(
+                        "the quilt \"{}\" already exists with axes [{}] rather than [{}]",
+                        quilt_name,
+                        existing.axes.join(", "),
+                        template.axes.join(", ")
+                    )));
+                }
+                Ok(_) | Err(StoiError::NotFound(..)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let axes = template.axes.iter().map(|name| name.as_str()).collect_vec();
+        let mut created = vec![];
+        for &quilt_name in quilt_names {
+            if self.create_quilt(quilt_name, &axes)? {
+                created.push(quilt_name);
+            }
+        }
+        for &quilt_name in &created {
+            if template.units.is_some() {
+                self.set_quilt_units(quilt_name, template.units.clone())?;
+            }
+            if template.quantization != PatchQuantization::default() {
+                self.set_quilt_quantization(quilt_name, template.quantization)?;
+            }
+            if template.quota.is_some() {
+                self.set_quilt_quota(quilt_name, template.quota)?;
+            }
+            if template.target_patch_bytes.is_some() {
+                self.set_quilt_target_patch_bytes(quilt_name, template.target_patch_bytes)?;
+            }
+            if template.append_axis.is_some() {
+                self.set_quilt_append_axis(quilt_name, template.append_axis.as_deref())?;
+            }
+            if template.integers.is_some() {
+                self.set_quilt_integers(quilt_name, template.integers)?;
+            }
+        }
+        Ok(created.len())
+    }
+
+    /// Set or clear the units of a quilt
+    ///
+    /// This only changes how values are converted from now on, so existing data
+    /// is reinterpreted rather than rewritten. It's best to set units before the first commit.
+    fn set_quilt_units(&mut self, quilt_name: &str, units: Option<QuiltUnits>) -> Fallible<()>;
+
+    /// Choose how precisely new patches of a quilt are stored
+    ///
+    /// Existing patches keep whatever precision they were written with.
+    /// The default is PatchQuantization::Exact.
+    fn set_quilt_quantization(
+        &mut self,
+        quilt_name: &str,
+        quantization: PatchQuantization,
+    ) -> Fallible<()>;
+
+    /// Set or clear the integer storage of a quilt, for values like counts
+    ///
+    /// Commits round and saturate their values as the QuiltIntegers say, or fail if a value
+    /// has a fraction and that's what they say to do. Like units, this only applies to later
+    /// commits. Quilts of integers must be stored losslessly, so they stay integers, see
+    /// PatchQuantization::is_lossless().
+    fn set_quilt_integers(
+        &mut self,
+        quilt_name: &str,
+        integers: Option<QuiltIntegers>,
+    ) -> Fallible<()>;
+
+    /// Set or clear the storage limits of a quilt
+    ///
+    /// This only applies to later commits, so a quilt may already be over a new quota.
+    /// In that case, no more commits to it will succeed until the limit is raised.
+    fn set_quilt_quota(&mut self, quilt_name: &str, quota: Option<QuiltQuota>) -> Fallible<()>;
+
+    /// Get how much history a quilt keeps, if it's limited
+    fn get_quilt_retention(&mut self, quilt_name: &str) -> Fallible<Option<QuiltRetention>>;
+
+    /// Set or clear how much history a quilt keeps
+    ///
+    /// Commits that expire are only marked in get_history(), until collect_expired_commits()
+    /// merges them away, which a MaintenanceWorker does on every pass.
+    fn set_quilt_retention(
+        &mut self,
+        quilt_name: &str,
+        retention: Option<QuiltRetention>,
+    ) -> Fallible<()>;
+
+    /// Merge the expired commits of a quilt into their parents, see QuiltRetention
+    ///
+    /// A commit is only merged into a parent that expired too, and that has no other children,
+    /// so what every tag fetches stays the same. That leaves the oldest expired commit of each
+    /// branch, holding the patches of the rest. Returns how many commits were merged away.
+    fn collect_expired_commits(&mut self, quilt_name: &str) -> Fallible<usize>;
+
+    /// Delete the commits of a quilt that no tag can reach, and their patches
+    ///
+    /// These are left behind by untag(), and by commits that move a tag to another branch.
+    /// Commits made before commits were ordered by quilt don't say which quilt they belong
+    /// to, so they are never collected. Returns how many commits were deleted.
+    fn collect_garbage(&mut self, quilt_name: &str) -> Fallible<usize>;
+
+    /// Get the storage used by all the commits of a quilt
+    ///
+    /// This is what the quilt's quota applies to. It's counted as patches are written and
+    /// deleted, so it's quick enough to check on every commit.
+    fn get_quilt_usage(&mut self, quilt_name: &str) -> Fallible<QuiltUsage>;
+
+    /// Get the target_patch_bytes() of a quilt, if it has its own rather than the catalog's
+    fn get_quilt_target_patch_bytes(&mut self, quilt_name: &str) -> Fallible<Option<usize>>;
+
+    /// Set or clear the target_patch_bytes() of a quilt, overriding the catalog's
+    ///
+    /// Like the catalog's, this only affects how later commits are split and merged.
+    fn set_quilt_target_patch_bytes(
+        &mut self,
+        quilt_name: &str,
+        bytes: Option<usize>,
+    ) -> Fallible<()>;
+
+    /// Get the axis a quilt is appended along, like the days of a time series, if it has one
+    fn get_quilt_append_axis(&mut self, quilt_name: &str) -> Fallible<Option<String>>;
+
+    /// Set or clear the axis a quilt is appended along
+    ///
+    /// Commits that only write past the end of this axis in their parent tag, like a new day
+    /// across all items and stores, can't overlap any patches, so they skip searching for
+    /// patches to merge with. Their patches are split into blocks aligned on the other axes
+    /// instead, so reads of a range along the append axis find the same layout throughout.
+    fn set_quilt_append_axis(&mut self, quilt_name: &str, axis_name: Option<&str>) -> Fallible<()>;
+
+    /// Adjust a quilt's target_patch_bytes() according to how its fetches used its patches
+    ///
+    /// `trace` is the quilt's counters over some period, usually from
+    /// PerformanceCounters::snapshot_since_tuning(). Fetches read whole patches but may only
+    /// use part of them, the rest being shuffled for nothing. If fetches use less than a quarter
+    /// of what they read, the target is halved, so later commits make smaller patches. If they
+    /// use most of it but need many patches each, the target is doubled.
+    ///
+    /// Returns None without changing anything if there were too few fetches to tell,
+    /// otherwise the target, whether it changed or not.
+    fn tune_target_patch_bytes(
+        &mut self,
+        quilt_name: &str,
+        trace: &EnumMap<Counter, usize>,
+    ) -> Fallible<Option<usize>> {
+        let fetches = trace[Counter::Fetch];
+        let shuffled = trace[Counter::ApplyShuffledBytes];
+        if fetches < MIN_TUNING_FETCHES || shuffled == 0 {
+            return Ok(None);
+        }
+        self.get_quilt_details(quilt_name)?;
+        self.trace_quilt(quilt_name);
+        let current = self.target_patch_bytes();
+        let efficiency = trace[Counter::ApplyCopiedBytes] as f64 / shuffled as f64;
+        let patches_per_fetch = trace[Counter::ReadPatch] as f64 / fetches as f64;
+        let target = if efficiency < 0.25 {
+            current / 2
+        } else if efficiency > 0.75 && patches_per_fetch > 4.0 {
+            current * 2
+        } else {
+            current
+        };
+        let target = target.max(TUNED_PATCH_BYTES.0).min(TUNED_PATCH_BYTES.1);
+        if target != current {
+            self.set_quilt_target_patch_bytes(quilt_name, Some(target))?;
+        }
+        Ok(Some(target))
+    }
+
+    /// List all the quilts in the catalog
+    fn list_quilts(&mut self) -> Fallible<HashMap<String, QuiltDetails>>;
+
+    /// List the tags of a quilt, sorted by name
+    fn list_tags(&mut self, quilt_name: &str) -> Fallible<Vec<String>>;
+
+    /// Create a namespace, so that quilts named like "namespace/quilt" can be created in it
+    ///
+    /// Namespaces let several projects share one catalog without their quilt names colliding,
+    /// and configure all of a project's quilts at once. Namespace names can't contain "/".
+    /// Returns false, leaving the existing configuration alone, if it already exists.
+    fn create_namespace(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<bool>;
+
+    /// Get the configuration of a namespace
+    fn get_namespace_config(&mut self, namespace: &str) -> Fallible<NamespaceConfig>;
+
+    /// Replace the configuration of a namespace
+    ///
+    /// Like the settings of a quilt, this only affects later commits.
+    fn set_namespace_config(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<()>;
+
+    /// List the namespaces in the catalog, sorted by name
+    fn list_namespaces(&mut self) -> Fallible<Vec<String>>;
+
+    /// List the quilts in a namespace, by their full names
+    fn list_quilts_in(&mut self, namespace: &str) -> Fallible<HashMap<String, QuiltDetails>>;
+
+    /// List all the patches that intersect a bounding box
+    ///
+    /// There may be false positives; some patches may not actually overlap
+    /// There are no false negatives; all patches that overlap will be returned
+    ///
+    /// This method exists in case the database supports efficient multidimensional range queries
+    /// such as SQLite or Postgres/PostGIS
+    fn search(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        deep: bool,
+        bounds: &[BoundingBox],
+    ) -> Fallible<Vec<PatchRef>>;
+
+    /// The commit a tag points to, or None if the tag doesn't exist
+    fn get_tag_commit(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<i64>>;
+
+    /// The size patches should be in storage, after compression
+    ///
+    /// Patches larger than this are split when they are committed.
+    fn target_patch_bytes(&self) -> usize;
+
+    /// The compression used for patches written in this transaction
+    fn patch_compression(&self) -> PatchCompressionType;
+
+    /// Set how much more than they were given commits in this transaction may write
+    ///
+    /// This overrides the catalog's setting, see Catalog::set_write_amplification_limit()
+    fn set_write_amplification_limit(&mut self, limit: WriteAmplificationLimit);
+
+    /// Get a single patch by ID
+    fn get_patch(&mut self, id: PatchID) -> Fallible<Patch>;
+
+    /// Create the empty patch a fetch assembles its result in
+    ///
+    /// Storage with a buffer pool can reuse memory here, see BufferPool.
+    fn new_target_patch(&mut self, axes: Vec<Axis>) -> Fallible<Patch> {
+        Patch::new(axes, None)
+    }
+
+    /// Give up a patch that was only needed for a moment, so its memory can be reused
+    fn recycle_patch(&mut self, _patch: Patch) {}
+
+    /// Get a single patch for reading, tolerating missing or unreadable content if allowed
+    ///
+    /// In recovery mode, a patch with missing content is skipped with a warning, and reads
+    /// will have a hole (NANs) where it would have been. If skipping unreadable patches, one
+    /// that can't be decoded is skipped the same way, and reported with its bounding box.
+    /// Otherwise this is just get_patch().
+    fn get_patch_or_hole(&mut self, patch_ref: &PatchRef) -> Fallible<Option<Patch>> {
+        let result = self.get_patch(patch_ref.id);
+        self.patch_or_hole(patch_ref, result)
+    }
+
+    /// Tolerate the result of reading a patch like get_patch_or_hole() does
+    fn patch_or_hole(
+        &mut self,
+        patch_ref: &PatchRef,
+        result: Fallible<Patch>,
+    ) -> Fallible<Option<Patch>> {
+        match result {
+            Err(StoiError::NotFound(what, detail)) if self.recovery_mode() => {
+                self.warn(format!(
+                    "no record found for the {} {}, so it was left as a hole",
+                    what, detail
+                ));
+                Ok(None)
+            }
+            Err(err) if err.is_unreadable_patch() && self.skip_unreadable() => {
+                self.skip_patch(SkippedPatch {
+                    patch_id: patch_ref.id,
+                    bounding_box: patch_ref.bounding_box,
+                    reason: err.to_string(),
+                });
+                Ok(None)
+            }
+            other => other.map(Some),
+        }
+    }
+
+    /// The stored content of a patch, to decode somewhere else with Patch::deserialize_stored()
+    ///
+    /// Patches without content of their own, like those packed into patchsets, are None,
+    /// and have to be read with get_patch() instead.
+    fn get_patch_content(&mut self, id: PatchID) -> Fallible<Option<Vec<u8>>>;
+
+    /// Stream the patches a fetch of these bounding boxes applies, in the order to apply them
+    ///
+    /// Layers of an overlay are streamed from the bottom one up, so the layers above overwrite
+    /// them, and a patch shared by several layers is only streamed the last time it's applied.
+    /// If the patches are more than max_read_bytes() decompressed, this fails with TooLarge
+    /// before reading any of them. See PatchStream.
+    fn stream_patches(
+        &mut self,
+        quilt_name: &str,
+        tag: &TagExpr,
+        bounding_boxes: &[BoundingBox],
+    ) -> Fallible<PatchStream<Self>> {
+        // The bottom layer is applied first, so the layers above overwrite it
+        let mut patch_refs = vec![];
+        for layer in tag.layers().iter().rev() {
+            patch_refs.extend(self.search(&quilt_name, layer, true, bounding_boxes)?);
+        }
+        // Layers often share patches from common ancestors. Applying a patch overwrites
+        // everything it applied before, so only its last application matters.
+        let last_application: HashMap<PatchID, usize> = patch_refs
+            .iter()
+            .enumerate()
+            .map(|(ix, patch_ref)| (patch_ref.id, ix))
+            .collect();
+        let patch_refs = patch_refs
+            .into_iter()
+            .enumerate()
+            .filter(|(ix, patch_ref)| last_application[&patch_ref.id] == *ix)
+            .map(|(_, patch_ref)| patch_ref)
+            .collect_vec();
+
+        if let Some(max_read_bytes) = self.max_read_bytes() {
+            if patch_refs.iter().map(|r| r.decompressed_size).sum::<u64>() > max_read_bytes {
+                return Err(StoiError::TooLarge(
+                    "the fetch would read more than CatalogOptions::max_read_bytes of patches",
+                ));
+            }
+        }
+        Ok(PatchStream {
+            batch_bytes: self.target_patch_bytes() as u64,
+            txn: self,
+            pending: patch_refs.into_iter(),
+            ready: Default::default(),
+            decoder: None,
+            decoding: false,
+            applied_stats: ApplyStats::default(),
+        })
+    }
+
+    /// The most bytes of patches one fetch may read, decompressed, see CatalogOptions
+    fn max_read_bytes(&self) -> Option<u64>;
+
+    /// The coldest patches of a quilt, until they add up to at least `bytes` of content
+    ///
+    /// This is for moving patches nobody reads to cheaper storage, while the hot ones stay.
+    /// Patches never read come first, oldest first, and then the rest by when they were last
+    /// read. Reads are sampled, about one in 16, so tracking them is cheap. Samples are saved
+    /// when write transactions finish, so read transactions only count from the next write.
+    /// Patches packed into patchsets are left out, since they can only move with their set.
+    fn get_coldest_patches(&mut self, quilt_name: &str, bytes: u64) -> Fallible<Vec<PatchAccess>>;
+
+    /// Whether reads should tolerate missing patches, see CatalogOptions
+    fn recovery_mode(&self) -> bool;
+
+    /// Whether reads should skip patches that can't be decoded, see CatalogOptions
+    fn skip_unreadable(&self) -> bool;
+
+    /// Record a patch that a read skipped, rather than returning an error
+    fn skip_patch(&mut self, skipped: SkippedPatch);
+
+    /// Take the patches skipped so far in this transaction, see CatalogOptions
+    fn take_skipped_patches(&mut self) -> Vec<SkippedPatch>;
+
+    /// Record a problem that was tolerated, rather than returning an error
+    fn warn(&mut self, message: String);
+
+    /// Take the warnings recorded so far in this transaction
+    fn take_warnings(&mut self) -> Vec<String>;
+
+    /// Get all the labels of an axis, in the order you would expect them to be stored.
+    ///
+    /// Returns an empty axis if this axis is missing.
+    fn get_axis(&mut self, name: &str) -> Fallible<&Axis>;
+
+    /// Get the storage index of every label of an axis, see Axis::label_index()
+    ///
+    /// This is built once per axis and kept up to date as labels are added, so looking up
+    /// labels for bounding boxes and selections doesn't scan the whole axis each time.
+    fn get_label_index(&mut self, name: &str) -> Fallible<&LabelIndex>;
+
+    /// Get the labels of an axis sorted by value, see Axis::sorted_index()
+    ///
+    /// Like get_label_index(), this is only built when it's needed, and kept until the axis
+    /// changes, so LabelRange selections only sort an axis once.
+    fn get_sorted_label_index(&mut self, name: &str) -> Fallible<&SortedLabelIndex>;
+
+    /// Get the generation of an axis, which changes every time labels are added to it
+    ///
+    /// Anything computed from an axis, like bounding boxes, is still valid as long as the
+    /// generation hasn't changed. An axis that doesn't exist yet is generation 0.
+    /// Generations are random rather than counted, so one made by a transaction that rolled
+    /// back is never made again for other labels.
+    fn get_axis_generation(&mut self, name: &str) -> Fallible<u64>;
+
+    /// Get an ID for this catalog, unique within the process
+    ///
+    /// Anything cached outside the catalog, like the bounding boxes patches carry, keeps this
+    /// too, so it's never used with another catalog.
+    fn get_catalog_id(&self) -> i64;
+
+    /// Get a range of the labels of an axis, by storage index
+    ///
+    /// Unlike get_axis(), this doesn't need to read the whole axis, so it's much cheaper for
+    /// large axes when you only need a few labels. The range is clipped to the axis length.
+    fn get_axis_slice(&mut self, name: &str, range: std::ops::Range<usize>) -> Fallible<Axis>;
+
+    /// Get the number of labels in an axis, without reading the axis
+    ///
+    /// Returns 0 if this axis is missing.
+    fn get_axis_len(&mut self, name: &str) -> Fallible<usize>;
+
+    /// Check that patches match the axes of a quilt, and add their labels to the catalog
+    ///
+    /// This is the first step of create_commit(), and returns the quilt's details.
+    fn union_patch_axes(
+        &mut self,
+        quilt_name: &str,
+        patches: &[&Patch],
+    ) -> Fallible<QuiltDetails> {
+        // Check that the axes are consistent
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+        for (ix, patch) in patches.iter().enumerate() {
+            if let Some(problem) = patch_axes_problem(&quilt_details.axes, patch) {
+                return Err(StoiError::MisalignedAxes(format!(
+                    "in the quilt \"{}\", patch {}: {}. Please note broadcasting is not (yet) supported. The patch axes should match exactly.",
+                    quilt_name, ix, problem
+                )));
+            }
+        }
+
+        // Extend all axes as necessary to complete the patching
+        for axis_name in &quilt_details.axes {
+            let mut axis = self.get_axis(axis_name)?.clone();
+            let mut mutated = false;
+            for patch in patches {
+                // Linear search over max 4 elements so don't sweat it
+                mutated |= axis.union(&patch.axes().iter().find(|a| &a.name == axis_name).unwrap());
+            }
+            if mutated {
+                // This is actually quite expensive so it's worth avoiding it where possible
+                self.union_axis(&axis)?;
+            }
+        }
+        Ok(quilt_details)
+    }
+
+    /// Commit a patch to a quilt.
+    ///
+    /// Commits are a pretty expensive operation - the system is designed for more reads than writes.
+    /// In specific, it will do at least all the following:
+    ///
+    /// - Get quilt details, including full copies of all the axes
+    /// - Check, compact, and compress all the patches, splitting and balancing search indices
+    /// - Extend all the axes (if necessary) to include the area the patches cover
+    /// - Upload all the patches and their data
+    /// - Log the commit and change the tags to point to it
+    ///
+    /// The message must follow the catalog's CommitRules, if it has any.
+    fn create_commit(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+    ) -> Fallible<()> {
+        self.check_commit_message(message)?;
+        create_generated_commit(self, quilt_name, parent_tag, new_tag, message, patches)
+    }
+
+    /// Commit patches to a quilt like create_commit(), at most once for each commit key
+    ///
+    /// If a commit was already made on this quilt with the same key, returns its id without
+    /// writing anything. Otherwise commits and returns the id of the new commit.
+    fn create_commit_keyed(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+        commit_key: &str,
+    ) -> Fallible<i64> {
+        if let Some(comm_id) = self.get_keyed_commit(quilt_name, commit_key)? {
+            return Ok(comm_id);
+        }
+        self.create_commit(quilt_name, parent_tag, new_tag, message, patches)?;
+        self.put_commit_key(quilt_name, new_tag, commit_key)
+    }
+
+    /// Make a new commit, checking every patch first and reporting all the problems at once
+    ///
+    /// Unlike create_commit(), which stops at the first patch that doesn't fit the quilt,
+    /// this checks every patch and reports each one that doesn't, with its index and axes.
+    /// If any are rejected, nothing is committed, unless `allow_partial` is set, when the rest
+    /// are committed. See CommitReport.
+    fn create_commit_report(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+        allow_partial: bool,
+    ) -> Fallible<CommitReport> {
+        let quilt_axes = self.get_quilt_details(quilt_name)?.axes;
+        let mut report = CommitReport::default();
+        for (ix, patch) in patches.iter().enumerate() {
+            match patch_axes_problem(&quilt_axes, patch) {
+                Some(problem) => report.rejected.push((ix, problem)),
+                None => report.committed.push(ix),
+            }
+        }
+        if report.committed.is_empty() || (!report.rejected.is_empty() && !allow_partial) {
+            report.committed.clear();
+            return Ok(report);
+        }
+        let valid = report.committed.iter().map(|&ix| patches[ix]).collect_vec();
+        self.create_commit(quilt_name, parent_tag, new_tag, message, &valid)?;
+        Ok(report)
+    }
+
+    /// Commit many scattered single cell updates to a quilt
+    ///
+    /// Each point is the labels of one cell, in the order of the quilt's axes, and its new value.
+    /// Rather than one dense patch covering every point, which is mostly NAN, the points are
+    /// grouped by aligned blocks of storage indices (see Axis::get_block()) and each block
+    /// becomes one patch with only the labels its points use. Points scattered across a block
+    /// are split further, so they fill most of their patch. Then the patches are split to
+    /// size and committed at once, like create_commit(). If a cell repeats, the last value wins.
+    fn create_commit_points(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        points: &[(Vec<Label>, f32)],
+    ) -> Fallible<()> {
+        self.check_commit_message(message)?;
+        self.trace_quilt(quilt_name);
+        self.trace(Counter::CreateCommit, 1);
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+        let ndim = quilt_details.axes.len();
+        if points.iter().any(|(labels, _value)| labels.len() != ndim) {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the quilt \"{}\" has axes [{}], so every point needs {} labels",
+                quilt_name,
+                quilt_details.axes.iter().join(", "),
+                ndim
+            )));
+        }
+
+        // Extend the axes first, so that every label has a storage index
+        let mut storage_indices = vec![];
+        for (ax_ix, axis_name) in quilt_details.axes.iter().enumerate() {
+            let mut axis = self.get_axis(axis_name)?.clone();
+            let labels = points.iter().map(|(labels, _value)| labels[ax_ix]).unique();
+            if axis.union(&Axis::new_unchecked(axis_name, labels.collect())) {
+                self.union_axis(&axis)?;
+            }
+            let axis = self.get_axis(axis_name)?;
+            let label_to_idx: HashMap<Label, u64> = axis
+                .labels()
+                .iter()
+                .enumerate()
+                .map(|(ix, &label)| (label, ix as u64))
+                .collect();
+            storage_indices.push(label_to_idx);
+        }
+
+        // Only the last value of each cell is kept, since the points could be split apart
+        let mut last_points: HashMap<&[Label], usize> = HashMap::new();
+        for (point_ix, (labels, _value)) in points.iter().enumerate() {
+            last_points.insert(labels.as_slice(), point_ix);
+        }
+
+        // Group the points by the block they fall in along every axis
+        let mut blocks: BTreeMap<Vec<u64>, Vec<usize>> = BTreeMap::new();
+        for point_ix in last_points.values().copied().sorted() {
+            let block = points[point_ix]
+                .0
+                .iter()
+                .zip(&storage_indices)
+                .map(|(label, label_to_idx)| {
+                    let ix = label_to_idx[label];
+                    Axis::get_block(ix, ix | (SCATTER_BLOCK_WIDTH - 1)).0
+                })
+                .collect_vec();
+            blocks.entry(block).or_default().push(point_ix);
+        }
+
+        // A patch has every combination of its points' labels, so points spread over a block
+        // could still make a huge patch of mostly NAN. Those are split at the middle of the
+        // axis they spread the most on, until they're dense enough.
+        let storage_index = |point_ix: usize, ax_ix: usize| {
+            storage_indices[ax_ix][&points[point_ix].0[ax_ix]]
+        };
+        let mut groups = vec![];
+        let mut pending = blocks.into_values().collect_vec();
+        while let Some(mut point_ixs) = pending.pop() {
+            let spread = (0..ndim)
+                .map(|ax_ix| {
+                    point_ixs
+                        .iter()
+                        .map(|&p| storage_index(p, ax_ix))
+                        .unique()
+                        .count()
+                })
+                .collect_vec();
+            let cells = spread.iter().product::<usize>();
+            if cells <= SCATTER_CELLS_PER_POINT * point_ixs.len() {
+                groups.push(point_ixs);
+                continue;
+            }
+            // There are at least two points, or there would only be one cell
+            let widest = (0..ndim).max_by_key(|&ax_ix| spread[ax_ix]).unwrap_or(0);
+            point_ixs.sort_by_key(|&p| storage_index(p, widest));
+            let upper = point_ixs.split_off(point_ixs.len() / 2);
+            pending.push(point_ixs);
+            pending.push(upper);
+        }
+
+        let mut patches = vec![];
+        for point_ixs in &groups {
+            let coords = quilt_details
+                .axes
+                .iter()
+                .enumerate()
+                .map(|(ax_ix, axis_name)| {
+                    let labels = point_ixs.iter().map(|&p| points[p].0[ax_ix]).collect();
+                    (axis_name.clone(), labels)
+                })
+                .collect_vec();
+            let values = point_ixs.iter().map(|&p| points[p].1).collect_vec();
+            let mut patch = Patch::from_coo(coords, &values)?;
+            quilt_details.to_stored(patch.content_mut())?;
+            patches.extend(self.maybe_split(patch)?);
+        }
+        self.put_commit(
+            quilt_name,
+            parent_tag,
+            new_tag,
+            message,
+            &patches.iter().collect_vec(),
+        )
+    }
+
+    /// Commit patches to a quilt, and record a data quality report alongside the commit
+    ///
+    /// This is create_commit() plus CommitStats, which is a little more CPU since it reads
+    /// every element before it's written. The report is returned and is also retrievable later
+    /// with get_commit_stats().
+    fn create_commit_with_stats(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+    ) -> Fallible<CommitStats> {
+        let stats = CommitStats::from_patches(patches);
+        self.create_commit(quilt_name, parent_tag, new_tag, message, patches)?;
+        self.put_commit_stats(quilt_name, new_tag, &stats)?;
+        Ok(stats)
+    }
+
+    /// Make changes to a tensor via a commit
+    ///
+    /// This is only available together, so that the underlying storage media can do this
+    /// atomically without a complicated API
+    ///
+    /// This needs a write transaction (Catalog::begin()), so that it can't race with
+    /// commits from other processes.
+    ///
+    /// If the quilt has a quota and the commit would leave it over, this fails with
+    /// StoiError::QuotaExceeded and makes no changes.
+    fn put_commit(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+    ) -> Fallible<()>;
+
+    /// Make changes to a tensor via a commit, with patches prepared outside the transaction
+    ///
+    /// This is put_commit() for Catalog::commit(), which splits and serializes patches before
+    /// it begins the transaction. Prepared patches are only serialized again if they need to
+    /// be merged with existing patches, or the compression or quantization changed since.
+    fn put_commit_prepared(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: Vec<PreparedPatch>,
+    ) -> Fallible<()>;
+
+    /// Get the rules every commit message must follow, which are empty unless they were set
+    fn get_commit_rules(&mut self) -> Fallible<CommitRules>;
+
+    /// Set the rules every commit message must follow from now on, for the whole catalog
+    ///
+    /// Existing commits are left as they are. Fails if the pattern isn't a valid regex.
+    fn set_commit_rules(&mut self, rules: &CommitRules) -> Fallible<()>;
+
+    /// Check a commit message against the catalog's CommitRules, see CommitRules::check()
+    fn check_commit_message(&mut self, message: &str) -> Fallible<()> {
+        self.get_commit_rules()?.check(message)
+    }
+
+    /// Save a data quality report for the commit a tag points to
+    fn put_commit_stats(&mut self, quilt_name: &str, tag: &str, stats: &CommitStats)
+        -> Fallible<()>;
+
+    /// Get the data quality report for the commit a tag points to
+    ///
+    /// Returns None if the commit was made without a report
+    fn get_commit_stats(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<CommitStats>>;
+
+    /// Record the key of the commit a tag points to, returning the id of that commit
+    ///
+    /// Keys are unique within a quilt, so recording one twice is an error.
+    fn put_commit_key(&mut self, quilt_name: &str, tag: &str, commit_key: &str) -> Fallible<i64>;
+
+    /// Get the id of the commit made on a quilt with this key, if there is one
+    fn get_keyed_commit(&mut self, quilt_name: &str, commit_key: &str) -> Fallible<Option<i64>>;
+
+    /// Mark a point in the transaction that can be rolled back to, without ending it
+    ///
+    /// Long ingestion jobs can checkpoint their progress this way, and undo only the last step
+    /// if it fails, while nothing is visible outside the transaction until finish().
+    /// Savepoints nest, and names are letters, digits and underscores.
+    fn savepoint(&mut self, name: &str) -> Fallible<()>;
+
+    /// Keep everything since a savepoint, and forget it and any savepoints made after it
+    fn release(&mut self, name: &str) -> Fallible<()>;
+
+    /// Undo everything since a savepoint, which is kept so it can be rolled back to again
+    ///
+    /// Savepoints made after it are forgotten.
+    fn rollback_to(&mut self, name: &str) -> Fallible<()>;
+
+    /// Rollback the transaction
+    fn rollback(self) -> Fallible<()>;
+
+    /// Finish and commit the transaction successfully
+    fn finish(self) -> Fallible<()>;
+
+    /// Use the actual axis values to resolve a request into specific labels
+    ///
+    /// This is necessary because we need to turn the axis labels into storage indices for range queries
+    fn resolve_selection(
+        &mut self,
+        name: &str,
+        sel: AxisSelection,
+    ) -> Fallible<(Axis, Vec<AxisSegment>)> {
+        self.trace(Counter::ResolveSelection, 1);
+        Ok(match sel {
+            AxisSelection::All => {
+                let axis = self.get_axis(&name)?;
+                let full_range = (0, axis.len());
+                (axis.clone(), vec![full_range])
+            }
+            AxisSelection::Labels(labels) => {
+                // Labels the axis doesn't have can't be stored, so they don't widen the segment,
+                // and without any it has, there's nothing to search for
+                let index = self.get_label_index(&name)?;
+                let segments = labels
+                    .iter()
+                    .filter_map(|label| index.get(label).copied())
+                    .minmax()
+                    .into_option()
+                    .into_iter()
+                    .collect();
+                (Axis::new(name, labels)?, segments)
+            }
+            AxisSelection::LabelSlice(start, end) => {
+                // Axis labels are not guaranteed to be sorted because it may be optimized for storage, not lookup
+                let index = self.get_label_index(&name)?;
+                let (start_ix, end_ix) =
+                    Axis::find_label_slice(name, start, end, |label| index.get(&label).copied())?;
+                let axis = self.get_axis(&name)?;
+                (
+                    Axis::new_unchecked(&axis.name, Vec::from(&axis.labels()[start_ix..end_ix])),
+                    vec![(start_ix, end_ix)],
+                )
+            }
+            AxisSelection::LabelRange(start, end) => {
+                let index = self.get_sorted_label_index(&name)?;
+                let storage_ixs = Axis::find_label_range(index, start, end);
+                let axis = self.get_axis(&name)?;
+                let labels = storage_ixs.iter().map(|&ix| axis.labels()[ix]).collect();
+                // Labels stored next to each other share a segment
+                let mut segments: Vec<AxisSegment> = vec![];
+                for ix in storage_ixs {
+                    match segments.last_mut() {
+                        Some(last) if last.1 == ix => last.1 = ix + 1,
+                        _ => segments.push((ix, ix + 1)),
+                    }
+                }
+                if segments.len() > MAX_RANGE_SEGMENTS {
+                    segments = vec![(segments[0].0, segments[segments.len() - 1].1)];
+                }
+                (Axis::new_unchecked(&axis.name, labels), segments)
+            }
+            AxisSelection::StorageSlice(start_ix, end_ix) => {
+                let axis = self.get_axis(&name)?;
+                let lab = axis.labels();
+                if start_ix > end_ix || end_ix > lab.len() {
+                    return Err(StoiError::InvalidValue(
+                        "storage slice ends before it starts, or after the end of the axis",
+                    ));
+                }
+                (
+                    Axis::new(&axis.name, Vec::from(&lab[start_ix..end_ix]))?,
+                    vec![(start_ix, end_ix)],
+                )
+            }
+        })
+    }
+
+    /// Replace the labels of an axis, in the order you would expect them to be stored.
+    ///
+    /// Returns true iff the axis was mutated in the process
+    fn union_axis(&mut self, new_axis: &Axis) -> Fallible<bool>;
+
+    /// Name labels of the MEASURE_AXIS, so quilts can keep several values for each cell
+    ///
+    /// Names that were already declared keep their labels, and new names get the next labels,
+    /// which are appended to the axis. Returns the label of each name, in the same order.
+    fn declare_measures(&mut self, names: &[&str]) -> Fallible<Vec<Label>>;
+
+    /// Get the name of every label of the MEASURE_AXIS that has one, in label order
+    fn get_measures(&mut self) -> Fallible<Vec<(String, Label)>>;
+
+    /// Put selections named by axis into the order of the quilt's axes, to use with fetch()
+    ///
+    /// Axes without a selection are taken in full. Naming an axis the quilt doesn't have is an
+    /// UnknownAxis error listing the quilt's axes, since it's usually a typo that would
+    /// otherwise fetch the whole axis. If `strict` is false, those selections are ignored.
+    fn order_named_request(
+        &mut self,
+        quilt_name: &str,
+        named: Vec<(String, AxisSelection)>,
+        strict: bool,
+    ) -> Fallible<Vec<AxisSelection>> {
+        let quilt_axes = self.get_quilt_details(quilt_name)?.axes;
+        let mut request = vec![AxisSelection::All; quilt_axes.len()];
+        for (axis_name, selection) in named {
+            match quilt_axes.iter().position(|name| name == &axis_name) {
+                Some(ax_ix) => request[ax_ix] = selection,
+                None if strict => {
+                    return Err(StoiError::UnknownAxis(
+                        quilt_name.to_string(),
+                        axis_name,
+                        quilt_axes.join(", "),
+                    ))
+                }
+                None => (),
+            }
+        }
+        Ok(request)
+    }
+
+    /// Resolve a fetch request into the axes of the result and the bounding boxes to search
+    ///
+    /// This is the part of fetch() that happens before any patches are read.
+    /// Any axes missing from the end of the request are taken in full, but more selections
+    /// than the quilt has axes is a MisalignedAxes error.
+    fn resolve_request(
+        &mut self,
+        quilt_name: &str,
+        mut request: Vec<AxisSelection>,
+    ) -> Fallible<(Vec<Axis>, Vec<BoundingBox>)> {
+        //
+        // Find all the labels of the axes they are planning to use
+        //
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+        if request.len() > quilt_details.axes.len() {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the quilt \"{}\" has {} axes [{}] but the request has {} selections",
+                quilt_name,
+                quilt_details.axes.len(),
+                quilt_details.axes.join(", "),
+                request.len()
+            )));
+        }
+
+        // Names and all labels of all of the axes involved
+        let mut axes = vec![];
+        // Segments of each axis, which will be the edges of bounding boxes
+        let mut segments_by_axis = vec![];
+
+        // They can't possibly use more than ten axes - just a safety measure.
+        request.reverse(); // So we can iterate it and take ownership
+        for axis_name in &quilt_details.axes {
+            let (axis, segments) = match request.pop() {
+                Some(sel) => self.resolve_selection(axis_name, sel)?,
+                None => self.resolve_selection(axis_name, AxisSelection::All)?,
+            };
+            axes.push(axis);
+            segments_by_axis.push(segments);
+        }
+        assert!(
+            axes.len() >= 1,
+            StoiError::MisalignedAxes("No axes for quilt in fetch()".into())
+        );
+
+        Ok((axes, bounding_boxes_of(&segments_by_axis)?))
+    }
+
+    /// Fetch a patch from a quilt.
+    ///
+    /// - You can request any slice, and it will be assembled from the underlying commits.
+    ///   - How many patches it's assembled from depends on the storage order
+    ///     (which is the order the labels are specified in the axis, not in your request)
+    /// - You can request elements you haven't initialized yet, and you'll get NANs.
+    /// - You can only request patches up to 1 GB, as a safety valve
+    /// - If the quilt has units, the values are converted from their stored form
+    /// - The tag can also be a TagExpr, to layer several tags over one another
+    fn fetch<'a, T: Into<TagExpr<'a>>>(
+        &mut self,
+        quilt_name: &str,
+        tag: T,
+        request: Vec<AxisSelection>,
+    ) -> Fallible<Patch> {
+        let mut patch = self.fetch_stored(quilt_name, tag, request)?;
+        if let Some(units) = self.get_quilt_details(quilt_name)?.units {
+            units.from_stored(patch.content_mut());
+        }
+        Ok(patch)
+    }
+
+    /// Fetch selections named by axis, with the axes of the patch in the order they were named
+    ///
+    /// This is order_named_request() and fetch() together, except fetch() always gives the
+    /// quilt's order. Axes that weren't named follow the named ones, in the quilt's order.
+    fn fetch_named<'a, T: Into<TagExpr<'a>>>(
+        &mut self,
+        quilt_name: &str,
+        tag: T,
+        named: Vec<(String, AxisSelection)>,
+        strict: bool,
+    ) -> Fallible<Patch> {
+        let quilt_axes = self.get_quilt_details(quilt_name)?.axes;
+        let order = named
+            .iter()
+            .map(|(axis_name, _)| axis_name)
+            .chain(&quilt_axes)
+            .filter(|&axis_name| quilt_axes.contains(axis_name))
+            .unique()
+            .cloned()
+            .collect_vec();
+        let request = self.order_named_request(quilt_name, named, strict)?;
+        let patch = self.fetch(quilt_name, tag, request)?;
+        patch.permute_axes(&order.iter().map(|name| name.as_str()).collect_vec())
+    }
+
+    /// Fetch the same selections from several quilts, aligned on the axes they share
+    ///
+    /// Each axis is resolved only once, so wherever quilts share an axis, their patches have
+    /// exactly the same labels in the same order, and can be combined cell by cell, like
+    /// forecasts against actuals, or price times volume. Two separate fetches could disagree
+    /// if labels were added in between. Each patch has the axes of its quilt in the quilt's
+    /// order, and axes without a selection are taken in full.
+    ///
+    /// Naming an axis that none of the quilts have is an InvalidSelection error.
+    /// Returns one patch for each (quilt, tag), in the same order.
+    fn fetch_joined(
+        &mut self,
+        sources: &[(&str, &str)],
+        named: Vec<(String, AxisSelection)>,
+    ) -> Fallible<Vec<Patch>> {
+        let quilt_axes = sources
+            .iter()
+            .map(|(quilt_name, _)| Ok(self.get_quilt_details(quilt_name)?.axes))
+            .collect::<Fallible<Vec<Vec<String>>>>()?;
+        let mut named: HashMap<String, AxisSelection> = named.into_iter().collect();
+        if let Some(axis_name) = named
+            .keys()
+            .find(|&axis_name| !quilt_axes.iter().any(|axes| axes.contains(axis_name)))
+        {
+            return Err(StoiError::InvalidSelection(format!(
+                "none of the quilts [{}] have an axis {}",
+                sources.iter().map(|(quilt_name, _)| quilt_name).join(", "),
+                axis_name
+            )));
+        }
+
+        // Resolve every axis once, for all of the quilts
+        let mut resolved: HashMap<String, (Axis, Vec<AxisSegment>)> = HashMap::new();
+        for axis_name in quilt_axes.iter().flatten() {
+            if !resolved.contains_key(axis_name) {
+                let selection = named.remove(axis_name).unwrap_or(AxisSelection::All);
+                let axis = self.resolve_selection(axis_name, selection)?;
+                resolved.insert(axis_name.clone(), axis);
+            }
+        }
+
+        let mut patches = vec![];
+        for ((quilt_name, tag), axis_names) in sources.iter().zip(quilt_axes) {
+            let (axes, segments_by_axis): (Vec<Axis>, Vec<Vec<AxisSegment>>) = axis_names
+                .iter()
+                .map(|axis_name| resolved[axis_name].clone())
+                .unzip();
+            let bounding_boxes = bounding_boxes_of(&segments_by_axis)?;
+            let tag = TagExpr::from(*tag);
+            let mut patch = self.fetch_resolved(quilt_name, &tag, axes, &bounding_boxes)?;
+            if let Some(units) = self.get_quilt_details(quilt_name)?.units {
+                units.from_stored(patch.content_mut());
+            }
+            patches.push(patch);
+        }
+        Ok(patches)
+    }
+
+    /// Fetch a patch from a quilt, exactly as it is stored
+    ///
+    /// This is the same as fetch(), except that quilt units are not applied.
+    /// Anything that writes the result back into the quilt, like compaction, should use this.
+    fn fetch_stored<'a, T: Into<TagExpr<'a>>>(
+        &mut self,
+        quilt_name: &str,
+        tag: T,
+        request: Vec<AxisSelection>,
+    ) -> Fallible<Patch> {
+        let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+        self.fetch_resolved(quilt_name, &tag.into(), axes, &bounding_boxes)
+    }
+
+    /// Estimate what a fetch would return and how much it would read, without reading patches
+    ///
+    /// This is useful to warn before a fetch that would be rejected for being too large.
+    fn estimate_fetch(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        request: Vec<AxisSelection>,
+    ) -> Fallible<FetchEstimate> {
+        let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+        let patch_refs = self.search(quilt_name, tag, true, &bounding_boxes)?;
+        let shape = axes.iter().map(|a| a.len()).collect_vec();
+        let elements = shape.iter().product::<usize>();
+        Ok(FetchEstimate {
+            shape,
+            elements,
+            patches: patch_refs.len(),
+            bytes: elements * std::mem::size_of::<f32>(),
+            read_bytes: patch_refs.iter().map(|r| r.decompressed_size).sum(),
+            too_large: elements > MAX_FETCH_ELEMENTS,
+        })
+    }
+
+    /// Inspect how a tag of a quilt is laid out in storage, and recommend improvements
+    ///
+    /// This only reads the patch index, plus a small sample of patches to see how densely
+    /// they fill their bounding boxes, so it's much cheaper than fetching the whole tag.
+    fn analyze_layout(&mut self, quilt_name: &str, tag: &str) -> Fallible<LayoutReport> {
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+        let patch_refs = self.search(quilt_name, tag, true, &[BoundingBox::everywhere()])?;
+        let bytes: u64 = patch_refs.iter().map(|r| r.decompressed_size).sum();
+        let median_patch_bytes = patch_refs
+            .iter()
+            .map(|r| r.decompressed_size)
+            .sorted()
+            .nth(patch_refs.len() / 2)
+            .unwrap_or(0);
+
+        // Patches come oldest first, so anything inside a later box may be hidden by it
+        let occluded_bytes: u64 = patch_refs
+            .iter()
+            .enumerate()
+            .filter(|(ix, older)| {
+                patch_refs[ix + 1..]
+                    .iter()
+                    .any(|newer| newer.bounding_box.contains(&older.bounding_box))
+            })
+            .map(|(_, older)| older.decompressed_size)
+            .sum();
+
+        // Read evenly spaced patches to see how much of their span on each axis they use
+        let step = (patch_refs.len() / LAYOUT_SAMPLE_PATCHES).max(1);
+        let mut fill_sums = vec![0.; quilt_details.axes.len()];
+        let mut weight_sum = 0.;
+        for patch_ref in patch_refs.iter().step_by(step) {
+            let patch = self.get_patch(patch_ref.id)?;
+            let weight = patch_ref.decompressed_size as f64;
+            for (ax_ix, axis) in patch.axes().iter().enumerate() {
+                let (start, end) = patch_ref.bounding_box[ax_ix];
+                fill_sums[ax_ix] += weight * axis.len() as f64 / (end + 1 - start) as f64;
+            }
+            weight_sum += weight;
+        }
+        let axis_fill = quilt_details
+            .axes
+            .iter()
+            .cloned()
+            .zip(fill_sums.into_iter().map(|sum| match weight_sum {
+                w if w > 0. => sum / w,
+                _ => 1.,
+            }))
+            .collect_vec();
+
+        let mut advice = vec![];
+        for (axis, fill) in &axis_fill {
+            if *fill < MIN_AXIS_FILL {
+                advice.push(LayoutAdvice::Reorder {
+                    axis: axis.clone(),
+                    fill: *fill,
+                });
+            }
+        }
+        let occluded_fraction = occluded_bytes as f64 / bytes.max(1) as f64;
+        if occluded_fraction > MAX_OCCLUDED_FRACTION {
+            advice.push(LayoutAdvice::Squash {
+                occluded_bytes,
+                fraction: occluded_fraction,
+            });
+        }
+        let target = self.target_patch_bytes() as u64;
+        let patches_after = ((bytes - occluded_bytes) / target.max(1)) as usize + 1;
+        if patch_refs.len() > 1
+            && (median_patch_bytes as f64) < MIN_PATCH_FILL * target as f64
+            && patches_after < patch_refs.len()
+        {
+            advice.push(LayoutAdvice::Compact {
+                median_patch_bytes,
+                patches_after,
+            });
+        }
+        Ok(LayoutReport {
+            patches: patch_refs.len(),
+            bytes,
+            median_patch_bytes,
+            occluded_bytes,
+            axis_fill,
+            reads: None,
+            advice,
+        })
+    }
+
+    /// Fetch using a plan, which only resolves the request again if the quilt's axes changed
+    ///
+    /// Otherwise this is the same as fetch(). The plan is updated if it had to be resolved.
+    fn fetch_planned(&mut self, plan: &mut FetchPlan, tag: &str) -> Fallible<Patch> {
+        let generations = plan
+            .axis_names
+            .iter()
+            .map(|name| self.get_axis_generation(name))
+            .collect::<Fallible<Vec<u64>>>()?;
+        if plan.resolved.is_none() || generations != plan.generations {
+            plan.resolved = Some(self.resolve_request(&plan.quilt_name, plan.request.clone())?);
+            plan.generations = generations;
+        }
+        let (axes, bounding_boxes) = plan.resolved.as_ref().unwrap(); // <- Resolved just above
+        let mut patch = self.fetch_resolved(
+            &plan.quilt_name,
+            &TagExpr::Tag(tag),
+            axes.clone(),
+            bounding_boxes,
+        )?;
+        if let Some(units) = self.get_quilt_details(&plan.quilt_name)?.units {
+            units.from_stored(patch.content_mut());
+        }
+        Ok(patch)
+    }
+
+    /// Divide a selection of a tag into a grid of chunks, to fetch one at a time
+    ///
+    /// `chunk_shape` has the most labels a chunk can have along each of the quilt's axes.
+    /// The grid is pinned to the commit the tag points to now, so fetch_chunk() returns
+    /// consistent chunks even if the tag moves before they are all fetched.
+    fn plan_chunks(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        request: Vec<AxisSelection>,
+        chunk_shape: &[usize],
+    ) -> Fallible<ChunkGrid> {
+        let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+        if chunk_shape.len() != axes.len() {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the quilt \"{}\" has {} axes but the chunk shape has {}",
+                quilt_name,
+                axes.len(),
+                chunk_shape.len()
+            )));
+        }
+        if chunk_shape.contains(&0) {
+            return Err(StoiError::InvalidValue(
+                "chunks must have at least one label per axis",
+            ));
+        }
+        Ok(ChunkGrid {
+            quilt_name: quilt_name.into(),
+            comm_id: self.get_tag_commit(quilt_name, tag)?,
+            patch_refs: self.search(quilt_name, tag, true, &bounding_boxes)?,
+            axes,
+            chunk_shape: chunk_shape.to_vec(),
+        })
+    }
+
+    /// Fetch one chunk of a grid planned with plan_chunks(), from the commit it was planned on
+    ///
+    /// Otherwise this is the same as fetch(). If the tag didn't exist, the chunk is all NAN.
+    /// If the commit's patches were rewritten since the grid was planned, this fails with
+    /// StaleChunks, and the grid should be planned again.
+    fn fetch_chunk(&mut self, grid: &ChunkGrid, key: &[usize]) -> Fallible<Patch> {
+        let chunk_axes = grid.chunk_axes(key)?;
+        let request = chunk_axes
+            .iter()
+            .map(|axis| AxisSelection::Labels(axis.labels().to_vec()))
+            .collect();
+        let (axes, bounding_boxes) = self.resolve_request(&grid.quilt_name, request)?;
+        if axes.iter().map(|a| a.len()).product::<usize>() > MAX_FETCH_ELEMENTS {
+            return Err(StoiError::TooLarge(
+                "Patches must be 256 million elements or less (1GB of 32bit floats)",
+            ));
+        }
+        self.trace_quilt(&grid.quilt_name);
+        self.trace(Counter::Fetch, 1);
+
+        // Only the patches of the planned commit, in the same order a fetch would apply them
+        let mut patch = self.new_target_patch(axes)?;
+        let mut applied = ApplyStats::default();
+        for patch_ref in &grid.patch_refs {
+            if !bounding_boxes
+                .iter()
+                .any(|bx| boxes_overlap(bx, &patch_ref.bounding_box))
+            {
+                continue;
+            }
+            let source_patch = match self.get_patch(patch_ref.id) {
+                Err(StoiError::NotFound(..)) => {
+                    return Err(StoiError::StaleChunks(grid.quilt_name.clone()))
+                }
+                source_patch => source_patch?,
+            };
+            let stats = patch.apply_counted(&source_patch)?;
+            self.trace_apply(stats);
+            applied += stats;
+            self.recycle_patch(source_patch);
+        }
+        self.trace_visible(applied, patch.content());
+        if let Some(units) = self.get_quilt_details(&grid.quilt_name)?.units {
+            units.from_stored(patch.content_mut());
+        }
+        Ok(patch)
+    }
+
+    /// Fetch the stored values of a request that was already resolved with resolve_request()
+    fn fetch_resolved(
+        &mut self,
+        quilt_name: &str,
+        tag: &TagExpr,
+        axes: Vec<Axis>,
+        bounding_boxes: &[BoundingBox],
+    ) -> Fallible<Patch> {
+        self.trace_quilt(quilt_name);
+        self.trace(Counter::Fetch, 1);
+
+        // At this point we know how big the output will be.
+        // The error here is early to avoid the IO
+        // and we don't construct the patch (which would have noticed and raised the same error)
+        // in order to avoid holding memory longer
+        if axes.iter().map(|a| a.len()).product::<usize>() > MAX_FETCH_ELEMENTS {
+            return Err(StoiError::TooLarge(
+                "Patches must be 256 million elements or less (1GB of 32bit floats)",
+            ));
+        }
+
+        // Patches decode in the background while the ones before them are applied
+        let mut target_patch = self.new_target_patch(axes)?;
+        let mut patches = self.stream_patches(quilt_name, tag, bounding_boxes)?;
+        while let Some(next) = patches.next() {
+            let (_, source_patch) = next?;
+            let stats = target_patch.apply_counted(&source_patch)?;
+            patches.applied(source_patch, stats);
+        }
+        let applied = patches.applied_stats();
+        drop(patches);
+        self.trace_visible(applied, target_patch.content());
+        Ok(target_patch)
+    }
+
+    /// Fetch a slice of a quilt directly into a buffer you provide
+    ///
+    /// This works like fetch(), but instead of allocating a new Patch, it assembles the result
+    /// in `buffer`, which could be a memory-mapped array or shared memory.
+    /// The whole buffer is overwritten, with NANs wherever there is no data.
+    ///
+    /// - The buffer must have exactly the shape of the selection, in the order of the quilt's axes
+    /// - Since the memory already exists, there is no size limit like there is for fetch()
+    ///
+    /// Returns the axes of the selection, which label the buffer
+    fn fetch_into(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        request: Vec<AxisSelection>,
+        buffer: &mut nd::ArrayViewMutD<f32>,
+    ) -> Fallible<Vec<Axis>> {
+        self.trace_quilt(quilt_name);
+        self.trace(Counter::Fetch, 1);
+        let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+        if axes.len() != buffer.ndim()
+            || axes
+                .iter()
+                .zip(buffer.shape())
+                .any(|(axis, &width)| axis.len() != width)
+        {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the buffer has shape {:?} but the selection has shape {:?}",
+                buffer.shape(),
+                axes.iter().map(|a| a.len()).collect_vec()
+            )));
+        }
+
+        let mut view = Patch::view_4d(buffer.view_mut())?;
+        view.fill(std::f32::NAN);
+
+        let mut patches = self.stream_patches(quilt_name, &TagExpr::Tag(tag), &bounding_boxes)?;
+        while let Some(next) = patches.next() {
+            let (_, source_patch) = next?;
+            let stats = Patch::apply_to_view(&axes, view.view_mut(), &source_patch)?;
+            patches.applied(source_patch, stats);
+        }
+        let applied = patches.applied_stats();
+        drop(patches);
+        self.trace_visible(applied, buffer.view());
+        if let Some(units) = self.get_quilt_details(quilt_name)?.units {
+            units.from_stored(buffer.view_mut());
+        }
+        Ok(axes)
+    }
+
+    /// Fetch the same slice of a quilt from several tags at once
+    ///
+    /// The result has an extra first axis named "tag", labeled by the index of each tag in
+    /// `tags`, so the quilt can have at most three axes. This is much cheaper than fetching each
+    /// tag separately, since the selection is only resolved once, and patches shared by the tags
+    /// (like those in common ancestors) are only read once.
+    fn fetch_many(
+        &mut self,
+        quilt_name: &str,
+        tags: &[&str],
+        request: Vec<AxisSelection>,
+    ) -> Fallible<Patch> {
+        self.trace_quilt(quilt_name);
+        self.trace(Counter::Fetch, 1);
+        let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+        if axes.len() > 3 {
+            return Err(StoiError::MisalignedAxes(
+                "fetch_many() needs an extra axis, so the quilt can have at most 3 axes".into(),
+            ));
+        }
+        if tags.len() * axes.iter().map(|a| a.len()).product::<usize>() > 256 << 20 {
+            return Err(StoiError::TooLarge(
+                "Patches must be 256 million elements or less (1GB of 32bit floats)",
+            ));
+        }
+
+        let mut patch_axes = vec![Axis::range("tag", 0..tags.len() as Label)];
+        patch_axes.extend(axes.iter().cloned());
+        let mut target_patch = Patch::new(patch_axes, None)?;
+
+        let mut patch_cache: HashMap<PatchID, Option<Patch>> = HashMap::new();
+        let mut applied = ApplyStats::default();
+        for (tag_ix, tag) in tags.iter().enumerate() {
+            let patch_refs = self.search(&quilt_name, tag, true, &bounding_boxes)?;
+            let mut content = target_patch.content_mut();
+            let mut view = Patch::view_4d(content.index_axis_mut(nd::Axis(0), tag_ix))?;
+            for patch_ref in patch_refs {
+                if !patch_cache.contains_key(&patch_ref.id) {
+                    let source_patch = self.get_patch_or_hole(&patch_ref)?;
+                    patch_cache.insert(patch_ref.id, source_patch);
+                }
+                if let Some(source_patch) = &patch_cache[&patch_ref.id] {
+                    let stats = Patch::apply_to_view(&axes, view.view_mut(), source_patch)?;
+                    self.trace_apply(stats);
+                    applied += stats;
+                }
+            }
+        }
+        self.trace_visible(applied, target_patch.content());
+        if let Some(units) = self.get_quilt_details(quilt_name)?.units {
+            units.from_stored(target_patch.content_mut());
+        }
+        Ok(target_patch)
+    }
+
+    /// Fetch several selections of the same tag at once, like many calls to fetch()
+    ///
+    /// Dashboards often need dozens of small slices at a time. This searches for the patches of
+    /// all the selections in one query, and reads each patch only once, even if it's used by
+    /// several selections. Returns one patch for each request, in the same order.
+    fn fetch_many_selections(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        requests: Vec<Vec<AxisSelection>>,
+    ) -> Fallible<Vec<Patch>> {
+        self.trace_quilt(quilt_name);
+        self.trace(Counter::Fetch, requests.len());
+        let mut resolved = vec![];
+        for request in requests {
+            let (axes, bounding_boxes) = self.resolve_request(quilt_name, request)?;
+            if axes.iter().map(|a| a.len()).product::<usize>() > MAX_FETCH_ELEMENTS {
+                return Err(StoiError::TooLarge(
+                    "Patches must be 256 million elements or less (1GB of 32bit floats)",
+                ));
+            }
+            resolved.push((axes, bounding_boxes));
+        }
+        let all_boxes = resolved
+            .iter()
+            .flat_map(|(_, boxes)| boxes.iter().copied())
+            .collect_vec();
+        let patch_refs = self.search(quilt_name, tag, true, &all_boxes)?;
+
+        let mut targets = vec![];
+        for (axes, _) in &resolved {
+            targets.push(self.new_target_patch(axes.clone())?);
+        }
+        let mut applied = vec![ApplyStats::default(); targets.len()];
+        for patch_ref in patch_refs {
+            let hits = resolved
+                .iter()
+                .positions(|(_, boxes)| {
+                    boxes
+                        .iter()
+                        .any(|bx| boxes_overlap(bx, &patch_ref.bounding_box))
+                })
+                .collect_vec();
+            if hits.is_empty() {
+                continue;
+            }
+            if let Some(source_patch) = self.get_patch_or_hole(&patch_ref)? {
+                for ix in hits {
+                    let stats = targets[ix].apply_counted(&source_patch)?;
+                    self.trace_apply(stats);
+                    applied[ix] += stats;
+                }
+                self.recycle_patch(source_patch);
+            }
+        }
+        for (target, applied) in targets.iter().zip(applied) {
+            self.trace_visible(applied, target.content());
+        }
+        if let Some(units) = self.get_quilt_details(quilt_name)?.units {
+            for target in &mut targets {
+                units.from_stored(target.content_mut());
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Fetch a slice of a quilt, filling the cells missing from one tag with another tag
+    ///
+    /// Every cell that is NAN in the `primary_tag`'s version of the selection comes from
+    /// `fallback_tag` instead, e.g. "manual_overrides" falling back to "model_output".
+    /// This happens while the patches are assembled, so neither tag's slice is fetched whole,
+    /// and patches both tags share are only read once. It's the same as fetching
+    /// TagExpr::Overlay(vec![primary_tag, fallback_tag]).
+    fn fetch_with_fallback(
+        &mut self,
+        quilt_name: &str,
+        primary_tag: &str,
+        fallback_tag: &str,
+        request: Vec<AxisSelection>,
+    ) -> Fallible<Patch> {
+        self.fetch(
+            quilt_name,
+            TagExpr::Overlay(vec![primary_tag, fallback_tag]),
+            request,
+        )
+    }
+
+    /// Copy a selection from one quilt and tag to another, as one commit
+    ///
+    /// This never assembles the whole selection. Instead each stored patch that intersects it is
+    /// trimmed to the selection and committed in its original order, so patch boundaries are
+    /// mostly preserved and memory use is bounded by the largest patch.
+    ///
+    /// The quilts must have the same axes. If they have different units, values are converted.
+    fn copy(
+        &mut self,
+        src_quilt: &str,
+        src_tag: &str,
+        request: Vec<AxisSelection>,
+        dst_quilt: &str,
+        dst_tag: &str,
+        message: &str,
+    ) -> Fallible<()> {
+        let src_units = self.get_quilt_details(src_quilt)?.units;
+        let (axes, bounding_boxes) = self.resolve_request(src_quilt, request)?;
+        let labelsets = axes
+            .iter()
+            .map(|a| (a.name.clone(), a.labelset()))
+            .collect::<HashMap<String, HashSet<Label>>>();
+
+        let mut trimmed_patches = vec![];
+        for patch_ref in self.search(src_quilt, src_tag, true, &bounding_boxes)? {
+            let source_patch = match self.get_patch_or_hole(&patch_ref)? {
+                Some(source_patch) => source_patch,
+                None => continue,
+            };
+            // Keep only the labels in the selection, in the patch's own order
+            let trimmed_axes = source_patch
+                .axes()
+                .iter()
+                .map(|axis| {
+                    Axis::new_unchecked(
+                        &axis.name,
+                        axis.labels()
+                            .iter()
+                            .copied()
+                            .filter(|label| labelsets[&axis.name].contains(label))
+                            .collect(),
+                    )
+                })
+                .collect_vec();
+            if trimmed_axes.iter().any(|a| a.len() == 0) {
+                continue;
+            }
+            let mut trimmed = Patch::new(trimmed_axes, None)?;
+            trimmed.apply(&source_patch)?;
+            if let Some(units) = &src_units {
+                units.from_stored(trimmed.content_mut());
+            }
+            trimmed_patches.push(trimmed.compact().into_owned());
+        }
+        self.create_commit(
+            dst_quilt,
+            dst_tag,
+            dst_tag,
+            message,
+            &trimmed_patches.iter().collect_vec(),
+        )
+    }
+
+    /// Commit a patch derived from a selection of one quilt into a companion quilt
+    ///
+    /// The selection is fetched from `tag` of `src_quilt`, passed through `derive`, and the
+    /// result is committed to the same tag of `dst_quilt`, so the companion follows its source.
+    /// For example, to keep weekly totals of a daily quilt:
+    ///
+    ///     # use stoicheia::*;
+    ///     # fn main() -> Fallible<()> {
+    ///     # let cat = Catalog::connect("")?;
+    ///     # let mut txn = cat.begin()?;
+    ///     # txn.create_quilt("daily_sales", &["itm", "day"])?;
+    ///     # txn.create_quilt("weekly_sales", &["itm", "day"])?;
+    ///     txn.commit_derived("daily_sales", "latest", vec![], "weekly_sales", |daily| {
+    ///         daily.coarsen("day", &Coarsening::Factor(7), ReduceOp::Sum)
+    ///     })?;
+    ///     # Ok(())
+    ///     # }
+    ///
+    /// Values are passed in the source quilt's units, and converted to the companion's.
+    fn commit_derived<F: FnOnce(Patch) -> Fallible<Patch>>(
+        &mut self,
+        src_quilt: &str,
+        tag: &str,
+        request: Vec<AxisSelection>,
+        dst_quilt: &str,
+        derive: F,
+    ) -> Fallible<()> {
+        let source = self.fetch(src_quilt, tag, request)?;
+        let derived = derive(source)?;
+        let message = format!("derived from {} at {}", src_quilt, tag);
+        create_generated_commit(self, dst_quilt, tag, tag, &message, &[&derived])
+    }
+
+    /// Split patches the way a commit would, so they line up with the catalog's storage
+    ///
+    /// Ingestion tools can use this to split their data before committing it, such as in
+    /// many threads with a short transaction each, so the commit itself has less to do.
+    /// Committing the planned patches stores the same thing as committing the originals.
+    ///
+    /// Splitting follows the storage order of each axis, so this adds any new labels of the
+    /// patches to the catalog's axes first, which is a write. Use get_bounding_box() to see
+    /// where each planned patch will be stored.
+    fn plan_patches(&mut self, patches: &[Patch]) -> Fallible<Vec<Patch>> {
+        let mut axes: BTreeMap<&str, Axis> = BTreeMap::new();
+        for axis in patches.iter().flat_map(|p| p.axes()) {
+            if !axes.contains_key(axis.name.as_str()) {
+                axes.insert(&axis.name, self.get_axis(&axis.name)?.clone());
+            }
+        }
+        for (name, mut global_axis) in axes {
+            let mut mutated = false;
+            for patch in patches {
+                if let Some(axis) = patch.axes().iter().find(|a| a.name == name) {
+                    mutated |= global_axis.union(axis);
+                }
+            }
+            if mutated {
+                self.union_axis(&global_axis)?;
+            }
+        }
+
+        let mut planned = vec![];
+        for patch in patches {
+            planned.extend(self.maybe_split(patch.compact().into_owned())?);
+        }
+        Ok(planned)
+    }
+
+    /// Split a patch in half if it's larger than it probably should be.
+    ///
+    /// This is decided by the estimated size after compression, compared to
+    /// target_patch_bytes(), so that patches in storage are a similar size regardless of how
+    /// well their content compresses.
+    ///
+    /// Accepts:
+    ///     long_axis: the global axis to split in half
+    ///
+    /// Returns:
+    ///     Either: A vec with one element, which is a Cow::Borrowed(&self)
+    ///     Or: A vec with 2+ elements, which are all Cow::Owned(Patch)
+    fn maybe_split(&mut self, original: Patch) -> Fallible<Vec<Patch>> {
+        let target_patch_bytes = self.target_patch_bytes();
+        let compression = self.patch_compression();
+        let (parts, splits) = split_patch(
+            original,
+            &mut |name: &str| Ok(self.get_axis(name)?.clone()),
+            target_patch_bytes,
+            compression,
+        )?;
+        // Each split checks both halves again
+        self.trace(Counter::MaybeSplit, 1 + 2 * splits);
+        self.trace(Counter::Split, splits);
+        Ok(parts)
+    }
+
+    /// Estimate which labels of an axis have the most data in a quilt, without reading patches
+    ///
+    /// This uses only the sizes and bounding boxes of the patches visible from `tag`, spreading
+    /// each patch's decompressed bytes evenly over its span of the axis. So it's an estimate:
+    /// NANs and occluded patches count like data, and labels inside a span share its bytes
+    /// even if a patch skips some of them.
+    ///
+    /// Returns up to `k` labels with their estimated bytes, the most first.
+    fn top_labels(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        axis_name: &str,
+        k: usize,
+    ) -> Fallible<Vec<(Label, f64)>> {
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+        let ax_ix = quilt_details
+            .axes
+            .iter()
+            .position(|name| name == axis_name)
+            .ok_or_else(|| StoiError::NotFound("axis of the quilt", axis_name.to_string()))?;
+        let patch_refs = self.search(quilt_name, tag, true, &[BoundingBox::everywhere()])?;
+        let axis = self.get_axis(axis_name)?;
+        let mut bytes = vec![0.; axis.len()];
+        for patch_ref in patch_refs {
+            let (start, end) = patch_ref.bounding_box[ax_ix];
+            let end = end.min(axis.len().saturating_sub(1));
+            if start > end {
+                continue;
+            }
+            let share = patch_ref.decompressed_size as f64 / (end + 1 - start) as f64;
+            for ix_bytes in &mut bytes[start..=end] {
+                *ix_bytes += share;
+            }
+        }
+        Ok(bytes
+            .into_iter()
+            .enumerate()
+            .filter(|(_ix, ix_bytes)| *ix_bytes > 0.)
+            .sorted_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal))
+            .take(k)
+            .map(|(ix, ix_bytes)| (axis.labels()[ix], ix_bytes))
+            .collect())
+    }
+
+    /// Get the bounding box of a patch
+    ///
+    /// These bounding boxes depend on the storage order of the catalog, so they aren't something
+    /// the Patch could know on its own, instead you find this through the catalog
+    ///
+    /// Each segment is the first and last storage index of the patch's labels on that axis,
+    /// inclusive. Unused axes are unbounded. Labels must already be in the catalog's axes.
+    /// Segments are in the order of the patch's axes, so searches only match them if that's
+    /// the quilt's order, which is how commits store patches.
+    ///
+    /// Patches remember their bounding box, so it's only found again if an axis of the patch
+    /// has changed since, or the patch is from another catalog.
+    fn get_bounding_box(&mut self, patch: &Patch) -> Fallible<BoundingBox> {
+        let catalog_id = self.get_catalog_id();
+        let generations = patch
+            .axes()
+            .iter()
+            .map(|patch_axis| self.get_axis_generation(&patch_axis.name))
+            .collect::<Fallible<Vec<u64>>>()?;
+        if let Some(bounding_box) = patch.global_bounds(catalog_id, &generations) {
+            self.trace(Counter::ReuseBoundingBox, 1);
+            return Ok(bounding_box);
+        }
+        self.trace(Counter::GetBoundingBox, 1);
+        let segments = patch
+            .axes()
+            .iter()
+            .map(|patch_axis| {
+                let index = self.get_label_index(&patch_axis.name)?;
+                let storage_indices = patch_axis
+                    .labels()
+                    .iter()
+                    .filter_map(|label| index.get(label).copied());
+                Ok(storage_indices
+                    .minmax()
+                    .into_option()
+                    .unwrap_or((0, BoundingBox::UNBOUNDED)))
+            })
+            .collect::<Fallible<Vec<AxisSegment>>>()?;
+        let bounding_box = BoundingBox::from_segments(segments)?;
+        patch.set_global_bounds(GlobalBounds {
+            catalog_id,
+            generations,
+            bounding_box,
+        });
+        Ok(bounding_box)
+    }
+
+    /// Get tight intervals of storage indices of a patch, see PatchIntervals
+    ///
+    /// Like get_bounding_box(), these depend on the storage order of the catalog, and labels
+    /// must already be in the catalog's axes.
+    fn get_patch_intervals(&mut self, patch: &Patch) -> Fallible<PatchIntervals> {
+        let axes = patch
+            .axes()
+            .iter()
+            .map(|patch_axis| {
+                let index = self.get_label_index(&patch_axis.name)?;
+                Ok(patch_axis
+                    .labels()
+                    .iter()
+                    .filter_map(|label| index.get(label).copied())
+                    .collect())
+            })
+            .collect::<Fallible<Vec<Vec<usize>>>>()?;
+        Ok(PatchIntervals::from_indices(
+            axes,
+            PatchIntervals::MAX_INTERVALS,
+        ))
+    }
+
+    /// Rewrite the patches of a tag's commit in a region into as few patches as possible
+    ///
+    /// All the patches of the commit that intersect `bounding_box`, and any others of the
+    /// commit that overlap them, are replaced with what they show together, split into
+    /// non-overlapping patches. This doesn't change what any fetch would return, but it reduces
+    /// read amplification in hot regions.
+    ///
+    /// Only the commit the tag points to is read and rewritten, since ancestors may be shared
+    /// by other tags, and copying their content into it would only make it bigger. Patches are
+    /// always rewritten in the current format, and if there is only one patch in the region
+    /// it is still upgraded if it uses an older format.
+    /// Returns the number of patches that were replaced or upgraded.
+    fn compact_region(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        bounding_box: BoundingBox,
+    ) -> Fallible<usize>;
+
+    /// Rewrite a patch in place in the current format, if it was written in an older one
+    ///
+    /// The content doesn't change, and it keeps the quantization it was stored with, so this
+    /// is safe even for patches shared by many tags.
+    /// Returns whether the patch needed to be upgraded.
+    fn upgrade_patch(&mut self, id: PatchID) -> Fallible<bool>;
+
+    /// Derive a quilt from another, or stop deriving it if `derivation` is None
+    ///
+    /// From then on, every commit to `src_quilt` queues work to update the same tag of
+    /// `dst_quilt` over the region the commit changed. The work is queued in the same
+    /// transaction as the commit, so it can't be lost, and it's done by run_derived_work().
+    /// Data committed before the rule was set isn't derived, use commit_derived() for that.
+    /// Quilts can't be derived from themselves, directly or through other derived quilts.
+    fn set_derived_quilt(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        derivation: Option<Derivation>,
+    ) -> Fallible<()>;
+
+    /// Get how one quilt is derived from another, if it is
+    fn get_derivation(&mut self, src_quilt: &str, dst_quilt: &str) -> Fallible<Option<Derivation>>;
+
+    /// Get the rules deriving quilts from `src_quilt`, or from any quilt, and their staleness
+    fn get_derived_quilts(&mut self, src_quilt: Option<&str>) -> Fallible<Vec<DerivedQuiltStatus>>;
+
+    /// Queue work to derive a whole tag of a derived quilt again, from all of its source
+    ///
+    /// This is how existing data of the source is derived, since set_derived_quilt() only
+    /// queues work for later commits.
+    fn queue_derived_refresh(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        tag: &str,
+    ) -> Fallible<()>;
+
+    /// Create a quilt that stoicheia keeps as an aggregate over one axis of another
+    ///
+    /// The new quilt has the same axes as `src_quilt`, but on `axis_name` it only has `label`,
+    /// which is the aggregate of all the labels there, see Derivation::Aggregate.
+    /// Work is queued to build it from `tag` of the source, and then every commit to the source
+    /// queues work to refresh only the region that commit changed, on the other axes.
+    /// Run it with run_derived_work(), or Catalog::run_derived_work().
+    fn create_aggregate_quilt(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        tag: &str,
+        axis_name: &str,
+        op: ReduceOp,
+        label: Label,
+    ) -> Fallible<()> {
+        let axes = self.get_quilt_details(src_quilt)?.axes;
+        if !axes.iter().any(|name| name == axis_name) {
+            return Err(StoiError::NotFound(
+                "axis of the quilt",
+                axis_name.to_string(),
+            ));
+        }
+        self.create_quilt(dst_quilt, &axes.iter().map(|a| a.as_str()).collect_vec())?;
+        let derivation = Derivation::Aggregate {
+            axis_name: axis_name.to_string(),
+            op,
+            label,
+        };
+        self.set_derived_quilt(src_quilt, dst_quilt, Some(derivation))?;
+        self.queue_derived_refresh(src_quilt, dst_quilt, tag)
+    }
+
+    /// Get the oldest work queued for derived quilts, up to `limit` items
+    fn get_derived_work(&mut self, limit: usize) -> Fallible<Vec<DerivedWork>>;
+
+    /// Remove an item from the queue of work for derived quilts, once it's done
+    fn finish_derived_work(&mut self, work_id: i64) -> Fallible<()>;
+
+    /// Do up to `limit` of the oldest work queued for derived quilts
+    ///
+    /// Each item leaves the queue in the same transaction as the commit that does it, so if
+    /// the transaction fails or is rolled back, the item is done again later. That is, work is
+    /// done at least once, which is fine since deriving the same region twice changes nothing.
+    /// Derived commits queue more work if other quilts are derived from them.
+    ///
+    /// Returns how many items were done.
+    fn run_derived_work(&mut self, limit: usize) -> Fallible<usize> {
+        let work = self.get_derived_work(limit)?;
+        for item in &work {
+            // The rule may have been removed since the work was queued
+            if let Some(derivation) = self.get_derivation(&item.src_quilt, &item.dst_quilt)? {
+                let axes = self.get_quilt_details(&item.src_quilt)?.axes;
+                let mut request = vec![];
+                for (axis_name, &segment) in axes.iter().zip(item.bounding_box.iter()) {
+                    let axis_len = self.get_axis_len(axis_name)?;
+                    request.push(derivation.selection(axis_name, segment, axis_len));
+                }
+                self.commit_derived(&item.src_quilt, &item.tag, request, &item.dst_quilt, |p| {
+                    derivation.derive(p)
+                })?;
+            }
+            self.finish_derived_work(item.work_id)?;
+        }
+        Ok(work.len())
+    }
+
+    /// Get the commits of a quilt, each with its parent, patch count, and tags, oldest first
+    ///
+    /// Commits only belong to a quilt through its tags, so commits no tag leads to anymore,
+    /// like those of an untagged branch, aren't included. Commits past the quilt's retention
+    /// are marked expired.
+    fn get_history(&mut self, quilt_name: &str) -> Fallible<Vec<HistoryCommit>>;
+
+    /// Get the most recent entries of the audit log, newest first
+    ///
+    /// Every change to the catalog is recorded: creating quilts, commits, and compaction.
+    /// Only entries about `quilt_name` are included, if it's given.
+    fn get_audit_log(
+        &mut self,
+        quilt_name: Option<&str>,
+        limit: usize,
+    ) -> Fallible<Vec<AuditEntry>>;
+
+    /// Get what was captured when this catalog was made as a backup, if it was
+    ///
+    /// See Catalog::backup_to(). A backup of a backup gives the latest one.
+    fn get_snapshot(&mut self) -> Fallible<Option<CatalogSnapshot>>;
+
+    /// Get up to `limit` commits to a quilt after `last`, oldest first
+    ///
+    /// Commits are ordered by their sequence and then their id, and `last` is the sequence and
+    /// id of the last commit already seen. See Catalog::changes_since(), which pages through
+    /// all of them.
+    fn get_changes_since(
+        &mut self,
+        quilt_name: &str,
+        last: (i64, i64),
+        limit: usize,
+    ) -> Fallible<Vec<CommitChanges>>;
+
+    /// Keep a visibility index for a tag, so fetches don't need to walk its ancestry
+    ///
+    /// The index is the set of patches a fetch from the tag could need. It is built now,
+    /// and then updated on each commit to the tag, which costs a little more on commit.
+    /// Patches completely hidden by a newer patch are dropped from the index as it goes.
+    /// Enabling it again just rebuilds it. Returns the number of patches indexed.
+    fn enable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize>;
+
+    /// Stop keeping a visibility index for a tag, and delete it
+    fn disable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<()>;
+
+    /// Build the visibility index of a tag again, by walking its ancestry
+    ///
+    /// Use this if check_visibility_index() reports problems. This reads every patch of the
+    /// tag, to find which ones hide the older patches inside them, so the index ends up the
+    /// same as if commits had kept it. Returns the number of patches indexed.
+    fn rebuild_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize>;
+
+    /// Compare the visibility index of a tag against its ancestry
+    ///
+    /// Returns a description of each problem found, so an empty list means it's consistent.
+    fn check_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<Vec<String>>;
+
+    /// Untag a commit, to "delete" it
+    ///
+    /// Untagging a commit doesn't remove its effects, it only makes it inaccessible
+    /// and allows (now or any time in the future) for the library to:
+    ///
+    /// - Merge it into its successors, if it has any
+    /// - Garbage collect it otherwise
+    ///
+    /// Commits that no tag can reach any more are garbage collected right away, see
+    /// collect_garbage(). Untagging a tag that doesn't exist is a NotFound error.
+    fn untag(&mut self, quilt_name: &str, tag: &str) -> Fallible<()>;
+
+    /// Retrieve performance counters, useful for debugging performance problems
+    ///
+    /// Returns: a Map containing the counters by name
+    fn get_performance_counters(&self) -> EnumMap<Counter, usize>;
+
+    /// Retrieve performance counters broken down by the quilt that caused them
+    ///
+    /// Counters traced before any quilt was involved are only in get_performance_counters().
+    fn get_performance_counters_by_quilt(&self) -> CounterBreakdown;
+
+    /// Retrieve the axis counters (ReadAxis, WriteAxisLabel, TrialAxisLabel) by axis
+    fn get_performance_counters_by_axis(&self) -> CounterBreakdown;
+}
+
+/// A data quality report for one commit
+///
+/// These are cheap to store, and a quick look at them can catch mistakes like an all-zero
+/// or all-NAN upload right away.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct CommitStats {
+    /// Total number of cells in all the patches, including NANs
+    pub cells_written: usize,
+    /// Number of NAN cells, which are not really written since they don't occlude anything
+    pub nans_written: usize,
+    /// Smallest value written, ignoring NANs, or None if everything was NAN
+    pub min: Option<f32>,
+    /// Largest value written, ignoring NANs, or None if everything was NAN
+    pub max: Option<f32>,
+    /// Number of distinct labels each axis covers, by axis name
+    pub axis_coverage: BTreeMap<String, usize>,
+}
+impl CommitStats {
+    /// Compute the report for a set of patches about to be committed
+    pub fn from_patches(patches: &[&Patch]) -> Self {
+        let mut stats = CommitStats::default();
+        let mut labels_by_axis: BTreeMap<String, HashSet<Label>> = BTreeMap::new();
+        for patch in patches {
+            for axis in patch.axes() {
+                labels_by_axis
+                    .entry(axis.name.clone())
+                    .or_default()
+                    .extend(axis.labels().iter().copied());
+            }
+            for &x in patch.content().iter() {
+                stats.cells_written += 1;
+                if x.is_nan() {
+                    stats.nans_written += 1;
+                } else {
+                    stats.min = Some(stats.min.map_or(x, |m| m.min(x)));
+                    stats.max = Some(stats.max.map_or(x, |m| m.max(x)));
+                }
+            }
+        }
+        stats.axis_coverage = labels_by_axis
+            .into_iter()
+            .map(|(name, labels)| (name, labels.len()))
+            .collect();
+        stats
+    }
+}
+
+/// Metadata about a quilt
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct QuiltDetails {
+    pub(crate) name: String,
+    pub(crate) axes: Vec<String>,
+    pub(crate) units: Option<QuiltUnits>,
+    pub(crate) quantization: PatchQuantization,
+    pub(crate) quota: Option<QuiltQuota>,
+    #[serde(default)]
+    pub(crate) namespace: Option<String>,
+    #[serde(default)]
+    pub(crate) integers: Option<QuiltIntegers>,
+}
+impl QuiltDetails {
+    /// The name of the quilt
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The names of the axes of the quilt, in order
+    pub fn axes(&self) -> &[String] {
+        &self.axes
+    }
+
+    /// The units of the quilt, if it has any
+    pub fn units(&self) -> Option<&QuiltUnits> {
+        self.units.as_ref()
+    }
+
+    /// How precisely new patches of the quilt are stored
+    pub fn quantization(&self) -> PatchQuantization {
+        self.quantization
+    }
+
+    /// How new patches of the quilt are actually serialized
+    ///
+    /// Quilts with units store whole numbers, so unless they were set to be quantized, those
+    /// are stored as integers.
+    pub(crate) fn storage_quantization(&self) -> PatchQuantization {
+        match (self.quantization, &self.units) {
+            (PatchQuantization::Exact, Some(_)) => PatchQuantization::Integers,
+            (quantization, _) => quantization,
+        }
+    }
+
+    /// The storage limits of the quilt, if it has any
+    pub fn quota(&self) -> Option<&QuiltQuota> {
+        self.quota.as_ref()
+    }
+
+    /// The namespace the quilt was created in, if any
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// The integer storage of the quilt, if it stores integers
+    pub fn integers(&self) -> Option<&QuiltIntegers> {
+        self.integers.as_ref()
+    }
+
+    /// Convert committed values into the form they are stored in, in place
+    ///
+    /// Units are converted first, so quilts of integers round the values as they're stored.
+    pub(crate) fn to_stored(&self, mut content: nd::ArrayViewMutD<f32>) -> Fallible<()> {
+        if let Some(units) = &self.units {
+            units.to_stored(content.view_mut());
+        }
+        if let Some(integers) = &self.integers {
+            integers.to_stored(content)?;
+        }
+        Ok(())
+    }
+}
+
+/// The structure and settings shared by many quilts, see create_quilts_from_template()
+///
+/// For example, one quilt per metric, all with the same axes and limits. Compression is set for
+/// the whole catalog, and missing values are always NAN, so neither is part of a template.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct QuiltTemplate {
+    /// The names of the axes, in order
+    pub axes: Vec<String>,
+    /// The units, see StorageTransaction::set_quilt_units()
+    pub units: Option<QuiltUnits>,
+    /// How precisely patches are stored, see StorageTransaction::set_quilt_quantization()
+    pub quantization: PatchQuantization,
+    /// The storage limits, see StorageTransaction::set_quilt_quota()
+    pub quota: Option<QuiltQuota>,
+    /// The size of patches, see StorageTransaction::set_quilt_target_patch_bytes()
+    pub target_patch_bytes: Option<usize>,
+    /// The axis appended along, see StorageTransaction::set_quilt_append_axis()
+    pub append_axis: Option<String>,
+    /// The integer storage, see StorageTransaction::set_quilt_integers()
+    #[serde(default)]
+    pub integers: Option<QuiltIntegers>,
+}
+impl QuiltTemplate {
+    /// A template with these axes, and the default for everything else
+    pub fn new(axes: &[&str]) -> Self {
+        QuiltTemplate {
+            axes: axes.iter().map(|name| name.to_string()).collect(),
+            ..QuiltTemplate::default()
+        }
+    }
+}
+
+/// Read a QuiltDetails from SQLite
+impl TryFrom<&rusqlite::Row<'_>> for QuiltDetails {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &rusqlite::Row) -> Result<Self, Self::Error> {
+        let units = match row.get::<_, Option<f64>>("unit_scale")? {
+            Some(scale) => Some(QuiltUnits {
+                unit: row.get("unit_name")?,
+                scale,
+                offset: row.get("unit_offset")?,
+            }),
+            None => None,
+        };
+        Ok(QuiltDetails {
+            name: row.get("quilt_name")?,
+            axes: serde_json::from_str(&row.get::<_, String>("axes")?)
+                // Fudging the error types here a little bit - but it's close
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+            units,
+            quantization: match row.get::<_, Option<String>>("quantization")? {
+                Some(quant) => serde_json::from_str(&quant)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+                None => PatchQuantization::Exact,
+            },
+            quota: match (
+                row.get::<_, Option<i64>>("max_bytes")?,
+                row.get::<_, Option<i64>>("max_patches")?,
+            ) {
+                (None, None) => None,
+                (max_bytes, max_patches) => Some(QuiltQuota {
+                    max_bytes: max_bytes.map(|x| x as u64),
+                    max_patches: max_patches.map(|x| x as u64),
+                }),
+            },
+            namespace: row.get("namespace")?,
+            integers: match row.get::<_, Option<String>>("integers")? {
+                Some(integers) => Some(
+                    serde_json::from_str(&integers)
+                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+                ),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Rules every commit message must follow, see StorageTransaction::set_commit_rules()
+///
+/// Audit tooling can rely on structured commits this way. Metadata is written as
+/// "key: value" lines after the first line of the message, like this:
+///
+/// ```text
+/// Refresh the weekly forecast
+///
+/// pipeline: forecast
+/// run_id: 2020-03-01-0042
+/// ```
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CommitRules {
+    /// The metadata keys every message must have, see commit_metadata()
+    pub required_metadata: Vec<String>,
+    /// A regular expression the first line of every message must match somewhere.
+    /// Anchor it with ^ and $ to match the whole line.
+    pub message_pattern: Option<String>,
+}
+impl CommitRules {
+    /// Check a commit message against the rules
+    ///
+    /// Missing metadata is reported all at once, with StoiError::MissingCommitMetadata,
+    /// before the format of the first line is checked.
+    pub fn check(&self, message: &str) -> Fallible<()> {
+        self.compiled()?.check(message)
+    }
+
+    /// Compile the message pattern, to check many messages without compiling it each time
+    pub(crate) fn compiled(&self) -> Fallible<CompiledCommitRules> {
+        let pattern = match &self.message_pattern {
+            Some(pattern) => Some(Self::compile(pattern)?),
+            None => None,
+        };
+        Ok(CompiledCommitRules {
+            required_metadata: self.required_metadata.clone(),
+            pattern,
+        })
+    }
+
+    /// Compile a message pattern, failing if it isn't a valid regex
+    pub(crate) fn compile(pattern: &str) -> Fallible<Regex> {
+        Regex::new(pattern).map_err(|_| {
+            StoiError::InvalidValue("the commit message pattern isn't a valid regular expression")
+        })
+    }
+}
+
+/// CommitRules with the message pattern already compiled, see CommitRules::compiled()
+#[derive(Clone, Debug)]
+pub(crate) struct CompiledCommitRules {
+    required_metadata: Vec<String>,
+    pattern: Option<Regex>,
+}
+impl CompiledCommitRules {
+    /// Check a commit message against the rules, see CommitRules::check()
+    pub fn check(&self, message: &str) -> Fallible<()> {
+        let metadata = commit_metadata(message);
+        let missing = self
+            .required_metadata
+            .iter()
+            .filter(|key| !metadata.contains_key(key.as_str()))
+            .cloned()
+            .collect_vec();
+        if !missing.is_empty() {
+            return Err(StoiError::MissingCommitMetadata(missing));
+        }
+        if let Some(pattern) = &self.pattern {
+            let first_line = message.lines().next().unwrap_or("");
+            if !pattern.is_match(first_line) {
+                return Err(StoiError::CommitMessageFormat(pattern.as_str().to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the "key: value" metadata lines of a commit message, see CommitRules
+///
+/// The first line is the summary, so it's never metadata. Keys can only have letters, digits,
+/// dashes and underscores, and the values are trimmed. If a key repeats, the last one wins.
+pub fn commit_metadata(message: &str) -> BTreeMap<String, String> {
+    message
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            let key = &line[..colon];
+            let is_key = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if is_key {
+                Some((key.to_string(), line[colon + 1..].trim().to_string()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Limits on the storage a quilt may use, so one quilt can't fill a shared catalog
+///
+/// Commits that would leave the quilt over either limit fail with StoiError::QuotaExceeded.
+/// Either limit can be None, to leave it unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct QuiltQuota {
+    /// The most bytes of (compressed) patch content the quilt's commits may store
+    pub max_bytes: Option<u64>,
+    /// The most patches the quilt's commits may store
+    pub max_patches: Option<u64>,
+}
+
+/// How much history a quilt keeps, see StorageTransaction::set_quilt_retention()
+///
+/// Commits past either limit expire, unless a tag points to them. Expired commits are merged
+/// into their parents by StorageTransaction::collect_expired_commits(), so the versions they
+/// recorded can't be fetched anymore, but what the tags fetch stays the same.
+/// Either limit can be None, to leave it unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct QuiltRetention {
+    /// Commits made more than this many days ago expire
+    pub max_age_days: Option<u32>,
+    /// Commits more than this many commits behind every tag expire
+    pub max_depth: Option<usize>,
+}
+impl QuiltRetention {
+    /// Mark the commits of a history that have expired, as of `now`
+    ///
+    /// Commits without a created_at, or with one that can't be read, never expire by age.
+    pub(crate) fn mark_expired(
+        &self,
+        history: &mut [HistoryCommit],
+        now: chrono::DateTime<chrono::Utc>,
+    ) {
+        let positions: HashMap<i64, usize> = history
+            .iter()
+            .enumerate()
+            .map(|(ix, commit)| (commit.comm_id, ix))
+            .collect();
+        // How far each commit is behind the nearest tag
+        let mut depths = vec![std::usize::MAX; history.len()];
+        for start in 0..history.len() {
+            if history[start].tags.is_empty() {
+                continue;
+            }
+            let mut next = Some(start);
+            let mut depth = 0;
+            while let Some(ix) = next {
+                if depths[ix] <= depth {
+                    // Another tag is nearer, to this commit and all its ancestors
+                    break;
+                }
+                depths[ix] = depth;
+                next = history[ix]
+                    .parent_comm_id
+                    .and_then(|parent| positions.get(&parent).copied());
+                depth += 1;
+            }
+        }
+        let max_age = self
+            .max_age_days
+            .map(|days| chrono::Duration::days(i64::from(days)));
+        for (commit, depth) in history.iter_mut().zip(depths) {
+            let created_at = commit
+                .created_at
+                .as_ref()
+                .and_then(|at| chrono::DateTime::parse_from_rfc3339(at).ok());
+            let too_old = match (max_age, created_at) {
+                (Some(max_age), Some(created_at)) => {
+                    now.signed_duration_since(created_at) > max_age
+                }
+                _ => false,
+            };
+            let too_deep = self.max_depth.map_or(false, |max_depth| depth > max_depth);
+            commit.expired = commit.tags.is_empty() && (too_old || too_deep);
+        }
+    }
+}
+
+/// Settings shared by the quilts of a namespace, see StorageTransaction::create_namespace()
+///
+/// Each one applies to the quilts in the namespace that don't have their own.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NamespaceConfig {
+    /// The storage limits of each quilt, see StorageTransaction::set_quilt_quota()
+    pub quota: Option<QuiltQuota>,
+    /// The size of patches, see StorageTransaction::set_quilt_target_patch_bytes()
+    pub target_patch_bytes: Option<usize>,
+}
+
+/// The storage used by the commits of a quilt, see StorageTransaction::get_quilt_usage()
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QuiltUsage {
+    /// Bytes of (compressed) patch content
+    pub bytes: u64,
+    /// Number of patches
+    pub patches: u64,
+}
+
+/// Units and an affine transform between the values you see and the values stored in a quilt
+///
+/// The values you fetch are `stored * scale + offset`, and commits are converted the other way.
+/// For example, dollars stored as cents would use a scale of 0.01. Stored values are rounded to
+/// whole numbers, like cents, which take only 2 or 4 bytes each, see PatchQuantization::Integers.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct QuiltUnits {
+    /// A human readable unit, like "USD"
+    pub unit: String,
+    pub scale: f64,
+    pub offset: f64,
+}
+impl QuiltUnits {
+    /// Create new units with a scale and offset
+    pub fn new<T: ToString>(unit: T, scale: f64, offset: f64) -> Fallible<Self> {
+        if scale == 0. || !scale.is_finite() || !offset.is_finite() {
+            return Err(StoiError::InvalidValue(
+                "Quilt unit scale must be finite and nonzero, and offset must be finite",
+            ));
+        }
+        Ok(QuiltUnits {
+            unit: unit.to_string(),
+            scale,
+            offset,
+        })
+    }
+
+    /// Convert values from the form they are stored in, in place
+    pub fn from_stored(&self, mut content: nd::ArrayViewMutD<f32>) {
+        let (scale, offset) = (self.scale, self.offset);
+        content.mapv_inplace(|x| (x as f64 * scale + offset) as f32);
+    }
+
+    /// Convert values into the form they are stored in, in place, rounding to whole numbers
+    pub fn to_stored(&self, mut content: nd::ArrayViewMutD<f32>) {
+        let (scale, offset) = (self.scale, self.offset);
+        content.mapv_inplace(|x| ((x as f64 - offset) / scale).round() as f32);
+    }
+}
+
+/// How values with a fraction are written to a quilt of integers, see QuiltIntegers
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntegerRounding {
+    /// Round to the nearest integer, and values halfway between to the even one, so 2.5 is 2
+    HalfEven,
+    /// Drop the fraction, rounding toward zero
+    Truncate,
+    /// Fail the commit with StoiError::FractionalValue
+    Error,
+}
+
+/// Integer values for a quilt, like counts, and how other values are made to fit
+///
+/// Values outside the range saturate to its ends, and so do infinities. NANs are still missing
+/// values. Content is stored as 32 bit floats for now, which hold every integer up to 2^24
+/// exactly, so the range can't go past that. See StorageTransaction::set_quilt_integers()
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QuiltIntegers {
+    /// The smallest value
+    pub min: i64,
+    /// The largest value
+    pub max: i64,
+    pub rounding: IntegerRounding,
+}
+impl QuiltIntegers {
+    /// The largest magnitude stored floats hold exactly, and so the widest range there can be
+    pub const MAX_EXACT: i64 = 1 << 24;
+
+    /// Create integer storage for values from `min` through `max`
+    pub fn new(min: i64, max: i64, rounding: IntegerRounding) -> Fallible<Self> {
+        if min > max || min < -Self::MAX_EXACT || max > Self::MAX_EXACT {
+            return Err(StoiError::InvalidValue(
+                "Quilt integers must range from min up to max, within QuiltIntegers::MAX_EXACT",
+            ));
+        }
+        Ok(QuiltIntegers { min, max, rounding })
+    }
+
+    /// Round and saturate values into the form they are stored in, in place
+    ///
+    /// With IntegerRounding::Error, nothing is changed if any value has a fraction.
+    pub fn to_stored(&self, mut content: nd::ArrayViewMutD<f32>) -> Fallible<()> {
+        if self.rounding == IntegerRounding::Error {
+            if let Some(&x) = content.iter().find(|x| x.is_finite() && x.fract() != 0.) {
+                return Err(StoiError::FractionalValue(x));
+            }
+        }
+        let (min, max, rounding) = (self.min as f32, self.max as f32, self.rounding);
+        content.mapv_inplace(|x| {
+            let x = match rounding {
+                IntegerRounding::HalfEven => round_half_even(x),
+                IntegerRounding::Truncate | IntegerRounding::Error => x.trunc(),
+            };
+            if x.is_nan() {
+                x
+            } else {
+                x.max(min).min(max)
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Round to the nearest integer, and values halfway between to the even one
+fn round_half_even(x: f32) -> f32 {
+    let rounded = x.round();
+    // round() goes away from zero at halfway, which is one too far if that's odd
+    if (x - x.trunc()).abs() == 0.5 && rounded % 2. != 0. {
+        rounded - x.signum()
+    } else {
+        rounded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::boxes_overlap;
+    use crate::{
+        commit_metadata, Axis, AxisSegment, AxisSelection, BoundingBox, Catalog, CatalogOptions,
+        CatalogUrl, Coarsening, CommitRules, ContentPattern, Counter, Derivation, FetchPlan,
+        HistoryFormat, IntegerRounding, LayoutAdvice, MaintenanceOptions, NamespaceConfig, Patch,
+        PatchQuantization, QuiltIntegers, QuiltQuota, QuiltRetention, QuiltTemplate, QuiltUnits,
+        ReadOcclusion, ReduceOp, StoiError, StorageTransaction, TagExpr, WriteAmplificationLimit,
+        MEASURE_AXIS,
+    };
+    use itertools::Itertools;
+
+    #[test]
+    fn test_create_quilt() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        // This should automatically create the axes as well, so it doesn't complain
+        txn.create_quilt("sales", &["itm", "lct", "day"])
+            .unwrap();
+    }
+    /// Fetching from an empty quilt should create an empty patch
+    #[test]
+    fn test_fetch_empty_quilt() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct", "day"])
+            .unwrap();
+
+        // This should assume the axes' labels exist if you specify them, but not if you don't
+        let pat = txn
+            .fetch(
+                "sales",
+                "latest",
+                vec![AxisSelection::All, AxisSelection::Labels(vec![1])],
+            )
+            .unwrap();
+        // We asked for two dimensions but any dimensions you missed will be tacked on
+        assert_eq!(pat.ndim(), 3);
+        assert_eq!(pat.content().shape(), &[0, 1, 0]);
+    }
+
+    /// Commit one patch to the quilt and check that it survives a round trip
+    #[test]
+    fn test_commit_first_patches() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["dim0", "dim1"]).unwrap();
+
+        //
+        // 1: Does commit + fetch work?
+        //
+
+        // Check that the quilt details match what we just posted
+        assert_eq!(
+            txn.get_quilt_details("sales").unwrap().axes,
+            vec!["dim0".to_string(), "dim1".to_string()]
+        );
+
+        // Commit a patch
+        let mut reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+            .unwrap();
+
+        // Check that the first patch was saved correctly
+        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(reference_patch.content(), output_patch.content());
+
+        //
+        // 2. Does overwriting work?
+        //
+
+        // Commit another patch over the same spot
+        // If we just used another random patch it would be somewhere else
+        // So instead we set the content of our first patch.
+        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        // This direct assignment ignores label alignment.
+        reference_patch
+            .content_mut()
+            .assign(&temp_reference_patch.content());
+        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+            .unwrap();
+
+        // We should see only the new patch because it occludes the first one totally
+        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(reference_patch.content(), output_patch.content());
+
+        //
+        // 3. Do transactions work?
+        //
+
+        // Commit the transaction and nothing should have changed
+        txn.finish().unwrap();
+        let mut txn = cat.begin().unwrap();
+        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(reference_patch.content(), output_patch.content());
+
+        // Duplicate of the first round of overwriting
+        // Commit another patch that occludes it totally.
+        // This time we're testing that multiple transactions work too.
+        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        reference_patch
+            .content_mut()
+            .assign(&temp_reference_patch.content());
+        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+            .unwrap();
+        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(reference_patch.content(), output_patch.content());
+        txn.finish().unwrap();
+
+        // Another duplicate of overwriting, this time we rollback
+        let mut txn = cat.begin().unwrap();
+        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        let mut rollback_reference_patch = reference_patch.clone();
+        rollback_reference_patch
+            .content_mut()
+            .assign(&temp_reference_patch.content());
+        txn.create_commit(
+            "sales",
+            "latest",
+            "latest",
+            "message",
+            &[&rollback_reference_patch],
+        )
+        .unwrap();
+        txn.rollback().unwrap(); // Oopsie, undo!
+        let mut txn = cat.begin().unwrap();
+        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        // Should be back to before
+        assert_eq!(reference_patch.content(), output_patch.content());
+    }
+
+    /// Test that fetches incur the right number of reads (low read amplification)
+    #[test]
+    fn test_read_amplification() {
+        let mut cat = populate_quilt();
+        let mut txn = cat.begin().unwrap();
+        txn.fetch(
+            "quilt",
+            "latest",
+            vec![
+                // Only label slice is inclusive
+                AxisSelection::LabelSlice(0, 100),
+                AxisSelection::StorageSlice(0, 100),
+            ],
+        )
+        .unwrap();
+        let ctr = txn.get_performance_counters();
+        // It could be done in 1, so cap it at 4.
+        println!("{:?}", ctr);
+        assert!(ctr[Counter::ReadPatch] <= 4);
+    }
+
+    /// Label slices follow storage order, and don't quietly return odd ranges
+    #[test]
+    fn test_fetch_label_slice() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[5, 3, 9, 1])
+            .content_1d(&[5., 3., 9., 1.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        let fetched = txn
+            .fetch("sales", "latest", vec![AxisSelection::LabelSlice(3, 1)])
+            .unwrap();
+        assert_eq!(fetched.axes()[0].labels(), &[3, 9, 1]);
+        assert_eq!(fetched.content().iter().copied().collect_vec(), vec![3., 9., 1.]);
+
+        match txn.fetch("sales", "latest", vec![AxisSelection::LabelSlice(9, 3)]) {
+            Err(StoiError::ReversedLabelSlice(_, 9, 3)) => (),
+            other => panic!("expected a reversed slice, got {:?}", other),
+        }
+        match txn.fetch("sales", "latest", vec![AxisSelection::LabelSlice(3, 4)]) {
+            Err(StoiError::LabelNotFound(_, 4)) => (),
+            other => panic!("expected a missing label, got {:?}", other),
+        }
+    }
+
+    /// Estimates should match what the fetch actually does
+    #[test]
+    fn test_estimate_fetch() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .axis("lct", &[1, 2])
+            .content_2d(&[[1., 2.], [3., 4.], [5., 6.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        let request = vec![AxisSelection::Labels(vec![1, 2]), AxisSelection::All];
+        let estimate = txn
+            .estimate_fetch("sales", "latest", request.clone())
+            .unwrap();
+        assert_eq!(estimate.shape, vec![2, 2]);
+        assert_eq!(estimate.elements, 4);
+        assert_eq!(estimate.bytes, 16);
+        assert!(!estimate.too_large);
+
+        let reads = txn.get_performance_counters()[Counter::ReadPatch];
+        txn.fetch("sales", "latest", request).unwrap();
+        assert_eq!(
+            txn.get_performance_counters()[Counter::ReadPatch] - reads,
+            estimate.patches
+        );
+    }
+
+    /// Fetch plans should only resolve their request again when an axis changes
+    #[test]
+    fn test_fetch_plan() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .content_1d(&[1., 2., 3.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.create_commit("sales", "latest", "other", "message", &[&patch])
+            .unwrap();
+        let mut plan = FetchPlan::new(&mut txn, "sales", vec![AxisSelection::All]).unwrap();
+        txn.finish().unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        let fetched = txn.fetch_planned(&mut plan, "latest").unwrap();
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 2., 3.]
+        );
+        txn.fetch_planned(&mut plan, "other").unwrap();
+        assert_eq!(txn.get_performance_counters()[Counter::ResolveSelection], 1);
+
+        // A new label means the plan is out of date
+        let patch = Patch::build().axis("itm", &[4]).content_1d(&[4.]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        let resolved = txn.get_performance_counters()[Counter::ResolveSelection];
+        let fetched = txn.fetch_planned(&mut plan, "latest").unwrap();
+        assert_eq!(fetched.axes()[0].labels(), &[1, 2, 3, 4]);
+        assert_eq!(
+            txn.get_performance_counters()[Counter::ResolveSelection],
+            resolved + 1
+        );
+    }
+
+    /// Chunks come from the commit the grid was planned on, even after the tag moves
+    #[test]
+    fn test_fetch_chunks() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3, 4])
+            .axis("lct", &[4, 5, 6])
+            .content_2d(&[[1., 2., 3.], [4., 5., 6.], [7., 8., 9.], [10., 11., 12.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        let grid = txn
+            .plan_chunks("sales", "latest", vec![AxisSelection::All], &[2, 2])
+            .unwrap();
+        assert_eq!(grid.grid_shape(), vec![2, 2]);
+        assert_eq!(grid.keys().len(), 4);
+        assert_eq!(grid.keys()[1], vec![0, 1]);
+
+        // Move the tag, which shouldn't affect the grid
+        let patch = Patch::build()
+            .axis("itm", &[3])
+            .axis("lct", &[4])
+            .content_2d(&[[100.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        let chunk = txn.fetch_chunk(&grid, &[1, 0]).unwrap();
+        assert_eq!(chunk.axes()[0].labels(), &[3, 4]);
+        assert_eq!(
+            chunk.content().iter().copied().collect_vec(),
+            vec![7., 8., 10., 11.]
+        );
+        let chunk = txn.fetch_chunk(&grid, &[1, 1]).unwrap();
+        assert_eq!(chunk.content().iter().copied().collect_vec(), vec![9., 12.]);
+        let fetched = txn
+            .fetch("sales", "latest", vec![AxisSelection::Labels(vec![3])])
+            .unwrap();
+        assert_eq!(fetched.content()[[0, 0]], 100.);
+
+        // Merging into the planned commit rewrites its patches, so the grid is out of date
+        let grid = txn
+            .plan_chunks("sales", "latest", vec![AxisSelection::All], &[2, 2])
+            .unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[3])
+            .axis("lct", &[4])
+            .content_2d(&[[200.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        match txn.fetch_chunk(&grid, &[1, 0]) {
+            Err(StoiError::StaleChunks(_)) => (),
+            other => panic!("expected StaleChunks, got {:?}", other.map(|_| ())),
+        }
+
+        // Keys outside the grid and chunks that don't fit the quilt are errors
+        assert!(txn.fetch_chunk(&grid, &[2, 0]).is_err());
+        assert!(txn
+            .plan_chunks("sales", "latest", vec![AxisSelection::All], &[2])
+            .is_err());
+    }
+
+    /// Axis generations should change with changes, not attempts, and never come back
+    #[test]
+    fn test_axis_generation() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_axis_generation("itm").unwrap(), 0);
+        txn.union_axis(&Axis::new("itm", vec![1, 2]).unwrap())
+            .unwrap();
+        let first = txn.get_axis_generation("itm").unwrap();
+        assert_ne!(first, 0);
+        txn.union_axis(&Axis::new("itm", vec![2, 1]).unwrap())
+            .unwrap();
+        assert_eq!(txn.get_axis_generation("itm").unwrap(), first);
+        txn.finish().unwrap();
+
+        // A change that's rolled back doesn't give its generation to the next change
+        let mut txn = cat.begin().unwrap();
+        txn.union_axis(&Axis::new("itm", vec![4]).unwrap()).unwrap();
+        let rolled_back = txn.get_axis_generation("itm").unwrap();
+        txn.rollback().unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_axis_generation("itm").unwrap(), first);
+        txn.union_axis(&Axis::new("itm", vec![3]).unwrap()).unwrap();
+        assert_eq!(txn.get_axis("itm").unwrap().labels(), &[1, 2, 3]);
+        let second = txn.get_axis_generation("itm").unwrap();
+        assert_ne!(second, first);
+        assert_ne!(second, rolled_back);
+    }
+
+    /// The layout report should notice small, hidden and sparse patches
+    #[test]
+    fn test_analyze_layout() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.union_axis(&Axis::new("itm", (1..=10).collect()).unwrap())
+            .unwrap();
+        let small = Patch::build()
+            .axis("itm", &[2, 3])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let big = Patch::build()
+            .axis("itm", &[1, 2, 3, 4])
+            .content_1d(&[1., 2., 3., 4.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&small])
+            .unwrap();
+        // A new tag has nothing to merge with, so the small patch stays, hidden
+        txn.create_commit("sales", "latest", "other", "message", &[&big])
+            .unwrap();
+
+        let report = txn.analyze_layout("sales", "other").unwrap();
+        assert_eq!(report.patches, 2);
+        assert_eq!(report.bytes, 24);
+        assert_eq!(report.occluded_bytes, 8);
+        assert_eq!(report.axis_fill, vec![("itm".to_string(), 1.)]);
+        assert_eq!(
+            report.advice,
+            vec![
+                LayoutAdvice::Squash {
+                    occluded_bytes: 8,
+                    fraction: 1. / 3.
+                },
+                LayoutAdvice::Compact {
+                    median_patch_bytes: 16,
+                    patches_after: 1
+                },
+            ]
+        );
+
+        let sparse = Patch::build()
+            .axis("itm", &[1, 10])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "sparse", "sparse", "message", &[&sparse])
+            .unwrap();
+        let report = txn.analyze_layout("sales", "sparse").unwrap();
+        assert_eq!(
+            report.advice,
+            vec![LayoutAdvice::Reorder {
+                axis: "itm".into(),
+                fill: 0.2
+            }]
+        );
+        assert!(report.advice[0].to_string().contains("reorder"));
+    }
+
+    /// Fetches should count what newer patches hid, and the layout advice should use it
+    #[test]
+    fn test_read_occlusion() {
+        // Without merging, both patches stay in the one tag
+        let options = CatalogOptions {
+            write_amplification: WriteAmplificationLimit::Append(0),
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with("", options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let small = Patch::build()
+            .axis("itm", &[2, 3])
+            .content_1d(&[std::f32::NAN, 2.])
+            .unwrap();
+        let big = Patch::build()
+            .axis("itm", &[1, 2, 3, 4])
+            .content_1d(&[1., 2., 3., 4.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&small])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&big])
+            .unwrap();
+        txn.finish().unwrap();
+
+        // The small patch copies one cell, which the big one then overwrites
+        let mut txn = cat.begin_read().unwrap();
+        txn.fetch("sales", "latest", vec![]).unwrap();
+        let counters = txn.get_performance_counters();
+        assert_eq!(counters[Counter::FetchVisibleBytes], 16);
+        assert_eq!(counters[Counter::FetchOccludedBytes], 4);
+        assert_eq!(counters[Counter::FetchSkippedPatch], 0);
+
+        // Where the small patch only has NAN, it's read for nothing
+        txn.fetch("sales", "latest", vec![AxisSelection::Labels(vec![2])])
+            .unwrap();
+        let counters = txn.get_performance_counters();
+        assert_eq!(counters[Counter::FetchVisibleBytes], 20);
+        assert_eq!(counters[Counter::FetchOccludedBytes], 4);
+        assert_eq!(counters[Counter::FetchSkippedPatch], 1);
+        drop(txn);
+
+        // Too few fetches to advise anything, but the report has them
+        let report = cat.analyze_layout("sales", "latest").unwrap();
+        let reads = ReadOcclusion {
+            fetches: 2,
+            visible_bytes: 20,
+            occluded_bytes: 4,
+            skipped_patches: 1,
+            tags: 1,
+        };
+        assert_eq!(report.reads, Some(reads));
+        assert!(!report.advice.iter().any(|advice| match advice {
+            LayoutAdvice::OccludedReads { .. } => true,
+            _ => false,
+        }));
+
+        for _ in 0..10 {
+            let mut txn = cat.begin_read().unwrap();
+            txn.fetch("sales", "latest", vec![]).unwrap();
+        }
+        let report = cat.analyze_layout("sales", "latest").unwrap();
+        assert_eq!(report.reads.unwrap().fetches, 12);
+        assert_eq!(
+            report.advice[0],
+            LayoutAdvice::OccludedReads {
+                occluded_bytes: 44,
+                fraction: 44. / 224.
+            }
+        );
+        let message = report.advice[0].to_string();
+        assert!(message.contains("hidden by newer patches"));
+
+        // With another tag, the reads may not have been from this one
+        let mut txn = cat.begin().unwrap();
+        txn.create_commit("sales", "latest", "trial", "branch", &[])
+            .unwrap();
+        txn.finish().unwrap();
+        let report = cat.analyze_layout("sales", "latest").unwrap();
+        assert_eq!(report.reads.unwrap().tags, 2);
+        assert!(!report.advice.iter().any(|advice| match advice {
+            LayoutAdvice::OccludedReads { .. } => true,
+            _ => false,
+        }));
+
+        // Once compacted, the reads before it don't count anymore
+        cat.settle_compaction("sales");
+        assert_eq!(cat.analyze_layout("sales", "latest").unwrap().reads, None);
+    }
+
+    /// Commits that would go over quota should fail without changing anything
+    #[test]
+    fn test_quilt_quota() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_quilt("returns", &["itm"]).unwrap();
+        txn.set_quilt_quota(
+            "sales",
+            Some(QuiltQuota {
+                max_bytes: None,
+                max_patches: Some(1),
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            txn.get_quilt_details("sales").unwrap().quota().unwrap().max_patches,
+            Some(1)
+        );
+        assert!(txn.get_quilt_details("returns").unwrap().quota().is_none());
+
+        let first = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        // Far enough away that it won't be merged with the first
+        let second = Patch::build()
+            .axis("itm", &[100])
+            .content_1d(&[3.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&first])
+            .unwrap();
+        let usage = txn.get_quilt_usage("sales").unwrap();
+        assert_eq!(usage.patches, 1);
+        assert!(usage.bytes > 0);
+
+        match txn.create_commit("sales", "latest", "latest", "message", &[&second]) {
+            Err(StoiError::QuotaExceeded(quilt, "patches", 1)) => assert_eq!(quilt, "sales"),
+            other => panic!("expected the quota to be exceeded, got {:?}", other),
+        }
+        assert_eq!(txn.get_quilt_usage("sales").unwrap(), usage);
+        let fetched = txn
+            .fetch("sales", "latest", vec![AxisSelection::Labels(vec![100])])
+            .unwrap();
+        assert!(fetched.content().iter().all(|x| x.is_nan()));
+
+        // Other quilts and cleared quotas are unlimited
+        txn.create_commit("returns", "latest", "latest", "message", &[&first, &second])
+            .unwrap();
+        txn.set_quilt_quota("sales", None).unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&second])
+            .unwrap();
+        assert_eq!(txn.get_quilt_usage("sales").unwrap().patches, 2);
+    }
+
+    /// The running usage of a quilt should agree with measuring all of its commits
+    #[test]
+    fn test_quilt_usage_running_count() {
+        let path = std::env::temp_dir().join(format!("stoi-usage-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let options = CatalogOptions {
+            patchset_bytes: 1 << 10,
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with(path, options.clone()).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        // Measuring it once starts the running count
+        assert_eq!(txn.get_quilt_usage("sales").unwrap().patches, 0);
+        // Small patches are packed, and the later ones merge with or replace earlier ones
+        for round in 0..5 {
+            let patches = (0..10)
+                .map(|i| {
+                    Patch::build()
+                        .axis("itm", &[i * 10 + round])
+                        .content_1d(&[round as f32])
+                        .unwrap()
+                })
+                .collect_vec();
+            txn.create_commit(
+                "sales",
+                "latest",
+                "latest",
+                "message",
+                &patches.iter().collect_vec(),
+            )
+            .unwrap();
+        }
+        let wide = Patch::build()
+            .axis_range("itm", 0..1000)
+            .content(Some(nd::ArrayD::from_elem(vec![1000], 1.)))
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&wide])
+            .unwrap();
+        txn.compact_region("sales", "latest", BoundingBox::everywhere())
+            .unwrap();
+        let counted = txn.get_quilt_usage("sales").unwrap();
+        assert!(counted.patches > 0);
+        txn.finish().unwrap();
+        cat.close().unwrap();
+
+        // Without a running count it's measured again from the commits
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute("DELETE FROM QuiltUsage;", rusqlite::NO_PARAMS)
+            .unwrap();
+        drop(conn);
+        let cat = Catalog::connect_with(path, options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_quilt_usage("sales").unwrap(), counted);
+        txn.finish().unwrap();
+        cat.close().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Quilts in different namespaces shouldn't collide, and share their namespace's settings
+    #[test]
+    fn test_namespaces() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        // The namespace has to exist first
+        assert!(txn.create_quilt("teamA/sales", &["itm"]).is_err());
+        let config = NamespaceConfig {
+            quota: Some(QuiltQuota {
+                max_bytes: None,
+                max_patches: Some(1),
+            }),
+            target_patch_bytes: None,
+        };
+        let default = NamespaceConfig::default();
+        assert!(txn.create_namespace("teamA", config).unwrap());
+        assert!(txn.create_namespace("teamB", default).unwrap());
+        assert!(!txn.create_namespace("teamA", default).unwrap());
+        assert!(txn.create_namespace("team/C", config).is_err());
+        assert_eq!(txn.get_namespace_config("teamA").unwrap(), config);
+
+        txn.create_quilt("teamA/sales", &["itm"]).unwrap();
+        txn.create_quilt("teamB/sales", &["itm"]).unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        assert_eq!(txn.list_namespaces().unwrap(), vec!["teamA", "teamB"]);
+        let listed = txn.list_quilts_in("teamA").unwrap();
+        assert_eq!(listed.keys().collect_vec(), vec!["teamA/sales"]);
+        let details = txn.get_quilt_details("teamB/sales").unwrap();
+        assert_eq!(details.namespace(), Some("teamB"));
+        assert_eq!(txn.get_quilt_details("sales").unwrap().namespace(), None);
+
+        // The namespace's quota applies to its quilts without their own
+        let first = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let second = Patch::build()
+            .axis("itm", &[100])
+            .content_1d(&[3.])
+            .unwrap();
+        for quilt_name in &["teamA/sales", "teamB/sales"] {
+            txn.create_commit(quilt_name, "latest", "latest", "message", &[&first])
+                .unwrap();
+        }
+        assert!(txn
+            .create_commit("teamA/sales", "latest", "latest", "message", &[&second])
+            .is_err());
+        txn.create_commit("teamB/sales", "latest", "latest", "message", &[&second])
+            .unwrap();
+        txn.set_namespace_config("teamA", default).unwrap();
+        txn.create_commit("teamA/sales", "latest", "latest", "message", &[&second])
+            .unwrap();
+    }
+
+    /// Commit messages should follow the catalog's rules, with every problem reported
+    #[test]
+    fn test_commit_rules() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let patch = Patch::build().axis("itm", &[1]).content_1d(&[1.]).unwrap();
+        assert_eq!(txn.get_commit_rules().unwrap(), CommitRules::default());
+        let rules = CommitRules {
+            required_metadata: vec!["pipeline".into(), "run_id".into()],
+            message_pattern: Some("^Refresh ".into()),
+        };
+        txn.set_commit_rules(&rules).unwrap();
+        assert_eq!(txn.get_commit_rules().unwrap(), rules);
+
+        match txn.create_commit("sales", "latest", "latest", "Refresh", &[&patch]) {
+            Err(StoiError::MissingCommitMetadata(missing)) => {
+                assert_eq!(missing, vec!["pipeline", "run_id"])
+            }
+            other => panic!("expected missing metadata, got {:?}", other),
+        }
+        let message = "Fix sales\n\npipeline: forecast\nrun_id: 42";
+        match txn.create_commit("sales", "latest", "latest", message, &[&patch]) {
+            Err(StoiError::CommitMessageFormat(_)) => (),
+            other => panic!("expected a bad format, got {:?}", other),
+        }
+        let message = "Refresh sales\n\npipeline: forecast\nrun_id: 42";
+        txn.create_commit("sales", "latest", "latest", message, &[&patch])
+            .unwrap();
+        assert_eq!(commit_metadata(message)["run_id"], "42");
+
+        // Rules rolled back, or that don't compile, don't replace the ones checked
+        txn.savepoint("rules").unwrap();
+        txn.set_commit_rules(&CommitRules::default()).unwrap();
+        txn.rollback_to("rules").unwrap();
+        assert!(txn
+            .create_commit("sales", "latest", "latest", "Fix", &[&patch])
+            .is_err());
+        let invalid = CommitRules {
+            message_pattern: Some("(".into()),
+            ..CommitRules::default()
+        };
+        assert!(txn.set_commit_rules(&invalid).is_err());
+        assert!(txn
+            .create_commit("sales", "latest", "latest", "Fix", &[&patch])
+            .is_err());
+    }
+
+    /// Changes should be recorded in the audit log
+    #[test]
+    fn test_audit_log() {
+        let options = CatalogOptions {
+            actor: Some("pipeline".into()),
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with("", options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_quilt("returns", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.finish().unwrap();
+
+        let log = cat.audit_log(Some("sales"), 10).unwrap();
+        assert_eq!(
+            log.iter().map(|e| e.operation.as_str()).collect_vec(),
+            vec!["create_commit", "create_quilt"]
+        );
+        assert_eq!(log[0].actor.as_deref(), Some("pipeline"));
+        assert_eq!(log[0].parameters["new_tag"], "latest");
+        assert_eq!(log[0].comm_ids.len(), 1);
+        assert_eq!(cat.audit_log(None, 10).unwrap().len(), 3);
+        assert_eq!(cat.audit_log(None, 1).unwrap().len(), 1);
+    }
+
+    /// Untagging should garbage collect only what no other tag reaches, and log both
+    #[test]
+    fn test_untag() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let first = Patch::build().axis("itm", &[1]).content_1d(&[1.]).unwrap();
+        let second = Patch::build().axis("itm", &[2]).content_1d(&[2.]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "first", &[&first])
+            .unwrap();
+        txn.create_commit("sales", "latest", "branch", "second", &[&second])
+            .unwrap();
+        assert_eq!(txn.get_quilt_usage("sales").unwrap().patches, 2);
+
+        // The branch's parent is still tagged, so only the branch's own commit goes
+        let branch = txn.get_tag_commit("sales", "branch").unwrap().unwrap();
+        txn.untag("sales", "branch").unwrap();
+        assert!(txn.get_tag_commit("sales", "branch").unwrap().is_none());
+        assert_eq!(txn.get_quilt_usage("sales").unwrap().patches, 1);
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(fetched.content()[[0]], 1.);
+        assert!(fetched.content()[[1]].is_nan());
+        let log = txn.get_audit_log(Some("sales"), 2).unwrap();
+        assert_eq!(log[0].operation, "collect_garbage");
+        assert_eq!(log[0].parameters["patches"], 1);
+        assert_eq!(log[0].comm_ids, vec![branch]);
+        assert_eq!(log[1].operation, "untag");
+        assert_eq!(log[1].parameters["tag"], "branch");
+        assert_eq!(log[1].comm_ids, vec![branch]);
+
+        // A commit another tag still reaches stays
+        txn.create_commit("sales", "latest", "kept", "second", &[&second])
+            .unwrap();
+        txn.untag("sales", "latest").unwrap();
+        let log = txn.get_audit_log(Some("sales"), 1).unwrap();
+        assert_eq!(log[0].operation, "untag");
+        let kept = txn.fetch("sales", "kept", vec![]).unwrap();
+        assert_eq!(kept.content().iter().cloned().collect_vec(), vec![1., 2.]);
+        match txn.untag("sales", "latest") {
+            Err(StoiError::NotFound("tag", tag)) => assert_eq!(tag, "latest"),
+            other => panic!("expected the tag to be missing, got {:?}", other),
+        }
+    }
+
+    /// Changes should list each commit after the one given, with its patches
+    #[test]
+    fn test_changes_since() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_quilt("returns", &["itm"]).unwrap();
+        let first = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let second = Patch::build()
+            .axis("itm", &[3, 4])
+            .content_1d(&[3., 4.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "first", &[&first])
+            .unwrap();
+        let first_comm_id = txn.get_tag_commit("sales", "latest").unwrap().unwrap();
+        txn.create_commit("sales", "latest", "latest", "second", &[&second])
+            .unwrap();
+        txn.finish().unwrap();
+
+        let changes = cat
+            .changes_since("sales", 0)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            changes.iter().map(|c| c.message.as_str()).collect_vec(),
+            vec!["first", "second"]
+        );
+        assert_eq!(changes[0].comm_id, first_comm_id);
+        assert_eq!(changes[1].patch_ids.len(), 1);
+        assert_eq!(changes[1].bounding_boxes[0][0], (2, 3));
+
+        let later = cat
+            .changes_since("sales", changes[0].sequence)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(later, changes[1..].to_vec());
+        assert_eq!(cat.changes_since("returns", 0).count(), 0);
+    }
+
+    /// Indexed tags should fetch the same as walking the ancestry
+    #[test]
+    fn test_visibility_index() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let first = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .content_1d(&[1., 2., 3.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&first])
+            .unwrap();
+        assert_eq!(txn.enable_visibility_index("sales", "latest").unwrap(), 1);
+
+        let second = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[4., 5.])
+            .unwrap();
+        let third = Patch::build()
+            .axis("itm", &[2, 3, 4])
+            .content_1d(&[std::f32::NAN, 6., 7.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&second])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&third])
+            .unwrap();
+        assert!(txn.check_visibility_index("sales", "latest").unwrap().is_empty());
+
+        let searches = txn.get_performance_counters()[Counter::SearchVisibilityIndex];
+        let indexed = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(
+            txn.get_performance_counters()[Counter::SearchVisibilityIndex],
+            searches + 1
+        );
+        assert_eq!(
+            indexed.content().iter().copied().collect_vec(),
+            vec![4., 5., 6., 7.]
+        );
+
+        // A patch with no gaps hides everything older inside it
+        let fourth = Patch::build()
+            .axis("itm", &[1, 2, 3, 4])
+            .content_1d(&[8., 9., 10., 11.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&fourth])
+            .unwrap();
+        assert!(txn.check_visibility_index("sales", "latest").unwrap().is_empty());
+        let indexed = txn.fetch("sales", "latest", vec![]).unwrap();
+
+        txn.disable_visibility_index("sales", "latest").unwrap();
+        let walked = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(indexed, walked);
+        assert!(txn.rebuild_visibility_index("sales", "latest").is_err());
+        // Every commit overlapped the last, so they all merged into one patch
+        assert_eq!(txn.enable_visibility_index("sales", "latest").unwrap(), 1);
+        assert_eq!(txn.rebuild_visibility_index("sales", "latest").unwrap(), 1);
+    }
+
+    /// Rebuilding a visibility index should give the same index that commits kept up to date
+    #[test]
+    fn test_rebuilt_visibility_index() {
+        // Without merging, every commit keeps its own patch
+        let options = CatalogOptions {
+            write_amplification: WriteAmplificationLimit::Append(0),
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with("", options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.enable_visibility_index("sales", "latest").unwrap();
+        let patches = vec![
+            Patch::build().axis("itm", &[1, 2]).content_1d(&[1., 2.]),
+            Patch::build()
+                .axis("itm", &[2, 3])
+                .content_1d(&[std::f32::NAN, 3.]),
+            // This hides the first, but not the second, which isn't inside its box
+            Patch::build().axis("itm", &[1, 2]).content_1d(&[4., 5.]),
+            Patch::build().axis("itm", &[5, 6]).content_1d(&[6., 7.]),
+        ];
+        for patch in patches {
+            txn.create_commit("sales", "latest", "latest", "message", &[&patch.unwrap()])
+                .unwrap();
+        }
+        let fetch_reads = |txn: &mut crate::Transaction| {
+            let before = txn.get_performance_counters()[Counter::ReadPatch];
+            let patch = txn.fetch("sales", "latest", vec![]).unwrap();
+            let reads = txn.get_performance_counters()[Counter::ReadPatch] - before;
+            (patch, reads)
+        };
+        let incremental = fetch_reads(&mut txn);
+        assert_eq!(incremental.1, 3);
+
+        assert_eq!(txn.rebuild_visibility_index("sales", "latest").unwrap(), 3);
+        assert!(txn.check_visibility_index("sales", "latest").unwrap().is_empty());
+        assert_eq!(fetch_reads(&mut txn), incremental);
+
+        // Patches of the rebuilt index still hide older ones on later commits
+        let cover = Patch::build()
+            .axis("itm", &[1, 2, 3, 5, 6])
+            .content_1d(&[8., 8., 8., 8., 8.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&cover])
+            .unwrap();
+        assert!(txn.check_visibility_index("sales", "latest").unwrap().is_empty());
+        assert_eq!(fetch_reads(&mut txn).1, 1);
+    }
+
+    /// Counters should outlive the transactions that produced them
+    #[test]
+    fn test_catalog_performance_snapshot() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        txn.fetch("sales", "latest", vec![]).unwrap();
+        txn.finish().unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        txn.fetch("sales", "latest", vec![]).unwrap();
+        txn.rollback().unwrap();
+
+        assert_eq!(cat.performance_snapshot()[Counter::Fetch], 2);
+        cat.reset_performance_counters();
+        assert_eq!(cat.performance_snapshot()[Counter::Fetch], 0);
+    }
+
+    /// Counters should say which quilt and which axis they came from
+    #[test]
+    fn test_performance_counters_by_quilt() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_quilt("inventory", &["lct"]).unwrap();
+        let pat = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&pat])
+            .unwrap();
+        txn.fetch("sales", "latest", vec![]).unwrap();
+        txn.fetch("inventory", "latest", vec![]).unwrap();
+        txn.fetch("inventory", "latest", vec![]).unwrap();
+
+        let by_quilt = txn.get_performance_counters_by_quilt();
+        assert_eq!(by_quilt["sales"][Counter::CreateCommit], 1);
+        assert_eq!(by_quilt["sales"][Counter::Fetch], 1);
+        assert_eq!(by_quilt["sales"][Counter::ReadPatch], 1);
+        assert_eq!(by_quilt["inventory"][Counter::Fetch], 2);
+        assert_eq!(by_quilt["inventory"][Counter::ReadPatch], 0);
+        let by_axis = txn.get_performance_counters_by_axis();
+        assert_eq!(
+            by_axis["itm"][Counter::WriteAxisLabel],
+            txn.get_performance_counters()[Counter::WriteAxisLabel]
+        );
+        assert!(!by_axis.contains_key("sales"));
+        txn.finish().unwrap();
+
+        // The catalog keeps the breakdown after the transaction ends
+        assert_eq!(
+            cat.performance_snapshot_by_quilt()["inventory"][Counter::Fetch],
+            2
+        );
+        assert!(cat.performance_snapshot_by_axis()["itm"][Counter::WriteAxisLabel] > 0);
+        cat.reset_performance_counters();
+        assert!(cat.performance_snapshot_by_quilt().is_empty());
+    }
+
+    /// A coarsened companion quilt should have the totals of its source
+    #[test]
+    fn test_commit_derived() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("daily", &["itm", "day"]).unwrap();
+        txn.create_quilt("weekly", &["itm", "day"]).unwrap();
+        let daily = Patch::build()
+            .axis("itm", &[1])
+            .axis("day", &[0, 1, 2, 7, 8])
+            .content_2d(&[[1., 2., 3., 4., 5.]])
+            .unwrap();
+        txn.create_commit("daily", "latest", "latest", "message", &[&daily])
+            .unwrap();
+
+        let groups = Coarsening::Groups(vec![(0, (0..7).collect()), (7, (7..14).collect())]);
+        txn.commit_derived("daily", "latest", vec![], "weekly", |patch| {
+            patch.coarsen("day", &groups, ReduceOp::Sum)
+        })
+        .unwrap();
+        let weekly = txn
+            .fetch(
+                "weekly",
+                "latest",
+                vec![AxisSelection::All, AxisSelection::Labels(vec![0, 7])],
+            )
+            .unwrap();
+        assert_eq!(weekly.content().as_slice().unwrap(), &[6., 9.]);
+    }
+
+    /// Commits should queue work for derived quilts, which is done at least once
+    #[test]
+    fn test_derived_quilt() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("daily", &["itm", "day"]).unwrap();
+        txn.create_quilt("weekly", &["itm", "day"]).unwrap();
+        let weekly_sum = Derivation::Coarsen {
+            axis_name: "day".into(),
+            coarsening: Coarsening::Factor(7),
+            op: ReduceOp::Sum,
+        };
+        txn.set_derived_quilt("daily", "weekly", Some(weekly_sum.clone()))
+            .unwrap();
+        assert_eq!(
+            txn.get_derivation("daily", "weekly").unwrap(),
+            Some(weekly_sum.clone())
+        );
+        let daily = Patch::build()
+            .axis("itm", &[1])
+            .axis("day", &(0..10).collect_vec())
+            .content_2d(&[[1., 1., 1., 1., 1., 1., 1., 2., 2., 2.]])
+            .unwrap();
+        txn.create_commit("daily", "latest", "latest", "message", &[&daily])
+            .unwrap();
+        let work = txn.get_derived_work(10).unwrap();
+        assert_eq!(work.len(), 1);
+        assert_eq!(work[0].dst_quilt, "weekly");
+        assert_eq!(work[0].bounding_box[1], (0, 9));
+        txn.finish().unwrap();
+
+        // Rolling back leaves the work queued
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.run_derived_work(10).unwrap(), 1);
+        txn.rollback().unwrap();
+        assert_eq!(cat.begin().unwrap().get_derived_work(10).unwrap().len(), 1);
+
+        assert_eq!(cat.run_derived_work().unwrap(), 1);
+        let mut txn = cat.begin().unwrap();
+        assert!(txn.get_derived_work(10).unwrap().is_empty());
+        let weekly = txn
+            .fetch(
+                "weekly",
+                "latest",
+                vec![AxisSelection::All, AxisSelection::Labels(vec![0, 7])],
+            )
+            .unwrap();
+        assert_eq!(weekly.content().as_slice().unwrap(), &[7., 6.]);
+
+        // Derived commits queue work too, so cycles are refused
+        assert!(txn
+            .set_derived_quilt("daily", "daily", Some(weekly_sum.clone()))
+            .is_err());
+        assert!(txn
+            .set_derived_quilt("weekly", "daily", Some(weekly_sum.clone()))
+            .is_err());
+        txn.create_quilt("monthly", &["itm", "day"]).unwrap();
+        txn.set_derived_quilt("weekly", "monthly", Some(weekly_sum.clone()))
+            .unwrap();
+        assert!(txn
+            .set_derived_quilt("monthly", "daily", Some(weekly_sum.clone()))
+            .is_err());
+
+        // Without a rule, nothing is queued
+        txn.set_derived_quilt("weekly", "monthly", None).unwrap();
+        txn.set_derived_quilt("daily", "weekly", None).unwrap();
+        txn.create_commit("daily", "latest", "latest", "message", &[&daily])
+            .unwrap();
+        assert!(txn.get_derived_work(10).unwrap().is_empty());
+    }
+
+    /// Aggregate quilts should be built, and then refreshed only where their source changed
+    #[test]
+    fn test_aggregate_quilt() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let sales = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[10, 11, 12])
+            .content_2d(&[[1., 2., 3.], [4., 5., std::f32::NAN]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&sales])
+            .unwrap();
+        txn.create_aggregate_quilt("sales", "all_lct", "latest", "lct", ReduceOp::Sum, -1)
+            .unwrap();
+        let status = txn.get_derived_quilts(Some("sales")).unwrap();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].dst_quilt, "all_lct");
+        assert_eq!(status[0].pending, 1);
+        assert!(status[0].stale_since.is_some());
+        assert!(status[0].refreshed_at.is_none());
+        txn.finish().unwrap();
+
+        assert_eq!(cat.run_derived_work().unwrap(), 1);
+        let totals = |cat: &Catalog| {
+            let mut txn = cat.begin_read().unwrap();
+            let request = vec![
+                AxisSelection::Labels(vec![1, 2]),
+                AxisSelection::Labels(vec![-1]),
+            ];
+            let patch = txn.fetch("all_lct", "latest", request).unwrap();
+            patch.content().iter().copied().collect_vec()
+        };
+        assert_eq!(totals(&cat), vec![6., 9.]);
+
+        // A change to one item queues a refresh of only the region it changed
+        let mut txn = cat.begin().unwrap();
+        let change = Patch::build()
+            .axis("itm", &[2])
+            .axis("lct", &[12])
+            .content_2d(&[[10.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&change])
+            .unwrap();
+        assert_eq!(txn.get_derived_quilts(None).unwrap()[0].pending, 1);
+        txn.finish().unwrap();
+        assert_eq!(cat.run_derived_work().unwrap(), 1);
+        assert_eq!(totals(&cat), vec![6., 19.]);
+
+        let status = cat.begin().unwrap().get_derived_quilts(None).unwrap();
+        assert_eq!(status[0].pending, 0);
+        assert!(status[0].stale_since.is_none());
+        assert!(status[0].refreshed_at.is_some());
+    }
+
+    /// The labels with the most data should come first, without reading any patches
+    #[test]
+    fn test_top_labels() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let wide = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[1, 2, 3, 4])
+            .content_2d(&[[1., 2., 3., 4.], [5., 6., 7., 8.]])
+            .unwrap();
+        let narrow = Patch::build()
+            .axis("itm", &[2])
+            .axis("lct", &[1, 2])
+            .content_2d(&[[9., 10.]])
+            .unwrap();
+        txn.create_commit("sales", "base", "base", "message", &[&wide])
+            .unwrap();
+        txn.create_commit("sales", "base", "latest", "message", &[&narrow])
+            .unwrap();
+
+        let reads = txn.get_performance_counters()[Counter::ReadPatch];
+        let top = txn.top_labels("sales", "latest", "itm", 1).unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, 2);
+        let top = txn.top_labels("sales", "latest", "lct", 10).unwrap();
+        assert_eq!(top.len(), 4);
+        assert!(top[0].0 == 1 || top[0].0 == 2);
+        assert_eq!(txn.get_performance_counters()[Counter::ReadPatch], reads);
+        assert!(txn.top_labels("sales", "latest", "day", 1).is_err());
+    }
+
+    /// Fetching into a buffer should match a regular fetch
+    #[test]
+    fn test_fetch_into_buffer() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["dim0", "dim1"]).unwrap();
+        let reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+            .unwrap();
+
+        let mut buffer = nd::ArrayD::<f32>::zeros(reference_patch.content().shape());
+        let axes = txn
+            .fetch_into("sales", "latest", vec![], &mut buffer.view_mut())
+            .unwrap();
+        assert_eq!(axes, reference_patch.axes());
+        assert_eq!(reference_patch.content(), buffer.view());
+
+        // The wrong shape is an error rather than a partial write
+        let mut wrong = nd::ArrayD::<f32>::zeros(vec![2, 2]);
+        assert!(txn
+            .fetch_into("sales", "latest", vec![], &mut wrong.view_mut())
+            .is_err());
+    }
+
+    /// Commit reports should be stored with the commit and found through its tag
+    #[test]
+    fn test_commit_stats() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[5, 6])
+            .content_2d(&[[1., std::f32::NAN], [-3., 4.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "no stats", &[&patch])
+            .unwrap();
+        assert_eq!(txn.get_commit_stats("sales", "latest").unwrap(), None);
+
+        let stats = txn
+            .create_commit_with_stats("sales", "latest", "latest", "stats", &[&patch])
+            .unwrap();
+        assert_eq!(stats.cells_written, 4);
+        assert_eq!(stats.nans_written, 1);
+        assert_eq!(stats.min, Some(-3.));
+        assert_eq!(stats.max, Some(4.));
+        assert_eq!(stats.axis_coverage["itm"], 2);
+        assert_eq!(
+            txn.get_commit_stats("sales", "latest").unwrap(),
+            Some(stats)
+        );
+    }
+
+    /// Units should be invisible except in how the values are stored
+    #[test]
+    fn test_quilt_units() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.set_quilt_units("sales", Some(QuiltUnits::new("USD", 0.01, 0.).unwrap()))
+            .unwrap();
+        assert_eq!(
+            txn.get_quilt_details("sales").unwrap().units().unwrap().unit,
+            "USD"
+        );
+
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1.5, std::f32::NAN])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        let stored = txn.fetch_stored("sales", "latest", vec![]).unwrap();
+        assert_abs_diff_eq!(stored.content()[[0]], 150., epsilon = 1e-3);
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_abs_diff_eq!(fetched.content()[[0]], 1.5, epsilon = 1e-5);
+        assert!(fetched.content()[[1]].is_nan());
+
+        txn.set_quilt_units("sales", None).unwrap();
+        assert!(txn.get_quilt_details("sales").unwrap().units().is_none());
+    }
+
+    /// Quilts with units should store their whole numbers in about half the space
+    #[test]
+    fn test_quilt_units_stored_size() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        let dollars = (0..1000)
+            .map(|i| (i * 7919 % 60000) as f32 / 100. - 300.)
+            .collect_vec();
+        let patch = Patch::build()
+            .axis("itm", &(0..1000).collect::<Vec<i64>>())
+            .content_1d(&dollars)
+            .unwrap();
+        let mut stored_bytes = vec![];
+        for units in vec![None, Some(QuiltUnits::new("USD", 0.01, 0.).unwrap())] {
+            let quilt_name = if units.is_some() { "cents" } else { "plain" };
+            txn.create_quilt(quilt_name, &["itm"]).unwrap();
+            txn.set_quilt_units(quilt_name, units).unwrap();
+            txn.create_commit(quilt_name, "latest", "latest", "message", &[&patch])
+                .unwrap();
+            let mut bytes = 0;
+            for patch_ref in txn
+                .search(quilt_name, "latest", true, &[BoundingBox::everywhere()])
+                .unwrap()
+            {
+                bytes += txn.get_patch_content(patch_ref.id).unwrap().unwrap().len();
+            }
+            stored_bytes.push(bytes);
+
+            // Either way the dollars come back, to within a cent's rounding
+            let fetched = txn.fetch(quilt_name, "latest", vec![]).unwrap();
+            for (a, b) in fetched.content().iter().zip(&dollars) {
+                assert_abs_diff_eq!(*a, *b, epsilon = 1e-4);
+            }
+        }
+        assert!(
+            stored_bytes[1] * 10 < stored_bytes[0] * 6,
+            "{:?}",
+            stored_bytes
+        );
+    }
+
+    /// Quilts of integers should round and saturate commits, or refuse fractions
+    #[test]
+    fn test_quilt_integers() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("visits", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3, 4, 5, 6])
+            .content_1d(&[2.5, 3.5, -2.5, 1e9, std::f32::NEG_INFINITY, std::f32::NAN])
+            .unwrap();
+        fn commit_stored(
+            txn: &mut impl StorageTransaction,
+            patch: &Patch,
+            rounding: IntegerRounding,
+        ) -> Result<Vec<f32>, StoiError> {
+            let integers = QuiltIntegers::new(-100, 1000, rounding).unwrap();
+            txn.set_quilt_integers("visits", Some(integers)).unwrap();
+            txn.create_commit("visits", "latest", "latest", "visits", &[patch])?;
+            let stored = txn.fetch("visits", "latest", vec![])?;
+            Ok(stored.content().iter().cloned().collect_vec())
+        }
+
+        let stored = commit_stored(&mut txn, &patch, IntegerRounding::HalfEven).unwrap();
+        assert_eq!(&stored[..5], &[2., 4., -2., 1000., -100.]);
+        assert!(stored[5].is_nan());
+        let stored = commit_stored(&mut txn, &patch, IntegerRounding::Truncate).unwrap();
+        assert_eq!(&stored[..5], &[2., 3., -2., 1000., -100.]);
+        match commit_stored(&mut txn, &patch, IntegerRounding::Error) {
+            Err(StoiError::FractionalValue(x)) => assert_eq!(x, 2.5),
+            other => panic!("expected FractionalValue, got {:?}", other),
+        }
+
+        // Integers have to be stored exactly, and in a range floats hold exactly
+        assert!(QuiltIntegers::new(0, 1 << 30, IntegerRounding::Truncate).is_err());
+        assert!(txn
+            .set_quilt_quantization("visits", PatchQuantization::U8)
+            .is_err());
+        txn.set_quilt_integers("visits", None).unwrap();
+        let details = txn.get_quilt_details("visits").unwrap();
+        assert!(details.integers().is_none());
+    }
+
+    /// Quantized quilts should round trip approximately
+    #[test]
+    fn test_quilt_quantization() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("features", &["dim0", "dim1"]).unwrap();
+        txn.set_quilt_quantization("features", PatchQuantization::F16)
+            .unwrap();
+        assert_eq!(
+            txn.get_quilt_details("features").unwrap().quantization(),
+            PatchQuantization::F16
+        );
+
+        let reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
+        txn.create_commit("features", "latest", "latest", "message", &[&reference_patch])
+            .unwrap();
+        let output_patch = txn.fetch("features", "latest", vec![]).unwrap();
+        for (a, b) in reference_patch
+            .content()
+            .iter()
+            .zip(output_patch.content().iter())
+        {
+            assert_abs_diff_eq!(*a, *b, epsilon = 1e-3);
+        }
+    }
+
+    /// Merging into a quantized patch over and over shouldn't round its values over and over
+    #[test]
+    fn test_quilt_quantization_repeated_merge() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("features", &["itm"]).unwrap();
+        txn.set_quilt_quantization("features", PatchQuantization::U8)
+            .unwrap();
+        let mut exact = (0..100).map(|i| (i * 31 % 101) as f32 / 10.).collect_vec();
+        exact[0] = 0.;
+        exact[99] = 10.;
+        let patch = Patch::build()
+            .axis("itm", &(0..100).collect::<Vec<i64>>())
+            .content_1d(&exact)
+            .unwrap();
+        txn.create_commit("features", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        for round in 0..30 {
+            let start = round * 7 % 80;
+            let values = (start..start + 20)
+                .map(|i| ((i * 31 + round * 13) % 101) as f32 / 10.)
+                .collect_vec();
+            exact[start..start + 20].copy_from_slice(&values);
+            let labels = (start as i64..start as i64 + 20).collect::<Vec<i64>>();
+            let patch = Patch::build()
+                .axis("itm", &labels)
+                .content_1d(&values)
+                .unwrap();
+            txn.create_commit("features", "latest", "latest", "message", &[&patch])
+                .unwrap();
+        }
+        assert_eq!(txn.get_performance_counters()[Counter::Merge], 30);
+
+        // Every value was rounded once, so it's within one level of what was committed
+        let step = 10. / 254.;
+        let fetched = txn.fetch("features", "latest", vec![]).unwrap();
+        for (a, b) in fetched.content().iter().zip(&exact) {
+            assert!((a - b).abs() <= step, "{} vs {}", a, b);
+        }
+    }
+
+    /// Partial axis reads should agree with reading the whole axis
+    #[test]
+    fn test_axis_slice() {
+        let mut cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.union_axis(&Axis::range("day", 100..200)).unwrap();
+        txn.finish().unwrap();
+
+        // A new transaction so the axis isn't cached
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_axis_len("day").unwrap(), 100);
+        assert_eq!(txn.get_axis_len("nope").unwrap(), 0);
+        assert_eq!(
+            txn.get_axis_slice("day", 10..13).unwrap().labels(),
+            &[110, 111, 112]
+        );
+        assert_eq!(txn.get_axis_slice("day", 98..1000).unwrap().len(), 2);
+        assert_eq!(txn.get_performance_counters()[Counter::ReadAxis], 0);
+    }
+
+    /// Fetching many tags should stack what you'd get by fetching each one
+    #[test]
+    fn test_fetch_many() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["dim0", "dim1"]).unwrap();
+        let first = Patch::autogenerate(ContentPattern::Random, 5);
+        txn.create_commit("sales", "first", "first", "message", &[&first])
+            .unwrap();
+        let mut second = first.clone();
+        second.content_mut().fill(2.);
+        txn.create_commit("sales", "first", "second", "message", &[&second])
+            .unwrap();
+
+        let both = txn
+            .fetch_many("sales", &["first", "second", "missing"], vec![])
+            .unwrap();
+        assert_eq!(both.axes()[0].labels(), &[0, 1, 2]);
+        assert_eq!(&both.axes()[1..], first.axes());
+        let content = both.content();
+        assert_eq!(content.index_axis(nd::Axis(0), 0), first.content());
+        assert_eq!(content.index_axis(nd::Axis(0), 1), second.content());
+        assert!(content
+            .index_axis(nd::Axis(0), 2)
+            .iter()
+            .all(|x| x.is_nan()));
+    }
+
+    /// Several selections should share one search, and read each patch once
+    #[test]
+    fn test_fetch_many_selections() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3, 4])
+            .axis("lct", &[5])
+            .content_2d(&[[1.], [2.], [3.], [4.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        let before = txn.get_performance_counters();
+        let fetched = txn
+            .fetch_many_selections(
+                "sales",
+                "latest",
+                vec![
+                    vec![AxisSelection::Labels(vec![1, 2])],
+                    vec![AxisSelection::LabelSlice(3, 4), AxisSelection::All],
+                    vec![AxisSelection::Labels(vec![9])],
+                ],
+            )
+            .unwrap();
+        let after = txn.get_performance_counters();
+        let contents = fetched
+            .iter()
+            .map(|p| p.content().iter().copied().collect_vec())
+            .collect_vec();
+        assert_eq!(contents[0], vec![1., 2.]);
+        assert_eq!(contents[1], vec![3., 4.]);
+        assert!(contents[2][0].is_nan());
+        let searches = after[Counter::SearchPatches] - before[Counter::SearchPatches];
+        assert_eq!(searches, 1);
+        assert_eq!(after[Counter::ReadPatch] - before[Counter::ReadPatch], 1);
+    }
+
+    /// Scattered points should be committed as a few small patches, not one huge one
+    #[test]
+    fn test_create_commit_points() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        txn.union_axis(&Axis::range("itm", 0..1000)).unwrap();
+
+        let points = vec![
+            (vec![5, 1], 1.),
+            (vec![7, 2], 2.),
+            (vec![900, 1], 3.),
+            (vec![5, 1], 4.),
+        ];
+        let writes = txn.get_performance_counters()[Counter::WritePatch];
+        txn.create_commit_points("sales", "latest", "latest", "message", &points)
+            .unwrap();
+        // Items 5 and 7 are in one block, and 900 is in another
+        assert_eq!(
+            txn.get_performance_counters()[Counter::WritePatch] - writes,
+            2
+        );
+
+        let patch = txn
+            .fetch(
+                "sales",
+                "latest",
+                vec![
+                    AxisSelection::Labels(vec![5, 7, 900]),
+                    AxisSelection::Labels(vec![1, 2]),
+                ],
+            )
+            .unwrap();
+        assert_eq!(patch.content()[[0, 0]], 4.);
+        assert_eq!(patch.content()[[1, 1]], 2.);
+        assert_eq!(patch.content()[[2, 0]], 3.);
+        assert!(patch.content()[[0, 1]].is_nan());
+
+        assert!(txn
+            .create_commit_points("sales", "latest", "latest", "message", &[(vec![1], 1.)])
+            .is_err());
+
+        // Points spread over one block are split, rather than filling a patch with NAN
+        txn.create_quilt("diagonal", &["x", "y"]).unwrap();
+        let points = (0..64).map(|i| (vec![i, i], i as f32)).collect_vec();
+        txn.create_commit_points("diagonal", "latest", "latest", "message", &points)
+            .unwrap();
+        let refs = txn
+            .search("diagonal", "latest", true, &[BoundingBox::everywhere()])
+            .unwrap();
+        let cells: usize = refs
+            .iter()
+            .map(|patch_ref| txn.get_patch(patch_ref.id).unwrap().content().len())
+            .sum();
+        assert!(cells <= 4 * 64, "{} cells for 64 points", cells);
+        let patch = txn
+            .fetch(
+                "diagonal",
+                "latest",
+                vec![AxisSelection::Labels(vec![10]), AxisSelection::All],
+            )
+            .unwrap();
+        assert_eq!(patch.content()[[0, 10]], 10.);
+        assert!(patch.content()[[0, 11]].is_nan());
+    }
+
+    /// The fallback tag should only show through where the primary tag has no data
+    #[test]
+    fn test_fetch_with_fallback() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let model = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[5, 6])
+            .content_2d(&[[1., 2.], [3., 4.]])
+            .unwrap();
+        txn.create_commit("sales", "model", "model", "message", &[&model])
+            .unwrap();
+        let overrides = Patch::build()
+            .axis("itm", &[2, 3])
+            .axis("lct", &[5, 6])
+            .content_2d(&[[30., std::f32::NAN], [50., 60.]])
+            .unwrap();
+        txn.create_commit("sales", "overrides", "overrides", "message", &[&overrides])
+            .unwrap();
+
+        let patch = txn
+            .fetch_with_fallback("sales", "overrides", "model", vec![])
+            .unwrap();
+        let expected = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .axis("lct", &[5, 6])
+            .content_2d(&[[1., 2.], [30., 4.], [50., 60.]])
+            .unwrap();
+        assert_eq!(patch, expected);
+
+        // A missing fallback is the same as a plain fetch
+        let patch = txn
+            .fetch_with_fallback("sales", "overrides", "missing", vec![])
+            .unwrap();
+        assert!(patch.content()[[0, 0]].is_nan());
+    }
+
+    /// Earlier layers of an overlay should occlude later ones, even when they share ancestors
+    #[test]
+    fn test_fetch_overlay() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let base = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "base", "base", "message", &[&base])
+            .unwrap();
+        let overrides = Patch::build().axis("itm", &[2]).content_1d(&[20.]).unwrap();
+        txn.create_commit("sales", "base", "overrides", "message", &[&overrides])
+            .unwrap();
+        let newer = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .content_1d(&[100., 200., 300.])
+            .unwrap();
+        txn.create_commit("sales", "base", "latest", "message", &[&newer])
+            .unwrap();
+
+        // "overrides" still has the old value of 1 from their common ancestor
+        let overlay = TagExpr::Overlay(vec!["overrides", "latest"]);
+        let patch = txn.fetch("sales", overlay, vec![]).unwrap();
+        assert_eq!(patch.content().as_slice().unwrap(), &[1., 20., 300.]);
+        let overlay = TagExpr::Overlay(vec!["latest", "overrides"]);
+        let patch = txn.fetch("sales", overlay, vec![]).unwrap();
+        assert_eq!(patch.content().as_slice().unwrap(), &[100., 200., 300.]);
+        assert_eq!(
+            txn.fetch("sales", TagExpr::Overlay(vec!["latest"]), vec![])
+                .unwrap(),
+            txn.fetch("sales", "latest", vec![]).unwrap()
+        );
+    }
+
+    /// Copying a selection should make the destination look like the source there
+    #[test]
+    fn test_copy_selection() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("prod", &["itm", "lct"]).unwrap();
+        txn.create_quilt("staging", &["itm", "lct"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .axis("lct", &[5, 6])
+            .content_2d(&[[1., 2.], [3., 4.], [5., 6.]])
+            .unwrap();
+        txn.create_commit("prod", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.finish().unwrap();
+
+        cat.copy(
+            "prod",
+            "latest",
+            vec![AxisSelection::Labels(vec![2, 3])],
+            "staging",
+            "latest",
+            "backfill",
+        )
+        .unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        let copied = txn
+            .fetch(
+                "staging",
+                "latest",
+                vec![AxisSelection::Labels(vec![1, 2, 3])],
+            )
+            .unwrap()
+            .to_dense();
+        assert!(copied[[0, 0]].is_nan());
+        assert_eq!(copied[[1, 0]], 3.);
+        assert_eq!(copied[[2, 1]], 6.);
+    }
+
+    /// Splitting should depend on compressed size, not the number of elements
+    #[test]
+    fn test_split_by_bytes() {
+        let cat = Catalog::connect("").unwrap();
+        cat.set_target_patch_bytes(4000);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+
+        // Empty patches compress very well so they aren't split
+        let empty = Patch::autogenerate(ContentPattern::Zero, 100);
+        txn.union_axis(&empty.axes()[0]).unwrap();
+        txn.union_axis(&empty.axes()[1]).unwrap();
+        assert_eq!(txn.maybe_split(empty).unwrap().len(), 1);
+
+        // 40KB of noise (and 1.6KB of labels) should be split into about 16 parts
+        let random = Patch::autogenerate(ContentPattern::Random, 100);
+        txn.union_axis(&random.axes()[0]).unwrap();
+        txn.union_axis(&random.axes()[1]).unwrap();
+        let parts = txn.maybe_split(random).unwrap();
+        assert!(parts.len() >= 16 && parts.len() <= 64);
+    }
+
+    /// Planned patches should be split like a commit would, and commit the same content
+    #[test]
+    fn test_plan_patches() {
+        let cat = Catalog::connect("").unwrap();
+        cat.set_target_patch_bytes(4000);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+
+        let random = Patch::autogenerate(ContentPattern::Random, 100);
+        let planned = txn.plan_patches(&[random.clone()]).unwrap();
+        assert!(planned.len() >= 16 && planned.len() <= 64);
+        assert_eq!(
+            planned.iter().map(|p| p.len()).sum::<usize>(),
+            random.len()
+        );
+
+        // The parts don't overlap, so they won't be merged on commit
+        let boxes = planned
+            .iter()
+            .map(|p| txn.get_bounding_box(p).unwrap())
+            .collect_vec();
+        for (ix, left) in boxes.iter().enumerate() {
+            for right in &boxes[ix + 1..] {
+                assert_eq!(super::overlap_ratio(left, right), 0.);
+            }
+        }
+
+        txn.create_commit(
+            "quilt",
+            "latest",
+            "latest",
+            "message",
+            &planned.iter().collect_vec(),
+        )
+        .unwrap();
+        assert_eq!(txn.fetch("quilt", "latest", vec![]).unwrap(), random);
+    }
+
+    /// Committing with prepared patches should store the same as a regular commit
+    #[test]
+    fn test_catalog_commit() {
+        let cat = Catalog::connect("").unwrap();
+        cat.set_target_patch_bytes(4000);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+        txn.finish().unwrap();
+
+        let random = Patch::autogenerate(ContentPattern::Random, 100);
+        cat.commit("quilt", "latest", "latest", "message", &[&random])
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.fetch("quilt", "latest", vec![]).unwrap(), random);
+        txn.finish().unwrap();
+
+        // Overlapping patches are merged, so they can't use what was prepared
+        let zero = Patch::autogenerate(ContentPattern::Zero, 100);
+        let zero = Patch::new(random.axes().to_vec(), Some(zero.content().to_owned())).unwrap();
+        cat.commit("quilt", "latest", "latest", "message", &[&zero])
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.fetch("quilt", "latest", vec![]).unwrap(), zero);
+    }
+
+    /// Small patches should be packed together, and read back as if they weren't
+    #[test]
+    fn test_patchsets() {
+        let patches = (0..100)
+            .map(|i| {
+                Patch::build()
+                    .axis("itm", &[10 * i])
+                    .content_1d(&[i as f32])
+                    .unwrap()
+            })
+            .collect_vec();
+        let patches = patches.iter().collect_vec();
+        let mut usages = vec![];
+        for &patchset_bytes in &[0, 1 << 10] {
+            let options = CatalogOptions {
+                patchset_bytes,
+                ..CatalogOptions::default()
+            };
+            let cat = Catalog::connect_with("", options).unwrap();
+            let mut txn = cat.begin().unwrap();
+            txn.create_quilt("sales", &["itm"]).unwrap();
+            txn.create_commit("sales", "latest", "latest", "small", &patches)
+                .unwrap();
+            usages.push(txn.get_quilt_usage("sales").unwrap());
+            let packed = txn.get_performance_counters()[Counter::PackPatch];
+            assert_eq!(packed, if patchset_bytes > 0 { 100 } else { 0 });
+            let all = txn.fetch("sales", "latest", vec![]).unwrap();
+            let expected = (0..100).map(|i| i as f32).collect_vec();
+            assert_eq!(all.content().iter().cloned().collect_vec(), expected);
+
+            // Replacing a packed patch leaves the rest of its patchset readable
+            let update = Patch::build()
+                .axis("itm", &[10])
+                .content_1d(&[50.])
+                .unwrap();
+            txn.create_commit("sales", "latest", "latest", "update", &[&update])
+                .unwrap();
+            let all = txn.fetch("sales", "latest", vec![]).unwrap();
+            assert_eq!(all.content()[[1]], 50.);
+            assert_eq!(all.content()[[99]], 99.);
+            assert_eq!(txn.get_quilt_usage("sales").unwrap().patches, 100);
+        }
+        assert_eq!(usages[0].patches, usages[1].patches);
+        assert!(usages[1].bytes < usages[0].bytes);
+    }
+
+    /// Streamed patches should come in batches, in the order a fetch applies them
+    #[test]
+    fn test_stream_patches() {
+        let patches = (0..10)
+            .map(|i| {
+                Patch::build()
+                    .axis("itm", &[i])
+                    .content_1d(&[i as f32])
+                    .unwrap()
+            })
+            .collect_vec();
+        let patches = patches.iter().collect_vec();
+        let cat = Catalog::connect("").unwrap();
+        // Every patch is a batch of its own, so each one is prefetched
+        cat.set_target_patch_bytes(1);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "many", &patches)
+            .unwrap();
+        let update = Patch::build().axis("itm", &[0]).content_1d(&[50.]).unwrap();
+        txn.create_commit("sales", "latest", "overrides", "update", &[&update])
+            .unwrap();
+
+        let everywhere = [BoundingBox::everywhere()];
+        let streamed = txn
+            .stream_patches("sales", &"latest".into(), &everywhere)
+            .unwrap()
+            .map(|next| next.unwrap().1)
+            .collect_vec();
+        assert_eq!(streamed.len(), 10);
+        assert!(streamed.iter().all(|patch| patches.contains(&patch)));
+
+        // Patches shared by the layers are streamed once, before the top layer's
+        let overlay = TagExpr::Overlay(vec!["overrides", "latest"]);
+        let streamed = txn
+            .stream_patches("sales", &overlay, &everywhere)
+            .unwrap()
+            .map(|next| next.unwrap().1)
+            .collect_vec();
+        assert_eq!(streamed.len(), 11);
+        assert_eq!(streamed.last(), Some(&update));
+        txn.finish().unwrap();
+
+        // Fetches reading more than allowed fail before reading anything
+        let options = CatalogOptions {
+            max_read_bytes: Some(8),
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with("", options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "many", &patches)
+            .unwrap();
+        let before = txn.get_performance_counters()[Counter::ReadPatch];
+        match txn.fetch("sales", "latest", vec![]) {
+            Err(StoiError::TooLarge(_)) => {}
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+        assert_eq!(txn.get_performance_counters()[Counter::ReadPatch], before);
+        let one = txn
+            .fetch("sales", "latest", vec![AxisSelection::Labels(vec![3])])
+            .unwrap();
+        assert_eq!(one.content()[[0]], 3.);
+    }
+
+    /// Patches read often should be the last listed as cold
+    #[test]
+    fn test_coldest_patches() {
+        let patches = (0..3)
+            .map(|i| {
+                Patch::build()
+                    .axis("itm", &[i])
+                    .content_1d(&[i as f32])
+                    .unwrap()
+            })
+            .collect_vec();
+        let patches = patches.iter().collect_vec();
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "few", &patches)
+            .unwrap();
+        txn.finish().unwrap();
+
+        // Reads are sampled, so read the hot one often enough to be all but sure it's sampled
+        let mut txn = cat.begin_read().unwrap();
+        for _ in 0..400 {
+            txn.fetch("sales", "latest", vec![AxisSelection::Labels(vec![1])])
+                .unwrap();
+        }
+        drop(txn);
+
+        let all = cat.coldest_patches("sales", std::u64::MAX).unwrap();
+        assert_eq!(all.len(), 3);
+        let hot = all.last().unwrap();
+        assert_eq!(hot.bounding_box[0], (1, 1));
+        assert!(hot.reads > 0 && hot.last_read.is_some());
+        assert!(all[..2].iter().all(|cold| cold.reads == 0));
+
+        // Only as many as it takes to add up to the bytes asked for
+        let coldest = cat.coldest_patches("sales", 1).unwrap();
+        assert_eq!(coldest.len(), 1);
+        assert_eq!(coldest[0], all[0]);
+    }
+
+    /// Retrying a commit with the same key should not commit it twice
+    #[test]
+    fn test_commit_keyed() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.create_quilt("stock", &["itm"]).unwrap();
+        txn.finish().unwrap();
+
+        let ones = Patch::build().axis("itm", &[1]).content_1d(&[1.]).unwrap();
+        let twos = Patch::build().axis("itm", &[1]).content_1d(&[2.]).unwrap();
+        let first = cat
+            .commit_keyed("sales", "latest", "latest", "message", &[&ones], "run-1")
+            .unwrap();
+        let retry = cat
+            .commit_keyed("sales", "latest", "latest", "message", &[&twos], "run-1")
+            .unwrap();
+        assert_eq!(first, retry);
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_tag_commit("sales", "latest").unwrap(), Some(first));
+        assert_eq!(txn.fetch("sales", "latest", vec![]).unwrap(), ones);
+
+        // Keys only apply within a quilt
+        let other = txn
+            .create_commit_keyed("stock", "latest", "latest", "message", &[&twos], "run-1")
+            .unwrap();
+        assert_ne!(other, first);
+        let second = txn
+            .create_commit_keyed("sales", "latest", "latest", "message", &[&twos], "run-2")
+            .unwrap();
+        assert_ne!(second, first);
+        let found = txn.get_keyed_commit("sales", "run-2").unwrap();
+        assert_eq!(found, Some(second));
+        assert_eq!(txn.fetch("sales", "latest", vec![]).unwrap(), twos);
+        assert!(txn.put_commit_key("sales", "latest", "run-2").is_err());
+    }
+
+    #[test]
+    fn test_overlap_ratio() {
+        use super::overlap_ratio;
+        let bx = |segments: Vec<AxisSegment>| BoundingBox::from_segments(segments).unwrap();
+        let a = bx(vec![(0, 9), (0, 9)]);
+        assert_eq!(overlap_ratio(&a, &a), 1.);
+        // Half of one axis
+        assert_eq!(overlap_ratio(&a, &bx(vec![(5, 14), (0, 9)])), 1. / 3.);
+        // Adjacent but disjoint
+        assert_eq!(overlap_ratio(&a, &bx(vec![(10, 19), (0, 9)])), 0.);
+        // Only the corners touch
+        assert!(overlap_ratio(&a, &bx(vec![(9, 18), (9, 18)])) < 0.01);
+    }
+
+    /// Commits that don't overlap existing patches shouldn't merge with them
+    #[test]
+    fn test_merge_skips_disjoint_patches() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        let make = |x: std::ops::Range<i64>, y: std::ops::Range<i64>| {
+            Patch::build()
+                .axis_range("x", x)
+                .axis_range("y", y)
+                .content(None)
+                .unwrap()
+        };
+        let mut first = make(0..10, 0..10);
+        first.content_mut().fill(1.);
+        txn.create_commit("quilt", "latest", "latest", "first", &[&first])
+            .unwrap();
+        // This one overlaps only a corner
+        let mut corner = make(9..19, 9..19);
+        corner.content_mut().fill(2.);
+        txn.create_commit("quilt", "latest", "latest", "corner", &[&corner])
+            .unwrap();
+        // This one overlaps the previous commit entirely (only that commit is searched)
+        corner.content_mut().fill(3.);
+        txn.create_commit("quilt", "latest", "latest", "again", &[&corner])
+            .unwrap();
+
+        let ctr = txn.get_performance_counters();
+        assert_eq!(ctr[Counter::Merge], 1);
+        assert_eq!(ctr[Counter::MergeCandidate], 2);
+
+        // Sending the same values again wouldn't change anything, so it isn't written
+        txn.create_commit("quilt", "latest", "latest", "unchanged", &[&corner])
+            .unwrap();
+        let ctr = txn.get_performance_counters();
+        assert_eq!(ctr[Counter::UnchangedPatch], 1);
+        assert_eq!(ctr[Counter::Merge], 1);
+    }
+
+    /// Measures have names in the catalog, and each one is a slice of the fetched patch
+    #[test]
+    fn test_measures() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        let labels = txn.declare_measures(&["value", "flag"]).unwrap();
+        assert_eq!(labels, vec![0, 1]);
+        // Old names keep their labels
+        let labels = txn.declare_measures(&["flag", "stamp", "flag"]).unwrap();
+        assert_eq!(labels, vec![1, 2, 1]);
+        let measures = txn.get_measures().unwrap();
+        assert_eq!(measures[1], ("flag".to_string(), 1));
+        assert_eq!(txn.get_axis(MEASURE_AXIS).unwrap().labels(), &[0, 1, 2]);
+
+        txn.create_quilt("sales", &[MEASURE_AXIS, "itm", "day"])
+            .unwrap();
+        let make = |value: f32| {
+            Patch::build()
+                .axis("itm", &[1, 2])
+                .axis("day", &[10])
+                .content_2d(&[[value], [value + 1.]])
+                .unwrap()
+        };
+        let (value, flag) = (make(5.), make(0.));
+        let both = Patch::stack(MEASURE_AXIS, &[0, 1], &[&value, &flag]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "both", &[&both])
+            .unwrap();
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(fetched.slice_label(MEASURE_AXIS, 1).unwrap(), flag);
+        assert_eq!(fetched.slice_label(MEASURE_AXIS, 0).unwrap(), value);
+        // Declared, but never written
+        let stamp = fetched.slice_label(MEASURE_AXIS, 2).unwrap();
+        assert!(stamp.is_empty_data());
+    }
+
+    /// Patches of only NAN have nothing to write, so they aren't stored
+    #[test]
+    fn test_skip_empty_patches() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        let empty = Patch::build()
+            .axis_range("x", 0..10)
+            .axis_range("y", 0..10)
+            .content(None)
+            .unwrap();
+        assert!(empty.is_empty_data());
+        let mut full = empty.clone();
+        full.content_mut()[[3, 4]] = 1.;
+        assert!(!full.is_empty_data());
+
+        txn.create_commit("quilt", "latest", "latest", "empty", &[&empty])
+            .unwrap();
+        assert_eq!(txn.get_performance_counters()[Counter::EmptyPatch], 1);
+        assert_eq!(txn.get_quilt_usage("quilt").unwrap().patches, 0);
+
+        txn.create_commit("quilt", "latest", "latest", "both", &[&empty, &full])
+            .unwrap();
+        assert_eq!(txn.get_performance_counters()[Counter::EmptyPatch], 2);
+        assert_eq!(txn.get_quilt_usage("quilt").unwrap().patches, 1);
+    }
+
+    /// Patches with scattered labels are only found where they actually have labels
+    #[test]
+    fn test_patch_intervals() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("dense", &["x"]).unwrap();
+        txn.create_quilt("sparse", &["x"]).unwrap();
+        let dense = Patch::build()
+            .axis_range("x", 0..100)
+            .content(Some(nd::ArrayD::from_elem(vec![100], 1.)))
+            .unwrap();
+        txn.create_commit("dense", "latest", "latest", "dense", &[&dense])
+            .unwrap();
+        // The first and last labels of x, so its bounding box covers the whole axis
+        let sparse = Patch::build()
+            .axis("x", &[0, 99])
+            .content(Some(nd::ArrayD::from_elem(vec![2], 2.)))
+            .unwrap();
+        txn.create_commit("sparse", "latest", "latest", "sparse", &[&sparse])
+            .unwrap();
+        let intervals = txn.get_patch_intervals(&sparse).unwrap();
+        assert_eq!(intervals.axes(), &[vec![(0, 0), (99, 99)]][..]);
+
+        let middle = BoundingBox::from_segments(vec![(40, 60)]).unwrap();
+        let found = txn.search("sparse", "latest", true, &[middle]).unwrap();
+        assert!(found.is_empty());
+        assert_eq!(txn.get_performance_counters()[Counter::IntervalRejected], 1);
+        let end = BoundingBox::from_segments(vec![(90, 99)]).unwrap();
+        let both = [middle, end];
+        let found = txn.search("sparse", "latest", true, &both).unwrap();
+        assert_eq!(found.len(), 1);
+        // Dense patches don't need intervals, and are found as before
+        let found = txn.search("dense", "latest", true, &[middle]).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(txn.get_performance_counters()[Counter::IntervalRejected], 1);
+
+        // Intervals are in the quilt's axis order, whatever the patch's was, even when indexed
+        txn.create_quilt("grid", &["x", "y"]).unwrap();
+        let flipped = Patch::build()
+            .axis("y", &[0])
+            .axis("x", &[0, 99])
+            .content_2d(&[[3., 4.]])
+            .unwrap();
+        txn.create_commit("grid", "latest", "latest", "flipped", &[&flipped])
+            .unwrap();
+        txn.enable_visibility_index("grid", "latest").unwrap();
+        let middle = BoundingBox::from_segments(vec![(40, 60), (0, 0)]).unwrap();
+        let found = txn.search("grid", "latest", true, &[middle]).unwrap();
+        assert!(found.is_empty());
+        assert_eq!(txn.get_performance_counters()[Counter::IntervalRejected], 2);
+        let end = BoundingBox::from_segments(vec![(90, 99), (0, 0)]).unwrap();
+        let found = txn.search("grid", "latest", true, &[end]).unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    /// Patches carry their bounding box, until their axes change or they go to another catalog
+    #[test]
+    fn test_reuse_bounding_box() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        let pat = Patch::build()
+            .axis_range("x", 0..10)
+            .axis_range("y", 0..10)
+            .content(Some(nd::ArrayD::from_elem(vec![10, 10], 1.)))
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "first", &[&pat])
+            .unwrap();
+        fn reused<T: StorageTransaction>(txn: &T) -> usize {
+            txn.get_performance_counters()[Counter::ReuseBoundingBox]
+        }
+        // The commit itself needs the box more than once, so it already reuses it
+        let committed = reused(&txn);
+
+        // Fetches don't find the box, since most fetched patches are never committed
+        let fetched = txn.fetch("quilt", "latest", vec![]).unwrap();
+        assert_eq!(reused(&txn), committed);
+        let bounding_box = txn.get_bounding_box(&fetched).unwrap();
+        let expected = BoundingBox::from_segments(vec![(0, 9), (0, 9)]).unwrap();
+        assert_eq!(bounding_box, expected);
+        assert_eq!(reused(&txn), committed);
+        // But once it's found, the patch keeps it
+        assert_eq!(txn.get_bounding_box(&fetched).unwrap(), bounding_box);
+        assert_eq!(reused(&txn), committed + 1);
+        // And so do its clones
+        let cloned = fetched.clone();
+        assert_eq!(txn.get_bounding_box(&cloned).unwrap(), bounding_box);
+        assert_eq!(reused(&txn), committed + 2);
+
+        // New labels could change it, so it's found again
+        let wider = Patch::build()
+            .axis_range("x", 5..15)
+            .axis_range("y", 0..10)
+            .content(Some(nd::ArrayD::from_elem(vec![10, 10], 2.)))
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "wider", &[&wider])
+            .unwrap();
+        let before = reused(&txn);
+        assert_eq!(txn.get_bounding_box(&fetched).unwrap(), bounding_box);
+        assert_eq!(reused(&txn), before);
+        txn.finish().unwrap();
+
+        // A box found in a transaction that rolled back isn't reused for other labels
+        let late = Patch::build()
+            .axis("x", &[20])
+            .axis_range("y", 0..10)
+            .content(None)
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.union_axis(&Axis::new("x", vec![20]).unwrap()).unwrap();
+        let rolled_back = txn.get_bounding_box(&late).unwrap();
+        txn.rollback().unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.union_axis(&Axis::new("x", vec![21, 20]).unwrap())
+            .unwrap();
+        let bounding_box = txn.get_bounding_box(&late).unwrap();
+        assert_ne!(bounding_box, rolled_back);
+        assert_eq!(
+            bounding_box,
+            BoundingBox::from_segments(vec![(16, 16), (0, 9)]).unwrap()
+        );
+
+        // Another catalog has its own storage order
+        let other = Catalog::connect("").unwrap();
+        let mut other_txn = other.begin().unwrap();
+        other_txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        other_txn.get_bounding_box(&fetched).unwrap();
+        assert_eq!(reused(&other_txn), 0);
+    }
+
+    /// Compacting a region shouldn't change what you fetch, but should use fewer patches
+    #[test]
+    fn test_compact_region() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        // Three disjoint strips in one commit, so none of them are merged
+        let strips = (0..3)
+            .map(|i| {
+                Patch::build()
+                    .axis_range("x", i * 10..i * 10 + 10)
+                    .axis_range("y", 0..10)
+                    .content(Some(nd::ArrayD::from_elem(vec![10, 10], i as f32)))
+                    .unwrap()
+            })
+            .collect_vec();
+        txn.create_commit(
+            "quilt",
+            "latest",
+            "latest",
+            "strips",
+            &strips.iter().collect_vec(),
+        )
+        .unwrap();
+        let before = txn.fetch("quilt", "latest", vec![]).unwrap();
+
+        let replaced = txn
+            .compact_region(
+                "quilt",
+                "latest",
+                BoundingBox::from_segments(vec![(0, 29), (0, 9)]).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(replaced, 3);
+        let refs = txn
+            .search("quilt", "latest", true, &[BoundingBox::everywhere()])
+            .unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(before, txn.fetch("quilt", "latest", vec![]).unwrap());
+    }
+
+    /// Compacting rewrites only the tag's own commit, and leaves no patches overlapping
+    #[test]
+    fn test_compact_region_tip_only() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["x", "y"]).unwrap();
+        txn.union_axis(&Axis::range("x", 0..20)).unwrap();
+        txn.union_axis(&Axis::range("y", 0..10)).unwrap();
+        let block = |xs: std::ops::Range<i64>, ys: std::ops::Range<i64>, value: f32| {
+            let shape = vec![(xs.end - xs.start) as usize, (ys.end - ys.start) as usize];
+            Patch::build()
+                .axis_range("x", xs)
+                .axis_range("y", ys)
+                .content(Some(nd::ArrayD::from_elem(shape, value)))
+                .unwrap()
+        };
+        let ancestor = block(15..20, 0..3, 9.);
+        txn.create_commit("quilt", "latest", "latest", "ancestor", &[&ancestor])
+            .unwrap();
+        // These overlap by one cell, which is too little to merge them
+        let left = block(0..10, 0..6, 1.);
+        let right = block(9..19, 5..10, 2.);
+        txn.create_commit("quilt", "latest", "latest", "tip", &[&left, &right])
+            .unwrap();
+        let before = txn.fetch("quilt", "latest", vec![]).unwrap();
+
+        // Only the left patch is in the region, but the right one overlaps it
+        let region = BoundingBox::from_segments(vec![(0, 0), (0, 0)]).unwrap();
+        assert_eq!(txn.compact_region("quilt", "latest", region).unwrap(), 2);
+        assert_eq!(before, txn.fetch("quilt", "latest", vec![]).unwrap());
+
+        let tip = txn
+            .search("quilt", "latest", false, &[BoundingBox::everywhere()])
+            .unwrap();
+        for (ix, patch_ref) in tip.iter().enumerate() {
+            for other in &tip[ix + 1..] {
+                assert!(!boxes_overlap(&patch_ref.bounding_box, &other.bounding_box));
+            }
+            let patch = txn.get_patch(patch_ref.id).unwrap();
+            for (labels, _) in patch.iter_nonnan() {
+                assert!(
+                    labels[0] < 15 || labels[1] >= 3,
+                    "ancestor cell {:?}",
+                    labels
+                );
+            }
+        }
+    }
+
+    /// Compacting a lone patch in an older format should upgrade it without changing it
+    #[test]
+    fn test_compact_region_upgrade() {
+        let path = std::env::temp_dir().join(format!("stoi-upgrade-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let cat = Catalog::connect(path).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+        txn.set_quilt_quantization("quilt", PatchQuantization::U8)
+            .unwrap();
+        let patch = Patch::autogenerate(ContentPattern::Random, 5);
+        txn.create_commit("quilt", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        let before = txn.fetch("quilt", "latest", vec![]).unwrap();
+        let patch_id = txn
+            .search("quilt", "latest", true, &[BoundingBox::everywhere()])
+            .unwrap()[0]
+            .id;
+        txn.finish().unwrap();
+        drop(cat);
+
+        // Version 1 is the same as version 2 without the integer filters
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let mut content: Vec<u8> = conn
+            .query_row(
+                "SELECT content FROM PatchContent WHERE patch_id = ?;",
+                &[&patch_id],
+                |r| r.get(0),
+            )
+            .unwrap();
+        content[4] = 1;
+        conn.execute(
+            "UPDATE PatchContent SET content = ? WHERE patch_id = ?;",
+            &[&content as &dyn rusqlite::ToSql, &patch_id],
+        )
+        .unwrap();
+        drop(conn);
+
+        let cat = Catalog::connect(path).unwrap();
+        let mut txn = cat.begin().unwrap();
+        let region = BoundingBox::everywhere();
+        assert_eq!(txn.compact_region("quilt", "latest", region).unwrap(), 1);
+        let content = txn.get_patch_content(patch_id).unwrap().unwrap();
+        assert_eq!(
+            Patch::serialized_version(&content).unwrap(),
+            crate::patch::PATCH_VERSION
+        );
+        // The quantized values were kept as they were, not rounded again
+        assert_eq!(before, txn.fetch("quilt", "latest", vec![]).unwrap());
+        assert_eq!(txn.compact_region("quilt", "latest", region).unwrap(), 0);
+        txn.finish().unwrap();
+        cat.close().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Every bad patch should be reported, and the rest committed only if that's allowed
+    #[test]
+    fn test_create_commit_report() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        let good = Patch::build()
+            .axis("lct", &[10])
+            .axis("itm", &[1, 2])
+            .content_2d(&[[1., 2.]])
+            .unwrap();
+        let missing = Patch::build().axis("itm", &[3]).content_1d(&[3.]).unwrap();
+        let extra = Patch::build()
+            .axis("itm", &[4])
+            .axis("color", &[5])
+            .content_2d(&[[4.]])
+            .unwrap();
+        let patches = [&good, &missing, &good, &extra];
+
+        let report = txn
+            .create_commit_report("sales", "latest", "latest", "message", &patches, false)
+            .unwrap();
+        assert!(report.committed.is_empty());
+        assert_eq!(
+            report.rejected.iter().map(|(ix, _)| *ix).collect_vec(),
+            vec![1, 3]
+        );
+        assert!(report.rejected[0].1.contains("missing [lct]"));
+        assert!(report.rejected[1].1.contains("extra [color]"));
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert!(fetched.content().is_empty());
+
+        let report = txn
+            .create_commit_report("sales", "latest", "latest", "message", &patches, true)
+            .unwrap();
+        assert_eq!(report.committed, vec![0, 2]);
+        assert_eq!(report.rejected.len(), 2);
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 2.]
+        );
+
+        // Plain commits say which patch was wrong
+        match txn.create_commit("sales", "latest", "latest", "message", &patches) {
+            Err(StoiError::MisalignedAxes(message)) => assert!(message.contains("patch 1")),
+            other => panic!("expected misaligned axes, got {:?}", other),
+        }
+
+        // Repeated labels are ambiguous in any quilt, until they're merged
+        let repeated = Patch::new(
+            vec![Axis::range("itm", vec![7, 7]), Axis::range("lct", vec![10])],
+            Some(nd::ArrayD::from_elem(vec![2, 1], 7.)),
+        )
+        .unwrap();
+        let report = txn
+            .create_commit_report("sales", "latest", "latest", "message", &[&repeated], true)
+            .unwrap();
+        assert!(report.rejected[0].1.contains("repeats 1 labels"));
+        let merged = repeated.dedup_labels(ReduceOp::Max).unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&merged])
+            .unwrap();
+    }
+
+    /// Selections for axes the quilt doesn't have should be errors, not ignored
+    #[test]
+    fn test_unknown_axis_selections() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+
+        let named = vec![
+            ("lct".to_string(), AxisSelection::Labels(vec![10])),
+            ("color".to_string(), AxisSelection::Labels(vec![5])),
+        ];
+        match txn.order_named_request("sales", named.clone(), true) {
+            Err(StoiError::UnknownAxis(quilt, axis, axes)) => {
+                assert_eq!((quilt.as_str(), axis.as_str()), ("sales", "color"));
+                assert_eq!(axes, "itm, lct");
+            }
+            other => panic!("expected an unknown axis, got {:?}", other),
+        }
+        // Lenient requests ignore it, and put the rest in the quilt's order
+        assert_eq!(
+            txn.order_named_request("sales", named, false).unwrap(),
+            vec![AxisSelection::All, AxisSelection::Labels(vec![10])]
+        );
+
+        // Positional requests can't have more selections than axes either
+        let request = vec![AxisSelection::All; 3];
+        assert!(txn.fetch("sales", "latest", request).is_err());
+    }
+
+    /// Named fetches keep the order the axes were named in, rather than the quilt's
+    #[test]
+    fn test_fetch_named() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct", "day"]).unwrap();
+        let mut pat = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[10])
+            .axis("day", &[5, 6, 7])
+            .content(None)
+            .unwrap();
+        for (ix, x) in pat.content_mut().iter_mut().enumerate() {
+            *x = ix as f32;
+        }
+        txn.create_commit("sales", "latest", "latest", "first", &[&pat])
+            .unwrap();
+
+        let named = vec![
+            ("day".to_string(), AxisSelection::Labels(vec![7, 5])),
+            ("itm".to_string(), AxisSelection::All),
+        ];
+        let fetched = txn.fetch_named("sales", "latest", named, true).unwrap();
+        let names = fetched.axes().iter().map(|a| a.name.as_str()).collect_vec();
+        assert_eq!(names, vec!["day", "itm", "lct"]);
+        assert_eq!(fetched.get(&[7, 2, 10]), pat.get(&[2, 10, 7]));
+        let plain = txn.fetch("sales", "latest", vec![]).unwrap();
+        let permuted = plain.permute_axes(&["lct", "itm", "day"]).unwrap();
+        assert_eq!(permuted.content().shape(), &[1, 2, 3]);
+        assert_eq!(permuted.get(&[10, 1, 6]), Some(1.));
+        assert!(plain.permute_axes(&["lct", "itm"]).is_err());
+        assert!(plain.permute_axes(&["lct", "itm", "itm"]).is_err());
+    }
+
+    /// Joined fetches share the labels of their common axes, even if the quilts differ
+    #[test]
+    fn test_fetch_joined() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("price", &["itm"]).unwrap();
+        txn.create_quilt("volume", &["itm", "lct"]).unwrap();
+        let price = Patch::build()
+            .axis("itm", &[3, 1])
+            .content_1d(&[2., 5.])
+            .unwrap();
+        txn.create_commit("price", "latest", "latest", "price", &[&price])
+            .unwrap();
+        let volume = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[10, 11])
+            .content_2d(&[[1., 2.], [3., 4.]])
+            .unwrap();
+        txn.create_commit("volume", "latest", "latest", "volume", &[&volume])
+            .unwrap();
+
+        let named = vec![("itm".to_string(), AxisSelection::Labels(vec![1, 2, 3]))];
+        let sources = [("price", "latest"), ("volume", "latest")];
+        let joined = txn.fetch_joined(&sources, named).unwrap();
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[0].axes()[0], joined[1].axes()[0]);
+        assert_eq!(joined[0].axes()[0].labels(), &[1, 2, 3]);
+        assert_eq!(joined[1].axes()[1].labels(), &[10, 11]);
+        assert_eq!(joined[0].get(&[1]), Some(5.));
+        assert_eq!(joined[1].get(&[1, 11]), Some(2.));
+
+        // A typo on an axis nobody has is an error, but one the other quilt has is fine
+        let typo = vec![("itme".to_string(), AxisSelection::All)];
+        assert!(txn.fetch_joined(&sources, typo).is_err());
+        let lct = vec![("lct".to_string(), AxisSelection::Labels(vec![11]))];
+        let joined = txn.fetch_joined(&sources, lct).unwrap();
+        // Without a selection, itm has every label of the axis in storage order, price's first
+        assert_eq!(joined[1].content().shape(), &[3, 1]);
+        assert_eq!(joined[1].axes()[0].labels(), &[3, 1, 2]);
+    }
+
+    /// The history has every commit a tag leads to, with the tags and how it branches
+    #[test]
+    fn test_export_history() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let pat = Patch::build().axis("itm", &[1]).content_1d(&[5.]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "first", &[&pat])
+            .unwrap();
+        txn.create_commit("sales", "latest", "trial", "a \"trial\"\nbranch", &[])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "second", &[])
+            .unwrap();
+        txn.finish().unwrap();
+
+        let history = cat.begin_read().unwrap().get_history("sales").unwrap();
+        let messages = history.iter().map(|c| c.message.as_str()).collect_vec();
+        assert_eq!(messages, vec!["first", "a \"trial\"\nbranch", "second"]);
+        assert_eq!(history[0].patches, 1);
+        assert_eq!(history[1].parent_comm_id, Some(history[0].comm_id));
+        assert_eq!(history[2].parent_comm_id, Some(history[0].comm_id));
+        assert_eq!(history[1].tags, vec!["trial"]);
+        assert_eq!(history[2].tags, vec!["latest"]);
+
+        let json = cat.export_history("sales", HistoryFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["commits"][0]["patches"], 1);
+        let dot = cat.export_history("sales", HistoryFormat::Dot).unwrap();
+        let edge = format!("c{} -> c{};", history[2].comm_id, history[0].comm_id);
+        assert!(dot.contains(&edge));
+        assert!(dot.contains(r#"[label="a \"trial\"\n0 patches"]"#));
+        assert!(dot.contains(&format!(r#""tag:trial" -> c{};"#, history[1].comm_id)));
+    }
+
+    /// Commits past the retention expire, and maintenance merges them into their parents
+    #[test]
+    fn test_retention() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        for itm in 0..5 {
+            // Every commit overwrites label 10, so they must stay in order
+            let pat = Patch::build()
+                .axis("itm", &[itm, 10])
+                .content_1d(&[itm as f32, itm as f32])
+                .unwrap();
+            if itm == 3 {
+                txn.create_commit_keyed("sales", "latest", "latest", "message", &[&pat], "run-3")
+                    .unwrap();
+            } else {
+                txn.create_commit("sales", "latest", "latest", "message", &[&pat])
+                    .unwrap();
+            }
+            if itm == 1 {
+                txn.create_commit("sales", "latest", "trial", "branch", &[])
+                    .unwrap();
+            }
+        }
+        let before = (
+            txn.fetch("sales", "latest", vec![]).unwrap(),
+            txn.fetch("sales", "trial", vec![]).unwrap(),
+        );
+        let retention = QuiltRetention {
+            max_age_days: None,
+            max_depth: Some(0),
+        };
+        txn.set_quilt_retention("sales", Some(retention)).unwrap();
+        assert_eq!(txn.get_quilt_retention("sales").unwrap(), Some(retention));
+        let history = txn.get_history("sales").unwrap();
+        let expired = history.iter().map(|commit| commit.expired).collect_vec();
+        assert_eq!(expired, vec![true, true, false, true, true, false]);
+        txn.finish().unwrap();
+
+        // The second commit has two children now, so the third can't be merged into it
+        let options = MaintenanceOptions {
+            derived_work: false,
+            tune_patch_bytes: false,
+            compact: false,
+            refresh_indices: false,
+            ..MaintenanceOptions::default()
+        };
+        assert_eq!(cat.run_maintenance(&options).unwrap().collected_commits, 2);
+        assert_eq!(cat.performance_snapshot()[Counter::ExpiredCommit], 2);
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(txn.get_history("sales").unwrap().len(), 4);
+        let after = (
+            txn.fetch("sales", "latest", vec![]).unwrap(),
+            txn.fetch("sales", "trial", vec![]).unwrap(),
+        );
+        assert_eq!(after, before);
+        assert_eq!(txn.collect_expired_commits("sales").unwrap(), 0);
+        // The keyed commit was merged into its parent, which the key finds instead
+        assert_eq!(
+            txn.get_keyed_commit("sales", "run-3").unwrap(),
+            Some(history[3].comm_id)
+        );
+
+        // Everything untagged is older than no days at all
+        let retention = QuiltRetention {
+            max_age_days: Some(0),
+            max_depth: None,
+        };
+        txn.set_quilt_retention("sales", Some(retention)).unwrap();
+        let history = txn.get_history("sales").unwrap();
+        assert!(history.iter().all(|c| c.expired == c.tags.is_empty()));
+
+        // Ages come from when commits were made, whatever their ids are
+        let retention = QuiltRetention {
+            max_age_days: Some(30),
+            max_depth: None,
+        };
+        let now = chrono::Utc::now();
+        let mut history = history;
+        for commit in history.iter_mut() {
+            commit.comm_id = now.timestamp_nanos() + commit.comm_id % 1000;
+            commit.tags.clear();
+        }
+        history[0].created_at = Some((now - chrono::Duration::days(31)).to_rfc3339());
+        history[1].created_at = Some((now - chrono::Duration::days(29)).to_rfc3339());
+        history[2].created_at = None;
+        retention.mark_expired(&mut history, now);
+        let expired = history.iter().map(|commit| commit.expired).collect_vec();
+        assert_eq!(expired, vec![true, false, false, false]);
+    }
+
+    /// Fetches should reuse buffers from the pool, and still get the same results
+    #[test]
+    fn test_buffer_pool() {
+        let options = CatalogOptions {
+            buffer_pool_bytes: 16 << 20,
+            ..CatalogOptions::default()
+        };
+        let cat = Catalog::connect_with("", options).unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+        let patch = Patch::build()
+            .axis_range("dim0", 0..200)
+            .axis_range("dim1", 0..200)
+            .content(nd::Array2::from_elem((200, 200), 1.).into_dyn())
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.finish().unwrap();
+        cat.reset_performance_counters();
+
+        let fetch = || {
+            let mut txn = cat.begin_read().unwrap();
+            txn.fetch("quilt", "latest", vec![]).unwrap()
+        };
+        let first = fetch();
+        assert_eq!(first, patch);
+        cat.recycle(first);
+        // Parts of a fetch are smaller, and the spare space has to be NAN again
+        let mut txn = cat.begin_read().unwrap();
+        let part = txn
+            .fetch(
+                "quilt",
+                "latest",
+                vec![AxisSelection::StorageSlice(0, 100), AxisSelection::All],
+            )
+            .unwrap();
+        assert!(part.content().iter().all(|&x| x == 1.));
+        drop(txn);
+        assert_eq!(fetch(), patch);
+
+        let counters = cat.performance_snapshot();
+        assert_eq!(counters[Counter::PoolAllocate], 1);
+        assert_eq!(counters[Counter::PoolReuse], 2);
+    }
+
+    /// Writes past the end of the append axis should skip merging and be split into blocks
+    #[test]
+    fn test_append_commits() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "day"]).unwrap();
+        txn.union_axis(&Axis::range("itm", 0..300)).unwrap();
+        assert!(txn.set_quilt_append_axis("sales", Some("lct")).is_err());
+        txn.set_quilt_append_axis("sales", Some("day")).unwrap();
+        assert_eq!(
+            txn.get_quilt_append_axis("sales").unwrap(),
+            Some("day".into())
+        );
+
+        let day = |d: i64, value: f32| {
+            Patch::build()
+                .axis_range("itm", 0..300)
+                .axis("day", &[d])
+                .content(nd::Array2::from_elem((300, 1), value).into_dyn())
+                .unwrap()
+        };
+        let before = txn.get_performance_counters();
+        txn.create_commit("sales", "latest", "latest", "day 1", &[&day(1, 1.)])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "day 2", &[&day(2, 2.)])
+            .unwrap();
+        let after = txn.get_performance_counters();
+        let appends = after[Counter::AppendCommit] - before[Counter::AppendCommit];
+        assert_eq!(appends, 2);
+        let candidates = after[Counter::MergeCandidate] - before[Counter::MergeCandidate];
+        assert_eq!(candidates, 0);
+        // Items 0-255 are one block and 256-299 are another, for each day
+        assert_eq!(after[Counter::WritePatch] - before[Counter::WritePatch], 4);
+
+        // Rewriting a day that's already there isn't an append
+        txn.create_commit("sales", "latest", "latest", "fix", &[&day(1, 3.)])
+            .unwrap();
+        let fixed = txn.get_performance_counters();
+        assert_eq!(fixed[Counter::AppendCommit], after[Counter::AppendCommit]);
+
+        let fetched = txn
+            .fetch("sales", "latest", vec![AxisSelection::Labels(vec![0, 299])])
+            .unwrap();
+        let content = fetched.content().iter().copied().collect_vec();
+        assert_eq!(content, vec![3., 2., 3., 2.]);
+    }
+
+    /// Quilts made from a template should have all its settings, and conflicts should stop it
+    #[test]
+    fn test_create_quilts_from_template() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        let template = QuiltTemplate {
+            quota: Some(QuiltQuota {
+                max_bytes: Some(1 << 20),
+                max_patches: None,
+            }),
+            target_patch_bytes: Some(1 << 16),
+            ..QuiltTemplate::new(&["itm", "day"])
+        };
+        let created = txn
+            .create_quilts_from_template(&template, &["sales", "returns", "sales"])
+            .unwrap();
+        assert_eq!(created, 2);
+        assert_eq!(txn.get_quilt_template("returns").unwrap(), template);
+
+        // Templates can come from existing quilts, and quilts that match are left alone
+        let mut copied = txn.get_quilt_template("sales").unwrap();
+        assert_eq!(copied, template);
+        copied.quota = None;
+        let created = txn
+            .create_quilts_from_template(&copied, &["sales", "orders"])
+            .unwrap();
+        assert_eq!(created, 1);
+        assert_eq!(txn.get_quilt_template("sales").unwrap(), template);
+        assert_eq!(txn.get_quilt_template("orders").unwrap().quota, None);
+
+        // A quilt with other axes is a conflict, and nothing is created
+        txn.create_quilt("stock", &["itm", "lct"]).unwrap();
+        assert!(txn
+            .create_quilts_from_template(&template, &["views", "stock"])
+            .is_err());
+        assert!(txn.get_quilt_details("views").is_err());
+    }
+
+    /// Commits that would rewrite much more than they were given should fail or stop merging
+    #[test]
+    fn test_write_amplification_limit() {
+        let cat = Catalog::connect("").unwrap();
+        cat.set_target_patch_bytes(4000);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+        let base = Patch::build()
+            .axis_range("dim0", 0..100)
+            .axis_range("dim1", 0..100)
+            .content(nd::Array2::from_elem((100, 100), 1.).into_dyn())
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&base])
+            .unwrap();
+        txn.finish().unwrap();
+        // This overlaps enough to merge, which would rewrite 40KB for 8KB
+        let update = Patch::build()
+            .axis_range("dim0", 0..20)
+            .axis_range("dim1", 0..100)
+            .content(nd::Array2::from_elem((20, 100), 2.).into_dyn())
+            .unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        txn.set_write_amplification_limit(WriteAmplificationLimit::Abort(2));
+        match txn.create_commit("quilt", "latest", "latest", "message", &[&update]) {
+            Err(StoiError::WriteAmplification(_, 40000, 8000)) => (),
+            other => panic!("expected too much amplification, got {:?}", other),
+        }
+        // Nothing changed, and the transaction is still usable
+        assert_eq!(txn.get_quilt_usage("quilt").unwrap().patches, 1);
+        let fetched = txn.fetch("quilt", "latest", vec![]).unwrap();
+        assert!(fetched.content().iter().all(|&x| x == 1.));
+        drop(txn);
+
+        // Appending instead keeps the old patch
+        cat.set_write_amplification_limit(WriteAmplificationLimit::Append(2))
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&update])
+            .unwrap();
+        assert_eq!(txn.get_quilt_usage("quilt").unwrap().patches, 2);
+        let fetched = txn.fetch("quilt", "latest", vec![]).unwrap();
+        assert_eq!(fetched.content().iter().filter(|&&x| x == 2.).count(), 2000);
+        let counters = txn.get_performance_counters();
+        assert_eq!(counters[Counter::WriteAmplificationLimited], 1);
+
+        txn.finish().unwrap();
+
+        // And without a limit it merges as usual, here with the patch appended just now
+        cat.set_write_amplification_limit(WriteAmplificationLimit::Unlimited)
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        let mut update = update;
+        update.content_mut().fill(3.);
+        txn.create_commit("quilt", "latest", "latest", "message", &[&update])
+            .unwrap();
+        assert_eq!(txn.get_performance_counters()[Counter::Merge], 1);
+        assert_eq!(txn.get_quilt_usage("quilt").unwrap().patches, 2);
+    }
+
+    /// Label indices should follow labels as they're added to an axis
+    #[test]
+    fn test_label_index() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0"]).unwrap();
+        let first = Patch::build()
+            .axis("dim0", &[5, 3])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&first])
+            .unwrap();
+        let index = txn.get_label_index("dim0").unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!((index[&5], index[&3]), (0, 1));
+
+        // New labels are appended to the index that's already there
+        let second = Patch::build()
+            .axis("dim0", &[3, 9])
+            .content_1d(&[3., 4.])
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&second])
+            .unwrap();
+        assert_eq!(txn.get_label_index("dim0").unwrap()[&9], 2);
+        let probe = Patch::build()
+            .axis("dim0", &[9, 5, 404])
+            .content_1d(&[0., 0., 0.])
+            .unwrap();
+        assert_eq!(txn.get_bounding_box(&probe).unwrap()[0], (0, 2));
+
+        // Selections only cover the labels the axis has
+        let (_, segments) = txn
+            .resolve_selection("dim0", AxisSelection::Labels(vec![404, 9, 3]))
+            .unwrap();
+        assert_eq!(segments, vec![(1, 2)]);
+        let (_, segments) = txn
+            .resolve_selection("dim0", AxisSelection::LabelSlice(3, 9))
+            .unwrap();
+        assert_eq!(segments, vec![(1, 3)]);
+
+        // Without any labels the axis has, there's nothing to search, not even storage index 0
+        for labels in vec![vec![], vec![404]] {
+            let request = vec![AxisSelection::Labels(labels.clone())];
+            let (axes, bounding_boxes) = txn.resolve_request("quilt", request.clone()).unwrap();
+            assert_eq!(axes[0].labels(), &labels[..]);
+            assert!(bounding_boxes.is_empty());
+            let patch = txn.fetch("quilt", "latest", request).unwrap();
+            assert!(patch.content().iter().all(|x| x.is_nan()));
+        }
+    }
+
+    /// Patches with axes in another order than the quilt's should still be found by label
+    #[test]
+    fn test_commit_axes_out_of_order() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "day"]).unwrap();
+        let wide = Patch::build()
+            .axis_range("itm", 0..10)
+            .axis_range("day", 0..2)
+            .content(None)
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&wide])
+            .unwrap();
+        // Day 1 of item 8 is far along itm, but not along day
+        let flipped = Patch::build()
+            .axis("day", &[1])
+            .axis("itm", &[8])
+            .content_2d(&[[5.]])
+            .unwrap();
+        txn.create_commit("sales", "latest", "other", "message", &[&flipped])
+            .unwrap();
+
+        let request = vec![
+            AxisSelection::Labels(vec![8]),
+            AxisSelection::Labels(vec![1]),
+        ];
+        let fetched = txn.fetch("sales", "other", request).unwrap();
+        assert_eq!(fetched.content()[[0, 0]], 5.);
+    }
+
+    /// Ranges of label values should find labels wherever they are stored
+    #[test]
+    fn test_fetch_label_range() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["day"]).unwrap();
+        let patch = Patch::build()
+            .axis("day", &[20200302, 20200228, 20200401, 20200301, 20200331])
+            .content_1d(&[1., 2., 3., 4., 5.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+
+        let march = vec![AxisSelection::LabelRange(20200301, 20200331)];
+        let fetched = txn.fetch("sales", "latest", march.clone()).unwrap();
+        assert_eq!(fetched.axes()[0].labels(), &[20200302, 20200301, 20200331]);
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 4., 5.]
+        );
+        let (_, segments) = txn.resolve_selection("day", march[0].clone()).unwrap();
+        assert_eq!(segments, vec![(0, 1), (3, 5)]);
+
+        // New labels are found too, since the sorted index is rebuilt when the axis changes
+        let patch = Patch::build()
+            .axis("day", &[20200315])
+            .content_1d(&[6.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        let fetched = txn.fetch("sales", "latest", march).unwrap();
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 4., 5., 6.]
+        );
+
+        // An empty range is an empty selection
+        let fetched = txn
+            .fetch("sales", "latest", vec![AxisSelection::LabelRange(1, 2)])
+            .unwrap();
+        assert_eq!(fetched.axes()[0].len(), 0);
+    }
+
+    /// Rolling back to a savepoint should undo only what came after it
+    #[test]
+    fn test_savepoints() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let first = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "first", &[&first])
+            .unwrap();
+        txn.savepoint("checkpoint").unwrap();
+        let second = Patch::build().axis("itm", &[3]).content_1d(&[3.]).unwrap();
+        txn.create_commit("sales", "latest", "latest", "second", &[&second])
+            .unwrap();
+        assert_eq!(txn.get_axis_len("itm").unwrap(), 3);
+
+        // The new label is gone too, even though the axis was cached
+        txn.rollback_to("checkpoint").unwrap();
+        assert_eq!(txn.get_axis("itm").unwrap().labels(), &[1, 2]);
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 2.]
+        );
+
+        // The savepoint is still there after rolling back to it, until it's released
+        txn.rollback_to("checkpoint").unwrap();
+        txn.release("checkpoint").unwrap();
+        assert!(txn.release("checkpoint").is_err());
+        assert!(txn.savepoint("no spaces").is_err());
+        txn.finish().unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(
+            fetched.content().iter().copied().collect_vec(),
+            vec![1., 2.]
+        );
+    }
 
-        //
-        // Find the patches we need to fill all the bounding boxes
-        //
+    /// A transaction should see its own commits, and nothing should be left if it rolls back
+    #[test]
+    fn test_read_your_writes() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0"]).unwrap();
+        txn.finish().unwrap();
 
-        let patch_refs = self.search(&quilt_name, &tag, true, &bounding_boxes)?;
+        let patch = Patch::build()
+            .axis("dim0", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        assert_eq!(txn.fetch("quilt", "latest", vec![]).unwrap(), patch);
+        drop(txn);
 
-        //
-        // Download and apply all the patches
-        //
+        // Dropping it rolls back, even the labels it added to the axis
+        let mut txn = cat.begin_read().unwrap();
+        let fetched = txn.fetch("quilt", "latest", vec![]).unwrap();
+        assert!(fetched.content().is_empty());
+        assert_eq!(txn.get_axis_len("dim0").unwrap(), 0);
+    }
 
-        // TODO: This should definitely be async or at least concurrent
-        let mut target_patch = Patch::new(axes, None)?;
-        for patch_ref in patch_refs {
-            let source_patch = self.get_patch(patch_ref.id)?;
-            target_patch.apply(&source_patch)?;
+    /// Fetching small parts of large patches should make later patches smaller
+    #[test]
+    fn test_auto_tune_patch_bytes() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("quilt", &["dim0", "dim1"]).unwrap();
+        let patch = Patch::build()
+            .axis_range("dim0", 0..200)
+            .axis_range("dim1", 0..200)
+            .content(nd::Array2::from_elem((200, 200), 1.).into_dyn())
+            .unwrap();
+        txn.create_commit("quilt", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        assert_eq!(txn.get_quilt_target_patch_bytes("quilt").unwrap(), None);
+        txn.finish().unwrap();
+
+        for ix in 0..10 {
+            let mut txn = cat.begin_read().unwrap();
+            let cell = AxisSelection::Labels(vec![ix]);
+            txn.fetch("quilt", "latest", vec![cell.clone(), cell])
+                .unwrap();
         }
+        let counters = cat.performance_snapshot();
+        assert_eq!(counters[Counter::ApplyShuffledBytes], 10 * 160_000);
+        assert_eq!(counters[Counter::ApplyCopiedBytes], 10 * 4);
 
-        Ok(target_patch)
-    }
+        // Almost everything read was wasted, so the target is halved
+        let tuned = cat.auto_tune_patch_bytes().unwrap();
+        assert_eq!(tuned["quilt"], 2 << 20);
+        let mut txn = cat.begin().unwrap();
+        assert_eq!(
+            txn.get_quilt_target_patch_bytes("quilt").unwrap(),
+            Some(2 << 20)
+        );
+        txn.trace_quilt("quilt");
+        assert_eq!(txn.target_patch_bytes(), 2 << 20);
+        txn.finish().unwrap();
 
-    /// Split a patch in half if it's larger than it probably should be.
-    ///
-    /// This
-    ///
-    /// Accepts:
-    ///     long_axis: the global axis to split in half
-    ///
-    /// Returns:
-    ///     Either: A vec with one element, which is a Cow::Borrowed(&self)
-    ///     Or: A vec with 2+ elements, which are all Cow::Owned(Patch)
-    fn maybe_split(&mut self, original: Patch) -> Fallible<Vec<Patch>> {
-        self.trace(Counter::MaybeSplit, 1);
-        match original.content().len() {
-            0 => Ok(vec![]),                   // Take out the trash
-            1..=1048576 => Ok(vec![original]), // Cap at 4 MB
-            _ => {
-                // Split everything else
-                self.trace(Counter::Split, 1);
-
-                // Split it along it's longest axis
-                let (long_ax_ix, long_axis) = original
-                    .axes()
-                    .iter()
-                    .enumerate()
-                    .max_by_key(|(_ax_ix, ax)| ax.labels().len())
-                    .unwrap(); // <- Patch::new() checks for at least one axis
-                               // Replace the patch axis for the global axis by that name
-
-                let global_long_axis = self.get_axis(&long_axis.name)?;
-                // This is a heuristic and it could use more serious study
-                let long_axis_labelset: HashMap<Label, usize> = long_axis
-                    .labels()
-                    .iter()
-                    .copied()
-                    .enumerate()
-                    .map(|(a, b)| (b, a))
-                    .collect();
+        // Those fetches were used up, so there's nothing to go on until there are more
+        assert!(cat.auto_tune_patch_bytes().unwrap().is_empty());
 
-                let global_locations = global_long_axis
-                    .labels()
-                    .iter()
-                    .filter_map(|global_label| long_axis_labelset.get(global_label))
-                    .copied()
-                    .collect_vec();
+        // Clearing it goes back to the catalog's
+        let mut txn = cat.begin().unwrap();
+        txn.set_quilt_target_patch_bytes("quilt", None).unwrap();
+        txn.trace_quilt("quilt");
+        assert_eq!(txn.target_patch_bytes(), 4 << 20);
+        assert!(txn
+            .set_quilt_target_patch_bytes("nope", Some(1 << 20))
+            .is_err());
+    }
 
-                if global_locations.len() < long_axis_labelset.len() {
-                    return Err(StoiError::MisalignedAxes(
-                        "Patch contains labels not present in the global axis. 
-                    Always union global axes against patch axes before splitting a patch,
-                    because otherwise it's not clear what the Patch's bounding box would be."
-                            .into(),
-                    ));
-                }
+    /// Backups should copy everything, and record which commits they captured
+    #[test]
+    fn test_backup_to() {
+        let path = std::env::temp_dir().join(format!("stoi-backup-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.finish().unwrap();
 
-                // The important part - split the long axis in half according to the global axis order
-                let (left_patch_indices, right_patch_indices) =
-                    global_locations.split_at(global_locations.len() / 2);
+        let snapshot = cat.backup_to(path).unwrap();
+        assert_eq!(snapshot.tags.len(), 1);
+        assert_eq!(snapshot.tags[0].0, "sales");
+        assert_eq!(snapshot.tags[0].1, "latest");
+        // Backups never overwrite anything
+        assert!(cat.backup_to(path).is_err());
+        assert_eq!(cat.begin_read().unwrap().get_snapshot().unwrap(), None);
 
-                let mut patches = vec![];
-                for indices in &[left_patch_indices, right_patch_indices] {
-                    let mut axes = original.axes().to_vec();
-                    // Replace the long axis
-                    axes[long_ax_ix] = Axis::new_unchecked(
-                        &long_axis.name,
-                        indices
-                            .iter()
-                            .map(|ix| long_axis.labels()[*ix])
-                            .collect_vec(),
-                    );
-                    // Slice the patch
-                    let sliced_patch = Patch::new(
-                        axes.to_vec(),
-                        Some(original.content().select(nd::Axis(long_ax_ix), indices)),
-                    )
-                    .unwrap()
-                    .compact()
-                    .into_owned();
-                    patches.extend(self.maybe_split(sliced_patch)?)
-                }
-                Ok(patches)
-            }
-        }
+        let backup = Catalog::connect(path).unwrap();
+        let mut txn = backup.begin_read().unwrap();
+        assert_eq!(txn.get_snapshot().unwrap(), Some(snapshot));
+        assert_eq!(txn.fetch("sales", "latest", vec![]).unwrap(), patch);
+        drop(txn);
+        backup.close().unwrap();
+        std::fs::remove_file(path).unwrap();
     }
 
-    /// Get the bounding box of a patch
-    ///
-    /// These bounding boxes depend on the storage order of the catalog, so they aren't something
-    /// the Patch could know on its own, instead you find this through the catalog
-    fn get_bounding_box(&mut self, patch: &Patch) -> Fallible<BoundingBox> {
-        self.trace(Counter::GetBoundingBox, 1);
-        let bbvec = (0..4)
-            .map(|ax_ix| match patch.axes().get(ax_ix) {
-                Some(patch_axis) => {
-                    let patch_axis_labelset: HashSet<Label> =
-                        patch_axis.labels().iter().copied().collect();
-                    let global_axis = self.get_axis(&patch_axis.name)?;
-                    let first = global_axis
-                        .labels()
-                        .iter()
-                        .position(|x| patch_axis_labelset.contains(x));
-                    let last = global_axis
-                        .labels()
-                        .iter()
-                        .rposition(|x| patch_axis_labelset.contains(x));
+    /// Catalog URLs choose their backend, and unknown backends are errors rather than files
+    #[test]
+    fn test_catalog_url() {
+        assert_eq!(CatalogUrl::parse("").unwrap(), CatalogUrl::Memory);
+        let path = std::path::PathBuf::from("/tmp/a.db");
+        let parsed = CatalogUrl::parse("/tmp/a.db").unwrap();
+        assert_eq!(parsed, CatalogUrl::SQLite(path.clone()));
+        assert_eq!(CatalogUrl::parse("sqlite:/tmp/a.db").unwrap(), parsed);
+        let parsed = CatalogUrl::parse("replica:/tmp/a.db").unwrap();
+        assert_eq!(parsed, CatalogUrl::Replica(path));
+        let parsed = CatalogUrl::parse("file:a.db?mode=memory").unwrap();
+        assert_eq!(parsed, CatalogUrl::SQLite("file:a.db?mode=memory".into()));
+        let parsed = CatalogUrl::parse("C://stoi/a.db").unwrap();
+        assert_eq!(parsed, CatalogUrl::SQLite("C://stoi/a.db".into()));
 
-                    Ok((first.unwrap_or(0), last.unwrap_or(1 << 60)))
-                }
-                None => Ok((0, 1 << 60)),
-            })
-            .collect::<Fallible<Vec<AxisSegment>>>()?;
-        Ok(bbvec[..].try_into()?)
+        match Catalog::connect("postgres://localhost/stoi") {
+            Err(StoiError::UnsupportedBackend(scheme)) => assert_eq!(scheme, "postgres://"),
+            _ => panic!("postgres catalogs aren't supported yet"),
+        }
     }
 
-    /// Untag a commit, to "delete" it
-    ///
-    /// Untagging a commit doesn't remove its effects, it only makes it inaccessible
-    /// and allows (now or any time in the future) for the library to:
-    ///
-    /// - Merge it into its successors, if it has any
-    /// - Garbage collect it otherwise
-    fn untag(&self, _quilt_name: &str, _tag: &str) -> Fallible<()> {
-        // TODO: Implement untag
-        Ok(())
-    }
+    /// Replicas should only see the primary's commits once they're synced, and report their lag
+    #[test]
+    fn test_sync_replica() {
+        let dir = std::env::temp_dir();
+        let primary_path = dir.join(format!("stoi-primary-{}.db", rand::random::<u32>()));
+        let replica_path = dir.join(format!("stoi-replica-{}.db", rand::random::<u32>()));
+        let (primary_path, replica_path) = (
+            primary_path.to_str().unwrap(),
+            replica_path.to_str().unwrap(),
+        );
+        let primary = Catalog::connect(primary_path).unwrap();
+        let mut txn = primary.begin().unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.finish().unwrap();
+        primary.backup_to(replica_path).unwrap();
+        assert!(primary.sync_replica(&primary).is_err());
+        assert_eq!(primary.replica_lag().unwrap(), None);
 
-    /// Retrieve performance counters, useful for debugging performance problems
-    ///
-    /// Returns: a Map containing the counters by name
-    fn get_performance_counters(&self) -> EnumMap<Counter, usize>;
-}
+        let replica = Catalog::connect(&format!("replica:{}", replica_path)).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let mut txn = primary.begin().unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+            .unwrap();
+        txn.finish().unwrap();
+        let mut txn = replica.begin_read().unwrap();
+        assert_eq!(txn.get_tag_commit("sales", "latest").unwrap(), None);
+        drop(txn);
 
-/// Metadata about a quilt
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct QuiltDetails {
-    pub(crate) name: String,
-    pub(crate) axes: Vec<String>,
-}
-/// Read a QuiltDetails from SQLite
-impl TryFrom<&rusqlite::Row<'_>> for QuiltDetails {
-    type Error = rusqlite::Error;
+        replica.sync_replica(&primary).unwrap();
+        let mut txn = replica.begin_read().unwrap();
+        assert_eq!(txn.fetch("sales", "latest", vec![]).unwrap(), patch);
+        assert!(txn.create_quilt("returns", &["itm"]).is_err());
+        drop(txn);
+        let lag = replica.replica_lag().unwrap().unwrap();
+        assert!(lag < std::time::Duration::from_secs(60));
 
-    fn try_from(row: &rusqlite::Row) -> Result<Self, Self::Error> {
-        Ok(QuiltDetails {
-            name: row.get("quilt_name")?,
-            axes: serde_json::from_str(&row.get::<_, String>("axes")?)
-                // Fudging the error types here a little bit - but it's close
-                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
-        })
-    }
-}
+        // A replica further behind than allowed refuses to read
+        let options = CatalogOptions {
+            max_replica_lag: Some(std::time::Duration::from_nanos(1)),
+            ..CatalogOptions::default()
+        };
+        let strict = Catalog::connect_with(&format!("replica:{}", replica_path), options).unwrap();
+        match strict.begin_read() {
+            Err(StoiError::StaleReplica(_)) => (),
+            _ => panic!("expected the replica to be too stale"),
+        }
 
-#[cfg(test)]
-mod tests {
-    use crate::{Axis, AxisSelection, Catalog, ContentPattern, Counter, Patch, StorageTransaction};
-    use itertools::Itertools;
+        for cat in &[primary, replica, strict] {
+            cat.close().unwrap();
+        }
+        std::fs::remove_file(primary_path).unwrap();
+        std::fs::remove_file(replica_path).unwrap();
+    }
 
+    /// Closing should release the file and stop every clone from starting transactions
     #[test]
-    fn test_create_quilt() {
-        let mut cat = Catalog::connect("").unwrap();
+    fn test_close() {
+        let path = std::env::temp_dir().join(format!("stoi-close-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let cat = Catalog::connect(path).unwrap();
+        let clone = cat.clone();
         let mut txn = cat.begin().unwrap();
-        // This should automatically create the axes as well, so it doesn't complain
-        txn.create_quilt("sales", &["itm", "lct", "day"])
-            .unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        txn.finish().unwrap();
+        cat.flush().unwrap();
+
+        // A transaction in progress elsewhere is rolled back instead of committed
+        let (began, wait) = std::sync::mpsc::channel();
+        let elsewhere = std::thread::spawn(move || {
+            let mut txn = clone.begin().unwrap();
+            txn.create_quilt("rolled_back", &["itm"]).unwrap();
+            began.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            txn.finish()
+        });
+        wait.recv().unwrap();
+        cat.close().unwrap();
+        match elsewhere.join().unwrap() {
+            Err(StoiError::Closed) => (),
+            other => panic!("expected the catalog to be closed, got {:?}", other),
+        }
+        assert!(match cat.begin_read() {
+            Err(StoiError::Closed) => true,
+            _ => false,
+        });
+        assert!(cat.flush().is_err());
+        cat.close().unwrap();
+
+        // Only what was finished before closing is there when it's opened again
+        let cat = Catalog::connect(path).unwrap();
+        let mut txn = cat.begin_read().unwrap();
+        assert!(txn.get_quilt_details("sales").is_ok());
+        assert!(txn.get_quilt_details("rolled_back").is_err());
+        drop(txn);
+        cat.close().unwrap();
+        std::fs::remove_file(path).unwrap();
     }
-    /// Fetching from an empty quilt should create an empty patch
+
+    /// Fetches should apply commits in the order they were made, whatever their ids are
     #[test]
-    fn test_fetch_empty_quilt() {
-        let mut cat = Catalog::connect("").unwrap();
+    fn test_deterministic_fetch_order() {
+        let path = std::env::temp_dir().join(format!("stoi-order-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let cat = Catalog::connect(path).unwrap();
         let mut txn = cat.begin().unwrap();
-        txn.create_quilt("sales", &["itm", "lct", "day"])
-            .unwrap();
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        for (tag, labels) in &[("first", vec![1, 2]), ("latest", vec![2])] {
+            let value = labels.len() as f32;
+            let patch = Patch::build()
+                .axis("itm", labels)
+                .content(Some(nd::ArrayD::from_elem(vec![labels.len()], value)))
+                .unwrap();
+            txn.create_commit("sales", "first", tag, "message", &[&patch])
+                .unwrap();
+        }
+        let first = txn.get_tag_commit("sales", "first").unwrap().unwrap();
+        let latest = txn.get_tag_commit("sales", "latest").unwrap().unwrap();
+        txn.finish().unwrap();
+        drop(cat);
 
-        // This should assume the axes' labels exist if you specify them, but not if you don't
-        let pat = txn
-            .fetch(
-                "sales",
-                "latest",
-                vec![AxisSelection::All, AxisSelection::Labels(vec![1])],
+        // Pretend the clock went backwards, so the first commit has a larger id than any later
+        let conn = rusqlite::Connection::open(path).unwrap();
+        // The ids are rewritten one table at a time, so they only agree again at the end
+        conn.execute_batch("PRAGMA foreign_keys = OFF;").unwrap();
+        let future = latest + (1 << 60);
+        for table in &["Comm", "Patch", "Tag", "CommitSequence", "CommitTime"] {
+            conn.execute(
+                &format!("UPDATE {} SET comm_id = ? WHERE comm_id = ?;", table),
+                &[future, first],
             )
             .unwrap();
-        // We asked for two dimensions but any dimensions you missed will be tacked on
-        assert_eq!(pat.ndim(), 3);
-        assert_eq!(pat.content().shape(), &[0, 1, 0]);
-    }
+        }
+        conn.execute(
+            "UPDATE Comm SET parent_comm_id = ? WHERE parent_comm_id = ?;",
+            &[future, first],
+        )
+        .unwrap();
+        drop(conn);
 
-    /// Commit one patch to the quilt and check that it survives a round trip
-    #[test]
-    fn test_commit_first_patches() {
-        let mut cat = Catalog::connect("").unwrap();
+        // The commits are still applied in the order they were made, with or without an index
+        let cat = Catalog::connect(path).unwrap();
         let mut txn = cat.begin().unwrap();
-        txn.create_quilt("sales", &["dim0", "dim1"]).unwrap();
+        let expected = vec![2., 1.];
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(fetched.content().iter().cloned().collect_vec(), expected);
+        txn.enable_visibility_index("sales", "latest").unwrap();
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(fetched.content().iter().cloned().collect_vec(), expected);
 
-        //
-        // 1: Does commit + fetch work?
-        //
+        // A newer patch hides the first commit's, even though its id is smaller
+        let cover = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[3., 3.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&cover])
+            .unwrap();
+        let problems = txn.check_visibility_index("sales", "latest").unwrap();
+        assert!(problems.is_empty());
+        let reads = txn.get_performance_counters()[Counter::ReadPatch];
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        let read = txn.get_performance_counters()[Counter::ReadPatch] - reads;
+        assert_eq!(fetched.content().iter().cloned().collect_vec(), [3., 3.]);
+        assert_eq!(read, 1);
+        drop(txn);
 
-        // Check that the quilt details match what we just posted
+        // Changes are listed in the same order, so resuming after the first skips only that one
+        let changes = cat
+            .changes_since("sales", 0)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
         assert_eq!(
-            txn.get_quilt_details("sales").unwrap().axes,
-            vec!["dim0".to_string(), "dim1".to_string()]
+            changes.iter().map(|c| c.comm_id).collect_vec(),
+            vec![future, latest]
         );
-
-        // Commit a patch
-        let mut reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
-        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
-            .unwrap();
-
-        // Check that the first patch was saved correctly
-        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
-        assert_eq!(reference_patch.content(), output_patch.content());
-
-        //
-        // 2. Does overwriting work?
-        //
-
-        // Commit another patch over the same spot
-        // If we just used another random patch it would be somewhere else
-        // So instead we set the content of our first patch.
-        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
-        // This direct assignment ignores label alignment.
-        reference_patch
-            .content_mut()
-            .assign(&temp_reference_patch.content());
-        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+        let later = cat
+            .changes_since("sales", changes[0].sequence)
+            .collect::<Result<Vec<_>, _>>()
             .unwrap();
+        assert_eq!(later, changes[1..].to_vec());
+        drop(cat);
+        std::fs::remove_file(path).unwrap();
+    }
 
-        // We should see only the new patch because it occludes the first one totally
-        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
-        assert_eq!(reference_patch.content(), output_patch.content());
+    /// Recovery mode should read around missing patches instead of failing
+    #[test]
+    fn test_recovery_mode() {
+        let path = std::env::temp_dir().join(format!("stoi-recovery-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
 
-        //
-        // 3. Do transactions work?
-        //
+        // Without create_if_missing, a new catalog can't be made
+        let no_create = CatalogOptions {
+            create_if_missing: false,
+            ..CatalogOptions::default()
+        };
+        assert!(Catalog::connect_with(path, no_create.clone()).is_err());
 
-        // Commit the transaction and nothing should have changed
-        txn.finish().unwrap();
+        let cat = Catalog::connect(path).unwrap();
         let mut txn = cat.begin().unwrap();
-        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
-        assert_eq!(reference_patch.content(), output_patch.content());
-
-        // Duplicate of the first round of overwriting
-        // Commit another patch that occludes it totally.
-        // This time we're testing that multiple transactions work too.
-        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
-        reference_patch
-            .content_mut()
-            .assign(&temp_reference_patch.content());
-        txn.create_commit("sales", "latest", "latest", "message", &[&reference_patch])
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        let patch = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        txn.create_commit("sales", "latest", "latest", "message", &[&patch])
             .unwrap();
-        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
-        assert_eq!(reference_patch.content(), output_patch.content());
         txn.finish().unwrap();
+        drop(cat);
 
-        // Another duplicate of overwriting, this time we rollback
+        // Corrupt it by removing all the content
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch("DELETE FROM PatchContent;").unwrap();
+        drop(conn);
+
+        let cat = Catalog::connect_with(path, no_create.clone()).unwrap();
         let mut txn = cat.begin().unwrap();
-        let temp_reference_patch = Patch::autogenerate(ContentPattern::Random, 5);
-        let mut rollback_reference_patch = reference_patch.clone();
-        rollback_reference_patch
-            .content_mut()
-            .assign(&temp_reference_patch.content());
-        txn.create_commit(
-            "sales",
-            "latest",
-            "latest",
-            "message",
-            &[&rollback_reference_patch],
+        assert!(txn.fetch("sales", "latest", vec![]).is_err());
+        drop(txn);
+
+        let cat = Catalog::connect_with(
+            path,
+            CatalogOptions {
+                recovery: true,
+                read_only: true,
+                ..no_create
+            },
         )
         .unwrap();
-        txn.rollback().unwrap(); // Oopsie, undo!
         let mut txn = cat.begin().unwrap();
-        let output_patch = txn.fetch("sales", "latest", vec![]).unwrap();
-        // Should be back to before
-        assert_eq!(reference_patch.content(), output_patch.content());
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert!(fetched.content().iter().all(|x| x.is_nan()));
+        assert_eq!(txn.take_warnings().len(), 1);
+        assert!(txn.create_quilt("other", &["itm"]).is_err());
+        drop(txn);
+        std::fs::remove_file(path).unwrap();
     }
 
-    /// Test that fetches incur the right number of reads (low read amplification)
+    /// Patches that fail to decode can be skipped, leaving holes and a report
     #[test]
-    fn test_read_amplification() {
-        let mut cat = populate_quilt();
+    fn test_skip_unreadable() {
+        let path =
+            std::env::temp_dir().join(format!("stoi-unreadable-{}.db", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let cat = Catalog::connect(path).unwrap();
         let mut txn = cat.begin().unwrap();
-        txn.fetch(
-            "quilt",
-            "latest",
-            vec![
-                // Only label slice is inclusive
-                AxisSelection::LabelSlice(0, 100),
-                AxisSelection::StorageSlice(0, 100),
-            ],
+        txn.create_quilt("sales", &["itm"]).unwrap();
+        for labels in &[[1, 2], [3, 4]] {
+            let patch = Patch::build()
+                .axis("itm", labels)
+                .content_1d(&[1., 2.])
+                .unwrap();
+            txn.create_commit("sales", "latest", "latest", "message", &[&patch])
+                .unwrap();
+        }
+        txn.finish().unwrap();
+        drop(cat);
+
+        // Corrupt the first patch, which is still there but can't be decoded
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(
+            "UPDATE PatchContent SET content = x'00010203'
+            WHERE patch_id = (SELECT min(patch_id) FROM PatchContent);",
         )
         .unwrap();
-        let ctr = txn.get_performance_counters();
-        // It could be done in 1, so cap it at 4.
-        println!("{:?}", ctr);
-        assert!(ctr[Counter::ReadPatch] <= 4);
+        drop(conn);
+
+        let cat = Catalog::connect(path).unwrap();
+        let mut txn = cat.begin().unwrap();
+        assert!(txn.fetch("sales", "latest", vec![]).is_err());
+        drop(txn);
+
+        let cat = Catalog::connect_with(
+            path,
+            CatalogOptions {
+                skip_unreadable: true,
+                ..CatalogOptions::default()
+            },
+        )
+        .unwrap();
+        let mut txn = cat.begin().unwrap();
+        let fetched = txn.fetch("sales", "latest", vec![]).unwrap();
+        let content = fetched.content().iter().copied().collect_vec();
+        assert!(content[0].is_nan() && content[1].is_nan());
+        assert_eq!(&content[2..], &[1., 2.]);
+        let skipped = txn.take_skipped_patches();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].bounding_box[0], (0, 1));
+        assert!(txn.take_skipped_patches().is_empty());
+        drop(txn);
+        std::fs::remove_file(path).unwrap();
     }
 
     /// Check that the state of the quilt is consistent as we keep adding patches and commits
@@ -1,21 +1,68 @@
+use crate::{Label, PatchReport};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum StoiError {
     #[error("SQLite storage error")]
-    SQLiteError(#[from] rusqlite::Error),
+    SQLiteError(#[source] rusqlite::Error),
+    #[error("the catalog is locked by another transaction, so this one should be retried")]
+    Busy,
+    #[error("this is a read transaction or a read only catalog, so it can't write")]
+    ReadOnly,
     #[error("Bincode serialization error")]
     BincodeError(#[from] bincode::Error),
     #[error("Json serialization error")]
     JsonError(#[from] serde_json::Error),
     #[error("no record found for the {0} {1}")]
     NotFound(&'static str, String),
+    #[error("label {1} was not found on axis {0}")]
+    LabelNotFound(String, Label),
+    #[error("the slice from label {1} to label {2} on axis {0} ends before it starts")]
+    ReversedLabelSlice(String, Label, Label),
+    #[error("axis {0} was changed by another transaction, so this one should be retried")]
+    StaleAxis(String),
+    #[error("quilt {0} would use more than its quota of {2} {1}")]
+    QuotaExceeded(String, &'static str, u64),
+    #[error("a commit to quilt {0} would write {1} bytes for {2} new bytes, past the limit")]
+    WriteAmplification(String, usize, usize),
+    #[error("the patches of quilt {0} were rewritten since its chunks were planned")]
+    StaleChunks(String),
+    #[error("the commit message is missing the required metadata {}", .0.join(", "))]
+    MissingCommitMetadata(Vec<String>),
+    #[error("the first line of the commit message doesn't match the required pattern {0}")]
+    CommitMessageFormat(String),
     #[error("resource request is too large: {0}")]
     TooLarge(&'static str),
     #[error("invalid value: {0}")]
     InvalidValue(&'static str),
+    #[error("the value {0} has a fraction, but it would be stored in a quilt of integers")]
+    FractionalValue(f32),
+    #[error("quilt {0} has no axis {1}, its axes are [{2}]")]
+    UnknownAxis(String, String, String),
+    #[error("invalid selection: {0}")]
+    InvalidSelection(String),
+    #[error("invalid bounding box: {0}")]
+    InvalidBoundingBox(String),
     #[error("misaligned axes: {0}")]
     MisalignedAxes(String),
+    #[error("axis {0} of a patch repeats {1} labels, see Patch::dedup_labels() to merge them")]
+    RepeatedLabels(String, usize),
+    #[error("malformed patch: {0}")]
+    MalformedPatch(&'static str),
+    #[error("the patch failed validation: {0}")]
+    InvalidPatch(PatchReport),
+    #[error("array shape error")]
+    ShapeError(#[from] ndarray::ShapeError),
+    #[error("unsupported patch format version {0}, maybe it was written by a newer stoicheia")]
+    UnsupportedPatchVersion(u8),
+    #[error("there is no storage backend for catalog URLs starting with {0}")]
+    UnsupportedBackend(String),
+    #[error("the catalog was closed")]
+    Closed,
+    #[error("the replica is {0} seconds behind its primary, more than allowed")]
+    StaleReplica(u64),
+    #[error("the operation was cancelled")]
+    Cancelled,
     #[error("runtime error: {0}")]
     RuntimeError(&'static str),
     #[error("impossible error to handle infallible conversions")]
@@ -26,4 +73,50 @@ pub enum StoiError {
     IOError(#[from] std::io::Error),
 }
 
+impl StoiError {
+    /// Whether retrying the whole transaction from the start could succeed
+    ///
+    /// This is the case when another transaction, maybe in another process, got in the way.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StoiError::Busy | StoiError::StaleAxis(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this is how decoding a stored patch failed, meaning the patch is corrupted
+    pub fn is_unreadable_patch(&self) -> bool {
+        match self {
+            StoiError::BincodeError(_)
+            | StoiError::MalformedPatch(_)
+            | StoiError::InvalidPatch(_)
+            | StoiError::UnsupportedPatchVersion(_)
+            | StoiError::ShapeError(_)
+            | StoiError::IOError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// SQLite reports lock contention as errors, which are retryable rather than storage problems,
+/// and writes where they aren't allowed, which aren't storage problems either
+impl From<rusqlite::Error> for StoiError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(rusqlite::ffi::Error { code, .. }, _)
+                if code == rusqlite::ErrorCode::DatabaseBusy
+                    || code == rusqlite::ErrorCode::DatabaseLocked =>
+            {
+                StoiError::Busy
+            }
+            rusqlite::Error::SqliteFailure(rusqlite::ffi::Error { code, .. }, _)
+                if code == rusqlite::ErrorCode::ReadOnly =>
+            {
+                StoiError::ReadOnly
+            }
+            err => StoiError::SQLiteError(err),
+        }
+    }
+}
+
 pub type Fallible<T> = Result<T, StoiError>;
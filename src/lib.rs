@@ -11,15 +11,47 @@ extern crate rusqlite as sql;
 extern crate approx; // for approximately eq for f32/f64
 
 mod patch;
-pub use patch::{ContentPattern, Patch, PatchCompressionType};
+pub use patch::{
+    register_patch_codec, ApplyStats, Coarsening, ContentPattern, Patch, PatchCodec,
+    PatchCompressionType, PatchProblem, PatchQuantization, PatchReport, ReduceOp, TransferProgress,
+};
 
 mod catalog;
-pub use catalog::{Catalog, QuiltDetails, StorageTransaction};
+pub use catalog::{
+    commit_metadata, AuditEntry, Catalog, CatalogOptions, CatalogSnapshot, CatalogUrl, Changes,
+    ChunkGrid, CommitChanges, CommitReport, CommitRules, CommitStats, CounterBreakdown, Derivation,
+    DerivedQuiltStatus, DerivedWork, FetchEstimate, FetchPlan, HistoryCommit, HistoryFormat,
+    IntegerRounding, LayoutAdvice, LayoutReport, NamespaceConfig, PatchAccess, PatchStream,
+    PerformanceCounters, PreparedPatch, QuiltDetails, QuiltIntegers, QuiltQuota, QuiltRetention,
+    QuiltTemplate, QuiltUnits, QuiltUsage, ReadOcclusion, SkippedPatch, StorageTransaction,
+    TagExpr, WriteAmplificationLimit,
+};
 
 mod sqlite;
 
+mod transaction;
+pub use transaction::Transaction;
+
 mod axis;
-pub use axis::Axis;
+pub use axis::{Axis, LabelIndex, SortedLabelIndex, MEASURE_AXIS};
+
+mod selection;
+
+mod bounding_box;
+pub use bounding_box::{BoundingBox, PatchIntervals};
+
+mod patchset;
+
+mod pool;
+pub use pool::BufferPool;
+
+mod typed;
+pub use typed::{AxisNames, TypedQuilt};
+
+mod maintenance;
+pub use maintenance::{
+    MaintenanceOptions, MaintenanceReport, MaintenanceStatus, MaintenanceWorker,
+};
 
 mod error;
 pub use error::{Fallible, StoiError};
@@ -63,20 +95,26 @@ impl rusqlite::types::FromSql for PatchID {
 /// Selection by axis labels, similar to .loc[] in Pandas
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub enum AxisSelection {
+    /// Every label of the axis
     All,
+    /// Labels from the first through the second, inclusive, in storage order
+    ///
+    /// Both labels must exist, or it's a LabelNotFound error. If the end is stored before the
+    /// start, it's a ReversedLabelSlice error rather than an empty selection.
     LabelSlice(Label, Label),
+    /// Exactly these labels, in this order
     Labels(Vec<Label>),
+    /// Storage indices from the first up to but not including the second
     StorageSlice(usize, usize),
+    /// Every label from the first through the second by value, inclusive, in storage order
+    ///
+    /// Unlike LabelSlice, this is a range of values, like all the days in March, wherever the
+    /// labels are stored. Neither label needs to exist, and the range may be empty.
+    LabelRange(Label, Label),
 }
 
 /// Selection by axis indices, similar to .iloc[] in Pandas
-pub(crate) type AxisSegment = (usize, usize);
-
-/// A 4-dimensional box referencing a contiguous region of multiple axes.
-///
-/// Remember that in these boxes, storage indices (usize) are always consecutive,
-/// but labels (i64) may not be.
-pub(crate) type BoundingBox = [AxisSegment; 4];
+pub type AxisSegment = (usize, usize);
 
 /// Performance metrics
 ///
@@ -110,11 +148,29 @@ pub enum Counter {
     /// This is typically fast, but can spike with certain label-based queries
     /// because they make create very many bounding boxes
     SearchPatches,
+    /// The patch search used a tag's visibility index instead of walking its ancestry.
+    SearchVisibilityIndex,
+    /// A patch inside a searched bounding box was left out, because none of its
+    /// PatchIntervals were in the box
+    IntervalRejected,
 
     /// Estimated total bytes of IO. (serialized)
     ReadBytes,
     /// Estimated total bytes of IO. (serialized)
     WriteBytes,
+    /// Bytes of patch content read while applying patches during fetches, used or not
+    ApplyShuffledBytes,
+    /// Bytes of patch content actually copied into the result of fetches.
+    /// If this is much less than ApplyShuffledBytes, patches are larger than fetches need.
+    ApplyCopiedBytes,
+    /// Bytes copied into the result of fetches that were still there when the fetch returned
+    FetchVisibleBytes,
+    /// Bytes copied into the result of fetches, only to be overwritten by newer patches.
+    /// Squashing or compacting the tag would save reading them, see analyze_layout().
+    FetchOccludedBytes,
+    /// A patch was read for a fetch but none of it was copied, because it only has NANs or
+    /// labels outside the selection where its bounding box overlaps the selection's.
+    FetchSkippedPatch,
 
     /// Created a commit
     CreateCommit,
@@ -123,10 +179,42 @@ pub enum Counter {
     /// Resolved an axis selection into a labelset
     ResolveSelection,
 
+    /// A patch was considered for merging with a patch being committed
+    MergeCandidate,
+    /// A patch being committed was merged with an existing patch
+    Merge,
+    /// A commit only wrote past the end of its quilt's append axis, so it didn't merge
+    AppendCommit,
+    /// A patch being committed was left out, because it wouldn't change anything in its tag
+    UnchangedPatch,
+    /// A patch being committed was left out, because all its content is NAN
+    EmptyPatch,
+    /// A patch being committed was packed into a patchset with other small patches.
+    /// See CatalogOptions::patchset_bytes
+    PackPatch,
+
+    /// Maintenance compacted a tag, because analyze_layout() advised it
+    MaintenanceCompaction,
+    /// Maintenance rebuilt a visibility index, because check_visibility_index() found problems
+    MaintenanceIndexRebuild,
+    /// An expired commit was merged into its parent, see QuiltRetention
+    ExpiredCommit,
+
     MaybeSplit,
     Split,
     GetBoundingBox,
+    /// A patch already carried its bounding box, so its labels weren't looked up again
+    ReuseBoundingBox,
     PutCommit,
     PutCommitGetPatch,
     PutCommitFetch,
+
+    /// A commit reached its write amplification limit, and either failed or stopped merging.
+    /// See WriteAmplificationLimit
+    WriteAmplificationLimited,
+
+    /// A buffer was reused from the buffer pool instead of allocated, see BufferPool
+    PoolReuse,
+    /// A buffer was allocated because the buffer pool had none to reuse
+    PoolAllocate,
 }
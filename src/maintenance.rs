@@ -0,0 +1,336 @@
+use crate::{BoundingBox, Catalog, Counter, Fallible, LayoutAdvice, StoiError, StorageTransaction};
+use itertools::Itertools;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// What Catalog::run_maintenance() does, and how often a MaintenanceWorker does it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceOptions {
+    /// How long a MaintenanceWorker waits after each pass before the next one
+    pub interval: Duration,
+    /// Do the work queued for derived quilts, see Catalog::run_derived_work()
+    pub derived_work: bool,
+    /// Tune the size of each quilt's patches, see Catalog::auto_tune_patch_bytes()
+    pub tune_patch_bytes: bool,
+    /// Merge away the commits past each quilt's retention, see QuiltRetention
+    pub collect_expired: bool,
+    /// Compact the tags that analyze_layout() advises compacting, which is off by default,
+    /// since it rewrites the whole commit of each tag it compacts
+    pub compact: bool,
+    /// Compact at most this many tags per pass, since each holds the write lock for a while
+    pub max_compactions: usize,
+    /// Rebuild the visibility indices check_visibility_index() finds problems with
+    pub refresh_indices: bool,
+}
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        MaintenanceOptions {
+            interval: Duration::from_secs(600),
+            derived_work: true,
+            tune_patch_bytes: true,
+            collect_expired: true,
+            compact: false,
+            max_compactions: 4,
+            refresh_indices: true,
+        }
+    }
+}
+
+/// What one pass of Catalog::run_maintenance() did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceReport {
+    /// Items of derived work done
+    pub derived_work: usize,
+    /// Quilts whose target_patch_bytes() changed
+    pub tuned_quilts: usize,
+    /// Expired commits merged into their parents
+    pub collected_commits: usize,
+    /// Tags that were compacted
+    pub compacted_tags: usize,
+    /// Patches replaced or upgraded by compacting them
+    pub compacted_patches: usize,
+    /// Visibility indices that were rebuilt
+    pub rebuilt_indices: usize,
+    /// Steps skipped because another process held the lock, which are tried again next pass
+    pub busy: usize,
+}
+
+/// How a MaintenanceWorker has been doing, see MaintenanceWorker::status()
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceStatus {
+    /// How many passes have finished, successfully or not
+    pub passes: usize,
+    /// What the last successful pass did
+    pub last_report: Option<MaintenanceReport>,
+    /// Why the last pass failed, if it did. The next success clears it.
+    pub last_error: Option<String>,
+}
+
+/// A thread doing Catalog::run_maintenance() now and then, see Catalog::start_maintenance()
+///
+/// The thread stops when this is dropped, after the pass in progress if there is one.
+pub struct MaintenanceWorker {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    status: Arc<Mutex<MaintenanceStatus>>,
+}
+impl MaintenanceWorker {
+    /// How the worker has been doing so far
+    pub fn status(&self) -> MaintenanceStatus {
+        match self.status.lock() {
+            Ok(status) => status.clone(),
+            // The worker panicked while updating it, but what it had is still useful
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Stop the worker, waiting for the pass in progress to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    /// Tell the thread to stop and wait for it
+    fn shutdown(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            // A panic in the thread was already recorded as a poisoned status
+            let _ = thread.join();
+        }
+    }
+}
+impl Drop for MaintenanceWorker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Skip a step if another process holds the lock, counting it, and fail on any other error
+fn unless_busy<T>(result: Fallible<T>, report: &mut MaintenanceReport) -> Fallible<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(StoiError::Busy) => {
+            report.busy += 1;
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+impl Catalog {
+    /// Keep the catalog in shape: derived work, patch sizes, retention, compaction and indices
+    ///
+    /// Each step runs in its own transaction, and compaction uses one per tag, so other
+    /// transactions in this process only wait for one step at a time. If another process
+    /// holds the lock, that step is skipped and counted in MaintenanceReport::busy rather
+    /// than waiting, so it can be tried again later. Compacting a tag or rebuilding an index
+    /// is also counted in the performance counters, see Counter::MaintenanceCompaction.
+    pub fn run_maintenance(&self, options: &MaintenanceOptions) -> Fallible<MaintenanceReport> {
+        let mut report = MaintenanceReport::default();
+        if options.derived_work {
+            if let Some(done) = unless_busy(self.run_derived_work(), &mut report)? {
+                report.derived_work = done;
+            }
+        }
+        if options.tune_patch_bytes {
+            if let Some(tuned) = unless_busy(self.auto_tune_patch_bytes(), &mut report)? {
+                report.tuned_quilts = tuned.len();
+            }
+        }
+        if options.collect_expired {
+            let retained = unless_busy(self.list_retained_quilts(), &mut report)?;
+            for quilt_name in retained.unwrap_or_default() {
+                let collected = unless_busy(
+                    self.begin().and_then(|mut txn| {
+                        txn.trace_quilt(&quilt_name);
+                        let collected = txn.collect_expired_commits(&quilt_name)?;
+                        txn.finish()?;
+                        Ok(collected)
+                    }),
+                    &mut report,
+                )?;
+                report.collected_commits += collected.unwrap_or(0);
+            }
+        }
+        if !options.compact && !options.refresh_indices {
+            return Ok(report);
+        }
+
+        let tags = match unless_busy(self.list_all_tags(), &mut report)? {
+            Some(tags) => tags,
+            None => return Ok(report),
+        };
+        let everywhere = BoundingBox::everywhere();
+        for (quilt_name, tag) in &tags {
+            if options.compact && report.compacted_tags < options.max_compactions {
+                let layout = unless_busy(self.analyze_layout(quilt_name, tag), &mut report)?;
+                let advised = layout.map_or(false, |layout| {
+                    layout.advice.iter().any(|advice| match advice {
+                        LayoutAdvice::OccludedReads { .. } | LayoutAdvice::Compact { .. } => true,
+                        // Compacting only rewrites the tag's own commit, and what's hidden
+                        // is mostly in older ones
+                        LayoutAdvice::Squash { .. } | LayoutAdvice::Reorder { .. } => false,
+                    })
+                });
+                if advised {
+                    let compacted = unless_busy(
+                        self.begin().and_then(|mut txn| {
+                            txn.trace_quilt(quilt_name);
+                            txn.trace(Counter::MaintenanceCompaction, 1);
+                            let replaced = txn.compact_region(quilt_name, tag, everywhere)?;
+                            txn.finish()?;
+                            Ok(replaced)
+                        }),
+                        &mut report,
+                    )?;
+                    if let Some(replaced) = compacted {
+                        self.settle_compaction(quilt_name);
+                        report.compacted_tags += 1;
+                        report.compacted_patches += replaced;
+                    }
+                }
+            }
+            if options.refresh_indices {
+                let problems = match self
+                    .begin_read()
+                    .and_then(|mut txn| txn.check_visibility_index(quilt_name, tag))
+                {
+                    // Most tags don't have an index at all
+                    Err(StoiError::NotFound(..)) => continue,
+                    result => unless_busy(result, &mut report)?,
+                };
+                if problems.map_or(false, |problems| !problems.is_empty()) {
+                    let rebuilt = unless_busy(
+                        self.begin().and_then(|mut txn| {
+                            txn.trace_quilt(quilt_name);
+                            txn.trace(Counter::MaintenanceIndexRebuild, 1);
+                            txn.rebuild_visibility_index(quilt_name, tag)?;
+                            txn.finish()
+                        }),
+                        &mut report,
+                    )?;
+                    if rebuilt.is_some() {
+                        report.rebuilt_indices += 1;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run maintenance in a background thread, every `options.interval`, until it's stopped
+    ///
+    /// The first pass starts right away. A pass that fails is recorded in the worker's
+    /// status, and the worker carries on with the next one. See run_maintenance().
+    pub fn start_maintenance(&self, options: MaintenanceOptions) -> MaintenanceWorker {
+        let catalog = self.clone();
+        let status = Arc::new(Mutex::new(MaintenanceStatus::default()));
+        let thread_status = status.clone();
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || loop {
+            let result = catalog.run_maintenance(&options);
+            if let Ok(mut status) = thread_status.lock() {
+                status.passes += 1;
+                match result {
+                    Ok(report) => {
+                        status.last_report = Some(report);
+                        status.last_error = None;
+                    }
+                    Err(err) => status.last_error = Some(err.to_string()),
+                }
+            }
+            // Nothing is ever sent, so this only ends early when the worker is dropped
+            match stopped.recv_timeout(options.interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+        MaintenanceWorker {
+            stop: Some(stop),
+            thread: Some(thread),
+            status,
+        }
+    }
+
+    /// Every quilt with a retention, sorted
+    fn list_retained_quilts(&self) -> Fallible<Vec<String>> {
+        let mut txn = self.begin_read()?;
+        let mut retained = vec![];
+        for quilt_name in txn.list_quilts()?.keys().cloned().sorted() {
+            if txn.get_quilt_retention(&quilt_name)?.is_some() {
+                retained.push(quilt_name);
+            }
+        }
+        Ok(retained)
+    }
+
+    /// Every quilt and tag in the catalog, sorted
+    fn list_all_tags(&self) -> Fallible<Vec<(String, String)>> {
+        let mut txn = self.begin_read()?;
+        let mut tags = vec![];
+        for quilt_name in txn.list_quilts()?.keys().cloned().sorted() {
+            for tag in txn.list_tags(&quilt_name)? {
+                tags.push((quilt_name.clone(), tag));
+            }
+        }
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::time::Duration;
+
+    #[test]
+    fn maintenance_compacts_and_stops() {
+        let cat = Catalog::connect("").unwrap();
+        cat.set_target_patch_bytes(1 << 20);
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+        // Many tiny disjoint patches, which analyze_layout() advises compacting
+        let patches = (0..20)
+            .map(|itm| {
+                Patch::build()
+                    .axis("itm", &[itm])
+                    .axis("lct", &[1, 2])
+                    .content_2d(&[[1., 2.]])
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let patches = patches.iter().collect::<Vec<_>>();
+        txn.create_commit("sales", "latest", "latest", "message", &patches)
+            .unwrap();
+        txn.finish().unwrap();
+
+        // Compaction is only done when it's asked for
+        let report = cat.run_maintenance(&MaintenanceOptions::default()).unwrap();
+        assert_eq!(report.compacted_tags, 0);
+        let options = MaintenanceOptions {
+            tune_patch_bytes: false,
+            compact: true,
+            ..MaintenanceOptions::default()
+        };
+        let report = cat.run_maintenance(&options).unwrap();
+        assert_eq!(report.compacted_tags, 1);
+        let counters = cat.performance_snapshot();
+        assert_eq!(counters[Counter::MaintenanceCompaction], 1);
+        let layout = cat.begin_read().unwrap().analyze_layout("sales", "latest");
+        assert!(layout.unwrap().advice.is_empty());
+
+        // The worker runs a pass right away, and stops when asked
+        let worker = cat.start_maintenance(MaintenanceOptions {
+            interval: Duration::from_secs(3600),
+            ..options
+        });
+        while worker.status().passes == 0 {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let status = worker.status();
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.last_report.unwrap().compacted_tags, 0);
+        worker.stop();
+    }
+}
@@ -1,17 +1,19 @@
-use crate::{Axis, Fallible, Label, StoiError};
-use arrayvec::ArrayVec;
+use crate::{Axis, BoundingBox, Fallible, Label, LabelIndex, StoiError};
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use ndarray as nd;
-use ndarray::{Array4, ArrayD, ArrayView, ArrayView4, ArrayViewMut, ArrayViewMut4};
+use ndarray::{Array4, ArrayD, ArrayViewD, ArrayViewMut4};
 use rand::rngs::SmallRng; // This RNG is much faster and not secure but we don't need that
 use rand::{Rng, SeedableRng};
-use std::borrow::Cow;
+use rayon::prelude::*;
+use std::borrow::{Borrow, Cow};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
-use std::io::{Read, Write};
-
-type A4D = ArrayVec<[usize; 4]>;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 /// A tensor with labeled axes
 ///
@@ -26,15 +28,51 @@ type A4D = ArrayVec<[usize; 4]>;
 ///       - They might overlap other patches (it depends on the Quilt)
 ///   - A regular array of 32-bit floats in the same order as the list of axes
 ///
-#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Patch {
     /// Names and labels of the axes
     axes: Vec<Axis>,
     /// Tensor containing all the elements of this patch
     dense: Array4<f32>,
-    // TODO: Bounding box for this patch on the global axes.
-    // - Includes an ID for the catalog, to prevent using them with the wrong catalog
-    // - If present, then the axes also match the order of the global axes
+    /// Label indices of the axes, for reading and writing cells by label
+    #[serde(skip)]
+    label_indices: LabelIndexCache,
+    /// Bounding box of this patch on a catalog's axes, from the last time it was found
+    #[serde(skip)]
+    global_bounds: GlobalBoundsCache,
+}
+/// The label index of each axis of a patch, built the first time a cell is found by labels
+///
+/// It's only a cache, so it isn't serialized, and clones start over empty.
+#[derive(Default)]
+struct LabelIndexCache(Mutex<Vec<Option<LabelIndex>>>);
+impl Clone for LabelIndexCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+/// The bounding box of a patch in one catalog, while the axes it depends on don't change
+///
+/// Bounding boxes depend on the storage order of the catalog, see
+/// StorageTransaction::get_bounding_box(), so they're only reused for the same catalog and
+/// the same generation of every axis of the patch.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct GlobalBounds {
+    pub catalog_id: i64,
+    pub generations: Vec<u64>,
+    pub bounding_box: BoundingBox,
+}
+/// The global bounds of a patch, if they've been found, see Patch::global_bounds()
+///
+/// Patches never change their axes, so unlike LabelIndexCache, clones keep it.
+#[derive(Default)]
+struct GlobalBoundsCache(Mutex<Option<GlobalBounds>>);
+impl Clone for GlobalBoundsCache {
+    fn clone(&self) -> Self {
+        GlobalBoundsCache(Mutex::new(
+            self.0.lock().ok().and_then(|bounds| bounds.clone()),
+        ))
+    }
 }
 impl fmt::Debug for Patch {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
@@ -46,6 +84,50 @@ impl fmt::Debug for Patch {
         Ok(())
     }
 }
+/// Patches are equal if they have the same axes and content, where all NANs are equal
+///
+/// NANs mean there is no data, so their payloads don't matter, and a patch is equal to itself
+/// even if it has some.
+impl PartialEq for Patch {
+    fn eq(&self, other: &Self) -> bool {
+        self.axes == other.axes
+            && self.dense.shape() == other.dense.shape()
+            && self
+                .dense
+                .iter()
+                .zip(other.dense.iter())
+                .all(|(a, b)| a == b || (a.is_nan() && b.is_nan()))
+    }
+}
+/// A short summary for logs, like `Patch(itm: 1, lct: 3; 33.3% NaN; values 1 to 6)`
+///
+/// This has the length of each axis, how much is NAN, and the range of the rest.
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let (mut nans, mut min, mut max) = (0, std::f32::INFINITY, std::f32::NEG_INFINITY);
+        for &x in self.dense.iter() {
+            if x.is_nan() {
+                nans += 1;
+            } else {
+                min = min.min(x);
+                max = max.max(x);
+            }
+        }
+        write!(
+            f,
+            "Patch({}; {:.1}% NaN",
+            self.axes
+                .iter()
+                .map(|a| format!("{}: {}", a.name, a.len()))
+                .join(", "),
+            100. * nans as f64 / self.len().max(1) as f64
+        )?;
+        if nans < self.len() {
+            write!(f, "; values {} to {}", min, max)?;
+        }
+        write!(f, ")")
+    }
+}
 
 impl Patch {
     /// Create a new patch from an array and some labels
@@ -63,7 +145,7 @@ impl Patch {
                 // They have not provided content; we must allocate
                 let mut dims = axes.iter().map(|a| a.len()).collect_vec();
                 let dims_size: usize = dims.iter().product::<usize>();
-                if dims_size > 256 << 20 {
+                if dims_size > MAX_PATCH_ELEMENTS {
                     return Err(StoiError::TooLarge(
                         "Patches must be 256 million elements or less (1GB of 32bit floats)",
                     ));
@@ -76,6 +158,8 @@ impl Patch {
                 Ok(Self {
                     axes,
                     dense: Array4::from_elem((dims[0], dims[1], dims[2], dims[3]), std::f32::NAN),
+                    label_indices: LabelIndexCache::default(),
+                    global_bounds: GlobalBoundsCache::default(),
                 })
             }
             Some(dense) => {
@@ -94,20 +178,109 @@ impl Patch {
                         "The shape of the axis labels doesn't match the shape of the dense tensor.",
                     ));
                 }
-                let mut dims = dense.shape().to_vec();
-                // Add empty dimensions where necessary
-                while dims.len() < 4 {
-                    dims.push(1);
+                if dense.shape()[axes.len()..].iter().any(|&d| d != 1) {
+                    return Err(StoiError::InvalidValue(
+                        "The dense tensor has more non-empty axes than labeled axes.",
+                    ));
                 }
 
                 Ok(Self {
                     axes,
-                    dense: dense
-                        .into_shape((dims[0], dims[1], dims[2], dims[3]))
-                        .unwrap(), // shape error is impossible here
-                })
+                    dense,
+                    label_indices: LabelIndexCache::default(),
+                    global_bounds: GlobalBoundsCache::default(),
+                }
+                .with_canonical_nans())
+            }
+        }
+    }
+
+    /// Check everything about a patch, and report every problem rather than just the first
+    ///
+    /// Patches from new() are always valid, but patches from serde or from untrusted input
+    /// might not be. This reads the whole content to count NANs and infinities, so it takes
+    /// about as long as copying the patch.
+    pub fn validate(&self) -> PatchReport {
+        let mut report = PatchReport {
+            problems: self.structural_problems(),
+            ..PatchReport::default()
+        };
+        for &x in self.dense.iter() {
+            if x.is_nan() {
+                report.nans += 1;
+            } else if x.is_infinite() {
+                report.infinities += 1;
+            }
+        }
+        report
+    }
+
+    /// Find the problems with a patch's axes and shape, without looking at its content
+    fn structural_problems(&self) -> Vec<PatchProblem> {
+        let mut problems = vec![];
+        if self.axes.is_empty() || self.axes.len() > 4 {
+            problems.push(PatchProblem::AxisCount(self.axes.len()));
+        }
+        let shape = self.dense.shape();
+        for (axis, &length) in self.axes.iter().zip(shape) {
+            if axis.len() != length {
+                problems.push(PatchProblem::ShapeMismatch {
+                    axis: axis.name.clone(),
+                    labels: axis.len(),
+                    length,
+                });
+            }
+        }
+        if shape[self.axes.len().min(4)..].iter().any(|&d| d != 1) {
+            problems.push(PatchProblem::UnlabeledDimension);
+        }
+        let mut names = HashSet::new();
+        for axis in &self.axes {
+            if !names.insert(&axis.name) {
+                problems.push(PatchProblem::RepeatedAxis(axis.name.clone()));
+            }
+        }
+        for axis in &self.axes {
+            let repeats = axis.len() - axis.labels().iter().unique().count();
+            if repeats > 0 {
+                problems.push(PatchProblem::RepeatedLabels {
+                    axis: axis.name.clone(),
+                    repeats,
+                });
             }
         }
+        if self.dense.len() > MAX_PATCH_ELEMENTS {
+            problems.push(PatchProblem::TooLarge(self.dense.len()));
+        }
+        problems
+    }
+
+    /// Check everything the rest of Patch assumes, for patches that didn't come from new()
+    ///
+    /// Deserialized patches could come from anywhere, and a patch with its axes out of line with
+    /// its content would panic later instead of failing here.
+    fn checked(self) -> Fallible<Self> {
+        match self.structural_problems().first() {
+            Some(problem) => Err(StoiError::MalformedPatch(problem.describe())),
+            None => Ok(self.with_canonical_nans()),
+        }
+    }
+
+    /// Replace every NAN with the same quiet NAN, whatever its payload was
+    ///
+    /// NANs from different sources can have different bits, which makes serialized patches
+    /// differ even when they hold the same data, and compresses worse.
+    pub fn canonicalize_nans(&mut self) {
+        self.dense
+            .mapv_inplace(|x| if x.is_nan() { std::f32::NAN } else { x });
+    }
+
+    /// Canonicalize NANs if the canonical-nan feature is enabled, for new patches with content
+    fn with_canonical_nans(mut self) -> Self {
+        if cfg!(feature = "canonical-nan") {
+            self.canonicalize_nans();
+        }
+        self
     }
 
     /// Create a new patch from an array and some labels
@@ -138,6 +311,8 @@ impl Patch {
                 Ok(Self {
                     axes,
                     dense: Array4::from_elem((dims[0], dims[1], dims[2], dims[3]), std::f32::NAN),
+                    label_indices: LabelIndexCache::default(),
+                    global_bounds: GlobalBoundsCache::default(),
                 })
             }
             Some(dense) => {
@@ -164,12 +339,86 @@ impl Patch {
 
                 Ok(Self {
                     axes,
-                    dense: dense
-                        .into_shape((dims[0], dims[1], dims[2], dims[3]))
-                        .unwrap(), // shape error is impossible here
-                })
+                    dense: dense.into_shape((dims[0], dims[1], dims[2], dims[3]))?,
+                    label_indices: LabelIndexCache::default(),
+                    global_bounds: GlobalBoundsCache::default(),
+                }
+                .with_canonical_nans())
+            }
+        }
+    }
+
+    /// Create an empty patch using an existing buffer of NANs, see BufferPool
+    ///
+    /// The buffer must have exactly as many elements as the axes call for.
+    pub(crate) fn from_buffer(axes: Vec<Axis>, buffer: Vec<f32>) -> Fallible<Self> {
+        let mut dims = axes.iter().map(|a| a.len()).collect_vec();
+        while dims.len() < 4 {
+            dims.push(1);
+        }
+        let dense = Array4::from_shape_vec((dims[0], dims[1], dims[2], dims[3]), buffer)?;
+        Self::new_4d(axes, Some(dense))
+    }
+
+    /// Create a patch from chunks of its content in row-major order, like blocks of rows
+    ///
+    /// Each chunk is some number of whole rows along the first axis, so its shape is the shape
+    /// of the axes except the first, which can differ from chunk to chunk. Chunks are copied
+    /// into place as they arrive, so producers that generate content block by block don't need
+    /// to hold the whole array themselves. The chunks must add up to exactly the first axis.
+    ///
+    ///     use stoicheia::{Axis, Patch};
+    ///     use ndarray::arr2;
+    ///     let axes = vec![Axis::range("day", 0..3), Axis::range("itm", 0..2)];
+    ///     let blocks = vec![arr2(&[[1., 2.], [3., 4.]]), arr2(&[[5., 6.]])];
+    ///     let chunks = blocks.iter().map(|b| b.view().into_dyn());
+    ///     let patch = Patch::from_chunks(axes, chunks).unwrap();
+    ///     assert_eq!(patch.content(), arr2(&[[1., 2.], [3., 4.], [5., 6.]]).into_dyn());
+    pub fn from_chunks<'a>(
+        axes: Vec<Axis>,
+        chunks: impl IntoIterator<Item = ArrayViewD<'a, f32>>,
+    ) -> Fallible<Self> {
+        if axes.is_empty() || axes.len() > 4 {
+            return Err(StoiError::MisalignedAxes(
+                "Patches must have 1 to 4 axes".into(),
+            ));
+        }
+        let shape = axes.iter().map(|a| a.len()).collect_vec();
+        let len = shape.iter().product::<usize>();
+        if len > 256 << 20 {
+            return Err(StoiError::TooLarge(
+                "Patches must be 256 million elements or less (1GB of 32bit floats)",
+            ));
+        }
+        let mut content = Vec::with_capacity(len);
+        let mut rows = 0;
+        for chunk in chunks {
+            if chunk.ndim() != shape.len() || chunk.shape()[1..] != shape[1..] {
+                return Err(StoiError::MisalignedAxes(format!(
+                    "a chunk has shape {:?} but rows of the patch have shape {:?}",
+                    chunk.shape(),
+                    &shape[1..]
+                )));
+            }
+            rows += chunk.shape()[0];
+            if rows > shape[0] {
+                return Err(StoiError::InvalidValue(
+                    "The chunks have more rows than the first axis has labels.",
+                ));
             }
+            content.extend(chunk.iter().copied());
         }
+        if rows != shape[0] {
+            return Err(StoiError::InvalidValue(
+                "The chunks have fewer rows than the first axis has labels.",
+            ));
+        }
+        Self::from_buffer(axes, content)
+    }
+
+    /// Take apart the patch for its buffer, to reuse the memory, see Catalog::recycle()
+    pub fn into_buffer(self) -> Vec<f32> {
+        self.dense.into_raw_vec()
     }
 
     /// Convenience method to create a builder
@@ -184,19 +433,108 @@ impl Patch {
         self.axes.len()
     }
 
+    /// Create a dense patch from coordinates, like a sparse COO matrix
+    ///
+    /// Each axis is given as a name and the label of every value on that axis, so each list of
+    /// labels must be as long as `values`. Labels are deduplicated in the order they first
+    /// appear, and anything without a value is NAN. If a coordinate repeats, the last value wins.
+    pub fn from_coo(coords: Vec<(String, Vec<Label>)>, values: &[f32]) -> Fallible<Self> {
+        if coords.iter().any(|(_name, labels)| labels.len() != values.len()) {
+            return Err(StoiError::InvalidValue(
+                "Every axis must have one label for each value",
+            ));
+        }
+        let mut axes = vec![];
+        // For each axis, the index of the label of each value
+        let mut indices = vec![];
+        for (name, labels) in coords {
+            let mut label_to_idx = HashMap::new();
+            let mut distinct = vec![];
+            indices.push(
+                labels
+                    .iter()
+                    .map(|&label| {
+                        *label_to_idx.entry(label).or_insert_with(|| {
+                            distinct.push(label);
+                            distinct.len() - 1
+                        })
+                    })
+                    .collect_vec(),
+            );
+            axes.push(Axis::new_unchecked(name, distinct));
+        }
+
+        let mut patch = Patch::new(axes, None)?;
+        for (value_ix, &value) in values.iter().enumerate() {
+            let mut ix = [0usize; 4];
+            for (ax_ix, axis_indices) in indices.iter().enumerate() {
+                ix[ax_ix] = axis_indices[value_ix];
+            }
+            patch.dense[ix] = value;
+        }
+        Ok(patch)
+    }
+
     /// Apply another patch to this one, changing `self` where it overlaps with `pat`.
     ///
     /// This is not the same as merging the patches, because this only changes `self` where it
     /// overlaps with `pat`, and won't allocate or expand either one.
     pub fn apply(&mut self, pat: &Patch) -> Fallible<()> {
-        if self.axes.iter().map(|a| &a.name).sorted().collect_vec()
+        Self::apply_to_view(&self.axes, self.dense.view_mut(), pat)?;
+        Ok(())
+    }
+
+    /// Apply another patch to this one, like apply(), and say how much work it was
+    ///
+    /// The stats also say what changed, so a patch that changed nothing can be left out of
+    /// a commit, see ApplyStats::changed_nothing().
+    pub fn apply_counted(&mut self, pat: &Patch) -> Fallible<ApplyStats> {
+        Self::apply_to_view(&self.axes, self.dense.view_mut(), pat)
+    }
+
+    /// Apply another patch to this one, but only where a mask patch is set
+    ///
+    /// The mask is aligned with `pat` by labels, like apply(), and it is set wherever it's
+    /// nonzero. Anywhere the mask is zero, NAN, or doesn't have labels at all, `self` is left
+    /// as it was. This is useful for partial updates, like only the stores in one region.
+    pub fn apply_where(&mut self, pat: &Patch, mask: &Patch) -> Fallible<()> {
+        // Align the mask with pat, where NAN means it's not set
+        let mut aligned_mask = Patch::new(pat.axes.clone(), None)?;
+        aligned_mask.apply(mask)?;
+        let mut masked = pat.clone();
+        masked
+            .dense
+            .zip_mut_with(&aligned_mask.dense, |value, &mask_value| {
+                if mask_value.is_nan() || mask_value == 0. {
+                    *value = std::f32::NAN;
+                }
+            });
+        self.apply(&masked)
+    }
+
+    /// Apply a patch to a borrowed 4D array, labeled by `axes`
+    ///
+    /// This is the same as apply(), but the target doesn't need to be owned by a Patch,
+    /// so it can be a buffer provided by the caller.
+    pub(crate) fn apply_to_view(
+        axes: &[Axis],
+        mut dense: ArrayViewMut4<f32>,
+        pat: &Patch,
+    ) -> Fallible<ApplyStats> {
+        if axes.iter().map(|a| &a.name).sorted().collect_vec()
             != pat.axes.iter().map(|a| &a.name).sorted().collect_vec()
         {
             return Err(StoiError::InvalidValue("The axes of two patches don't match (broadcasting is not supported yet so they must match exactly)"));
         }
-        if self.dense.is_empty() || pat.dense.is_empty() {
+        // Whatever part of it is used, the whole patch had to be read
+        let mut stats = ApplyStats {
+            shuffled_bytes: pat.dense.len() * std::mem::size_of::<f32>(),
+            outside_cells: pat.dense.len(),
+            ..ApplyStats::default()
+        };
+        if dense.is_empty() || pat.dense.is_empty() {
             // It's a no op either way
-            return Ok(());
+            return Ok(stats);
         }
 
         // TODO: Support broadcasting smaller patches
@@ -210,7 +548,7 @@ impl Patch {
         // Any missing axes are just 1's and don't have labels
         let mut axis_shuffle = [0usize; 4];
         for self_ax_ix in 0..4 {
-            axis_shuffle[self_ax_ix] = match self.axes.get(self_ax_ix) {
+            axis_shuffle[self_ax_ix] = match axes.get(self_ax_ix) {
                 Some(self_axis) => pat
                     .axes
                     .iter()
@@ -229,259 +567,388 @@ impl Patch {
         // Because it's axes don't match self.
         std::mem::drop(pat);
 
-        // Create a new box large enough to hold either patch or self
-        let max_shape = self
-            .dense
-            .shape()
-            .iter()
-            .zip(shard.shape().iter())
-            .map(|(&x, &y)| x.max(y))
-            .collect::<A4D>()
-            .into_inner()
-            .unwrap();
-        let mut union = Array4::from_elem(max_shape, std::f32::NAN);
-
-        // 2: Precompute the axes so we don't search on every element
-        //    This also helps for optimizing the rectangle to copy
         //
-        // label_shuffles =
-        //  for each axis:
-        //      for each label on self's patch:
-        //          If pat has a matching label:
-        //              The index into pat of the corresponding label
-        //              or else None
-        let mut label_shuffles = vec![];
+        // 2: Find the intersection of the labels, before touching any content
+        //
+        // For each axis, the labels both patches have, as pairs of indices:
+        //  self_indices[ax_ix][i] in self matches shard_indices[ax_ix][i] in the shard
+        let mut self_indices = vec![];
+        let mut shard_indices = vec![];
         for ax_ix in 0..4 {
-            if ax_ix < self.ndim() {
-                let pat_label_to_idx: HashMap<Label, usize> = shard_axes[ax_ix]
+            if ax_ix < axes.len() {
+                let shard_label_to_idx: HashMap<Label, usize> = shard_axes[ax_ix]
                     .labels()
                     .iter()
                     .copied()
                     .enumerate()
                     .map(|(i, l)| (l, i))
                     .collect();
-                label_shuffles.push(
-                    self.axes[ax_ix]
-                        .labels()
-                        .iter()
-                        .map(|l| *pat_label_to_idx.get(l).unwrap_or(&std::usize::MAX))
-                        .collect::<Vec<usize>>(),
-                );
+                // Otherwise only the last of each repeated label would be used, silently
+                let repeats = shard_axes[ax_ix].len() - shard_label_to_idx.len();
+                if repeats > 0 {
+                    return Err(StoiError::RepeatedLabels(
+                        shard_axes[ax_ix].name.clone(),
+                        repeats,
+                    ));
+                }
+                let (self_ixs, shard_ixs): (Vec<usize>, Vec<usize>) = axes[ax_ix]
+                    .labels()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(self_ix, l)| {
+                        shard_label_to_idx
+                            .get(l)
+                            .map(|&shard_ix| (self_ix, shard_ix))
+                    })
+                    .unzip();
+                if self_ixs.is_empty() {
+                    // They don't overlap at all, so there is nothing to copy
+                    return Ok(stats);
+                }
+                self_indices.push(self_ixs);
+                shard_indices.push(shard_ixs);
             } else {
-                label_shuffles.push(vec![0]);
+                self_indices.push(vec![0]);
+                shard_indices.push(vec![0]);
             }
         }
 
-        // 3. Shuffle the intersection into self-space and apply the patch
-        //  - Fill the union box with the incoming patch
-        //  - For each axis
-        //    - Copy data from one to the other in label-shuffle order
-        //    - Swap the buffers
-        //  - Erase the planes that aren't used in self, so that they don't mutate
-        {
-            Self::merge_slice(shard.view(), union.view_mut(), shard.shape(), |x, y| {
-                *x = *y
-            });
-
-            union = self.shuffle_pull_ndim(union, &label_shuffles[..]);
+        //
+        // 3: Gather the intersection out of the shard, in the order of self
+        //
+        // Each select() copies, so start with the axis that keeps the smallest fraction
+        let kept = |ax_ix: usize| shard_indices[ax_ix].len();
+        let total = |ax_ix: usize| shard.len_of(nd::Axis(ax_ix));
+        let mut gather_order = (0..4).collect_vec();
+        gather_order.sort_by(|&a, &b| (kept(a) * total(b)).cmp(&(kept(b) * total(a))));
+        let mut gathered = shard.select(nd::Axis(gather_order[0]), &shard_indices[gather_order[0]]);
+        for &ax_ix in &gather_order[1..] {
+            gathered = gathered.select(nd::Axis(ax_ix), &shard_indices[ax_ix]);
+        }
 
-            for (ax_ix, label_shuffle) in label_shuffles.iter().enumerate() {
-                for (self_idx, pat_idx) in label_shuffle.iter().enumerate() {
-                    if *pat_idx == std::usize::MAX {
-                        union
-                            .index_axis_mut(nd::Axis(ax_ix), self_idx)
-                            .fill(std::f32::NAN);
+        //
+        // 4: Scatter it into self, skipping NANs so they don't erase anything
+        //
+        let contiguous = self_indices
+            .iter()
+            .all(|ixs| ixs.windows(2).all(|w| w[1] == w[0] + 1));
+        let (mut copied, mut changed) = (0, 0);
+        if contiguous {
+            // The common case, which can copy whole runs at once
+            let mut target = dense.view_mut();
+            for (ax_ix, ixs) in self_indices.iter().enumerate() {
+                target.slice_axis_inplace(nd::Axis(ax_ix), (ixs[0]..ixs[0] + ixs.len()).into());
+            }
+            target.zip_mut_with(&gathered, |a, b| {
+                if !b.is_nan() {
+                    if *a != *b {
+                        *a = *b;
+                        changed += 1;
+                    }
+                    copied += 1;
+                }
+            });
+        } else {
+            for ((i0, i1, i2, i3), &value) in gathered.indexed_iter() {
+                if !value.is_nan() {
+                    let target = &mut dense[[
+                        self_indices[0][i0],
+                        self_indices[1][i1],
+                        self_indices[2][i2],
+                        self_indices[3][i3],
+                    ]];
+                    if *target != value {
+                        *target = value;
+                        changed += 1;
                     }
+                    copied += 1;
                 }
             }
         }
+        stats.copied_bytes = copied * std::mem::size_of::<f32>();
+        stats.written_cells = copied;
+        stats.changed_cells = changed;
+        stats.nan_cells = gathered.len() - copied;
+        stats.outside_cells -= gathered.len();
+        Ok(stats)
+    }
 
-        // 5. Now that all labels on all axes match, apply the patch
-        let sh = self.dense.shape().to_owned();
-        Self::merge_slice(union.view(), self.dense.view_mut(), &sh[..], |a, b| {
-            if !b.is_nan() {
-                *a = *b;
-            }
-        });
-        Ok(())
+    /// Pad a view with trailing unit axes until it has four, without copying
+    ///
+    /// This works for any memory layout, so it's useful for buffers from outside.
+    pub(crate) fn view_4d(mut view: nd::ArrayViewMutD<f32>) -> Fallible<ArrayViewMut4<f32>> {
+        while view.ndim() < 4 {
+            let ndim = view.ndim();
+            view = view.insert_axis(nd::Axis(ndim));
+        }
+        view.into_dimensionality::<nd::Ix4>()
+            .map_err(|_| StoiError::InvalidValue("buffers must have 4 dimensions or less"))
     }
 
-    /// Copy an N-d rectangle at the origin between incongruent arrays
+    /// Stack patches along a new first axis, like scenarios of an ensemble
     ///
-    /// Make congruent slices on both sides and then assign()'s
-    fn merge_slice<F: Fn(&mut f32, &f32)>(
-        read: ArrayView4<f32>,
-        write: ArrayViewMut4<f32>,
-        size: &[usize],
-        merge: F,
-    ) {
-        let read_slice = size
-            .iter()
-            .enumerate()
-            .fold(read, |mut rd, (ax_ix, &width)| {
-                rd.slice_axis_inplace(nd::Axis(ax_ix), (0..width).into());
-                rd
-            });
-        let mut write_slice = size
-            .iter()
-            .enumerate()
-            .fold(write, |mut wr, (ax_ix, &width)| {
-                wr.slice_axis_inplace(nd::Axis(ax_ix), (0..width).into());
-                wr
-            });
+    /// Each patch becomes the slice of the new axis at the label with the same position in
+    /// `labels`. The patches must all have the same axes with the same labels, but the labels
+    /// can be in any order, and they're aligned with the first patch. They can have at most
+    /// three axes, since the result has one more.
+    pub fn stack(axis_name: &str, labels: &[Label], patches: &[&Patch]) -> Fallible<Patch> {
+        let first = match patches.first() {
+            Some(first) => first,
+            None => return Err(StoiError::InvalidValue("there must be patches to stack")),
+        };
+        if labels.len() != patches.len() {
+            return Err(StoiError::InvalidValue(
+                "stacking needs exactly one label for each patch",
+            ));
+        }
+        if first.ndim() > 3 {
+            return Err(StoiError::TooLarge(
+                "patches can have at most four axes, so only patches with three can be stacked",
+            ));
+        }
+        if first.axes.iter().any(|axis| axis.name == axis_name) {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the patches already have an axis named {}",
+                axis_name
+            )));
+        }
+        for patch in patches {
+            let compatible = patch.ndim() == first.ndim()
+                && first.axes.iter().all(|axis| {
+                    patch
+                        .axes
+                        .iter()
+                        .find(|other| other.name == axis.name)
+                        .map_or(false, |other| other.labelset() == axis.labelset())
+                });
+            if !compatible {
+                return Err(StoiError::MisalignedAxes(format!(
+                    "can't stack patches with axes {:?} and {:?}, they must match",
+                    first.axes, patch.axes
+                )));
+            }
+        }
 
-        write_slice.zip_mut_with(&read_slice, merge);
+        let mut axes = vec![Axis::new(axis_name, labels.to_vec())?];
+        axes.extend(first.axes.iter().cloned());
+        let mut stacked = Patch::new(axes, None)?;
+        for (ix, patch) in patches.iter().enumerate() {
+            let mut content = stacked.content_mut();
+            let view = Patch::view_4d(content.index_axis_mut(nd::Axis(0), ix))?;
+            Patch::apply_to_view(&first.axes, view, patch)?;
+        }
+        Ok(stacked)
     }
 
-    /// Shuffle each plane of all tensor axes, with possible replication
+    /// Take the slice at one label of an axis, without that axis, the opposite of stack()
     ///
-    /// "pull" here means there is one index for each write plane.
-    /// As a result, you can make multiple copies of the each plane if you want.
-    ///
-    /// Use std::usize::MAX to skip a plane
-    fn shuffle_pull_ndim(&self, original: Array4<f32>, shuffles: &[Vec<usize>]) -> Array4<f32> {
-        assert!(shuffles.len() == original.ndim());
-        let mut scratch = original.clone();
-        match self.ndim() {
-            4 => Self::shuffle_pull_4d(&original.view(), &mut scratch.view_mut(), &shuffles[..]),
-            3 => Self::shuffle_pull_3d(
-                &original.index_axis(nd::Axis(3), 0),
-                &mut scratch.index_axis_mut(nd::Axis(3), 0),
-                &shuffles[..shuffles.len() - 1],
-            ),
-            2 => Self::shuffle_pull_2d(
-                &original
-                    .index_axis(nd::Axis(3), 0)
-                    .index_axis(nd::Axis(2), 0),
-                &mut scratch
-                    .index_axis_mut(nd::Axis(3), 0)
-                    .index_axis_mut(nd::Axis(2), 0),
-                &shuffles[..shuffles.len() - 2],
-            ),
-            1 => Self::shuffle_pull_1d(
-                &original
-                    .index_axis(nd::Axis(3), 0)
-                    .index_axis(nd::Axis(2), 0)
-                    .index_axis(nd::Axis(1), 0),
-                &mut scratch
-                    .index_axis_mut(nd::Axis(3), 0)
-                    .index_axis_mut(nd::Axis(2), 0)
-                    .index_axis_mut(nd::Axis(1), 0),
-                &shuffles[0],
-            ),
-            _ => panic!("Invalid Patch! Can't have more than 4 dimensions!"),
-        }
-        //original
-        scratch
-    }
-
-    /// Shuffle each plane of all tensor axes, with possible replication
-    ///
-    /// "pull" here means there is one index for each write plane.
-    /// As a result, you can make multiple copies of the each plane if you want.
-    ///
-    /// Use std::usize::MAX to skip a plane
-    ///
-    /// The results will be in "original" after this function completes
-    fn shuffle_pull_4d(
-        read: &ArrayView<f32, nd::Ix4>,
-        write: &mut ArrayViewMut<f32, nd::Ix4>,
-        shuffles: &[Vec<usize>],
-    ) {
-        assert_eq!(shuffles.len(), 4);
-
-        // Not last dimension: recursive copy
-        let ax0 = nd::Axis(0);
-        let shuf0 = &shuffles[0];
-        assert!(shuf0.len() <= write.len_of(ax0));
-        for write_index in 0..shuf0.len() {
-            if shuf0[write_index] != std::usize::MAX {
-                Self::shuffle_pull_3d(
-                    &read.index_axis(ax0, shuf0[write_index]),
-                    &mut write.index_axis_mut(ax0, write_index),
-                    &shuffles[1..],
-                )
+    /// With a MEASURE_AXIS, this reads one measure of every cell, like its flags.
+    pub fn slice_label(&self, axis_name: &str, label: Label) -> Fallible<Patch> {
+        let ax_ix = match self.axes.iter().position(|axis| axis.name == axis_name) {
+            Some(ax_ix) => ax_ix,
+            None => {
+                return Err(StoiError::MisalignedAxes(format!(
+                    "the patch has no axis named {}",
+                    axis_name
+                )))
             }
+        };
+        if self.ndim() == 1 {
+            return Err(StoiError::InvalidValue(
+                "a slice of the only axis of a patch would have no axes",
+            ));
         }
+        let index = self.axes[ax_ix]
+            .labels()
+            .iter()
+            .position(|&other| other == label)
+            .ok_or_else(|| StoiError::LabelNotFound(axis_name.to_string(), label))?;
+        let mut axes = self.axes.clone();
+        axes.remove(ax_ix);
+        let content = self.content().index_axis(nd::Axis(ax_ix), index).to_owned();
+        Patch::new(axes, Some(content))
     }
 
-    /// Shuffle each plane of all tensor axes, with possible replication
-    /// see shuffle_pull_4d.
-    fn shuffle_pull_3d(
-        read: &ArrayView<f32, nd::Ix3>,
-        write: &mut ArrayViewMut<f32, nd::Ix3>,
-        shuffles: &[Vec<usize>],
-    ) {
-        assert_eq!(shuffles.len(), 3);
-
-        // Not last dimension: recursive copy
-        let ax0 = nd::Axis(0);
-        let shuf0 = &shuffles[0];
-        assert!(shuf0.len() <= write.len_of(ax0));
-        for write_index in 0..shuf0.len() {
-            if shuf0[write_index] != std::usize::MAX {
-                Self::shuffle_pull_2d(
-                    &read.index_axis(ax0, shuf0[write_index]),
-                    &mut write.index_axis_mut(ax0, write_index),
-                    &shuffles[1..],
-                )
+    /// Aggregate groups of labels along an axis into coarser labels, like days into weeks
+    ///
+    /// Each group becomes one label of the new axis, with the values of the group reduced by
+    /// `op`. NANs are skipped, and a group with no data at all is NAN, so it won't occlude
+    /// anything when it's committed. The other axes are unchanged.
+    pub fn coarsen(
+        &self,
+        axis_name: &str,
+        coarsening: &Coarsening,
+        op: ReduceOp,
+    ) -> Fallible<Patch> {
+        let ax_ix = self
+            .axes
+            .iter()
+            .position(|axis| axis.name == axis_name)
+            .ok_or_else(|| StoiError::NotFound("axis of the patch", axis_name.to_string()))?;
+        let axis = &self.axes[ax_ix];
+        // The coarse labels, and the indices of the fine labels in each
+        let groups: Vec<(Label, Vec<usize>)> = match coarsening {
+            Coarsening::Factor(0) => {
+                return Err(StoiError::InvalidValue("can't coarsen by a factor of zero"))
             }
-        }
+            Coarsening::Factor(factor) => (0..axis.len())
+                .step_by(*factor)
+                .map(|start| {
+                    let end = (start + factor).min(axis.len());
+                    (axis.labels()[start], (start..end).collect())
+                })
+                .collect(),
+            Coarsening::Groups(groups) => {
+                let label_to_idx: HashMap<Label, usize> = axis
+                    .labels()
+                    .iter()
+                    .enumerate()
+                    .map(|(ix, &label)| (label, ix))
+                    .collect();
+                groups
+                    .iter()
+                    .map(|(coarse, fine)| {
+                        let indices = fine
+                            .iter()
+                            .filter_map(|label| label_to_idx.get(label).copied())
+                            .collect();
+                        (*coarse, indices)
+                    })
+                    .collect()
+            }
+        };
+        self.reduce_groups(ax_ix, &groups, op)
     }
 
-    /// Shuffle each plane of all tensor axes, with possible replication
-    /// see shuffle_pull_4d.
-    fn shuffle_pull_2d(
-        read: &ArrayView<f32, nd::Ix2>,
-        write: &mut ArrayViewMut<f32, nd::Ix2>,
-        shuffles: &[Vec<usize>],
-    ) {
-        assert_eq!(shuffles.len(), 2);
-
-        // Not last dimension: recursive copy
-        let ax0 = nd::Axis(0);
-        let shuf0 = &shuffles[0];
-        assert!(shuf0.len() <= write.len_of(ax0));
-        for write_index in 0..shuf0.len() {
-            if shuf0[write_index] != std::usize::MAX {
-                Self::shuffle_pull_1d(
-                    &read.index_axis(ax0, shuf0[write_index]),
-                    &mut write.index_axis_mut(ax0, write_index),
-                    &shuffles[1],
-                )
+    /// Merge repeated labels on every axis into one label, reducing their values with `op`
+    ///
+    /// Patches shouldn't repeat labels, but ones built with Axis::range() or deserialized with
+    /// serde can. apply() and commits refuse them, since it's ambiguous which value is meant,
+    /// so this is how to choose. Labels stay in the order they first appear.
+    pub fn dedup_labels(&self, op: ReduceOp) -> Fallible<Patch> {
+        let mut patch = self.clone();
+        for ax_ix in 0..self.axes.len() {
+            // Each distinct label, and the indices where it appears
+            let mut groups: Vec<(Label, Vec<usize>)> = vec![];
+            let mut group_of: HashMap<Label, usize> = HashMap::new();
+            for (ix, &label) in patch.axes[ax_ix].labels().iter().enumerate() {
+                match group_of.get(&label) {
+                    Some(&group) => groups[group].1.push(ix),
+                    None => {
+                        group_of.insert(label, groups.len());
+                        groups.push((label, vec![ix]));
+                    }
+                }
+            }
+            if groups.len() < patch.axes[ax_ix].len() {
+                patch = patch.reduce_groups(ax_ix, &groups, op)?;
             }
         }
+        Ok(patch)
+    }
+
+    /// Reduce groups of indices along an axis into one new label each, see coarsen()
+    fn reduce_groups(
+        &self,
+        ax_ix: usize,
+        groups: &[(Label, Vec<usize>)],
+        op: ReduceOp,
+    ) -> Fallible<Patch> {
+        let mut axes = self.axes.clone();
+        let coarse_labels = groups.iter().map(|(coarse, _indices)| *coarse).collect();
+        axes[ax_ix] = Axis::new(&self.axes[ax_ix].name, coarse_labels)?;
+        let reduced = groups
+            .iter()
+            .map(|(_coarse, indices)| {
+                self.dense
+                    .select(nd::Axis(ax_ix), indices)
+                    .map_axis(nd::Axis(ax_ix), |lane| op.reduce(lane.iter().copied()))
+                    .insert_axis(nd::Axis(ax_ix))
+            })
+            .collect_vec();
+        let dense = if reduced.is_empty() {
+            None
+        } else {
+            let parts = reduced.iter().map(|part| part.view()).collect_vec();
+            // The parts only differ in length along the coarsened axis
+            Some(nd::stack(nd::Axis(ax_ix), &parts).unwrap())
+        };
+        Patch::new_4d(axes, dense)
+    }
+
+    /// Replace every NAN with a value, like pandas' fillna()
+    pub fn fill_nan(&mut self, value: f32) {
+        self.dense
+            .mapv_inplace(|x| if x.is_nan() { value } else { x });
     }
 
-    /// Copy (N-1)D planes, with possible replication
+    /// Replace NANs with the last value before them along an axis, like pandas' ffill()
     ///
-    /// "pull" here means there is one index for each write plane.
-    /// As a result, you can make multiple copies of the each plane if you want.
+    /// "Before" is by label, not by where the label is in the axis, so it works even when the
+    /// labels aren't sorted. NANs before the first value along the axis stay NAN.
+    pub fn fill_forward(&mut self, axis_name: &str) -> Fallible<()> {
+        let (ax_ix, order) = self.label_order(axis_name)?;
+        for mut lane in self.dense.lanes_mut(nd::Axis(ax_ix)) {
+            let mut last = std::f32::NAN;
+            for &ix in &order {
+                if lane[ix].is_nan() {
+                    lane[ix] = last;
+                } else {
+                    last = lane[ix];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace NANs by linear interpolation along an axis, like pandas' interpolate()
     ///
-    /// Use std::usize::MAX to skip a plane
-    fn shuffle_pull_1d(
-        read: &ArrayView<f32, nd::Ix1>,
-        write: &mut ArrayViewMut<f32, nd::Ix1>,
-        shuffle: &[usize],
-    ) {
-        // Note: it's totally OK if some of the patch is not written
-        // I'm not sure what the preference would be if shuffle is larger
-        // so let's just jump out then
-        assert!(shuffle.len() <= write.len());
-        for write_index in 0..shuffle.len() {
-            if shuffle[write_index] != std::usize::MAX {
-                write[write_index] = read[shuffle[write_index]];
+    /// The labels are the positions to interpolate between, so a gap of two days between
+    /// values counts twice as much as a gap of one, and like fill_forward() they don't need to
+    /// be sorted. NANs before the first value or after the last value along the axis stay NAN.
+    pub fn interpolate(&mut self, axis_name: &str) -> Fallible<()> {
+        let (ax_ix, order) = self.label_order(axis_name)?;
+        let labels = self.axes[ax_ix].labels().to_vec();
+        for mut lane in self.dense.lanes_mut(nd::Axis(ax_ix)) {
+            // The last index with a value, in label order
+            let mut last: Option<usize> = None;
+            for (rank, &ix) in order.iter().enumerate() {
+                if lane[ix].is_nan() {
+                    continue;
+                }
+                if let Some(last_rank) = last {
+                    let start = order[last_rank];
+                    let (x0, y0) = (labels[start] as f64, lane[start] as f64);
+                    let (x1, y1) = (labels[ix] as f64, lane[ix] as f64);
+                    for &gap_ix in &order[last_rank + 1..rank] {
+                        let t = (labels[gap_ix] as f64 - x0) / (x1 - x0);
+                        lane[gap_ix] = (y0 + t * (y1 - y0)) as f32;
+                    }
+                }
+                last = Some(rank);
             }
         }
+        Ok(())
+    }
+
+    /// Find an axis, and the indices of its labels sorted by label
+    fn label_order(&self, axis_name: &str) -> Fallible<(usize, Vec<usize>)> {
+        let ax_ix = self
+            .axes
+            .iter()
+            .position(|axis| axis.name == axis_name)
+            .ok_or_else(|| StoiError::NotFound("axis of the patch", axis_name.to_string()))?;
+        let labels = self.axes[ax_ix].labels();
+        let order = (0..labels.len()).sorted_by_key(|&ix| labels[ix]).collect();
+        Ok((ax_ix, order))
     }
 
     /// Merge two patches together into a larger patch
     ///
-    /// This is actually pretty simple, it works by creating a new Patch and applying
-    /// all of the patches to it.
+    /// This is actually pretty simple, it works by creating a new Patch with the labels of both,
+    /// and merging both into it with merge_into(), so `other` wins where they overlap.
     pub fn merge(&self, other: &Patch) -> Fallible<Patch> {
-        // There must have been a first one and it must have had axes
-        let mut axes = other.axes().iter().cloned().collect_vec();
+        let mut axes = self.axes().iter().cloned().collect_vec();
         if !other
             .axes()
             .iter()
@@ -493,16 +960,31 @@ impl Patch {
             ));
         }
 
-        for (ax_ix, axis) in other.axes().into_iter().enumerate() {
-            axes[ax_ix].union(&axis); // In-place
+        for (ax_ix, axis) in other.axes().iter().enumerate() {
+            axes[ax_ix].union(axis); // In-place
         }
-        // TODO: Maybe we don't need to allocate here?
         let mut target = Patch::new(axes, None)?;
-        target.apply(&self)?;
-        target.apply(other)?;
+        Patch::merge_into(&mut target, vec![self, other])?;
         Ok(target)
     }
 
+    /// Merge any number of patches into a target you already have, in order
+    ///
+    /// Each patch is applied as it comes, like apply(), so later patches win where they overlap
+    /// and patches can be produced one at a time rather than collected first. The target isn't
+    /// expanded, so it needs to have every label worth keeping already, and it can be a reused
+    /// buffer. Returns the stats of all the applies together.
+    pub fn merge_into<P: Borrow<Patch>>(
+        target: &mut Patch,
+        patches: impl IntoIterator<Item = P>,
+    ) -> Fallible<ApplyStats> {
+        let mut stats = ApplyStats::default();
+        for patch in patches {
+            stats += target.apply_counted(patch.borrow())?;
+        }
+        Ok(stats)
+    }
+
     /// Possibly compact the patch, removing unused labels
     ///
     /// You can compact a source patch but not a target patch for an apply().
@@ -582,13 +1064,107 @@ impl Patch {
         }
     }
 
+    /// Copy the patch with its axes in another order, given every axis name once
+    ///
+    ///     use stoicheia::Patch;
+    ///     let pat = Patch::build()
+    ///         .axis("itm", &[1, 2])
+    ///         .axis("day", &[720])
+    ///         .content_2d(&[[1.], [2.]])
+    ///         .unwrap();
+    ///     let flipped = pat.permute_axes(&["day", "itm"]).unwrap();
+    ///     assert_eq!(flipped.axes()[0].name, "day");
+    ///     assert_eq!(flipped.content().as_slice().unwrap(), &[1., 2.]);
+    pub fn permute_axes(&self, axis_names: &[&str]) -> Fallible<Patch> {
+        let order = axis_names
+            .iter()
+            .filter_map(|&name| self.axes.iter().position(|axis| axis.name == name))
+            .unique()
+            .collect_vec();
+        if order.len() != self.ndim() || axis_names.len() != self.ndim() {
+            return Err(StoiError::MisalignedAxes(format!(
+                "can't permute axes {:?} into [{}], they must be the same names",
+                self.axes,
+                axis_names.join(", ")
+            )));
+        }
+        let axes = order
+            .iter()
+            .map(|&ax_ix| self.axes[ax_ix].clone())
+            .collect();
+        let content = self.content().permuted_axes(&order[..]);
+        Patch::new(axes, Some(content.as_standard_layout().into_owned()))
+    }
+
+    /// The canonical form of the patch, which is the same for all logically equal patches
+    ///
+    /// Patches with the same content can still differ in the order of their axes and labels,
+    /// in slices that are all NAN (which hold no data), or in the bits of their NANs and zeros.
+    /// The canonical form has axes sorted by name, labels sorted within each axis, no slices
+    /// that are all NAN, and one NAN and one zero. Use it to compare or deduplicate patches.
+    pub fn canonicalize(&self) -> Patch {
+        let order = (0..self.ndim())
+            .sorted_by_key(|&ax_ix| &self.axes[ax_ix].name)
+            .collect_vec();
+        let mut dense = self.content().to_owned().permuted_axes(&order[..]);
+        let mut axes = vec![];
+        for (ax_ix, &original_ax_ix) in order.iter().enumerate() {
+            let axis = &self.axes[original_ax_ix];
+            // Removing slices that are all NAN never leaves another slice all NAN
+            let keep = (0..axis.len())
+                .filter(|&ix| {
+                    dense
+                        .index_axis(nd::Axis(ax_ix), ix)
+                        .iter()
+                        .any(|x| !x.is_nan())
+                })
+                .sorted_by_key(|&ix| axis.labels()[ix])
+                .collect_vec();
+            dense = dense.select(nd::Axis(ax_ix), &keep);
+            axes.push(Axis::new_unchecked(
+                &axis.name,
+                keep.iter().map(|&ix| axis.labels()[ix]).collect(),
+            ));
+        }
+        let dense = ArrayD::from_shape_vec(
+            dense.raw_dim(),
+            dense
+                .iter()
+                .map(|&x| match x {
+                    x if x.is_nan() => std::f32::NAN,
+                    x if x == 0. => 0.,
+                    x => x,
+                })
+                .collect(),
+        )
+        .unwrap(); // <- The same shape and length it came from
+        Patch::new(axes, Some(dense)).unwrap() // <- Axes were sliced along with the content
+    }
+
+    /// Hash the canonical form of the patch, so logically equal patches hash the same
+    ///
+    /// This is 64-bit FNV-1a of the canonical form serialized with bincode, so it's stable
+    /// across platforms and runs, which makes it usable for deduplication. It's not
+    /// cryptographic, so don't rely on it against deliberate collisions.
+    pub fn canonical_hash(&self) -> Fallible<u64> {
+        let bytes = bincode::serialize(&self.canonicalize())?;
+        Ok(bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        }))
+    }
+
     /// Render the patch as a dense array. This always copies the data.
     pub fn to_dense(&self) -> nd::ArrayD<f32> {
-        self.dense
+        self.try_to_dense().unwrap() // <- Patches are validated when built or deserialized
+    }
+
+    /// Render the patch as a dense array, like to_dense(), but without panicking
+    pub fn try_to_dense(&self) -> Fallible<nd::ArrayD<f32>> {
+        Ok(self
+            .dense
             .clone()
             .into_dyn()
-            .into_shape(&self.dense.shape()[..self.ndim()])
-            .unwrap()
+            .into_shape(&self.dense.shape()[..self.ndim()])?)
     }
 
     /// Get a reference to the content
@@ -610,40 +1186,219 @@ impl Patch {
             .unwrap()
     }
 
-    /// Get a shared reference to the axes within
-    pub fn axes(&self) -> &[Axis] {
-        &self.axes
+    /// Iterate over every element with its labels, one for each axis, in storage order
+    ///
+    /// NAN elements are included, see iter_nonnan() to skip them.
+    pub fn iter_labeled(&self) -> impl Iterator<Item = (Vec<Label>, f32)> + '_ {
+        self.dense
+            .indexed_iter()
+            .map(move |((i0, i1, i2, i3), &value)| (self.labels_at([i0, i1, i2, i3]), value))
     }
 
-    /// Get the total number of elements
-    pub fn len(&self) -> usize {
-        self.dense.len()
+    /// Iterate over the elements that have data with their labels, one for each axis
+    pub fn iter_nonnan(&self) -> impl Iterator<Item = (Vec<Label>, f32)> + '_ {
+        self.iter_labeled()
+            .filter(|(_labels, value)| !value.is_nan())
     }
 
-    /// Serialize a patch the default way
-    ///
+    /// Iterate over every element with its labels in parallel, like iter_labeled()
+    pub fn par_iter_labeled(&self) -> impl IndexedParallelIterator<Item = (Vec<Label>, f32)> + '_ {
+        let (_d0, d1, d2, d3) = self.dense.dim();
+        // The content is always in standard layout, see content()
+        (0..self.dense.len()).into_par_iter().map(move |flat_ix| {
+            let ix = [
+                flat_ix / (d1 * d2 * d3),
+                flat_ix / (d2 * d3) % d1,
+                flat_ix / d3 % d2,
+                flat_ix % d3,
+            ];
+            (self.labels_at(ix), self.dense[ix])
+        })
+    }
+
+    /// Iterate over the elements that have data with their labels in parallel, like iter_nonnan()
+    pub fn par_iter_nonnan(&self) -> impl ParallelIterator<Item = (Vec<Label>, f32)> + '_ {
+        self.par_iter_labeled()
+            .filter(|(_labels, value)| !value.is_nan())
+    }
+
+    /// The labels of an element, by its index in the dense 4D content
+    fn labels_at(&self, ix: [usize; 4]) -> Vec<Label> {
+        self.axes
+            .iter()
+            .zip(&ix)
+            .map(|(axis, &i)| axis.labels()[i])
+            .collect()
+    }
+
+    /// Get a shared reference to the axes within
+    pub fn axes(&self) -> &[Axis] {
+        &self.axes
+    }
+
+    /// Get the value of one cell, by its label on each axis in order
+    ///
+    /// This is None if a label isn't on its axis, or there isn't one label for each axis.
+    /// The first lookup indexes the labels of every axis, so later ones are cheap.
+    ///
+    ///     use stoicheia::Patch;
+    ///     let mut pat = Patch::build()
+    ///         .axis("itm", &[7, 3])
+    ///         .axis("day", &[720, 721])
+    ///         .content_2d(&[[1., 2.], [3., 4.]])
+    ///         .unwrap();
+    ///     assert_eq!(pat.get(&[3, 721]), Some(4.));
+    ///     assert_eq!(pat.get(&[5, 721]), None);
+    ///     pat.set(&[7, 721], 10.).unwrap();
+    ///     assert_eq!(pat.get(&[7, 721]), Some(10.));
+    pub fn get(&self, labels: &[Label]) -> Option<f32> {
+        self.cell_index(labels).ok().map(|index| self.dense[index])
+    }
+
+    /// Set the value of one cell, by its label on each axis in order, see get()
+    ///
+    /// Patches can't grow, so every label must already be on its axis.
+    pub fn set(&mut self, labels: &[Label], value: f32) -> Fallible<()> {
+        let index = self.cell_index(labels)?;
+        self.dense[index] = if value.is_nan() && cfg!(feature = "canonical-nan") {
+            std::f32::NAN
+        } else {
+            value
+        };
+        Ok(())
+    }
+
+    /// The bounding box of this patch in a catalog, if it was found for these axis generations
+    ///
+    /// Patches keep theirs once it's found, like when they're committed, so committing them
+    /// again doesn't have to look up every label in the catalog's axes.
+    pub(crate) fn global_bounds(
+        &self,
+        catalog_id: i64,
+        generations: &[u64],
+    ) -> Option<BoundingBox> {
+        let bounds = self.global_bounds.0.lock().ok()?;
+        bounds
+            .as_ref()
+            .filter(|b| b.catalog_id == catalog_id && b.generations == generations)
+            .map(|b| b.bounding_box)
+    }
+
+    /// Remember the bounding box of this patch in a catalog, see global_bounds()
+    pub(crate) fn set_global_bounds(&self, bounds: GlobalBounds) {
+        if let Ok(mut cached) = self.global_bounds.0.lock() {
+            *cached = Some(bounds);
+        }
+    }
+
+    /// Find the index of a cell in the content by its labels, building label indices as needed
+    fn cell_index(&self, labels: &[Label]) -> Fallible<[usize; 4]> {
+        if labels.len() != self.ndim() {
+            return Err(StoiError::InvalidValue(
+                "a cell needs exactly one label for each axis of the patch",
+            ));
+        }
+        let mut indices = self
+            .label_indices
+            .0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        indices.resize(self.ndim(), None);
+        let mut index = [0; 4];
+        for (ax_ix, (axis, label)) in self.axes.iter().zip(labels).enumerate() {
+            index[ax_ix] = *indices[ax_ix]
+                .get_or_insert_with(|| axis.label_index())
+                .get(label)
+                .ok_or_else(|| StoiError::LabelNotFound(axis.name.clone(), *label))?;
+        }
+        Ok(index)
+    }
+
+    /// Get the total number of elements
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    /// Check if the patch has no data at all, because every element is NAN.
+    ///
+    /// Such a patch wouldn't change anything when committed, even after compact().
+    pub fn is_empty_data(&self) -> bool {
+        self.dense.iter().all(|x| x.is_nan())
+    }
+
+    /// Serialize a patch the default way
+    ///
     /// It's still possible to serialize a patch with serde, but this is the
     /// recommended method if you don't have reason to do otherwise, to avoid
     /// needless incompatibilities
     pub fn serialize_into<W: Write>(
         &self,
         compression: Option<PatchCompressionType>,
+        buffer: &mut W,
+    ) -> Fallible<()> {
+        self.serialize_quantized_into(compression, PatchQuantization::Exact, buffer)
+    }
+
+    /// Serialize a patch, possibly losing some precision to save space
+    ///
+    /// The scale and offset of the quantization are chosen here for each patch, and stored with
+    /// it, so deserialize_from() doesn't need to know what quantization was used.
+    pub fn serialize_quantized_into<W: Write>(
+        &self,
+        compression: Option<PatchCompressionType>,
+        quantization: PatchQuantization,
+        buffer: &mut W,
+    ) -> Fallible<()> {
+        let filter = PatchFilter::choose(quantization, &self.dense);
+        self.serialize_filtered_into(compression, filter, buffer)
+    }
+
+    /// Serialize a patch merged from an older one, without quantizing its values again
+    ///
+    /// The older patch's values were already rounded to the levels of its own quantization.
+    /// Rounding them to other levels would lose a little more precision with every merge, so
+    /// if this patch fits in the same levels they are used again, and the older values are
+    /// stored as they were.
+    pub(crate) fn serialize_merged_into<W: Write>(
+        &self,
+        compression: Option<PatchCompressionType>,
+        quantization: PatchQuantization,
+        older: &Patch,
+        buffer: &mut W,
+    ) -> Fallible<()> {
+        let filter = match PatchFilter::choose(quantization, &older.dense) {
+            Some(filter) if filter.fits(&self.dense) => Some(filter),
+            _ => PatchFilter::choose(quantization, &self.dense),
+        };
+        self.serialize_filtered_into(compression, filter, buffer)
+    }
+
+    /// Serialize a patch with a filter that was already chosen for it
+    fn serialize_filtered_into<W: Write>(
+        &self,
+        compression: Option<PatchCompressionType>,
+        filter: Option<PatchFilter>,
         mut buffer: &mut W,
     ) -> Fallible<()> {
-        let compression = compression.unwrap_or(PatchCompressionType::Off);
+        let compression = match compression.unwrap_or(PatchCompressionType::Off) {
+            PatchCompressionType::Auto => self.choose_compression()?,
+            compression => compression,
+        };
+        let filters = match compression {
+            // Custom codecs get the whole patch, and do their own quantization if any
+            PatchCompressionType::Custom { .. } => vec![],
+            _ => filter.into_iter().collect(),
+        };
         let options = PatchTag {
-            magic: 0x494f5453, // "STOI"
-            version: 1,
+            magic: PATCH_MAGIC,
+            version: PATCH_VERSION,
             compression,
-            filters: vec![],
+            filters,
         };
         bincode::serialize_into(&mut buffer, &options)?;
 
         match options.compression {
-            PatchCompressionType::Off => {
-                bincode::serialize_into(&mut buffer, &self)?;
-                Ok(())
-            }
+            PatchCompressionType::Off => self.serialize_payload(&options.filters, &mut buffer),
             PatchCompressionType::Brotli { quality } => {
                 let mut brotli_writer = brotli::CompressorWriter::new(
                     &mut buffer,
@@ -652,7 +1407,7 @@ impl Patch {
                     20,      /* Log2 buffer size */
                 );
 
-                bincode::serialize_into(&mut brotli_writer, &self)?;
+                self.serialize_payload(&options.filters, &mut brotli_writer)?;
                 brotli_writer.flush()?;
                 Ok(())
             }
@@ -661,14 +1416,139 @@ impl Patch {
                     .level(quality)
                     .build(&mut buffer)?;
 
-                bincode::serialize_into(&mut lz4_writer, &self)?;
+                self.serialize_payload(&options.filters, &mut lz4_writer)?;
                 lz4_writer.finish().1?;
 
                 Ok(())
             }
+            PatchCompressionType::Custom { format } => {
+                find_patch_codec(format)?.encode_into(self, buffer)
+            }
+            PatchCompressionType::Auto => Err(StoiError::RuntimeError(
+                "automatic compression should have been chosen already",
+            )),
         }
     }
 
+    /// Serialize everything after the PatchTag, applying filters if there are any
+    fn serialize_payload<W: Write>(&self, filters: &[PatchFilter], writer: &mut W) -> Fallible<()> {
+        match filters.first() {
+            None => bincode::serialize_into(writer, &self)?,
+            Some(&PatchFilter::QuantizeF16 { scale, offset }) => bincode::serialize_into(
+                writer,
+                &QuantizedPatch {
+                    axes: Cow::Borrowed(&self.axes),
+                    shape: self.dense.dim(),
+                    codes: self
+                        .dense
+                        .iter()
+                        .map(|&x| f32_to_f16((x - offset) / scale))
+                        .collect_vec(),
+                },
+            )?,
+            Some(&PatchFilter::QuantizeU8 { scale, offset }) => bincode::serialize_into(
+                writer,
+                &QuantizedPatch {
+                    axes: Cow::Borrowed(&self.axes),
+                    shape: self.dense.dim(),
+                    codes: self
+                        .dense
+                        .iter()
+                        .map(|&x| {
+                            if x.is_nan() {
+                                std::u8::MAX
+                            } else {
+                                ((x - offset) / scale).round().max(0.).min(254.) as u8
+                            }
+                        })
+                        .collect_vec(),
+                },
+            )?,
+            Some(&PatchFilter::Integers16) => bincode::serialize_into(
+                writer,
+                &QuantizedPatch {
+                    axes: Cow::Borrowed(&self.axes),
+                    shape: self.dense.dim(),
+                    codes: self
+                        .dense
+                        .iter()
+                        .map(|&x| if x.is_nan() { std::i16::MIN } else { x as i16 })
+                        .collect_vec(),
+                },
+            )?,
+            Some(&PatchFilter::Integers32) => bincode::serialize_into(
+                writer,
+                &QuantizedPatch {
+                    axes: Cow::Borrowed(&self.axes),
+                    shape: self.dense.dim(),
+                    codes: self
+                        .dense
+                        .iter()
+                        .map(|&x| if x.is_nan() { std::i32::MIN } else { x as i32 })
+                        .collect_vec(),
+                },
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Deserialize everything after the PatchTag, undoing any filters
+    fn deserialize_payload<R: Read>(filters: &[PatchFilter], mut reader: R) -> Fallible<Self> {
+        // A corrupted length could be anything, and bincode allocates it before reading, so
+        // nothing may claim more bytes than the payload has left
+        let mut payload = vec![];
+        reader.read_to_end(&mut payload)?;
+        let mut config = bincode::config();
+        config.limit(payload.len() as u64);
+        match filters.first() {
+            None => config.deserialize::<Self>(&payload)?.checked(),
+            Some(&PatchFilter::QuantizeF16 { scale, offset }) => {
+                Self::dequantize(config.deserialize(&payload)?, |c: u16| {
+                    f16_to_f32(c) * scale + offset
+                })
+            }
+            Some(&PatchFilter::QuantizeU8 { scale, offset }) => {
+                Self::dequantize(config.deserialize(&payload)?, |c: u8| {
+                    if c == std::u8::MAX {
+                        std::f32::NAN
+                    } else {
+                        c as f32 * scale + offset
+                    }
+                })
+            }
+            Some(&PatchFilter::Integers16) => {
+                Self::dequantize(config.deserialize(&payload)?, |c: i16| {
+                    if c == std::i16::MIN {
+                        std::f32::NAN
+                    } else {
+                        c as f32
+                    }
+                })
+            }
+            Some(&PatchFilter::Integers32) => {
+                Self::dequantize(config.deserialize(&payload)?, |c: i32| {
+                    if c == std::i32::MIN {
+                        std::f32::NAN
+                    } else {
+                        c as f32
+                    }
+                })
+            }
+        }
+    }
+
+    /// Build a patch from the codes of a quantization filter, given how to decode each one
+    fn dequantize<C>(quant: QuantizedPatch<C>, decode: impl Fn(C) -> f32) -> Fallible<Self> {
+        let values = quant.codes.into_iter().map(decode).collect_vec();
+        Self::new_4d(
+            quant.axes.into_owned(),
+            Some(Array4::from_shape_vec(quant.shape, values).map_err(|_| {
+                StoiError::MalformedPatch("quantized patch shape doesn't match its content")
+            })?),
+        )?
+        .checked()
+    }
+
     /// Serialize the default way, into a fresh new Vec
     ///
     /// While this method is convenient, patches are usually pretty large, so
@@ -680,24 +1560,204 @@ impl Patch {
         Ok(buffer)
     }
 
+    /// Estimate how many bytes serialize() would produce, without serializing everything
+    ///
+    /// Small patches are just serialized. Larger patches are sampled, by serializing evenly
+    /// spaced planes along the longest axis and scaling up the result.
+    pub fn estimate_serialized_size(
+        &self,
+        compression: Option<PatchCompressionType>,
+    ) -> Fallible<usize> {
+        const SAMPLE_ELEMENTS: usize = 1 << 16;
+        if self.len() <= SAMPLE_ELEMENTS {
+            return Ok(self.serialize(compression)?.len());
+        }
+        let (long_ax_ix, long_axis) = self
+            .axes
+            .iter()
+            .enumerate()
+            .max_by_key(|(_ax_ix, ax)| ax.len())
+            .unwrap(); // <- Patch::new() checks for at least one axis
+        let plane_len = self.len() / long_axis.len();
+        let sample_planes = (SAMPLE_ELEMENTS / plane_len.max(1)).max(1);
+        let step = (long_axis.len() / sample_planes).max(1);
+        let indices = (0..long_axis.len()).step_by(step).collect_vec();
+
+        let mut axes = self.axes.clone();
+        axes[long_ax_ix] = Axis::new_unchecked(
+            &long_axis.name,
+            indices.iter().map(|&ix| long_axis.labels()[ix]).collect(),
+        );
+        let sample = Patch::new_4d(axes, Some(self.dense.select(nd::Axis(long_ax_ix), &indices)))?;
+        let sample_bytes = sample.serialize(compression)?.len();
+        Ok(sample_bytes * long_axis.len() / indices.len())
+    }
+
+    /// Choose how to compress this patch, for PatchCompressionType::Auto
+    ///
+    /// This samples the patch with a fast LZ4 pass, like estimate_serialized_size(). Content
+    /// that hardly compresses, like noise, isn't worth compressing at all, and content that
+    /// compresses very well, like mostly zeros or NANs, is already small enough with LZ4.
+    /// Everything in between is worth the slower, but stronger, Brotli.
+    pub fn choose_compression(&self) -> Fallible<PatchCompressionType> {
+        const FAST: PatchCompressionType = PatchCompressionType::LZ4 { quality: 0 };
+        let raw_bytes = (4 * self.len()).max(1) as f64;
+        let ratio = self.estimate_serialized_size(Some(FAST))? as f64 / raw_bytes;
+        Ok(if ratio > AUTO_INCOMPRESSIBLE_RATIO {
+            PatchCompressionType::Off
+        } else if ratio < AUTO_COMPRESSIBLE_RATIO {
+            FAST
+        } else {
+            PatchCompressionType::Brotli { quality: 3 }
+        })
+    }
+
     /// Deserialize a patch the default way
     ///
     /// It's still possible to deserialize a patch with serde, but this is the
     /// recommended method if you don't have reason to do otherwise, to avoid
     /// needless incompatibilities.
-    pub fn deserialize_from<R: Read>(mut buffer: R) -> Fallible<Self> {
-        let options: PatchTag = bincode::deserialize_from(buffer.by_ref())?;
+    ///
+    /// Patches written by any earlier version of the format can still be read, but patches
+    /// from a newer version are an UnsupportedPatchVersion error.
+    pub fn deserialize_from<R: Read>(buffer: R) -> Fallible<Self> {
+        Self::deserialize_stored(buffer)
+    }
 
-        match options.compression {
-            PatchCompressionType::Off => Ok(bincode::deserialize_from(buffer)?),
+    /// Deserialize a patch like deserialize_from(), and validate() it, for input that can't
+    /// be trusted
+    ///
+    /// Deserialized patches always have their axes checked against their content, but this
+    /// also rejects infinities, and any problem or infinity is an InvalidPatch error with the
+    /// whole report instead of the first problem.
+    pub fn deserialize_strict<R: Read>(buffer: R) -> Fallible<Self> {
+        let patch = Self::deserialize_stored(buffer)?;
+        let report = patch.validate();
+        if !report.problems.is_empty() || report.infinities > 0 {
+            return Err(StoiError::InvalidPatch(report));
+        }
+        Ok(patch)
+    }
+
+    /// Deserialize a patch the catalog wrote itself, so it doesn't need strict validation
+    pub(crate) fn deserialize_stored<R: Read>(mut buffer: R) -> Fallible<Self> {
+        // The magic and version come first so the rest of the tag is free to change later
+        match Self::read_version(buffer.by_ref())? {
+            // Version 2 only added filters, which version 1 readers wouldn't know
+            1 | 2 => {
+                let (compression, filters) = bincode::config()
+                    .limit(MAX_TAG_BYTES)
+                    .deserialize_from(buffer.by_ref())?;
+                Self::deserialize_v1(compression, filters, buffer)
+            }
+            version => Err(StoiError::UnsupportedPatchVersion(version)),
+        }
+    }
+
+    /// Serialize a patch straight into a file, without holding the serialized patch in memory
+    ///
+    /// This is the same format as serialize(). Progress is reported as bytes written so far,
+    /// see TransferProgress. If it fails or is cancelled, the partial file is removed.
+    /// Returns the size of the file.
+    pub fn write_to_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+        compression: Option<PatchCompressionType>,
+        progress: TransferProgress,
+    ) -> Fallible<u64> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)?;
+        let mut writer = BufWriter::new(ProgressIo::new(file, None, progress));
+        let result = self
+            .serialize_into(compression, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .map_err(|err| writer.get_ref().explain(err));
+        match result {
+            Ok(()) => Ok(writer.get_mut().finish()),
+            Err(err) => {
+                // Don't leave a truncated patch behind, which would fail to read later
+                drop(writer);
+                let _ = std::fs::remove_file(path);
+                Err(err)
+            }
+        }
+    }
+
+    /// Deserialize a patch from a file, like deserialize_from(), reporting progress
+    ///
+    /// Progress is reported as bytes read so far, out of the size of the file.
+    pub fn read_from_path<P: AsRef<Path>>(path: P, progress: TransferProgress) -> Fallible<Self> {
+        let file = std::fs::File::open(path)?;
+        let total = file.metadata()?.len();
+        let mut reader = BufReader::new(ProgressIo::new(file, Some(total), progress));
+        let patch =
+            Self::deserialize_from(&mut reader).map_err(|err| reader.get_ref().explain(err))?;
+        reader.get_mut().finish();
+        Ok(patch)
+    }
+
+    /// Get the format version of a serialized patch, without deserializing it
+    ///
+    /// Patches with a version older than the current one can be upgraded in place, see
+    /// StorageTransaction::upgrade_patch()
+    pub fn serialized_version(buffer: &[u8]) -> Fallible<u8> {
+        Self::read_version(buffer)
+    }
+
+    /// Rewrite a serialized patch in the current format version
+    ///
+    /// It keeps the filter it was stored with, so quantized values aren't rounded again.
+    pub(crate) fn upgrade_serialized<W: Write>(
+        buffer: &[u8],
+        compression: Option<PatchCompressionType>,
+        output: &mut W,
+    ) -> Fallible<()> {
+        let mut reader = buffer;
+        let filter = match Self::read_version(reader.by_ref())? {
+            1 | 2 => {
+                let (_, filters): (PatchCompressionType, Vec<PatchFilter>) = bincode::config()
+                    .limit(MAX_TAG_BYTES)
+                    .deserialize_from(reader.by_ref())?;
+                filters.into_iter().next()
+            }
+            version => return Err(StoiError::UnsupportedPatchVersion(version)),
+        };
+        Self::deserialize_stored(buffer)?.serialize_filtered_into(compression, filter, output)
+    }
+
+    /// Read the magic number and version at the start of a serialized patch
+    fn read_version<R: Read>(reader: R) -> Fallible<u8> {
+        let (magic, version): (u32, u8) = bincode::deserialize_from(reader)?;
+        if magic != PATCH_MAGIC {
+            return Err(StoiError::InvalidValue(
+                "not a serialized patch, because the magic number is wrong",
+            ));
+        }
+        Ok(version)
+    }
+
+    /// Deserialize the rest of a version 1 or 2 patch, after the magic and version
+    fn deserialize_v1<R: Read>(
+        compression: PatchCompressionType,
+        filters: Vec<PatchFilter>,
+        mut buffer: R,
+    ) -> Fallible<Self> {
+        match compression {
+            PatchCompressionType::Off => Self::deserialize_payload(&filters, buffer),
             PatchCompressionType::Brotli { quality: _ } => {
                 let brotli_reader = brotli::Decompressor::new(buffer, 4096);
-                Ok(bincode::deserialize_from(brotli_reader)?)
+                Self::deserialize_payload(&filters, brotli_reader)
             }
             PatchCompressionType::LZ4 { quality: _ } => {
                 let lz4_reader = lz4::Decoder::new(buffer)?;
-                Ok(bincode::deserialize_from(lz4_reader)?)
+                Self::deserialize_payload(&filters, lz4_reader)
             }
+            PatchCompressionType::Custom { format } => {
+                find_patch_codec(format)?.decode_from(&mut buffer)
+            }
+            PatchCompressionType::Auto => Err(StoiError::MalformedPatch(
+                "patches are never saved with automatic compression",
+            )),
         }
     }
 
@@ -754,6 +1814,44 @@ impl Patch {
     }
 }
 
+/// How much work one apply() did, and what it changed, see Patch::apply_counted()
+///
+/// Reading a patch only to use a little of it is wasted work, so when shuffled_bytes is much
+/// larger than copied_bytes, patches are probably too large for how they're read.
+/// Every cell of the patch being applied is either written, NAN, or outside the overlap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyStats {
+    /// Bytes of content in the patch being applied, used or not
+    pub shuffled_bytes: usize,
+    /// Bytes actually written, which excludes anything outside the overlap, and NANs
+    pub copied_bytes: usize,
+    /// Cells written, whether or not their value changed
+    pub written_cells: usize,
+    /// Cells written with a different value than they had, including over NANs
+    pub changed_cells: usize,
+    /// Cells in the overlap that were skipped because they were NAN in the patch
+    pub nan_cells: usize,
+    /// Cells of the patch that were skipped because the target has none of their labels
+    pub outside_cells: usize,
+}
+impl ApplyStats {
+    /// Whether applying the patch left the target exactly as it was
+    pub fn changed_nothing(&self) -> bool {
+        self.changed_cells == 0
+    }
+}
+/// Add up the stats of several applies, like Patch::merge_into() does
+impl std::ops::AddAssign for ApplyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.shuffled_bytes += other.shuffled_bytes;
+        self.copied_bytes += other.copied_bytes;
+        self.written_cells += other.written_cells;
+        self.changed_cells += other.changed_cells;
+        self.nan_cells += other.nan_cells;
+        self.outside_cells += other.outside_cells;
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 /// Fill patterns used for autogenerated patches
 pub enum ContentPattern {
@@ -767,6 +1865,59 @@ pub enum ContentPattern {
     Sparse,
 }
 
+/// Which labels of an axis Patch::coarsen() aggregates together
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Coarsening {
+    /// Every so many consecutive labels of the patch, labeled by the first of them
+    Factor(usize),
+    /// Each coarse label, with the fine labels it covers. Other labels are left out.
+    Groups(Vec<(Label, Vec<Label>)>),
+}
+
+/// How Patch::coarsen() aggregates values, which all skip NANs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    /// The number of values that aren't NAN
+    Count,
+}
+impl ReduceOp {
+    /// Reduce some values to one, which is NAN if there are no values that aren't NAN
+    pub fn reduce<I: IntoIterator<Item = f32>>(self, values: I) -> f32 {
+        let (count, sum, min, max) = values.into_iter().filter(|x| !x.is_nan()).fold(
+            (0usize, 0f32, std::f32::INFINITY, std::f32::NEG_INFINITY),
+            |(count, sum, min, max), x| (count + 1, sum + x, min.min(x), max.max(x)),
+        );
+        if count == 0 {
+            return std::f32::NAN;
+        }
+        match self {
+            ReduceOp::Sum => sum,
+            ReduceOp::Mean => sum / count as f32,
+            ReduceOp::Min => min,
+            ReduceOp::Max => max,
+            ReduceOp::Count => count as f32,
+        }
+    }
+}
+
+/// The first four bytes of every serialized patch, "STOI"
+const PATCH_MAGIC: u32 = 0x494f5453;
+
+/// The format version patches are written with
+///
+/// Increment this when the format changes, and keep a way to read the old versions in
+/// Patch::deserialize_from(), so existing catalogs can still be read and upgraded.
+pub(crate) const PATCH_VERSION: u8 = 2;
+
+/// The most bytes the rest of a PatchTag may take, after the magic and version
+///
+/// Tags are tiny, so this only guards against corrupted lengths in them.
+pub(crate) const MAX_TAG_BYTES: u64 = 4096;
+
 /// An uncompressed prelude to Patch, to allow versions and serialization options
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PatchTag {
@@ -776,16 +1927,398 @@ struct PatchTag {
     filters: Vec<PatchFilter>,
 }
 /// Part of PatchTag, used for deserializing patches
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PatchCompressionType {
     Off,
     Brotli { quality: u32 },
     LZ4 { quality: u32 },
+    /// A PatchCodec, registered with register_patch_codec() under this format id
+    Custom { format: u32 },
+    /// Chosen for each patch by sampling its content, see Patch::choose_compression()
+    ///
+    /// This is only a setting. The compression chosen is what's saved in the PatchTag.
+    Auto,
+}
+
+/// Patch::choose_compression() doesn't compress patches LZ4 shrinks less than this much
+const AUTO_INCOMPRESSIBLE_RATIO: f64 = 0.9;
+
+/// Patch::choose_compression() uses LZ4 for patches it shrinks at least this much
+const AUTO_COMPRESSIBLE_RATIO: f64 = 0.25;
+
+/// Patches must be 256 million elements or less (1GB of 32bit floats)
+const MAX_PATCH_ELEMENTS: usize = 256 << 20;
+
+/// Everything Patch::validate() found out about a patch
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct PatchReport {
+    /// Everything that makes the patch unusable, in no particular order
+    pub problems: Vec<PatchProblem>,
+    /// How many elements are NAN, meaning they have no data
+    pub nans: usize,
+    /// How many elements are positive or negative infinity
+    pub infinities: usize,
+}
+impl PatchReport {
+    /// Whether the patch has no problems, although it may still have NANs or infinities
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+/// A short summary, like `2 problems (axis labels must not repeat, ...); 0 NaN; 3 infinite`
+impl fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} problems ({}); {} NaN; {} infinite",
+            self.problems.len(),
+            self.problems.iter().map(|p| p.describe()).join(", "),
+            self.nans,
+            self.infinities
+        )
+    }
+}
+
+/// Something that makes a patch unusable, see Patch::validate()
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum PatchProblem {
+    /// Patches must have 1 to 4 axes, but this one has some other number
+    AxisCount(usize),
+    /// An axis has a different number of labels than the content has along it
+    ShapeMismatch {
+        axis: String,
+        labels: usize,
+        length: usize,
+    },
+    /// The content has a dimension longer than 1 with no axis
+    UnlabeledDimension,
+    /// Two axes have this name
+    RepeatedAxis(String),
+    /// An axis has some labels more than once
+    RepeatedLabels { axis: String, repeats: usize },
+    /// The patch has more than 256 million elements
+    TooLarge(usize),
+}
+impl PatchProblem {
+    /// A description of this kind of problem, without the details
+    fn describe(&self) -> &'static str {
+        match self {
+            PatchProblem::AxisCount(_) => "patches must have 1 to 4 axes",
+            PatchProblem::ShapeMismatch { .. } | PatchProblem::UnlabeledDimension => {
+                "the axis labels don't match the shape of the content"
+            }
+            PatchProblem::RepeatedAxis(_) => "axis names must not repeat",
+            PatchProblem::RepeatedLabels { .. } => "axis labels must not repeat",
+            PatchProblem::TooLarge(_) => "patches must be 256 million elements or less",
+        }
+    }
+}
+
+/// Progress reporting and cancellation for Patch::write_to_path() and read_from_path()
+///
+/// Both are optional, and the default has neither.
+#[derive(Default)]
+pub struct TransferProgress<'a> {
+    /// Called with the bytes transferred so far, and the total if it's known in advance,
+    /// about once per megabyte and once at the end
+    pub callback: Option<&'a mut dyn FnMut(u64, Option<u64>)>,
+    /// Set this, from another thread or the callback, to stop with StoiError::Cancelled
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+/// Report progress every this many bytes
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// A reader or writer that counts bytes for a TransferProgress, and stops when cancelled
+struct ProgressIo<'a, T> {
+    inner: T,
+    done: u64,
+    reported: u64,
+    total: Option<u64>,
+    progress: TransferProgress<'a>,
+}
+impl<'a, T> ProgressIo<'a, T> {
+    fn new(inner: T, total: Option<u64>, progress: TransferProgress<'a>) -> Self {
+        ProgressIo {
+            inner,
+            done: 0,
+            reported: 0,
+            total,
+            progress,
+        }
+    }
+
+    /// Count some bytes, reporting progress if it's been long enough
+    fn advance(&mut self, bytes: usize) {
+        self.done += bytes as u64;
+        if self.done - self.reported >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.reported = self.done;
+        if let Some(callback) = &mut self.progress.callback {
+            callback(self.done, self.total);
+        }
+    }
+
+    fn check_cancelled(&self) -> std::io::Result<()> {
+        match self.progress.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "the transfer was cancelled",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace whatever error the cancellation caused deeper down with Cancelled
+    fn explain(&self, err: StoiError) -> StoiError {
+        match self.check_cancelled() {
+            Err(_) => StoiError::Cancelled,
+            Ok(()) => err,
+        }
+    }
+
+    /// Report the final progress, and get the bytes transferred
+    fn finish(&mut self) -> u64 {
+        self.report();
+        self.done
+    }
+}
+impl<'a, R: Read> Read for ProgressIo<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check_cancelled()?;
+        let bytes = self.inner.read(buf)?;
+        self.advance(bytes);
+        Ok(bytes)
+    }
+}
+impl<'a, W: Write> Write for ProgressIo<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check_cancelled()?;
+        let bytes = self.inner.write(buf)?;
+        self.advance(bytes);
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A pluggable way to store patches, for compressors or formats not built into Stoicheia
+///
+/// A codec is used by serializing with `PatchCompressionType::Custom { format }`, or with
+/// Catalog::set_patch_compression(). Its format id is saved in the PatchTag of every patch
+/// it writes, so the same codec needs to be registered under the same id to read them back.
+pub trait PatchCodec: Send + Sync {
+    /// Write a patch, everything after the PatchTag
+    fn encode_into(&self, patch: &Patch, writer: &mut dyn Write) -> Fallible<()>;
+
+    /// Read a patch written by encode_into()
+    fn decode_from(&self, reader: &mut dyn Read) -> Fallible<Patch>;
+}
+
+lazy_static! {
+    /// Custom codecs, by their format id
+    static ref PATCH_CODECS: RwLock<HashMap<u32, Arc<dyn PatchCodec>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Register a custom codec so patches can be written and read with it
+///
+/// Registering a codec under an id that is already in use replaces the old codec.
+pub fn register_patch_codec(format: u32, codec: Arc<dyn PatchCodec>) -> Fallible<()> {
+    PATCH_CODECS
+        .write()
+        .map_err(|_| StoiError::RuntimeError("patch codec registry was poisoned"))?
+        .insert(format, codec);
+    Ok(())
+}
+
+/// Find a custom codec by its format id
+fn find_patch_codec(format: u32) -> Fallible<Arc<dyn PatchCodec>> {
+    PATCH_CODECS
+        .read()
+        .map_err(|_| StoiError::RuntimeError("patch codec registry was poisoned"))?
+        .get(&format)
+        .cloned()
+        .ok_or_else(|| StoiError::NotFound("patch codec", format.to_string()))
 }
 /// Things you might have done to the patch to try to save space
-/// There aren't any yet but it could happen and this lets us be compatible
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum PatchFilter {}
+enum PatchFilter {
+    /// Stored as half precision floats of `(x - offset) / scale`
+    QuantizeF16 { scale: f32, offset: f32 },
+    /// Stored as bytes of `(x - offset) / scale`, where 255 means NAN
+    QuantizeU8 { scale: f32, offset: f32 },
+    /// Stored as 16 bit integers, where i16::MIN means NAN
+    Integers16,
+    /// Stored as 32 bit integers, where i32::MIN means NAN
+    Integers32,
+}
+impl PatchFilter {
+    /// Choose the filter and its parameters for some content
+    fn choose(quantization: PatchQuantization, dense: &Array4<f32>) -> Option<PatchFilter> {
+        // Infinities can't be scaled into a range, so they are ignored (and clamped later)
+        let (min, max) = dense
+            .iter()
+            .filter(|x| x.is_finite())
+            .fold((std::f32::INFINITY, std::f32::NEG_INFINITY), |(lo, hi), &x| {
+                (lo.min(x), hi.max(x))
+            });
+        let (min, max) = if min > max { (0., 0.) } else { (min, max) };
+        match quantization {
+            PatchQuantization::Exact => None,
+            PatchQuantization::F16 => {
+                // Center the values, and only scale them if they would overflow
+                let half_range = (max - min) / 2.;
+                Some(PatchFilter::QuantizeF16 {
+                    scale: if half_range > 65504. { half_range / 65504. } else { 1. },
+                    offset: min + half_range,
+                })
+            }
+            PatchQuantization::U8 => Some(PatchFilter::QuantizeU8 {
+                scale: if max > min { (max - min) / 254. } else { 1. },
+                offset: min,
+            }),
+            PatchQuantization::Integers => {
+                // The smallest integers are saved for NAN, and anything else stays a float
+                let whole = dense
+                    .iter()
+                    .all(|x| x.is_nan() || (x.fract() == 0. && x.abs() < 2f32.powi(31)));
+                if !whole {
+                    None
+                } else if min > std::i16::MIN as f32 && max <= std::i16::MAX as f32 {
+                    Some(PatchFilter::Integers16)
+                } else {
+                    Some(PatchFilter::Integers32)
+                }
+            }
+        }
+    }
+
+    /// Whether some content can be stored with this filter, as well as with its own
+    ///
+    /// Values more than half a level outside the range of a quantization would be clamped,
+    /// and integers only hold whole numbers, so those don't fit. Infinities are clamped anyway.
+    fn fits(&self, dense: &Array4<f32>) -> bool {
+        let mut finite = dense.iter().filter(|x| x.is_finite());
+        match *self {
+            PatchFilter::QuantizeF16 { scale, offset } => {
+                finite.all(|&x| ((x - offset) / scale).abs() <= 65504.)
+            }
+            PatchFilter::QuantizeU8 { scale, offset } => {
+                finite.all(|&x| x >= offset - scale / 2. && x <= offset + 254.5 * scale)
+            }
+            PatchFilter::Integers16 => dense.iter().all(|&x| {
+                x.is_nan()
+                    || (x.fract() == 0. && x > std::i16::MIN as f32 && x <= std::i16::MAX as f32)
+            }),
+            PatchFilter::Integers32 => dense
+                .iter()
+                .all(|&x| x.is_nan() || (x.fract() == 0. && x.abs() < 2f32.powi(31))),
+        }
+    }
+}
+
+/// Lossy storage formats for patch content
+///
+/// Exact is the default. The others trade precision for space, which is fine for data like
+/// ML features but probably not for accounting.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchQuantization {
+    /// Full 32 bit floats
+    Exact,
+    /// 16 bit floats, centered on the range of each patch
+    F16,
+    /// 254 evenly spaced levels spanning the range of each patch
+    U8,
+    /// 16 or 32 bit integers, for patches of whole numbers, and full 32 bit floats otherwise
+    ///
+    /// Nothing is lost, so this is as exact as Exact. Quilts with units use it by default.
+    Integers,
+}
+impl PatchQuantization {
+    /// Whether patches come back exactly the same as they were stored
+    pub fn is_lossless(self) -> bool {
+        match self {
+            PatchQuantization::Exact | PatchQuantization::Integers => true,
+            PatchQuantization::F16 | PatchQuantization::U8 => false,
+        }
+    }
+}
+impl Default for PatchQuantization {
+    fn default() -> Self {
+        PatchQuantization::Exact
+    }
+}
+
+/// The payload of a patch after a quantization filter
+#[derive(Serialize, Deserialize)]
+struct QuantizedPatch<'a, C> {
+    axes: Cow<'a, [Axis]>,
+    shape: (usize, usize, usize, usize),
+    codes: Vec<C>,
+}
+
+/// Convert a float to half precision, rounding to the nearest even
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // Infinity or NAN
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        // Overflow rounds to infinity
+        return sign | 0x7c00;
+    }
+    let (half_mant, shift, full_mant) = if half_exp <= 0 {
+        // Subnormal, or too small and rounds to zero
+        if half_exp < -10 {
+            return sign;
+        }
+        let full_mant = mant | 0x80_0000;
+        let shift = (14 - half_exp) as u32;
+        (full_mant >> shift, shift, full_mant)
+    } else {
+        (((half_exp as u32) << 10) | (mant >> 13), 13, mant)
+    };
+    // Round up if the first dropped bit is set, unless it's a tie and we're already even
+    let round_bit = 1 << (shift - 1);
+    let rounded = if full_mant & round_bit != 0 && full_mant & (3 * round_bit - 1) != 0 {
+        half_mant + 1
+    } else {
+        half_mant
+    };
+    sign | rounded as u16
+}
+
+/// Convert a half precision float back to single precision, which is exact
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mant = (h & 0x3ff) as u32;
+    match exp {
+        0 => {
+            // Zero or subnormal
+            let magnitude = mant as f32 * (2f32).powi(-24);
+            if sign == 0 {
+                magnitude
+            } else {
+                -magnitude
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mant << 13)),
+        _ => f32::from_bits(sign | ((exp + 112) << 23) | (mant << 13)),
+    }
+}
 
 /// Convenience class to build patches with less typing
 pub struct PatchBuilder {
@@ -838,6 +2371,10 @@ impl PatchBuilder {
 #[cfg(test)]
 mod test {
     use crate::*;
+    use itertools::Itertools;
+    use rayon::prelude::*;
+
+    use super::{GlobalBoundsCache, LabelIndexCache};
 
     #[test]
     fn patch_1d_apply_total_overlap_same_order() {
@@ -1078,6 +2615,402 @@ mod test {
         assert_eq!(m[[0, 1]], 2.);
         assert_eq!(m[[1, 0]], 3.);
         assert_eq!(m[[1, 1]], 4.);
+
+        // The labels of both are kept, even the ones only the first one has
+        let pat3 = Patch::build()
+            .axis_range("x", 1..3)
+            .axis_range("y", 0..2)
+            .content_2d(&[[5., 6.], [7., 8.]])
+            .unwrap();
+        let m = pat1.merge(&pat3).unwrap();
+        assert_eq!(m.axes()[0].labels(), &[0, 1, 2]);
+        let content = m.content().iter().copied().collect_vec();
+        assert!(content[0].is_nan());
+        assert_eq!(&content[1..], &[2., 5., 6., 7., 8.]);
+    }
+
+    #[test]
+    fn patch_merge_into() {
+        let mut target = Patch::build()
+            .axis_range("x", 0..4)
+            .content_1d(&[std::f32::NAN; 4])
+            .unwrap();
+        // Produced one at a time, and owned, like patches read from storage
+        let patches = (0..3).map(|i| {
+            Patch::build()
+                .axis_range("x", i..i + 2)
+                .content_1d(&[i as f32, i as f32])
+                .unwrap()
+        });
+        let stats = Patch::merge_into(&mut target, patches).unwrap();
+        assert_eq!(
+            target.content().iter().copied().collect_vec(),
+            vec![0., 1., 2., 2.]
+        );
+        assert_eq!(stats.written_cells, 6);
+        assert_eq!(stats.changed_cells, 6);
+        assert_eq!(stats.outside_cells, 0);
+    }
+
+    #[test]
+    fn patch_2d_apply_intersection_only() {
+        let mut base = Patch::build()
+            .axis("item", &[1, 2, 3])
+            .axis("store", &[7, 8])
+            .content_2d(&[[1., 2.], [3., 4.], [5., 6.]])
+            .unwrap();
+
+        // No labels in common on one axis means nothing changes
+        let disjoint = Patch::build()
+            .axis("store", &[9])
+            .axis("item", &[1, 2, 3])
+            .content_2d(&[[100., 200., 300.]])
+            .unwrap();
+        let stats = base.apply_counted(&disjoint).unwrap();
+        assert_eq!(
+            stats,
+            ApplyStats {
+                shuffled_bytes: 12,
+                copied_bytes: 0,
+                written_cells: 0,
+                changed_cells: 0,
+                nan_cells: 0,
+                outside_cells: 3,
+            }
+        );
+        assert_eq!(
+            base.content().iter().copied().collect_vec(),
+            vec![1., 2., 3., 4., 5., 6.]
+        );
+
+        // Scattered overlap, in a different order, with labels self doesn't have
+        let revision = Patch::build()
+            .axis("item", &[3, 9, 1])
+            .axis("store", &[8])
+            .content_2d(&[[60.], [90.], [std::f32::NAN]])
+            .unwrap();
+        // Only the 60 is copied, since self has no 9 and NANs are skipped
+        let stats = base.apply_counted(&revision).unwrap();
+        assert_eq!(
+            stats,
+            ApplyStats {
+                shuffled_bytes: 12,
+                copied_bytes: 4,
+                written_cells: 1,
+                changed_cells: 1,
+                nan_cells: 1,
+                outside_cells: 1,
+            }
+        );
+        assert_eq!(
+            base.content().iter().copied().collect_vec(),
+            vec![1., 2., 3., 4., 5., 60.]
+        );
+
+        // Applying it again writes the same values, which changes nothing
+        let stats = base.apply_counted(&revision).unwrap();
+        assert_eq!((stats.written_cells, stats.changed_cells), (1, 0));
+        assert!(stats.changed_nothing());
+    }
+
+    #[test]
+    fn patch_display() {
+        let patch = Patch::build()
+            .axis("itm", &[1])
+            .axis("lct", &[2, 3, 4])
+            .content_2d(&[[1., std::f32::NAN, 6.5]])
+            .unwrap();
+        assert_eq!(
+            patch.to_string(),
+            "Patch(itm: 1, lct: 3; 33.3% NaN; values 1 to 6.5)"
+        );
+        let empty = Patch::new(vec![Axis::range("itm", 0..2)], None).unwrap();
+        assert_eq!(empty.to_string(), "Patch(itm: 2; 100.0% NaN)");
+    }
+
+    #[test]
+    fn patch_eq_nans() {
+        let quiet = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., std::f32::NAN])
+            .unwrap();
+        let mut noisy = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., f32::from_bits(0x7fc0_0123)])
+            .unwrap();
+        // All NANs are equal, including to themselves
+        assert_eq!(quiet, quiet.clone());
+        assert_eq!(quiet, noisy);
+        let other = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[2., std::f32::NAN])
+            .unwrap();
+        assert_ne!(quiet, other);
+
+        noisy.canonicalize_nans();
+        let bits = noisy.content().iter().map(|x| x.to_bits()).collect_vec();
+        assert_eq!(bits[1], std::f32::NAN.to_bits());
+        assert_eq!(
+            noisy.serialize(None).unwrap(),
+            quiet.serialize(None).unwrap()
+        );
+    }
+
+    #[test]
+    fn patch_from_chunks() {
+        let axes = vec![Axis::range("day", 0..3), Axis::range("itm", 0..2)];
+        let whole = nd::arr2(&[[1., 2.], [3., 4.], [5., 6.]]);
+        let chunks = || (0..3).map(|ix| whole.slice(s![ix..ix + 1, ..]).into_dyn());
+        let patch = Patch::from_chunks(axes.clone(), chunks()).unwrap();
+        assert_eq!(patch.content(), whole.clone().into_dyn());
+
+        // Chunks in any layout are read in logical order
+        let transposed = nd::arr2(&[[1., 3., 5.], [2., 4., 6.]]);
+        let patch = Patch::from_chunks(axes.clone(), vec![transposed.t().into_dyn()]).unwrap();
+        assert_eq!(patch.content(), whole.clone().into_dyn());
+
+        // The rows have to add up, and be the right shape
+        assert!(Patch::from_chunks(axes.clone(), chunks().take(2)).is_err());
+        assert!(Patch::from_chunks(axes.clone(), chunks().chain(chunks())).is_err());
+        assert!(Patch::from_chunks(axes, vec![transposed.view().into_dyn()]).is_err());
+    }
+
+    #[test]
+    fn patch_from_coo() {
+        let pat = Patch::from_coo(
+            vec![
+                ("item".into(), vec![5, 3, 5, 3, 5]),
+                ("store".into(), vec![1, 1, 2, 2, 2]),
+            ],
+            &[1., 2., 3., 4., 5.],
+        )
+        .unwrap();
+        assert_eq!(pat.axes()[0].labels(), &[5, 3]);
+        assert_eq!(pat.axes()[1].labels(), &[1, 2]);
+        // The last of the repeated coordinates wins
+        assert_eq!(
+            pat.content().iter().copied().collect_vec(),
+            vec![1., 5., 2., 4.]
+        );
+
+        let missing = Patch::from_coo(
+            vec![("item".into(), vec![1, 2]), ("store".into(), vec![1, 2])],
+            &[1., 2.],
+        )
+        .unwrap();
+        assert!(missing.content()[[0, 1]].is_nan());
+
+        assert!(Patch::from_coo(vec![("item".into(), vec![1])], &[1., 2.]).is_err());
+    }
+
+    #[test]
+    fn patch_apply_where() {
+        let mut target = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .content_1d(&[1., 2., 3.])
+            .unwrap();
+        let update = Patch::build()
+            .axis("itm", &[1, 2, 3])
+            .content_1d(&[10., 20., 30.])
+            .unwrap();
+        // The mask has its own labels, and is only set for 2
+        let mask = Patch::build()
+            .axis("itm", &[3, 2, 4])
+            .content_1d(&[0., 1., 1.])
+            .unwrap();
+        target.apply_where(&update, &mask).unwrap();
+        assert_eq!(target.content().as_slice().unwrap(), &[1., 20., 3.]);
+
+        let other = Patch::build().axis("lct", &[2]).content_1d(&[1.]).unwrap();
+        assert!(target.apply_where(&update, &other).is_err());
+    }
+
+    #[test]
+    fn patch_iter_labeled() {
+        let pat = Patch::build()
+            .axis("itm", &[5, 3])
+            .axis("lct", &[2, 1, 7])
+            .content_2d(&[[1., 2., std::f32::NAN], [3., 4., 5.]])
+            .unwrap();
+        let all = pat.iter_labeled().collect_vec();
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[1], (vec![5, 1], 2.));
+        assert_eq!(all[5], (vec![3, 7], 5.));
+        assert!(all[2].1.is_nan());
+
+        let nonnan = pat.iter_nonnan().collect_vec();
+        assert_eq!(nonnan.len(), 5);
+        assert!(nonnan.iter().all(|(labels, _value)| labels != &vec![5, 7]));
+        assert_eq!(pat.par_iter_nonnan().collect::<Vec<_>>(), nonnan);
+        assert_eq!(pat.par_iter_labeled().count(), 6);
+    }
+
+    #[test]
+    fn patch_get_set() {
+        let mut pat = Patch::build()
+            .axis("itm", &[9, 4])
+            .axis("lct", &[5])
+            .axis("day", &[30, 10, 20])
+            .content(None)
+            .unwrap();
+        assert!(pat.get(&[4, 5, 10]).unwrap().is_nan());
+        pat.set(&[4, 5, 10], 2.).unwrap();
+        pat.set(&[9, 5, 20], 1.).unwrap();
+        assert_eq!(pat.get(&[4, 5, 10]), Some(2.));
+        assert_eq!(pat.content()[[1, 0, 1]], 2.);
+        assert_eq!(pat.content()[[0, 0, 2]], 1.);
+
+        // Labels that aren't there, or too few of them
+        assert_eq!(pat.get(&[4, 6, 10]), None);
+        assert_eq!(pat.get(&[4, 5]), None);
+        assert!(pat.set(&[4, 6, 10], 3.).is_err());
+        assert!(pat.set(&[4, 5], 3.).is_err());
+
+        // Copies index their own labels
+        let copy = pat.clone();
+        assert_eq!(copy.get(&[9, 5, 20]), Some(1.));
+        assert_eq!(copy, pat);
+    }
+
+    #[test]
+    fn patch_stack() {
+        let low = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("lct", &[5])
+            .content_2d(&[[1.], [2.]])
+            .unwrap();
+        let high = Patch::build()
+            .axis("lct", &[5])
+            .axis("itm", &[2, 1])
+            .content_2d(&[[20., 10.]])
+            .unwrap();
+        let stacked = Patch::stack("scenario", &[7, 9], &[&low, &high]).unwrap();
+        assert_eq!(stacked.axes()[0].labels(), &[7, 9]);
+        assert_eq!(stacked.axes()[1].name, "itm");
+        assert_eq!(stacked.content().as_slice().unwrap(), &[1., 2., 10., 20.]);
+
+        let other = Patch::build()
+            .axis("itm", &[1, 3])
+            .axis("lct", &[5])
+            .content_2d(&[[1.], [3.]])
+            .unwrap();
+        assert!(Patch::stack("scenario", &[7, 9], &[&low, &other]).is_err());
+        assert!(Patch::stack("scenario", &[7], &[&low, &high]).is_err());
+        assert!(Patch::stack("itm", &[7, 9], &[&low, &high]).is_err());
+
+        // Slicing takes them apart again
+        let sliced = stacked.slice_label("scenario", 9).unwrap();
+        assert_eq!(sliced.axes(), low.axes());
+        assert_eq!(sliced.content().as_slice().unwrap(), &[10., 20.]);
+        assert_eq!(stacked.slice_label("scenario", 7).unwrap(), low);
+        assert!(stacked.slice_label("scenario", 8).is_err());
+        assert!(low.slice_label("scenario", 7).is_err());
+    }
+
+    #[test]
+    fn patch_dedup_labels() {
+        let repeated = Patch::new(
+            vec![
+                Axis::range("itm", vec![1, 2, 1]),
+                Axis::range("day", vec![5]),
+            ],
+            Some(ndarray::ArrayD::from_shape_vec(vec![3, 1], vec![1., 2., 3.]).unwrap()),
+        )
+        .unwrap();
+
+        // Applying it would have to pick one of the values of itm 1
+        let mut target = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("day", &[5])
+            .content(None)
+            .unwrap();
+        match target.apply(&repeated) {
+            Err(StoiError::RepeatedLabels(axis, 1)) => assert_eq!(axis, "itm"),
+            other => panic!("expected repeated labels, got {:?}", other),
+        }
+
+        let summed = repeated.dedup_labels(ReduceOp::Sum).unwrap();
+        assert_eq!(summed.axes()[0].labels(), &[1, 2]);
+        assert_eq!(summed.get(&[1, 5]), Some(4.));
+        let latest = repeated.dedup_labels(ReduceOp::Max).unwrap();
+        assert_eq!(latest.get(&[1, 5]), Some(3.));
+        target.apply(&latest).unwrap();
+        assert_eq!(target.get(&[2, 5]), Some(2.));
+        // Patches without repeats don't change
+        assert_eq!(latest.dedup_labels(ReduceOp::Sum).unwrap(), latest);
+    }
+
+    #[test]
+    fn patch_coarsen() {
+        let daily = Patch::build()
+            .axis("itm", &[1, 2])
+            .axis("day", &[10, 11, 12, 13, 14])
+            .content_2d(&[
+                [1., 2., 3., 4., 5.],
+                [std::f32::NAN, std::f32::NAN, 1., std::f32::NAN, 2.],
+            ])
+            .unwrap();
+        let weekly = daily
+            .coarsen("day", &Coarsening::Factor(2), ReduceOp::Sum)
+            .unwrap();
+        assert_eq!(weekly.axes()[1].labels(), &[10, 12, 14]);
+        assert_eq!(weekly.content()[[0, 0]], 3.);
+        assert_eq!(weekly.content()[[0, 1]], 7.);
+        assert_eq!(weekly.content()[[0, 2]], 5.);
+        assert!(weekly.content()[[1, 0]].is_nan());
+        assert_eq!(weekly.content()[[1, 1]], 1.);
+
+        let groups = Coarsening::Groups(vec![(100, vec![10, 14]), (200, vec![11, 99])]);
+        let grouped = daily.coarsen("day", &groups, ReduceOp::Max).unwrap();
+        assert_eq!(grouped.axes()[1].labels(), &[100, 200]);
+        assert_eq!(grouped.content()[[0, 0]], 5.);
+        assert_eq!(grouped.content()[[0, 1]], 2.);
+        let counted = daily.coarsen("day", &groups, ReduceOp::Count).unwrap();
+        assert_eq!(counted.content()[[1, 0]], 1.);
+        assert!(counted.content()[[1, 1]].is_nan());
+
+        assert!(daily
+            .coarsen("week", &Coarsening::Factor(2), ReduceOp::Sum)
+            .is_err());
+        assert!(daily
+            .coarsen("day", &Coarsening::Factor(0), ReduceOp::Sum)
+            .is_err());
+    }
+
+    #[test]
+    fn patch_canonicalize() {
+        let pat1 = Patch::build()
+            .axis("item", &[5, 3])
+            .axis("store", &[2, 1, 7])
+            .content_2d(&[[1., 2., std::f32::NAN], [3., -0., std::f32::NAN]])
+            .unwrap();
+        let pat2 = Patch::build()
+            .axis("store", &[1, 2])
+            .axis("item", &[3, 5])
+            .content_2d(&[[0., 2.], [3., 1.]])
+            .unwrap();
+        let canonical = pat1.canonicalize();
+        assert_eq!(canonical, pat2.canonicalize());
+        assert_eq!(canonical.axes()[0].labels(), &[3, 5]);
+        assert_eq!(canonical.axes()[1].labels(), &[1, 2]);
+        assert_eq!(
+            canonical.content().iter().copied().collect_vec(),
+            vec![0., 3., 2., 1.]
+        );
+        assert_eq!(
+            pat1.canonical_hash().unwrap(),
+            pat2.canonical_hash().unwrap()
+        );
+
+        let pat3 = Patch::build()
+            .axis("store", &[1, 2])
+            .axis("item", &[3, 5])
+            .content_2d(&[[0., 2.], [3., 4.]])
+            .unwrap();
+        assert_ne!(
+            pat1.canonical_hash().unwrap(),
+            pat3.canonical_hash().unwrap()
+        );
     }
 
     #[test]
@@ -1095,4 +3028,385 @@ mod test {
         let pat2 = Patch::deserialize_from(&buffer[..]).unwrap();
         assert_eq!(pat1, pat2);
     }
+
+    #[test]
+    fn patch_serialize_quantized_round_trip() {
+        let pat1 = Patch::build()
+            .axis("item", &[0, 3, 4])
+            .axis("store", &[3, 1])
+            .content_2d(&[[200., 100.], [400., std::f32::NAN], [-0.5, 1e-3]])
+            .unwrap();
+
+        for &(quantization, epsilon) in &[
+            (PatchQuantization::F16, 0.25),
+            (PatchQuantization::U8, 400.5 / 254.),
+        ] {
+            let mut buffer = vec![0u8; 0];
+            pat1.serialize_quantized_into(
+                Some(PatchCompressionType::LZ4 { quality: 0 }),
+                quantization,
+                &mut buffer,
+            )
+            .unwrap();
+            let pat2 = Patch::deserialize_from(&buffer[..]).unwrap();
+            assert_eq!(pat1.axes(), pat2.axes());
+            for (a, b) in pat1.content().iter().zip(pat2.content().iter()) {
+                if a.is_nan() {
+                    assert!(b.is_nan());
+                } else {
+                    assert_abs_diff_eq!(*a, *b, epsilon = epsilon);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn patch_serialize_integers_round_trip() {
+        let exact_size = |pat: &Patch, quantization| {
+            let mut buffer = vec![];
+            pat.serialize_quantized_into(None, quantization, &mut buffer)
+                .unwrap();
+            assert_eq!(Patch::deserialize_from(&buffer[..]).unwrap(), *pat);
+            buffer.len()
+        };
+        // Whole numbers take 2 bytes each, or 4 if they're too large for that
+        let small = Patch::build()
+            .axis("item", &[0, 3, 4, 5])
+            .content_1d(&[-32767., 32767., 0., std::f32::NAN])
+            .unwrap();
+        let large = Patch::build()
+            .axis("item", &[0, 3, 4, 5])
+            .content_1d(&[-1e9, 32768., 0., std::f32::NAN])
+            .unwrap();
+        let int16 = exact_size(&small, PatchQuantization::Integers);
+        assert_eq!(
+            exact_size(&large, PatchQuantization::Integers),
+            int16 + 2 * 4
+        );
+        assert!(int16 < exact_size(&small, PatchQuantization::Exact));
+
+        // Anything else stays a float
+        let fraction = Patch::build()
+            .axis("item", &[0, 3, 4, 5])
+            .content_1d(&[0.5, 1., std::f32::INFINITY, std::f32::NAN])
+            .unwrap();
+        assert_eq!(
+            exact_size(&fraction, PatchQuantization::Integers),
+            exact_size(&fraction, PatchQuantization::Exact)
+        );
+    }
+
+    #[test]
+    fn patch_deserialize_version() {
+        let pat = Patch::build()
+            .axis("item", &[0, 3])
+            .content_1d(&[200., 100.])
+            .unwrap();
+        let mut buffer = pat.serialize(None).unwrap();
+        assert_eq!(Patch::serialized_version(&buffer).unwrap(), 2);
+
+        // Version 1 reads the same, without the filters added since
+        buffer[4] = 1;
+        assert_eq!(Patch::deserialize_from(&buffer[..]).unwrap(), pat);
+
+        // Newer versions can't be read
+        buffer[4] = 3;
+        match Patch::deserialize_from(&buffer[..]) {
+            Err(StoiError::UnsupportedPatchVersion(3)) => (),
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+
+        // Neither can things that aren't patches at all
+        buffer[0] = 0;
+        assert!(Patch::serialized_version(&buffer).is_err());
+        assert!(Patch::deserialize_from(&buffer[..]).is_err());
+    }
+
+    #[test]
+    fn patch_deserialize_malformed() {
+        use ndarray::Array4;
+        use rand::rngs::SmallRng;
+        use rand::{Rng, SeedableRng};
+        let off = Some(PatchCompressionType::Off);
+
+        // Patches that serialize fine but break what the rest of Patch assumes
+        let malformed = vec![
+            // The content has a dimension with no axis
+            Patch {
+                axes: vec![Axis::new("item", vec![0, 1]).unwrap()],
+                dense: Array4::zeros((2, 3, 1, 1)),
+                label_indices: LabelIndexCache::default(),
+                global_bounds: GlobalBoundsCache::default(),
+            },
+            // The axis is longer than the content
+            Patch {
+                axes: vec![Axis::new("item", vec![0, 1, 2]).unwrap()],
+                dense: Array4::zeros((2, 1, 1, 1)),
+                label_indices: LabelIndexCache::default(),
+                global_bounds: GlobalBoundsCache::default(),
+            },
+            // No axes at all
+            Patch {
+                axes: vec![],
+                dense: Array4::zeros((1, 1, 1, 1)),
+                label_indices: LabelIndexCache::default(),
+                global_bounds: GlobalBoundsCache::default(),
+            },
+            // Repeated labels and repeated axes
+            Patch {
+                axes: vec![Axis::new_unchecked("item", vec![0, 0])],
+                dense: Array4::zeros((2, 1, 1, 1)),
+                label_indices: LabelIndexCache::default(),
+                global_bounds: GlobalBoundsCache::default(),
+            },
+            Patch {
+                axes: vec![Axis::range("item", 0..2), Axis::range("item", 0..2)],
+                dense: Array4::zeros((2, 2, 1, 1)),
+                label_indices: LabelIndexCache::default(),
+                global_bounds: GlobalBoundsCache::default(),
+            },
+        ];
+        for pat in malformed {
+            let buffer = pat.serialize(off).unwrap();
+            match Patch::deserialize_from(&buffer[..]) {
+                Err(StoiError::MalformedPatch(_)) => (),
+                other => panic!("expected a malformed patch, got {:?}", other),
+            }
+        }
+
+        // Corrupted patches should be errors or valid patches, but never panic
+        let pat = Patch::build()
+            .axis("item", &[0, 3, 4])
+            .axis("store", &[3, 1])
+            .content_2d(&[[200., 100.], [400., std::f32::NAN], [-0.5, 1e-3]])
+            .unwrap();
+        let mut rng = SmallRng::seed_from_u64(3170);
+        for &compression in &[off, Some(PatchCompressionType::LZ4 { quality: 0 })] {
+            for &quantization in &[PatchQuantization::Exact, PatchQuantization::U8] {
+                let mut original = vec![];
+                pat.serialize_quantized_into(compression, quantization, &mut original)
+                    .unwrap();
+                for _ in 0..500 {
+                    let mut buffer = original.clone();
+                    for _ in 0..rng.gen_range(1, 4) {
+                        let ix = rng.gen_range(0, buffer.len());
+                        buffer[ix] = rng.gen();
+                    }
+                    buffer.truncate(rng.gen_range(buffer.len() / 2, buffer.len() + 1));
+                    if let Ok(fuzzed) = Patch::deserialize_from(&buffer[..]) {
+                        fuzzed.try_to_dense().unwrap();
+                        fuzzed.canonical_hash().unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gaps should be filled in label order, not storage order
+    #[test]
+    fn patch_fill_gaps() {
+        use std::f32::NAN;
+        // Day 3 comes before day 4 even though it's stored after it
+        let pat = Patch::build()
+            .axis("store", &[1, 2])
+            .axis("day", &[1, 2, 4, 3, 6])
+            .content_2d(&[[NAN, 1., NAN, NAN, 7.], [2., NAN, 8., NAN, NAN]])
+            .unwrap();
+        let values = |p: &Patch| p.content().iter().copied().collect_vec();
+        let same = |a: Vec<f32>, b: Vec<f32>| {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(&b)
+                    .all(|(x, y)| x == y || (x.is_nan() && y.is_nan()))
+        };
+
+        let mut filled = pat.clone();
+        filled.fill_nan(0.);
+        let expected = vec![0., 1., 0., 0., 7., 2., 0., 8., 0., 0.];
+        assert_eq!(values(&filled), expected);
+
+        let mut filled = pat.clone();
+        filled.fill_forward("day").unwrap();
+        let expected = vec![NAN, 1., 1., 1., 7., 2., 2., 8., 2., 8.];
+        assert!(same(values(&filled), expected));
+
+        let mut filled = pat.clone();
+        filled.interpolate("day").unwrap();
+        let expected = vec![NAN, 1., 4., 2.5, 7., 2., 4., 8., 6., NAN];
+        assert!(same(values(&filled), expected));
+
+        assert!(filled.interpolate("week").is_err());
+    }
+
+    /// Patches should round trip through files, with progress, and stop when cancelled
+    #[test]
+    fn patch_write_to_path() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let path = std::env::temp_dir().join(format!("stoi-patch-{}.bin", rand::random::<u32>()));
+        let pat = Patch::autogenerate(ContentPattern::Random, 1024);
+        let mut reports = vec![];
+        let mut callback = |done: u64, total: Option<u64>| reports.push((done, total));
+        let written = pat
+            .write_to_path(
+                &path,
+                None,
+                TransferProgress {
+                    callback: Some(&mut callback),
+                    ..TransferProgress::default()
+                },
+            )
+            .unwrap();
+        // 4MB of content is reported every megabyte, and once more at the end
+        assert!(reports.len() >= 5);
+        assert_eq!(reports.last(), Some(&(written, None)));
+
+        let read = Patch::read_from_path(&path, TransferProgress::default()).unwrap();
+        assert_eq!(read, pat);
+
+        let cancel = AtomicBool::new(false);
+        let mut cancel_early = |done: u64, _total: Option<u64>| {
+            if done > 1 << 20 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        };
+        let progress = TransferProgress {
+            callback: Some(&mut cancel_early),
+            cancel: Some(&cancel),
+        };
+        match Patch::read_from_path(&path, progress) {
+            Err(StoiError::Cancelled) => (),
+            other => panic!("expected the read to be cancelled, got {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Validation should report every problem, and strict deserialization should reject them
+    #[test]
+    fn patch_validate() {
+        use ndarray::Array4;
+        let pat = Patch::build()
+            .axis("item", &[0, 3])
+            .axis("store", &[3, 1])
+            .content_2d(&[[1., std::f32::INFINITY], [std::f32::NAN, 2.]])
+            .unwrap();
+        let report = pat.validate();
+        assert!(report.is_valid());
+        assert_eq!((report.nans, report.infinities), (1, 1));
+
+        let pat = Patch {
+            axes: vec![Axis::new_unchecked("item", vec![0, 0, 1])],
+            dense: Array4::zeros((2, 1, 1, 1)),
+            label_indices: LabelIndexCache::default(),
+            global_bounds: GlobalBoundsCache::default(),
+        };
+        let report = pat.validate();
+        assert_eq!(
+            report.problems,
+            vec![
+                PatchProblem::ShapeMismatch {
+                    axis: "item".into(),
+                    labels: 3,
+                    length: 2
+                },
+                PatchProblem::RepeatedLabels {
+                    axis: "item".into(),
+                    repeats: 1
+                },
+            ]
+        );
+
+        // Infinities are fine, unless deserialization is strict
+        let pat = Patch::build()
+            .axis("item", &[0, 3])
+            .content_1d(&[1., std::f32::NEG_INFINITY])
+            .unwrap();
+        let buffer = pat.serialize(None).unwrap();
+        assert_eq!(Patch::deserialize_from(&buffer[..]).unwrap(), pat);
+        match Patch::deserialize_strict(&buffer[..]) {
+            Err(StoiError::InvalidPatch(report)) => assert_eq!(report.infinities, 1),
+            other => panic!("expected an invalid patch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn patch_serialize_custom_codec() {
+        use std::io::{Read, Write};
+        use std::sync::Arc;
+
+        /// Plain bincode, which is good enough to see the codec is used
+        struct PlainCodec;
+        impl PatchCodec for PlainCodec {
+            fn encode_into(&self, patch: &Patch, writer: &mut dyn Write) -> Fallible<()> {
+                Ok(bincode::serialize_into(writer, patch)?)
+            }
+            fn decode_from(&self, reader: &mut dyn Read) -> Fallible<Patch> {
+                Ok(bincode::deserialize_from(reader)?)
+            }
+        }
+
+        let pat1 = Patch::build()
+            .axis("item", &[0, 3])
+            .content_1d(&[200., std::f32::NAN])
+            .unwrap();
+        let custom = Some(PatchCompressionType::Custom { format: 0x7e57 });
+        register_patch_codec(0x7e57, Arc::new(PlainCodec)).unwrap();
+        let buffer = pat1.serialize(custom).unwrap();
+        let pat2 = Patch::deserialize_from(&buffer[..]).unwrap();
+        assert_eq!(pat1.axes(), pat2.axes());
+        assert_eq!(pat2.content().iter().next(), Some(&200.));
+
+        // Unknown codecs can't be used at all
+        let unknown = Some(PatchCompressionType::Custom { format: 0xbad });
+        assert!(pat1.serialize(unknown).is_err());
+    }
+
+    #[test]
+    fn patch_estimate_serialized_size() {
+        // Noise doesn't compress, so the estimate should be close to the raw size
+        let pat = Patch::autogenerate(ContentPattern::Random, 1000);
+        let estimate = pat
+            .estimate_serialized_size(Some(PatchCompressionType::LZ4 { quality: 0 }))
+            .unwrap();
+        assert!(estimate > 3_000_000 && estimate < 5_000_000);
+
+        let pat = Patch::autogenerate(ContentPattern::Zero, 1000);
+        let estimate = pat
+            .estimate_serialized_size(Some(PatchCompressionType::LZ4 { quality: 0 }))
+            .unwrap();
+        assert!(estimate < 400_000);
+    }
+
+    #[test]
+    fn patch_choose_compression() {
+        use super::PatchTag;
+        let auto = Some(PatchCompressionType::Auto);
+        let noise = Patch::autogenerate(ContentPattern::Random, 300);
+        let chosen = noise.choose_compression().unwrap();
+        assert_eq!(chosen, PatchCompressionType::Off);
+        for &pattern in &[ContentPattern::Zero, ContentPattern::Sparse] {
+            let pat = Patch::autogenerate(pattern, 300);
+            assert_eq!(
+                pat.choose_compression().unwrap(),
+                PatchCompressionType::LZ4 { quality: 0 }
+            );
+        }
+
+        // The choice is saved in the PatchTag, so reading doesn't need to choose again
+        let buffer = noise.serialize(auto).unwrap();
+        let tag: PatchTag = bincode::deserialize_from(&buffer[..]).unwrap();
+        assert_eq!(tag.compression, PatchCompressionType::Off);
+        assert_eq!(Patch::deserialize_from(&buffer[..]).unwrap(), noise);
+    }
+
+    #[test]
+    fn patch_f16_conversion() {
+        use super::{f16_to_f32, f32_to_f16};
+        for &x in &[0., -0., 1., -2.5, 65504., 6.1035156e-5, 5.9604645e-8] {
+            assert_eq!(f16_to_f32(f32_to_f16(x)), x);
+        }
+        assert_eq!(f16_to_f32(f32_to_f16(1e6)), std::f32::INFINITY);
+        assert!(f16_to_f32(f32_to_f16(std::f32::NAN)).is_nan());
+        // 1 + 2^-11 is a tie, and rounds to the even neighbor
+        assert_eq!(f16_to_f32(f32_to_f16(1. + (2f32).powi(-11))), 1.);
+    }
 }
@@ -0,0 +1,226 @@
+//! Many small patches packed into one blob
+//!
+//! Commits of thousands of tiny patches spend more on a row and a blob for each patch than on
+//! the content itself, and tiny blobs hardly compress. A patchset stores the patches together
+//! in one PatchContent row, compressed as a whole, with an index to find each one again.
+use crate::patch::MAX_TAG_BYTES;
+use crate::{
+    BoundingBox, Fallible, Patch, PatchCompressionType, PatchID, PatchQuantization, StoiError,
+};
+use std::io::{Read, Write};
+
+/// The first four bytes of every serialized patchset, "STOS"
+const PATCHSET_MAGIC: u32 = 0x534f_5453;
+
+/// The format version patchsets are written with
+const PATCHSET_VERSION: u8 = 1;
+
+/// An uncompressed prelude to a patchset, like PatchTag is for patches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PatchSetTag {
+    magic: u32,
+    version: u8,
+    compression: PatchCompressionType,
+}
+
+/// Where one patch is in a patchset, and what it covers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct PatchSetEntry {
+    patch_id: PatchID,
+    bounding_box: BoundingBox,
+    offset: usize,
+    length: usize,
+}
+
+/// Small patches packed together, see the module docs
+///
+/// Each patch is serialized as usual, but without compression, so the whole set can be
+/// compressed at once. Custom codecs compress each patch themselves, so sets of those
+/// aren't compressed again.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PatchSet {
+    index: Vec<PatchSetEntry>,
+    members: Vec<u8>,
+}
+impl PatchSet {
+    /// Add a patch to the set, serializing it right away
+    pub fn push(
+        &mut self,
+        patch_id: PatchID,
+        bounding_box: BoundingBox,
+        patch: &Patch,
+        compression: PatchCompressionType,
+        quantization: PatchQuantization,
+    ) -> Fallible<()> {
+        let offset = self.members.len();
+        patch.serialize_quantized_into(
+            Some(Self::member_compression(compression)),
+            quantization,
+            &mut self.members,
+        )?;
+        self.index.push(PatchSetEntry {
+            patch_id,
+            bounding_box,
+            offset,
+            length: self.members.len() - offset,
+        });
+        Ok(())
+    }
+
+    /// Deserialize one patch of the set
+    pub fn get(&self, patch_id: PatchID) -> Fallible<Patch> {
+        let entry = self
+            .index
+            .iter()
+            .find(|entry| entry.patch_id == patch_id)
+            .ok_or_else(|| StoiError::NotFound("patch in patchset", patch_id.0.to_string()))?;
+        let content = entry
+            .offset
+            .checked_add(entry.length)
+            .and_then(|end| self.members.get(entry.offset..end))
+            .ok_or(StoiError::MalformedPatch(
+                "patchset index is past its content",
+            ))?;
+        Patch::deserialize_stored(content)
+    }
+
+    /// Serialize the whole set, compressing it with the catalog's patch compression
+    pub fn serialize_into<W: Write>(
+        &self,
+        compression: PatchCompressionType,
+        mut buffer: &mut W,
+    ) -> Fallible<()> {
+        // Members were already compressed by the codec, if it's a custom one
+        let compression = match compression {
+            PatchCompressionType::Custom { .. } => PatchCompressionType::Off,
+            // The members are too varied to choose for them all from a sample
+            PatchCompressionType::Auto => PatchCompressionType::LZ4 { quality: 0 },
+            compression => compression,
+        };
+        let tag = PatchSetTag {
+            magic: PATCHSET_MAGIC,
+            version: PATCHSET_VERSION,
+            compression,
+        };
+        bincode::serialize_into(&mut buffer, &tag)?;
+        let payload = (&self.index, &self.members);
+        match compression {
+            PatchCompressionType::Brotli { quality } => {
+                let mut brotli_writer = brotli::CompressorWriter::new(buffer, 4096, quality, 20);
+                bincode::serialize_into(&mut brotli_writer, &payload)?;
+                brotli_writer.flush()?;
+            }
+            PatchCompressionType::LZ4 { quality } => {
+                let mut lz4_writer = lz4::EncoderBuilder::new().level(quality).build(buffer)?;
+                bincode::serialize_into(&mut lz4_writer, &payload)?;
+                lz4_writer.finish().1?;
+            }
+            _ => bincode::serialize_into(buffer, &payload)?,
+        }
+        Ok(())
+    }
+
+    /// Deserialize a set written by serialize_into(), decompressing it all at once
+    pub fn deserialize_from<R: Read>(mut buffer: R) -> Fallible<Self> {
+        let tag: PatchSetTag = bincode::config()
+            .limit(MAX_TAG_BYTES)
+            .deserialize_from(buffer.by_ref())?;
+        if tag.magic != PATCHSET_MAGIC {
+            return Err(StoiError::InvalidValue(
+                "not a serialized patchset, because the magic number is wrong",
+            ));
+        }
+        if tag.version != PATCHSET_VERSION {
+            return Err(StoiError::UnsupportedPatchVersion(tag.version));
+        }
+        let mut payload = vec![];
+        match tag.compression {
+            PatchCompressionType::Brotli { .. } => {
+                brotli::Decompressor::new(buffer, 4096).read_to_end(&mut payload)?
+            }
+            PatchCompressionType::LZ4 { .. } => {
+                lz4::Decoder::new(buffer)?.read_to_end(&mut payload)?
+            }
+            _ => buffer.read_to_end(&mut payload)?,
+        };
+        // Like for patches, corrupted lengths can't claim more than the payload has
+        let (index, members) = bincode::config()
+            .limit(payload.len() as u64)
+            .deserialize(&payload)?;
+        Ok(PatchSet { index, members })
+    }
+
+    /// How each member is compressed, given the compression of the whole set
+    fn member_compression(compression: PatchCompressionType) -> PatchCompressionType {
+        match compression {
+            PatchCompressionType::Custom { .. } => compression,
+            _ => PatchCompressionType::Off,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PatchSet, PatchSetEntry};
+    use crate::*;
+
+    #[test]
+    fn patchset_roundtrip() {
+        let a = Patch::build()
+            .axis("itm", &[1, 2])
+            .content_1d(&[1., 2.])
+            .unwrap();
+        let b = Patch::build().axis("itm", &[5]).content_1d(&[5.]).unwrap();
+        let box_a = BoundingBox::new([(0, 1), (0, 0), (0, 0), (0, 0)]).unwrap();
+        let box_b = BoundingBox::new([(4, 4), (0, 0), (0, 0), (0, 0)]).unwrap();
+        for &compression in &[
+            PatchCompressionType::Off,
+            PatchCompressionType::LZ4 { quality: 1 },
+            PatchCompressionType::Brotli { quality: 3 },
+        ] {
+            let mut set = PatchSet::default();
+            set.push(PatchID(1), box_a, &a, compression, PatchQuantization::Exact)
+                .unwrap();
+            set.push(PatchID(2), box_b, &b, compression, PatchQuantization::Exact)
+                .unwrap();
+            let mut buffer = vec![];
+            set.serialize_into(compression, &mut buffer).unwrap();
+            // A patch isn't a patchset, or the other way around
+            assert!(PatchSet::deserialize_from(&a.serialize(None).unwrap()[..]).is_err());
+            assert!(Patch::deserialize_from(&buffer[..]).is_err());
+
+            let set = PatchSet::deserialize_from(&buffer[..]).unwrap();
+            assert_eq!(set.get(PatchID(1)).unwrap(), a);
+            assert_eq!(set.get(PatchID(2)).unwrap(), b);
+            assert!(set.get(PatchID(3)).is_err());
+
+            // Corrupted sets are errors, but never panic or allocate without bound
+            for ix in 0..buffer.len() {
+                let mut corrupted = buffer.clone();
+                corrupted[ix] = !corrupted[ix];
+                if let Ok(set) = PatchSet::deserialize_from(&corrupted[..]) {
+                    let _ = set.get(PatchID(1));
+                }
+                let _ = PatchSet::deserialize_from(&buffer[..ix]);
+            }
+        }
+    }
+
+    /// An index entry that ends past the end of memory is malformed, not an overflow
+    #[test]
+    fn patchset_index_overflow() {
+        let set = PatchSet {
+            index: vec![PatchSetEntry {
+                patch_id: PatchID(1),
+                bounding_box: BoundingBox::new([(0, 0), (0, 0), (0, 0), (0, 0)]).unwrap(),
+                offset: std::usize::MAX,
+                length: 2,
+            }],
+            members: vec![0; 4],
+        };
+        match set.get(PatchID(1)) {
+            Err(StoiError::MalformedPatch(_)) => (),
+            other => panic!("expected a malformed patch, got {:?}", other),
+        }
+    }
+}
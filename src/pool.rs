@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+
+/// Buffers smaller than this many bytes aren't worth keeping, since the allocator handles them
+const MIN_POOLED_BYTES: usize = 64 << 10;
+
+/// Large allocations kept for reuse across fetches
+///
+/// Fetches allocate a buffer for the patch they assemble and for the content of each patch they
+/// read. In a server fetching many times a second, recycling them saves the allocator, and the
+/// page faults of touching fresh memory. See CatalogOptions::buffer_pool_bytes.
+///
+/// The pool holds at most `max_bytes` of spare buffers, and a max of 0 disables it.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    max_bytes: usize,
+    floats: Mutex<Vec<Vec<f32>>>,
+    bytes: Mutex<Vec<Vec<u8>>>,
+}
+impl BufferPool {
+    /// Create a pool holding up to `max_bytes` of spare buffers
+    pub fn new(max_bytes: usize) -> Self {
+        BufferPool {
+            max_bytes,
+            floats: Mutex::new(vec![]),
+            bytes: Mutex::new(vec![]),
+        }
+    }
+
+    /// Whether the pool keeps anything at all
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    /// How many bytes of spare buffers the pool holds right now
+    pub fn held_bytes(&self) -> usize {
+        let floats = self.floats.lock().map_or(0, |floats| {
+            floats.iter().map(|b| b.capacity() * 4).sum::<usize>()
+        });
+        let bytes = self
+            .bytes
+            .lock()
+            .map_or(0, |bytes| bytes.iter().map(|b| b.capacity()).sum::<usize>());
+        floats + bytes
+    }
+
+    /// Get a buffer of `len` NANs, and whether it was reused rather than allocated
+    ///
+    /// The smallest spare buffer that's large enough is used.
+    pub(crate) fn take_floats(&self, len: usize) -> (Vec<f32>, bool) {
+        let spare = self.floats.lock().ok().and_then(|mut floats| {
+            let (ix, _) = floats
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.capacity() >= len)
+                .min_by_key(|(_, b)| b.capacity())?;
+            Some(floats.swap_remove(ix))
+        });
+        match spare {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer.resize(len, std::f32::NAN);
+                (buffer, true)
+            }
+            None => (vec![std::f32::NAN; len], false),
+        }
+    }
+
+    /// Keep a buffer for later, if it's large enough to matter and there's room
+    pub(crate) fn give_floats(&self, buffer: Vec<f32>) {
+        let size = buffer.capacity() * 4;
+        if size >= MIN_POOLED_BYTES && self.held_bytes() + size <= self.max_bytes {
+            if let Ok(mut floats) = self.floats.lock() {
+                floats.push(buffer);
+            }
+        }
+    }
+
+    /// Get an empty buffer for bytes, which may have room already
+    pub(crate) fn take_bytes(&self) -> Vec<u8> {
+        let spare = self.bytes.lock().ok().and_then(|mut bytes| bytes.pop());
+        match spare {
+            Some(mut buffer) => {
+                buffer.clear();
+                buffer
+            }
+            None => vec![],
+        }
+    }
+
+    /// Keep a buffer of bytes for later, if it's large enough to matter and there's room
+    pub(crate) fn give_bytes(&self, buffer: Vec<u8>) {
+        let size = buffer.capacity();
+        if size >= MIN_POOLED_BYTES && self.held_bytes() + size <= self.max_bytes {
+            if let Ok(mut bytes) = self.bytes.lock() {
+                bytes.push(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_reuses_large_buffers() {
+        let pool = BufferPool::new(1 << 20);
+        let (buffer, reused) = pool.take_floats(100_000);
+        assert!(!reused);
+        pool.give_floats(buffer);
+        assert_eq!(pool.held_bytes(), 400_000);
+
+        // Smaller requests can use a larger buffer, and they're all NAN again
+        let (buffer, reused) = pool.take_floats(50_000);
+        assert!(reused);
+        assert_eq!(buffer.len(), 50_000);
+        assert!(buffer.iter().all(|x| x.is_nan()));
+        assert_eq!(pool.held_bytes(), 0);
+
+        // Small buffers, and buffers past the limit, aren't kept
+        pool.give_floats(vec![0.; 10]);
+        pool.give_bytes(vec![0; 2 << 20]);
+        assert_eq!(pool.held_bytes(), 0);
+
+        // A disabled pool keeps nothing
+        let pool = BufferPool::new(0);
+        assert!(!pool.is_enabled());
+        pool.give_floats(buffer);
+        assert_eq!(pool.held_bytes(), 0);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::StorageTransaction;
 use numpy::{IntoPyArray, PyArray1, PyArrayDyn};
 use pyo3::prelude::*;
+use pyo3::types::PySlice;
+use pyo3::{PyMappingProtocol, PyObjectProtocol};
+use std::os::raw::c_long;
 
 /// A sequence of distinct signed integer labels uniquely mapping to indices of an axis
 ///
@@ -42,4 +46,94 @@ impl Axis {
     pub fn union(&mut self, other: &Self) {
         self.inner.union(&other.inner);
     }
+
+    /// Choose up to `n` distinct labels at random, in storage order
+    pub fn sample<'py>(&self, py: Python<'py>, n: usize) -> &'py PyArray1<i64> {
+        self.inner.sample(n).into_pyarray(py)
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for Axis {
+    /// Summarize the axis with its length and range of labels, without all the labels
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(self.inner.to_string())
+    }
+}
+
+/// An axis of a catalog, which reads labels only as you index it
+///
+/// Catalog axes can have many millions of labels, so this lets you page through them,
+/// or get the length, without copying the whole axis to Python:
+///
+/// ```py
+/// days = cat.axis("day")
+/// len(days)
+/// days[1000:2000]
+/// ```
+#[pyclass]
+pub struct CatalogAxis {
+    pub catalog: crate::Catalog,
+    pub name: String,
+}
+#[pymethods]
+impl CatalogAxis {
+    /// Get the name of this axis
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Choose up to `n` distinct labels at random, in storage order
+    ///
+    /// This reads only the sampled labels, so it's fine for very long axes.
+    pub fn sample<'py>(&self, py: Python<'py>, n: usize) -> PyResult<&'py PyArray1<i64>> {
+        let mut txn = self.catalog.begin_read()?;
+        let len = txn.get_axis_len(&self.name)?;
+        let mut labels = vec![];
+        for index in crate::Axis::sample_indices(len, n) {
+            labels.push(txn.get_axis_slice(&self.name, index..index + 1)?.labels()[0]);
+        }
+        Ok(labels.into_pyarray(py))
+    }
+}
+#[pyproto]
+impl PyMappingProtocol for CatalogAxis {
+    /// Count the labels of the axis, without reading them
+    fn __len__(&self) -> PyResult<usize> {
+        let mut txn = self.catalog.begin_read()?;
+        Ok(txn.get_axis_len(&self.name)?)
+    }
+
+    /// Get one label by storage index, or a slice of labels as an array
+    fn __getitem__(&self, key: &PyAny) -> PyResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let mut txn = self.catalog.begin_read()?;
+        let len = txn.get_axis_len(&self.name)?;
+        if let Ok(slice) = key.downcast_ref::<PySlice>() {
+            let ix = slice.indices(len as c_long)?;
+            if ix.slicelength <= 0 {
+                return Ok(Vec::<i64>::new().into_pyarray(py).to_object(py));
+            }
+            // Read the smallest contiguous range and then take the steps from it
+            let last = ix.start + (ix.slicelength - 1) * ix.step;
+            let (lo, hi) = (ix.start.min(last) as usize, ix.start.max(last) as usize);
+            let axis = txn.get_axis_slice(&self.name, lo..hi + 1)?;
+            let labels = (0..ix.slicelength)
+                .map(|i| axis.labels()[(ix.start + i * ix.step) as usize - lo])
+                .collect::<Vec<i64>>();
+            Ok(labels.into_pyarray(py).to_object(py))
+        } else {
+            let index: isize = key.extract()?;
+            let index = if index < 0 { index + len as isize } else { index };
+            if index < 0 || index as usize >= len {
+                return Err(PyErr::new::<pyo3::exceptions::IndexError, _>(
+                    "axis index out of range",
+                ));
+            }
+            let index = index as usize;
+            let axis = txn.get_axis_slice(&self.name, index..index + 1)?;
+            Ok(axis.labels()[0].to_object(py))
+        }
+    }
 }
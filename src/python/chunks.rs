@@ -0,0 +1,31 @@
+use pyo3::prelude::*;
+
+/// A selection of one commit of a quilt, divided into chunks to fetch one at a time
+///
+/// Plan one with Catalog.plan_chunks(), then fetch each key with Catalog.fetch_chunk().
+/// Every chunk comes from the commit the tag pointed to when it was planned.
+#[pyclass]
+pub struct ChunkGrid {
+    pub inner: crate::ChunkGrid,
+}
+
+#[pymethods]
+impl ChunkGrid {
+    /// The keys of all the chunks, which are their positions in the grid
+    #[getter]
+    pub fn keys(&self) -> Vec<Vec<usize>> {
+        self.inner.keys()
+    }
+
+    /// How many chunks there are along each axis
+    #[getter]
+    pub fn grid_shape(&self) -> Vec<usize> {
+        self.inner.grid_shape()
+    }
+
+    /// The commit all the chunks are fetched from, or None if the tag didn't exist
+    #[getter]
+    pub fn comm_id(&self) -> Option<i64> {
+        self.inner.comm_id()
+    }
+}
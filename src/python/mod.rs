@@ -12,20 +12,27 @@ use itertools::Itertools;
 use ndarray::prelude::*;
 use numpy::{IntoPyArray, PyArrayDyn};
 use pyo3::prelude::*;
-use pyo3::types::{PyAny, PyDict};
+use pyo3::types::{PyAny, PyDict, PySlice};
 use std::collections::HashMap;
 
 mod axis;
+mod chunks;
 mod patch;
+mod transaction;
 
-pub use axis::Axis;
+pub use axis::{Axis, CatalogAxis};
+pub use chunks::ChunkGrid;
 pub use patch::Patch;
+pub use transaction::Transaction;
 
 #[pymodule]
 fn stoicheia(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<crate::python::axis::Axis>()?;
+    m.add_class::<crate::python::axis::CatalogAxis>()?;
     m.add_class::<crate::python::patch::Patch>()?;
+    m.add_class::<ChunkGrid>()?;
     m.add_class::<Catalog>()?;
+    m.add_class::<Transaction>()?;
     Ok(())
 }
 
@@ -36,6 +43,78 @@ impl From<crate::StoiError> for PyErr {
     }
 }
 
+/// Read the selection of each axis from keyword arguments, like fetch() and estimate() take
+///
+/// Unless `strict` is false, naming an axis the quilt doesn't have is an error.
+fn parse_selections<T: StorageTransaction>(
+    txn: &mut T,
+    quilt_name: &str,
+    axes: Option<&PyDict>,
+    strict: bool,
+) -> PyResult<Vec<crate::AxisSelection>> {
+    let named = parse_named_selections(axes)?;
+    Ok(txn.order_named_request(quilt_name, named, strict)?)
+}
+
+/// Take an option like `_strict` out of the keyword arguments of fetch() and the like
+///
+/// Options start with an underscore so they can't be confused with axes. pyo3 can't give a
+/// Python argument another name than its Rust parameter, so they come in with the axes, and
+/// have to be taken out before the axes are read.
+fn take_option(kwargs: Option<&PyDict>, name: &str, default: bool) -> PyResult<bool> {
+    let kwargs = match kwargs {
+        Some(kwargs) => kwargs,
+        None => return Ok(default),
+    };
+    match kwargs.get_item(name) {
+        Some(value) => {
+            let value = value.extract()?;
+            kwargs.del_item(name)?;
+            Ok(value)
+        }
+        None => Ok(default),
+    }
+}
+
+/// Read the selection of each axis from keyword arguments, in the order they were given
+fn parse_named_selections(axes: Option<&PyDict>) -> PyResult<Vec<(String, crate::AxisSelection)>> {
+    let specified_axes: Vec<(String, &PyAny)> = match axes {
+        Some(axes) => axes
+            .iter()
+            .map(|(k, v)| Ok((k.extract()?, v)))
+            .collect::<PyResult<_>>()?,
+        None => vec![],
+    };
+    let mut named = vec![];
+    for (axis_name, v) in specified_axes {
+        // Tuples are checked first, because they would also extract as a Vec
+        let selection = if let Ok(selection) = v.extract::<(i64, i64)>() {
+            crate::AxisSelection::LabelSlice(selection.0, selection.1)
+        } else if let Ok(slice) = v.downcast_ref::<PySlice>() {
+            // Open ends take every label on that side
+            let start: Option<i64> = slice.getattr("start")?.extract()?;
+            let stop: Option<i64> = slice.getattr("stop")?.extract()?;
+            crate::AxisSelection::LabelRange(
+                start.unwrap_or(std::i64::MIN),
+                stop.unwrap_or(std::i64::MAX),
+            )
+        } else if let Ok(selection) = v.extract::<Vec<i64>>() {
+            crate::AxisSelection::Labels(selection)
+        } else if let Ok(selection) = v.extract::<i64>() {
+            crate::AxisSelection::Labels(vec![selection])
+        } else if v.is_none() {
+            crate::AxisSelection::All
+        } else {
+            // Play it safe and don't just ignore errors
+            Err(StoiError::InvalidValue(
+                "Didn't recognize one of the axis selections",
+            ))?
+        };
+        named.push((axis_name, selection));
+    }
+    Ok(named)
+}
+
 #[pyclass]
 pub struct Catalog {
     inner: crate::Catalog,
@@ -57,18 +136,148 @@ impl Catalog {
         Ok(())
     }
 
+    /// Close the catalog, so the database file is released right away
+    ///
+    /// Otherwise it's released when Python collects every object using the catalog.
+    /// Using the catalog after closing it raises an error. It's easiest to use a `with` block:
+    ///
+    /// ```py
+    /// with Catalog("example.db") as cat:
+    ///     patch = cat.fetch("tot_sal_amt", "latest")
+    /// # <- Closed here, even if there was an exception
+    /// ```
+    pub fn close(&self) -> PyResult<()> {
+        Ok(self.inner.close()?)
+    }
+
+    /// Copy the catalog to a new file as a consistent backup, while others keep using it
+    ///
+    /// Returns a dict with when it was `taken_at`, and the `tags` it captured, as a list of
+    /// (quilt_name, tag_name, comm_id). The file must not already exist.
+    pub fn backup_to(&self, py: Python, path: &str) -> PyResult<PyObject> {
+        let snapshot = self.inner.backup_to(path)?;
+        let dict = PyDict::new(py);
+        dict.set_item("taken_at", snapshot.taken_at)?;
+        dict.set_item("tags", snapshot.tags)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Replace this replica, opened with Catalog("replica:path"), with a fresh copy of primary
+    ///
+    /// Returns the same dict as backup_to(). Make the first copy with backup_to().
+    pub fn sync_replica(&self, py: Python, primary: &Catalog) -> PyResult<PyObject> {
+        let snapshot = self.inner.sync_replica(&primary.inner)?;
+        let dict = PyDict::new(py);
+        dict.set_item("taken_at", snapshot.taken_at)?;
+        dict.set_item("tags", snapshot.tags)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// How many seconds behind its primary this replica is, or None if it isn't a copy
+    pub fn replica_lag(&self) -> PyResult<Option<f64>> {
+        Ok(self.inner.replica_lag()?.map(|lag| lag.as_secs_f64()))
+    }
+
+    /// Make sure everything committed is in the database file itself, like before copying it
+    pub fn flush(&self) -> PyResult<()> {
+        Ok(self.inner.flush()?)
+    }
+
+    /// Use the catalog in a `with` block, which closes it at the end
+    pub fn __enter__(&self) -> Catalog {
+        Catalog {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Close the catalog at the end of a `with` block, letting any exception through
+    pub fn __exit__(&self, _ty: &PyAny, _value: &PyAny, _traceback: &PyAny) -> PyResult<bool> {
+        self.inner.close()?;
+        Ok(false)
+    }
+
+    /// Start a transaction, to make several commits all at once, or not at all
+    ///
+    /// ```py
+    /// with cat.transaction() as txn:
+    ///     txn.commit("tot_sal_amt", message="...", patches=[patch])
+    ///     check(txn.fetch("tot_sal_amt", "latest"))  # <- Sees the commit already
+    /// # <- Written here, unless there was an exception
+    /// ```
+    ///
+    /// This takes the catalog's write lock until the transaction finishes or rolls back.
+    pub fn transaction(&self) -> PyResult<Transaction> {
+        Ok(Transaction::begin(self.inner.clone())?)
+    }
+
     /// Create a new quilt in the catalog, given a name and the axes it uses
     pub fn create_quilt(&self, quilt_name: String, axes: Vec<String>) -> PyResult<()> {
-        let txn = self.inner.begin()?;
-        txn.create_quilt(
-            &quilt_name,
-            &axes.iter().map(|s| s.as_ref()).collect_vec()[..],
-            true,
-        )?;
+        let mut txn = self.inner.begin()?;
+        txn.create_quilt(&quilt_name, &axes.iter().map(|s| s.as_str()).collect_vec())?;
         txn.finish()?;
         Ok(())
     }
 
+    /// Create many quilts like an existing one, with the same axes, units, and limits
+    ///
+    /// ```py
+    /// # One quilt per metric, all like the first
+    /// cat.create_quilts_from_template("tot_sal_amt", ["tot_sal_qty", "tot_rtn_amt"])
+    /// ```
+    ///
+    /// Quilts that already exist with the same axes are left alone. Returns how many were created.
+    pub fn create_quilts_from_template(
+        &self,
+        template_quilt: &str,
+        quilt_names: Vec<String>,
+    ) -> PyResult<usize> {
+        let mut txn = self.inner.begin()?;
+        let template = txn.get_quilt_template(template_quilt)?;
+        let created = txn.create_quilts_from_template(
+            &template,
+            &quilt_names.iter().map(|s| s.as_str()).collect_vec(),
+        )?;
+        txn.finish()?;
+        Ok(created)
+    }
+
+    /// Get an axis of the catalog, which reads labels lazily as you index it
+    ///
+    /// ```py
+    /// days = cat.axis("day")
+    /// len(days)          # <- Doesn't read the labels
+    /// days[1000:2000]    # <- Only reads these labels
+    /// ```
+    pub fn axis(&self, name: String) -> CatalogAxis {
+        CatalogAxis {
+            catalog: self.inner.clone(),
+            name,
+        }
+    }
+
+    /// Name the labels of the "measure" axis, for quilts with several values in each cell
+    ///
+    /// ```py
+    /// cat.declare_measures(["value", "flag", "stamp"])  # <- Returns their labels
+    /// cat.create_quilt("readings", ["measure", "sensor", "hour"])
+    /// patch = cat.fetch("readings", "latest", sensor=[1, 2])
+    /// patch["flag"]  # <- The flag of every sensor and hour, as an array
+    /// ```
+    ///
+    /// Names that were already declared keep their labels.
+    pub fn declare_measures(&self, names: Vec<String>) -> PyResult<Vec<i64>> {
+        let mut txn = self.inner.begin()?;
+        let labels = txn.declare_measures(&names.iter().map(|s| s.as_str()).collect_vec())?;
+        txn.finish()?;
+        Ok(labels)
+    }
+
+    /// Get the label of every declared measure, as a dict by name
+    pub fn measures(&self) -> PyResult<HashMap<String, i64>> {
+        let mut txn = self.inner.begin_read()?;
+        Ok(txn.get_measures()?.into_iter().collect())
+    }
+
     /// Fetch a patch from a quilt, assembling it from parts as necessary
     ///
     /// ```py
@@ -84,10 +293,28 @@ impl Catalog {
     ///     # Omitting an axis or giving None will get the whole axis.
     ///     lct = None,
     ///
+    ///     # A tuple is an inclusive slice from one label to another, in storage order.
+    ///     # Both labels must exist and the end can't be stored before the start.
+    ///     wk = (201901, 201952),
+    ///
     ///     # Giving just one label will not remove that axis
     ///     # (because that makes merging patches easier)
     ///     day = 721,
     /// )
+    ///
+    /// # A slice is every label from one value through another, inclusive, wherever they are
+    /// # stored, like all the days in March. Either end can be left open.
+    /// patch = cat.fetch("tot_sal_amt", "latest", day=slice(20200301, 20200331))
+    ///
+    /// # Naming an axis the quilt doesn't have is an error, since it's probably a typo.
+    /// # Pass _strict=False to ignore those instead. It starts with an underscore so it can't
+    /// # be confused with an axis.
+    /// patch = cat.fetch("tot_sal_amt", "latest", _strict=False, itm=[1,2,3], color=5)
+    ///
+    /// # The axes are in the quilt's order, however you list them, unless you pass _ordered=True.
+    /// # Then they're in the order you listed them, followed by any others in the quilt's order.
+    /// patch = cat.fetch("tot_sal_amt", "latest", _ordered=True, day=721, itm=[1,2,3])
+    /// patch.axis_names  # <- "day", then "itm", then the rest
     /// ```
     #[args(axes = "**")]
     pub fn fetch(
@@ -96,40 +323,169 @@ impl Catalog {
         tag: &str,
         axes: Option<&PyDict>,
     ) -> PyResult<crate::python::Patch> {
-        let specified_axes: HashMap<String, &PyAny> =
-            axes.map(|a| a.extract()).transpose()?.unwrap_or_default();
-        let mut txn = self.inner.begin()?;
-        let quilt_details = txn.get_quilt_details(quilt_name)?;
-        let mut axes_selections = vec![];
-
-        // We need to iterate because the order matters and HashSet would have missed that
-        for axis_name in &quilt_details.axes {
-            if let Some(v) = specified_axes.get(axis_name.as_str()) {
-                if let Ok(selection) = v.extract::<Vec<i64>>() {
-                    axes_selections.push(crate::AxisSelection::Labels(selection));
-                } else if let Ok(selection) = v.extract::<(i64, i64)>() {
-                    axes_selections
-                        .push(crate::AxisSelection::LabelSlice(selection.0, selection.1));
-                } else if let Ok(selection) = v.extract::<i64>() {
-                    axes_selections.push(crate::AxisSelection::Labels(vec![selection]));
-                } else if v.is_none() {
-                    axes_selections.push(crate::AxisSelection::All);
-                } else {
-                    // Play it safe and don't just ignore errors
-                    Err(StoiError::InvalidValue(
-                        "Didn't recognize one of the axis selections",
-                    ))?;
-                }
-            } else {
-                axes_selections.push(crate::AxisSelection::All);
-            }
+        let strict = take_option(axes, "_strict", true)?;
+        let ordered = take_option(axes, "_ordered", false)?;
+        let mut txn = self.inner.begin_read()?;
+        let patch = if ordered {
+            let named = parse_named_selections(axes)?;
+            txn.fetch_named(&quilt_name, tag, named, strict)?
+        } else {
+            let axes_selections = parse_selections(&mut txn, quilt_name, axes, strict)?;
+            txn.fetch(&quilt_name, tag, axes_selections)?
+        };
+        crate::python::Patch::fetched(&mut txn, patch)
+    }
+
+    /// Fetch the same selections from several quilts, aligned on the axes they share
+    ///
+    /// Shared axes are resolved once, so they have the same labels in the same order in every
+    /// patch, even if labels were added in between. Naming an axis none of the quilts have
+    /// is an error. Returns one patch for each (quilt, tag), each in its quilt's axis order.
+    ///
+    /// ```py
+    /// price, volume = cat.fetch_joined([("price", "latest"), ("volume", "latest")], itm=[1,2])
+    /// ```
+    #[args(axes = "**")]
+    pub fn fetch_joined(
+        &self,
+        sources: Vec<(String, String)>,
+        axes: Option<&PyDict>,
+    ) -> PyResult<Vec<crate::python::Patch>> {
+        let mut txn = self.inner.begin_read()?;
+        let named = parse_named_selections(axes)?;
+        let sources = sources
+            .iter()
+            .map(|(quilt_name, tag)| (quilt_name.as_str(), tag.as_str()))
+            .collect::<Vec<_>>();
+        txn.fetch_joined(&sources, named)?
+            .into_iter()
+            .map(|patch| crate::python::Patch::fetched(&mut txn, patch))
+            .collect()
+    }
+
+    /// Fetch several selections of the same tag at once, which is much faster than one by one
+    ///
+    /// Each selection is a dict of the keyword arguments fetch() takes for the axes, and
+    /// `_strict` works like it does for fetch(). Returns a list of patches, one for each
+    /// selection.
+    ///
+    /// ```py
+    /// left, right = cat.fetch_many_selections("tot_sal_amt", "latest", [
+    ///     {"itm": [1, 2, 3]},
+    ///     {"itm": 4, "day": (721, 728)},
+    /// ])
+    /// ```
+    #[args(options = "**")]
+    pub fn fetch_many_selections(
+        &self,
+        quilt_name: &str,
+        tag: &str,
+        selections: Vec<&PyDict>,
+        options: Option<&PyDict>,
+    ) -> PyResult<Vec<crate::python::Patch>> {
+        let strict = take_option(options, "_strict", true)?;
+        if let Some((name, _)) = options.and_then(|options| options.iter().next()) {
+            return Err(PyErr::new::<pyo3::exceptions::TypeError, _>(format!(
+                "fetch_many_selections() got an unexpected keyword argument {:?}",
+                name
+            )));
+        }
+        let mut txn = self.inner.begin_read()?;
+        let mut requests = vec![];
+        for selection in selections {
+            requests.push(parse_selections(
+                &mut txn,
+                quilt_name,
+                Some(selection),
+                strict,
+            )?);
         }
+        txn.fetch_many_selections(quilt_name, tag, requests)?
+            .into_iter()
+            .map(|patch| crate::python::Patch::fetched(&mut txn, patch))
+            .collect()
+    }
 
-        Ok(crate::python::Patch {
-            inner: txn.fetch(&quilt_name, &tag, axes_selections)?,
+    /// Divide a selection into chunks, to fetch one at a time with fetch_chunk()
+    ///
+    /// This takes the same arguments as fetch(), plus the most labels a chunk can have along
+    /// each axis. The chunks all come from the commit the tag points to now, even if it moves.
+    ///
+    /// ```py
+    /// grid = cat.plan_chunks("tot_sal_amt", "latest", [100, 100, 7], itm=(1, 50000))
+    /// for key in grid.keys:
+    ///     draw(cat.fetch_chunk(grid, key))
+    /// ```
+    #[args(axes = "**")]
+    pub fn plan_chunks(
+        &self,
+        quilt_name: &str,
+        tag: &str,
+        chunk_shape: Vec<usize>,
+        axes: Option<&PyDict>,
+    ) -> PyResult<ChunkGrid> {
+        let strict = take_option(axes, "_strict", true)?;
+        let mut txn = self.inner.begin_read()?;
+        let axes_selections = parse_selections(&mut txn, quilt_name, axes, strict)?;
+        Ok(ChunkGrid {
+            inner: txn.plan_chunks(quilt_name, tag, axes_selections, &chunk_shape)?,
         })
     }
 
+    /// Fetch one chunk of a grid from plan_chunks(), given its key
+    ///
+    /// If the commit was rewritten since the grid was planned, by merging or compaction,
+    /// this raises an error and the grid should be planned again.
+    pub fn fetch_chunk(&self, grid: &ChunkGrid, key: Vec<usize>) -> PyResult<crate::python::Patch> {
+        let mut txn = self.inner.begin_read()?;
+        let patch = txn.fetch_chunk(&grid.inner, &key)?;
+        crate::python::Patch::fetched(&mut txn, patch)
+    }
+
+    /// Estimate what a fetch would return, without reading any patches
+    ///
+    /// This takes the same arguments as fetch(), and returns a dict with the result's
+    /// `shape`, its number of `elements` and `bytes`, the number of `patches` it would read
+    /// and their decompressed `read_bytes`, and whether fetch() would reject it as `too_large`.
+    #[args(axes = "**")]
+    pub fn estimate(
+        &self,
+        py: Python,
+        quilt_name: &str,
+        tag: &str,
+        axes: Option<&PyDict>,
+    ) -> PyResult<PyObject> {
+        let strict = take_option(axes, "_strict", true)?;
+        let mut txn = self.inner.begin_read()?;
+        let axes_selections = parse_selections(&mut txn, quilt_name, axes, strict)?;
+        let estimate = txn.estimate_fetch(quilt_name, tag, axes_selections)?;
+        let dict = PyDict::new(py);
+        dict.set_item("shape", estimate.shape)?;
+        dict.set_item("elements", estimate.elements)?;
+        dict.set_item("patches", estimate.patches)?;
+        dict.set_item("bytes", estimate.bytes)?;
+        dict.set_item("read_bytes", estimate.read_bytes)?;
+        dict.set_item("too_large", estimate.too_large)?;
+        Ok(dict.to_object(py))
+    }
+
+    /// Estimate which labels of an axis have the most data, without reading any patches
+    ///
+    /// ```py
+    /// # The 10 items with the most sales data, with their estimated bytes
+    /// cat.top_labels("tot_sal_amt", "latest", "itm", 10)
+    /// ```
+    pub fn top_labels(
+        &self,
+        quilt_name: &str,
+        tag: &str,
+        axis_name: &str,
+        k: usize,
+    ) -> PyResult<Vec<(i64, f64)>> {
+        let mut txn = self.inner.begin_read()?;
+        Ok(txn.top_labels(quilt_name, tag, axis_name, k)?)
+    }
+
     /// Commit a patch to the catalog
     ///
     /// ```py
@@ -146,7 +502,12 @@ impl Catalog {
     ///     message = "Elements have been satisfactorily frobnicated",
     ///     patch
     /// )
+    /// # Every patch is checked first, and if any don't fit the quilt, the error lists them all.
+    /// # With allow_partial, the rest are committed, and it returns the [(index, problem)] of
+    /// # the patches left out.
+    /// rejected = cat.commit("tot_sal_amt", message="...", patches=patches, allow_partial=True)
     ///```
+    #[args(allow_partial = "false")]
     pub fn commit(
         &self,
         quilt_name: &str,
@@ -154,17 +515,50 @@ impl Catalog {
         new_tag: Option<&str>,
         message: &str,
         patches: Vec<&crate::python::Patch>,
-    ) -> PyResult<()> {
+        allow_partial: bool,
+    ) -> PyResult<Vec<(usize, String)>> {
         let mut txn = self.inner.begin()?;
-        txn.create_commit(
+        let report = txn.create_commit_report(
             &quilt_name,
             parent_tag.unwrap_or("latest"),
             new_tag.unwrap_or("latest"),
             &message,
             &patches.iter().map(|p| &p.inner).collect_vec(),
+            allow_partial,
         )?;
+        if !allow_partial && !report.rejected.is_empty() {
+            return Err(PyErr::new::<pyo3::exceptions::ValueError, _>(format!(
+                "{} of {} patches can't be committed:\n{}",
+                report.rejected.len(),
+                patches.len(),
+                report
+                    .rejected
+                    .iter()
+                    .map(|(ix, problem)| format!("patch {}: {}", ix, problem))
+                    .join("\n")
+            )));
+        }
         txn.finish()?;
-        Ok(())
+        Ok(report.rejected)
+    }
+
+    /// Draw the commits of a quilt and its tags, as "dot" for Graphviz or as "json"
+    ///
+    /// ```py
+    /// open("sales.dot", "w").write(cat.export_history("tot_sal_amt", "dot"))
+    /// ```
+    #[args(format = "\"dot\"")]
+    pub fn export_history(&self, quilt_name: &str, format: &str) -> PyResult<String> {
+        let format = match format {
+            "dot" => crate::HistoryFormat::Dot,
+            "json" => crate::HistoryFormat::Json,
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::ValueError, _>(
+                    "the history format must be \"dot\" or \"json\"",
+                ))
+            }
+        };
+        Ok(self.inner.export_history(quilt_name, format)?)
     }
 
     /// Untag a commit, to "delete" it
@@ -172,9 +566,12 @@ impl Catalog {
     /// Untagging a commit doesn't remove its effects, it only makes it inaccessible
     /// and allows (now or any time in the future) for the library to:
     ///
-    /// - Merge it into its successots, if it has any
+    /// - Merge it into its successors, if it has any
     /// - Garbage collect it otherwise
     pub fn untag(&self, quilt_name: String, tag: String) -> PyResult<()> {
-        Ok(self.inner.untag(&quilt_name, &tag)?)
+        let mut txn = self.inner.begin()?;
+        txn.untag(&quilt_name, &tag)?;
+        txn.finish()?;
+        Ok(())
     }
 }
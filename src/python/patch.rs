@@ -1,10 +1,34 @@
+use crate::{StorageTransaction, MEASURE_AXIS};
 use numpy::{IntoPyArray, PyArray1, PyArrayDyn};
 use pyo3::prelude::*;
-use pyo3::types::PyList;
+use pyo3::types::{PyAny, PyDict, PyList};
+use pyo3::{PyMappingProtocol, PyObjectProtocol};
+use std::collections::HashMap;
 
 #[pyclass]
 pub struct Patch {
     pub inner: crate::Patch,
+    /// The labels of the "measure" axis by name, if it was fetched from a catalog with one
+    pub measures: HashMap<String, crate::Label>,
+}
+impl Patch {
+    /// Wrap a patch fetched in a transaction, which knows the names of its measures
+    pub fn fetched<T: StorageTransaction>(txn: &mut T, inner: crate::Patch) -> PyResult<Self> {
+        Ok(Self::with_measures(txn, inner)?)
+    }
+
+    /// Like fetched(), but without Python errors, so it can run outside of Python
+    pub fn with_measures<T: StorageTransaction>(
+        txn: &mut T,
+        inner: crate::Patch,
+    ) -> crate::Fallible<Self> {
+        let measures = if inner.axes().iter().any(|a| a.name == MEASURE_AXIS) {
+            txn.get_measures()?.into_iter().collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Patch { inner, measures })
+    }
 }
 
 #[pymethods]
@@ -21,6 +45,7 @@ impl Patch {
                 axes.into_iter().map(|ax| ax.inner.clone()).collect(),
                 Some(content.as_array().to_owned()),
             )?,
+            measures: HashMap::new(),
         });
         Ok(())
     }
@@ -36,20 +61,175 @@ impl Patch {
         let axes: Vec<&super::Axis> = axes.extract()?;
         Ok(Self {
             inner: crate::Patch::new(axes.into_iter().map(|ax| ax.inner.clone()).collect(), None)?,
+            measures: HashMap::new(),
+        })
+    }
+
+    /// Create a new patch from the coordinates of each value
+    ///
+    /// ```py
+    /// pat = Patch.from_coo(
+    ///     axes = {"itm": np.array([1, 1, 2]), "day": np.array([720, 721, 720])},
+    ///     values = np.array([1.5, 2.5, 3.5], dtype=np.float32),
+    /// )
+    /// ```
+    ///
+    /// The labels of each axis are deduplicated in the order they first appear, and the axes
+    /// are in the order of the dict. Labels must be int64, and values float32.
+    #[staticmethod]
+    pub fn from_coo(axes: &PyDict, values: &PyArray1<f32>) -> PyResult<Self> {
+        let mut coords = vec![];
+        for (name, labels) in axes.iter() {
+            let labels: &PyArray1<i64> = labels.extract()?;
+            coords.push((
+                name.extract::<String>()?,
+                labels.as_array().iter().copied().collect(),
+            ));
+        }
+        let values = values.as_array().iter().copied().collect::<Vec<f32>>();
+        Ok(Self {
+            inner: crate::Patch::from_coo(coords, &values)?,
+            measures: HashMap::new(),
         })
     }
 
     /// Export this patch to a list of axes and a content array
     ///
     /// This copies the content to prevent mutation, so it's not very efficient.
-    pub fn export<'py>(&self, py: Python<'py>) -> (Vec<&'py PyArray1<i64>>, Py<PyArrayDyn<f32>>) {
-        (
-            self.inner
-                .axes()
-                .iter()
-                .map(|a| PyArray1::from_slice(py, a.labels()))
-                .collect(),
-            self.inner.to_dense().into_pyarray(py).to_owned(),
-        )
+    /// Cells without data are NAN, unless you choose another way to show them:
+    ///
+    /// ```py
+    /// axes, content = pat.export()                        # <- NAN where there's no data
+    /// axes, content = pat.export(fill=0)                  # <- 0 where there's no data
+    /// axes, content = pat.export(mask="masked")           # <- a numpy.ma.MaskedArray
+    /// axes, content, valid = pat.export(mask="separate")  # <- valid is True where there's data
+    /// ```
+    ///
+    /// The fill value is used for the content under the mask too, if there is one.
+    #[args(fill = "None", mask = "\"nan\"")]
+    pub fn export(&self, py: Python, fill: Option<f32>, mask: &str) -> PyResult<PyObject> {
+        let axes = self
+            .inner
+            .axes()
+            .iter()
+            .map(|a| PyArray1::from_slice(py, a.labels()))
+            .collect::<Vec<_>>();
+        let mut content = self.inner.to_dense();
+        let missing = content.mapv(f32::is_nan);
+        if let Some(fill) = fill {
+            content.mapv_inplace(|v| if v.is_nan() { fill } else { v });
+        }
+        let content = content.into_pyarray(py);
+        Ok(match mask {
+            "nan" => (axes, content).to_object(py),
+            "masked" => {
+                let masked = py
+                    .import("numpy.ma")?
+                    .call1("masked_array", (content, missing.into_pyarray(py)))?;
+                (axes, masked).to_object(py)
+            }
+            "separate" => {
+                let valid = missing.mapv(|m| !m).into_pyarray(py);
+                (axes, content, valid).to_object(py)
+            }
+            _ => {
+                return Err(PyErr::new::<pyo3::exceptions::ValueError, _>(format!(
+                    "mask should be \"nan\", \"masked\" or \"separate\", not {:?}",
+                    mask
+                )))
+            }
+        })
+    }
+
+    /// The length of each axis, without the padding used internally
+    #[getter]
+    pub fn shape(&self) -> Vec<usize> {
+        self.inner.axes().iter().map(|a| a.len()).collect()
+    }
+
+    /// The number of axes
+    #[getter]
+    pub fn ndim(&self) -> usize {
+        self.inner.ndim()
+    }
+
+    /// The names of the axes, in order
+    #[getter]
+    pub fn axis_names(&self) -> Vec<String> {
+        self.inner.axes().iter().map(|a| a.name.clone()).collect()
+    }
+
+    /// The labels of one axis, by name
+    ///
+    /// This copies only that axis, unlike export()
+    pub fn labels<'py>(&self, py: Python<'py>, axis_name: &str) -> PyResult<&'py PyArray1<i64>> {
+        match self.inner.axes().iter().find(|a| a.name == axis_name) {
+            Some(axis) => Ok(PyArray1::from_slice(py, axis.labels())),
+            None => Err(PyErr::new::<pyo3::exceptions::KeyError, _>(format!(
+                "the patch has no axis named {}",
+                axis_name
+            ))),
+        }
+    }
+
+    /// Get one value by its label on each axis, or None if a label isn't in the patch
+    ///
+    /// ```py
+    /// pat.get([1, 720])        # <- itm 1, day 720
+    /// pat.set([1, 720], 2.5)
+    /// ```
+    pub fn get(&self, labels: Vec<i64>) -> Option<f32> {
+        self.inner.get(&labels)
+    }
+
+    /// Set one value by its label on each axis, in place, see get()
+    pub fn set(&mut self, labels: Vec<i64>, value: f32) -> PyResult<()> {
+        Ok(self.inner.set(&labels, value)?)
+    }
+
+    /// Replace every NAN with a value, in place, like pandas' fillna()
+    pub fn fill_nan(&mut self, value: f32) {
+        self.inner.fill_nan(value)
+    }
+
+    /// Replace NANs with the last value before them along an axis, in place, like ffill()
+    pub fn fill_forward(&mut self, axis_name: &str) -> PyResult<()> {
+        Ok(self.inner.fill_forward(axis_name)?)
+    }
+
+    /// Replace NANs by linear interpolation between labels along an axis, in place
+    pub fn interpolate(&mut self, axis_name: &str) -> PyResult<()> {
+        Ok(self.inner.interpolate(axis_name)?)
+    }
+}
+#[pyproto]
+impl PyObjectProtocol for Patch {
+    /// Summarize the axes, how much of the patch is NAN, and the range of the rest
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(self.inner.to_string())
+    }
+}
+#[pyproto]
+impl PyMappingProtocol for Patch {
+    /// Get one measure of every cell as an array, like `patch["flag"]`, without the measure axis
+    ///
+    /// Measures are named by Catalog.declare_measures(), but a label works too, like `patch[1]`.
+    fn __getitem__(&self, key: &PyAny) -> PyResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let label = match key.extract::<String>() {
+            Ok(name) => match self.measures.get(&name) {
+                Some(&label) => label,
+                None => {
+                    return Err(PyErr::new::<pyo3::exceptions::KeyError, _>(format!(
+                        "the patch has no measure named {}",
+                        name
+                    )))
+                }
+            },
+            Err(_) => key.extract()?,
+        };
+        let slice = self.inner.slice_label(MEASURE_AXIS, label)?;
+        Ok(slice.to_dense().into_pyarray(py).to_object(py))
     }
 }
@@ -0,0 +1,229 @@
+use crate::{Fallible, StoiError, StorageTransaction};
+use itertools::Itertools;
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyDict};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+
+/// Something to run in the database transaction of a Transaction
+type Job = Box<dyn FnOnce(&mut crate::Transaction<'_>) + Send>;
+
+/// What the thread holding a Transaction's database transaction should do next
+enum Request {
+    Run(Job),
+    Finish(mpsc::Sender<Fallible<()>>),
+    Rollback(mpsc::Sender<Fallible<()>>),
+}
+
+/// Several commits, written all at once or not at all
+///
+/// Fetches in the transaction see its own commits, even though they aren't written yet:
+///
+/// ```py
+/// with cat.transaction() as txn:
+///     txn.commit("tot_sal_amt", message="...", patches=[patch])
+///     txn.fetch("tot_sal_amt", "latest")  # <- Includes patch
+///     raise Exception("on second thought")   # <- Nothing is written
+/// ```
+///
+/// The commits are written when the `with` block ends, unless it ends with an exception.
+/// Without a `with` block, call finish() or rollback() yourself.
+///
+/// This is one write transaction on the catalog from when it's created until it finishes,
+/// like Catalog::begin() in Rust, so it doesn't see other writers and they wait for it.
+/// Other calls on the same catalog wait too, so fetch through the transaction inside it,
+/// and keep transactions short.
+#[pyclass]
+pub struct Transaction {
+    /// Requests for the thread holding the database transaction, or None once it's finished
+    /// or rolled back
+    requests: Arc<Mutex<Option<mpsc::Sender<Request>>>>,
+}
+impl Transaction {
+    /// Begin a write transaction, held by a thread of its own until it finishes
+    ///
+    /// Database transactions belong to the thread that began them, while Python could use
+    /// this from anywhere, so every call is sent to that thread.
+    pub fn begin(catalog: crate::Catalog) -> Fallible<Self> {
+        let (requests, incoming) = mpsc::channel();
+        let (began, beginning) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut txn = match catalog.begin() {
+                Ok(txn) => {
+                    let _ = began.send(Ok(()));
+                    txn
+                }
+                Err(err) => {
+                    let _ = began.send(Err(err));
+                    return;
+                }
+            };
+            for request in incoming {
+                match request {
+                    Request::Run(job) => job(&mut txn),
+                    Request::Finish(done) => {
+                        let _ = done.send(txn.finish());
+                        return;
+                    }
+                    Request::Rollback(done) => {
+                        let _ = done.send(txn.rollback());
+                        return;
+                    }
+                }
+            }
+            // Every handle was dropped, and dropping the transaction rolls it back
+        });
+        beginning.recv().map_err(|_| Self::stopped())??;
+        Ok(Transaction {
+            requests: Arc::new(Mutex::new(Some(requests))),
+        })
+    }
+
+    /// The error when the thread holding the database transaction is gone
+    fn stopped() -> StoiError {
+        StoiError::RuntimeError("the transaction's thread stopped unexpectedly")
+    }
+
+    /// Run something in the database transaction, which is an error once it's over
+    fn run<R: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut crate::Transaction<'_>) -> Fallible<R> + Send + 'static,
+    ) -> PyResult<R> {
+        let (done, result) = mpsc::channel();
+        {
+            let requests = self.requests.lock().unwrap_or_else(|err| err.into_inner());
+            let requests = requests.as_ref().ok_or_else(Self::already_over)?;
+            requests
+                .send(Request::Run(Box::new(move |txn| {
+                    let _ = done.send(job(txn));
+                })))
+                .map_err(|_| Self::stopped())?;
+        }
+        Ok(result.recv().map_err(|_| Self::stopped())??)
+    }
+
+    /// End the database transaction, one way or the other
+    ///
+    /// Either way the transaction is over afterward, even if this fails.
+    fn end(&self, request: impl FnOnce(mpsc::Sender<Fallible<()>>) -> Request) -> PyResult<()> {
+        let requests = self
+            .requests
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take()
+            .ok_or_else(Self::already_over)?;
+        let (done, result) = mpsc::channel();
+        requests
+            .send(request(done))
+            .map_err(|_| Self::stopped())?;
+        Ok(result.recv().map_err(|_| Self::stopped())??)
+    }
+
+    /// The error for using a transaction after it's finished or rolled back
+    fn already_over() -> PyErr {
+        PyErr::new::<pyo3::exceptions::ValueError, _>(
+            "the transaction is already finished or rolled back",
+        )
+    }
+}
+
+#[pymethods]
+impl Transaction {
+    /// Fetch a patch, including the commits made in this transaction so far
+    ///
+    /// This takes the same arguments as Catalog.fetch()
+    #[args(axes = "**")]
+    pub fn fetch(
+        &self,
+        quilt_name: &str,
+        tag: &str,
+        axes: Option<&PyDict>,
+    ) -> PyResult<super::Patch> {
+        let strict = super::take_option(axes, "_strict", true)?;
+        let ordered = super::take_option(axes, "_ordered", false)?;
+        let named = super::parse_named_selections(axes)?;
+        let quilt_name = quilt_name.to_string();
+        let tag = tag.to_string();
+        self.run(move |txn| {
+            let patch = if ordered {
+                txn.fetch_named(&quilt_name, tag.as_str(), named, strict)?
+            } else {
+                let axes_selections = txn.order_named_request(&quilt_name, named, strict)?;
+                txn.fetch(&quilt_name, tag.as_str(), axes_selections)?
+            };
+            super::Patch::with_measures(txn, patch)
+        })
+    }
+
+    /// Commit patches as part of this transaction
+    ///
+    /// This takes the same arguments as Catalog.commit(), except allow_partial.
+    /// The patches are checked right away, so a bad one raises an error here rather than when
+    /// the transaction finishes, and the transaction goes on without it.
+    pub fn commit(
+        &self,
+        quilt_name: &str,
+        parent_tag: Option<&str>,
+        new_tag: Option<&str>,
+        message: &str,
+        patches: Vec<&super::Patch>,
+    ) -> PyResult<()> {
+        let quilt_name = quilt_name.to_string();
+        let parent_tag = parent_tag.unwrap_or("latest").to_string();
+        let new_tag = new_tag.unwrap_or("latest").to_string();
+        let message = message.to_string();
+        let patches = patches.iter().map(|p| p.inner.clone()).collect_vec();
+        self.run(move |txn| {
+            // Leave it out again if it doesn't work
+            txn.savepoint("python_commit")?;
+            let result = txn.create_commit(
+                &quilt_name,
+                &parent_tag,
+                &new_tag,
+                &message,
+                &patches.iter().collect_vec(),
+            );
+            if result.is_err() {
+                txn.rollback_to("python_commit")?;
+            }
+            txn.release("python_commit")?;
+            result
+        })
+    }
+
+    /// Write all the commits of this transaction at once
+    pub fn finish(&self) -> PyResult<()> {
+        self.end(Request::Finish)
+    }
+
+    /// Forget all the commits of this transaction, without writing any of them
+    pub fn rollback(&self) -> PyResult<()> {
+        self.end(Request::Rollback)
+    }
+
+    /// Use the transaction in a `with` block, which finishes or rolls back at the end
+    pub fn __enter__(&self) -> Transaction {
+        Transaction {
+            requests: self.requests.clone(),
+        }
+    }
+
+    /// Finish the transaction at the end of a `with` block, or roll back if there's an exception
+    ///
+    /// If it was already finished or rolled back inside the block, there's nothing left to do.
+    pub fn __exit__(&self, ty: &PyAny, _value: &PyAny, _traceback: &PyAny) -> PyResult<bool> {
+        let open = self
+            .requests
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .is_some();
+        if open {
+            if ty.is_none() {
+                self.finish()?;
+            } else {
+                self.rollback()?;
+            }
+        }
+        Ok(false)
+    }
+}
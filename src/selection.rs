@@ -0,0 +1,293 @@
+use crate::{Axis, AxisSelection, Fallible, Label, StoiError};
+use chrono::Datelike;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+/// Combining selections of the same axis
+///
+/// Selections are resolved against an axis into segments of storage indices, which are
+/// combined and then turned back into the simplest selection that means the same thing.
+/// Labels that aren't in the axis can't be stored, so they are dropped along the way.
+impl AxisSelection {
+    /// Resolve a selection into sorted, disjoint, non-adjacent storage segments
+    ///
+    /// Each segment is (start, end) where end is exclusive, the same as StorageSlice.
+    pub fn segments(&self, axis: &Axis) -> Fallible<Vec<(usize, usize)>> {
+        let segments = match self {
+            AxisSelection::All => vec![(0, axis.len())],
+            AxisSelection::LabelSlice(start, end) => vec![axis.label_slice(*start, *end)?],
+            AxisSelection::LabelRange(start, end) => axis
+                .label_range(*start, *end)
+                .into_iter()
+                .map(|ix| (ix, ix + 1))
+                .collect(),
+            AxisSelection::StorageSlice(start_ix, end_ix) => {
+                if start_ix > end_ix || *end_ix > axis.len() {
+                    return Err(StoiError::InvalidValue(
+                        "storage slice ends before it starts, or after the end of the axis",
+                    ));
+                }
+                vec![(*start_ix, *end_ix)]
+            }
+            AxisSelection::Labels(labels) => {
+                let index: HashMap<Label, usize> = axis
+                    .labels()
+                    .iter()
+                    .enumerate()
+                    .map(|(ix, &label)| (label, ix))
+                    .collect();
+                let mut indices = labels
+                    .iter()
+                    .filter_map(|label| index.get(label).copied())
+                    .collect_vec();
+                indices.sort_unstable();
+                indices.into_iter().map(|ix| (ix, ix + 1)).collect()
+            }
+        };
+        Ok(Self::merge_segments(segments))
+    }
+
+    /// The simplest selection of the same labels of an axis
+    ///
+    /// That is All if it covers the whole axis, a StorageSlice if it's contiguous, and
+    /// otherwise the Labels in storage order.
+    pub fn normalize(&self, axis: &Axis) -> Fallible<AxisSelection> {
+        Ok(Self::from_segments(&self.segments(axis)?, axis))
+    }
+
+    /// Select only the labels selected by both
+    pub fn intersect(&self, other: &AxisSelection, axis: &Axis) -> Fallible<AxisSelection> {
+        let left = self.segments(axis)?;
+        let right = other.segments(axis)?;
+        let mut both = vec![];
+        let (mut li, mut ri) = (0, 0);
+        while li < left.len() && ri < right.len() {
+            let start = left[li].0.max(right[ri].0);
+            let end = left[li].1.min(right[ri].1);
+            if start < end {
+                both.push((start, end));
+            }
+            // Advance whichever ends first, since it can't overlap anything else
+            if left[li].1 < right[ri].1 {
+                li += 1;
+            } else {
+                ri += 1;
+            }
+        }
+        Ok(Self::from_segments(&both, axis))
+    }
+
+    /// Select the labels selected by either
+    pub fn union(&self, other: &AxisSelection, axis: &Axis) -> Fallible<AxisSelection> {
+        let mut either = self.segments(axis)?;
+        either.extend(other.segments(axis)?);
+        either.sort_unstable();
+        Ok(Self::from_segments(&Self::merge_segments(either), axis))
+    }
+
+    /// Select the labels of the axis that this doesn't select
+    pub fn complement(&self, axis: &Axis) -> Fallible<AxisSelection> {
+        let mut rest = vec![];
+        let mut cursor = 0;
+        for (start, end) in self.segments(axis)? {
+            if cursor < start {
+                rest.push((cursor, start));
+            }
+            cursor = end;
+        }
+        if cursor < axis.len() {
+            rest.push((cursor, axis.len()));
+        }
+        Ok(Self::from_segments(&rest, axis))
+    }
+
+    /// Join sorted segments that overlap or touch, and drop empty ones
+    fn merge_segments(segments: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (start, end) in segments.into_iter().filter(|(start, end)| start < end) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    /// The simplest selection of some merged segments
+    fn from_segments(segments: &[(usize, usize)], axis: &Axis) -> AxisSelection {
+        match segments {
+            [(0, end)] if *end == axis.len() => AxisSelection::All,
+            [(start, end)] => AxisSelection::StorageSlice(*start, *end),
+            _ => AxisSelection::Labels(
+                segments
+                    .iter()
+                    .flat_map(|&(start, end)| axis.labels()[start..end].iter().copied())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Selections written as text, for quick ad-hoc fetches
+impl AxisSelection {
+    /// Parse selections of several axes, like "itm=1,2,3; day=2021-01-01..2021-03-31; lct=*"
+    ///
+    /// Clauses are separated by semicolons, and each is an axis name, "=", and a selection
+    /// (see parse()). Each axis can only be named once. Whitespace is ignored. The result can
+    /// be put in order for fetch() with StorageTransaction::order_named_request().
+    pub fn parse_request(text: &str) -> Fallible<Vec<(String, AxisSelection)>> {
+        let mut request: Vec<(String, AxisSelection)> = vec![];
+        for clause in text.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+            let eq = clause.find('=').ok_or_else(|| {
+                StoiError::InvalidSelection(format!("expected axis=selection, not {:?}", clause))
+            })?;
+            let axis_name = clause[..eq].trim();
+            if axis_name.is_empty() {
+                return Err(StoiError::InvalidSelection(format!(
+                    "{:?} doesn't name an axis",
+                    clause
+                )));
+            }
+            if request.iter().any(|(name, _)| name == axis_name) {
+                return Err(StoiError::InvalidSelection(format!(
+                    "the axis {} is selected more than once",
+                    axis_name
+                )));
+            }
+            let selection = Self::parse(&clause[eq + 1..])?;
+            request.push((axis_name.to_string(), selection));
+        }
+        Ok(request)
+    }
+
+    /// Parse the selection of one axis, which is one of:
+    ///
+    /// - "*", for every label (All)
+    /// - labels separated by commas, like "1,2,3" (Labels)
+    /// - two labels separated by "..", like "10..20", for every label between them by value,
+    ///   inclusive (LabelRange)
+    ///
+    /// Labels are integers, or dates like 2021-03-31, which are the label 20210331.
+    pub fn parse(text: &str) -> Fallible<AxisSelection> {
+        let text = text.trim();
+        if text == "*" {
+            return Ok(AxisSelection::All);
+        }
+        if let Some(dots) = text.find("..") {
+            let start = parse_label(&text[..dots])?;
+            let end = parse_label(&text[dots + 2..])?;
+            return Ok(AxisSelection::LabelRange(start, end));
+        }
+        Ok(AxisSelection::Labels(
+            text.split(',').map(parse_label).collect::<Fallible<_>>()?,
+        ))
+    }
+}
+
+/// Parse one label, which is an integer or a date like 2021-03-31 (meaning 20210331)
+fn parse_label(text: &str) -> Fallible<Label> {
+    let text = text.trim();
+    if let Ok(label) = text.parse::<Label>() {
+        return Ok(label);
+    }
+    match chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(date) => {
+            let (year, month, day) = (date.year() as Label, date.month(), date.day());
+            Ok(year * 10000 + (month * 100 + day) as Label)
+        }
+        Err(_) => Err(StoiError::InvalidSelection(format!(
+            "{:?} is neither an integer label nor a date like 2021-03-31",
+            text
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Axis, AxisSelection};
+
+    #[test]
+    fn test_selection_normalize() {
+        let axis = Axis::new("itm", vec![5, 3, 9, 1, 7]).unwrap();
+        let norm = |sel: AxisSelection| sel.normalize(&axis).unwrap();
+        assert_eq!(norm(AxisSelection::LabelSlice(5, 7)), AxisSelection::All);
+        assert_eq!(
+            norm(AxisSelection::Labels(vec![1, 9, 9])),
+            AxisSelection::StorageSlice(2, 4)
+        );
+        // Labels come out in storage order, without labels the axis doesn't have
+        assert_eq!(
+            norm(AxisSelection::Labels(vec![7, 2, 5])),
+            AxisSelection::Labels(vec![5, 7])
+        );
+        assert_eq!(
+            norm(AxisSelection::StorageSlice(1, 1)),
+            AxisSelection::Labels(vec![])
+        );
+        // Ranges are by value, wherever the labels are stored
+        assert_eq!(
+            norm(AxisSelection::LabelRange(4, 8)),
+            AxisSelection::Labels(vec![5, 7])
+        );
+        assert!(AxisSelection::StorageSlice(3, 6).normalize(&axis).is_err());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            AxisSelection::parse_request("itm=1,2,3; day = 2021-01-01..2021-03-31; lct=*;")
+                .unwrap();
+        assert_eq!(
+            request,
+            vec![
+                ("itm".to_string(), AxisSelection::Labels(vec![1, 2, 3])),
+                (
+                    "day".to_string(),
+                    AxisSelection::LabelRange(20210101, 20210331)
+                ),
+                ("lct".to_string(), AxisSelection::All),
+            ]
+        );
+        assert_eq!(
+            AxisSelection::parse("-5..5").unwrap(),
+            AxisSelection::LabelRange(-5, 5)
+        );
+        assert!(AxisSelection::parse_request("itm").is_err());
+        assert!(AxisSelection::parse_request("itm=1; itm=2").is_err());
+        assert!(AxisSelection::parse("1,two").is_err());
+        assert!(AxisSelection::parse("2021-02-30").is_err());
+    }
+
+    #[test]
+    fn test_selection_algebra() {
+        let axis = Axis::new("itm", vec![5, 3, 9, 1, 7]).unwrap();
+        let first = AxisSelection::LabelSlice(5, 9);
+        let second = AxisSelection::Labels(vec![9, 7]);
+        assert_eq!(
+            first.intersect(&second, &axis).unwrap(),
+            AxisSelection::StorageSlice(2, 3)
+        );
+        assert_eq!(
+            first.union(&second, &axis).unwrap(),
+            AxisSelection::Labels(vec![5, 3, 9, 7])
+        );
+        assert_eq!(
+            first.complement(&axis).unwrap(),
+            AxisSelection::StorageSlice(3, 5)
+        );
+        assert_eq!(
+            second.complement(&axis).unwrap(),
+            AxisSelection::Labels(vec![5, 3, 1])
+        );
+        assert_eq!(
+            AxisSelection::All.complement(&axis).unwrap(),
+            AxisSelection::Labels(vec![])
+        );
+        assert_eq!(
+            first
+                .union(&first.complement(&axis).unwrap(), &axis)
+                .unwrap(),
+            AxisSelection::All
+        );
+    }
+}
@@ -1,41 +1,332 @@
-use crate::catalog::{StorageConnection, StorageTransaction};
-use crate::patch::PatchCompressionType;
+use crate::catalog::{
+    boxes_overlap, overlap_ratio, split_aligned, AuditEntry, CatalogOptions, CatalogSnapshot,
+    CommitChanges, CommitRules, CompiledCommitRules, CounterBreakdown, Derivation,
+    DerivedQuiltStatus, DerivedWork, HistoryCommit, NamespaceConfig, PatchAccess,
+    PerformanceCounters, PreparedPatch, QuiltIntegers, QuiltRetention, SkippedPatch,
+    StorageConnection, StorageTransaction, WriteAmplificationLimit, APPEND_BLOCK_WIDTH,
+    DEFAULT_PATCH_COMPRESSION, DEFAULT_TARGET_PATCH_BYTES, MIN_MERGE_OVERLAP,
+};
+use crate::patch::{PatchCompressionType, PatchQuantization, PATCH_VERSION};
+use crate::patchset::PatchSet;
 use crate::{
-    Axis, AxisSelection, BoundingBox, Counter, Fallible, Patch, PatchID, PatchRef, QuiltDetails,
-    StoiError,
+    Axis, AxisSelection, BoundingBox, BufferPool, CommitStats, Counter, Fallible, Label,
+    LabelIndex, Patch, PatchID, PatchIntervals, PatchRef, QuiltDetails, QuiltQuota, QuiltUnits,
+    QuiltUsage, SortedLabelIndex, StoiError, MEASURE_AXIS,
 };
 use itertools::Itertools;
-use rusqlite::{OptionalExtension, ToSql, NO_PARAMS};
-use std::collections::HashMap;
+use rusqlite::types::ValueRef;
+use rusqlite::{OpenFlags, OptionalExtension, ToSql, NO_PARAMS};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 use enum_map::EnumMap;
 
+/// One in this many reads of patches is sampled, see StorageTransaction::get_coldest_patches()
+const ACCESS_SAMPLE_RATE: u32 = 16;
+
+/// The catalog_id of the next connection, see StorageTransaction::get_catalog_id()
+static NEXT_CATALOG_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Sampled reads of patches, with when each was last read, in RFC 3339 format
+type AccessSamples = HashMap<PatchID, (u64, String)>;
+
+/// Add sampled reads of a patch to others, keeping the latest time it was read
+fn merge_access_sample(samples: &mut AccessSamples, id: PatchID, sample: (u64, String)) {
+    let (reads, last_read) = samples.entry(id).or_insert((0, String::new()));
+    *reads += sample.0;
+    if sample.1 > *last_read {
+        *last_read = sample.1;
+    }
+}
+
 /// An implementation of tensor storage on SQLite
 pub(crate) struct SQLiteConnection {
     conn: Mutex<rusqlite::Connection>,
+    counters: Arc<PerformanceCounters>,
+    pub(crate) target_patch_bytes: AtomicUsize,
+    pub(crate) patch_compression: Mutex<PatchCompressionType>,
+    pub(crate) write_amplification: Mutex<WriteAmplificationLimit>,
+    recovery: bool,
+    max_read_bytes: Option<u64>,
+    skip_unreadable: bool,
+    patchset_bytes: usize,
+    read_only: bool,
+    actor: Option<String>,
+    catalog_id: i64,
+    closed: AtomicBool,
+    /// Reads sampled by transactions that couldn't save them, for the next write to save
+    access_backlog: Mutex<AccessSamples>,
+    pub(crate) pool: BufferPool,
+    /// Where a replica's file is, if this is one, see Catalog::sync_replica()
+    replica: Option<ReplicaFile>,
+    max_replica_lag: Option<Duration>,
+}
+
+/// A replica's file, and the version of it that is open
+struct ReplicaFile {
+    path: PathBuf,
+    /// When the file was modified, as of when it was opened
+    modified: Mutex<Option<SystemTime>>,
 }
+
 impl SQLiteConnection {
     /// Create an in-memory SQLite database.
     ///
     /// Each connection creates a new database.
-    pub fn connect_in_memory() -> Fallible<Arc<Self>> {
-        Self::connect(":memory:".into())
+    pub fn connect_in_memory(
+        counters: Arc<PerformanceCounters>,
+        options: &CatalogOptions,
+    ) -> Fallible<Arc<Self>> {
+        Self::connect(":memory:".into(), counters, options)
     }
 
     /// Connect to an SQLite database
     ///
     /// SQLite treats the path ":memory:" as special and will only create an in-memory database
     /// in that case. See SQLite documentation for more details
-    pub fn connect(base: PathBuf) -> Fallible<Arc<Self>> {
-        let conn = rusqlite::Connection::open(base)?;
+    ///
+    /// Every transaction adds its performance counters to `counters` when it ends.
+    pub fn connect(
+        base: PathBuf,
+        counters: Arc<PerformanceCounters>,
+        options: &CatalogOptions,
+    ) -> Fallible<Arc<Self>> {
+        let flags = if options.read_only {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        } else if options.create_if_missing {
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        };
+        let conn = rusqlite::Connection::open_with_flags(base, flags | OpenFlags::SQLITE_OPEN_URI)?;
         conn.busy_timeout(std::time::Duration::from_secs(5))?;
-        conn.execute_batch(include_str!("sqlite_catalog_schema.sql"))?;
+        if !options.read_only {
+            // A read only catalog can't be migrated, so it had better already be there
+            conn.execute_batch(include_str!("sqlite_catalog_schema.sql"))?;
+        }
+        Ok(Arc::new(Self {
+            conn: Mutex::new(conn),
+            counters,
+            target_patch_bytes: AtomicUsize::new(DEFAULT_TARGET_PATCH_BYTES),
+            patch_compression: Mutex::new(DEFAULT_PATCH_COMPRESSION),
+            write_amplification: Mutex::new(options.write_amplification),
+            recovery: options.recovery,
+            max_read_bytes: options.max_read_bytes,
+            skip_unreadable: options.skip_unreadable,
+            patchset_bytes: options.patchset_bytes,
+            read_only: options.read_only,
+            actor: options.actor.clone(),
+            catalog_id: NEXT_CATALOG_ID.fetch_add(1, Ordering::Relaxed) as i64,
+            closed: AtomicBool::new(false),
+            access_backlog: Mutex::new(HashMap::new()),
+            pool: BufferPool::new(options.buffer_pool_bytes),
+            replica: None,
+            max_replica_lag: None,
+        }))
+    }
+
+    /// Open a replica of another catalog, which must already exist, only for reading
+    ///
+    /// See Catalog::sync_replica() for how replicas are updated.
+    pub fn connect_replica(
+        path: PathBuf,
+        counters: Arc<PerformanceCounters>,
+        options: &CatalogOptions,
+    ) -> Fallible<Arc<Self>> {
+        let conn = Self::open_replica_file(&path)?;
+        let modified = std::fs::metadata(&path)?.modified().ok();
         Ok(Arc::new(Self {
             conn: Mutex::new(conn),
+            counters,
+            target_patch_bytes: AtomicUsize::new(DEFAULT_TARGET_PATCH_BYTES),
+            patch_compression: Mutex::new(DEFAULT_PATCH_COMPRESSION),
+            write_amplification: Mutex::new(options.write_amplification),
+            recovery: options.recovery,
+            max_read_bytes: options.max_read_bytes,
+            skip_unreadable: options.skip_unreadable,
+            patchset_bytes: options.patchset_bytes,
+            read_only: true,
+            actor: options.actor.clone(),
+            catalog_id: NEXT_CATALOG_ID.fetch_add(1, Ordering::Relaxed) as i64,
+            closed: AtomicBool::new(false),
+            access_backlog: Mutex::new(HashMap::new()),
+            pool: BufferPool::new(options.buffer_pool_bytes),
+            replica: Some(ReplicaFile {
+                path,
+                modified: Mutex::new(modified),
+            }),
+            max_replica_lag: options.max_replica_lag,
         }))
     }
+
+    /// Open a replica's file as it is now
+    fn open_replica_file(path: &Path) -> Fallible<rusqlite::Connection> {
+        let conn = rusqlite::Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        Ok(conn)
+    }
+
+    /// Open a replica's file again if it was replaced since it was opened, maybe by another
+    /// process, and check it isn't too far behind
+    fn refresh_replica(&self, conn: &mut rusqlite::Connection) -> Fallible<()> {
+        let replica = match &self.replica {
+            Some(replica) => replica,
+            None => return Ok(()),
+        };
+        let modified = std::fs::metadata(&replica.path)?.modified().ok();
+        let mut opened = replica
+            .modified
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("replica file version was poisoned"))?;
+        if *opened != modified {
+            *conn = Self::open_replica_file(&replica.path)?;
+            *opened = modified;
+        }
+        if let Some(max_lag) = self.max_replica_lag {
+            let taken_at: Option<String> = conn
+                .query_row(
+                    "SELECT taken_at FROM Snapshot ORDER BY snapshot_id DESC LIMIT 1;",
+                    NO_PARAMS,
+                    |r| r.get(0),
+                )
+                .optional()?;
+            let lag = replica_lag(taken_at.as_deref())?.ok_or_else(|| {
+                StoiError::NotFound(
+                    "snapshot of the replica",
+                    replica.path.display().to_string(),
+                )
+            })?;
+            if lag > max_lag {
+                return Err(StoiError::StaleReplica(lag.as_secs()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace a replica with a fresh copy of the primary, see Catalog::sync_replica()
+    pub fn sync_replica(&self, primary: &SQLiteConnection) -> Fallible<CatalogSnapshot> {
+        let replica = self.replica.as_ref().ok_or(StoiError::InvalidValue(
+            "only replicas, connected with \"replica:\", can be synced",
+        ))?;
+        // Copy next to the replica, and then swap it in all at once, so it's never half copied
+        let staging = replica
+            .path
+            .with_extension(format!("sync-{}", rand::random::<u32>()));
+        let staging_str = staging.to_str().ok_or(StoiError::InvalidValue(
+            "the replica's path must be valid unicode",
+        ))?;
+        let snapshot = primary.backup_to(staging_str)?;
+        let mut conn = self.lock()?;
+        if let Err(err) = std::fs::rename(&staging, &replica.path) {
+            let _ = std::fs::remove_file(&staging);
+            return Err(err.into());
+        }
+        *conn = Self::open_replica_file(&replica.path)?;
+        *replica
+            .modified
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("replica file version was poisoned"))? =
+            std::fs::metadata(&replica.path)?.modified().ok();
+        Ok(snapshot)
+    }
+
+    /// Wait for the connection, like beginning a transaction does
+    fn lock(&self) -> Fallible<MutexGuard<rusqlite::Connection>> {
+        for i in 0..10 {
+            if let Ok(conn) = self.conn.try_lock() {
+                return Ok(conn);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1 << i));
+        }
+        // Another thread is using the connection, which is retryable like any other lock
+        Err(StoiError::Busy)
+    }
+
+    /// Move everything committed into the main database file
+    ///
+    /// Commits are durable anyway, but in WAL mode they can wait in the -wal file, which
+    /// matters if you're about to copy the file. Otherwise this does nothing.
+    pub fn flush(&self) -> Fallible<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoiError::Closed);
+        }
+        self.lock()?
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE);", NO_PARAMS, |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Copy the database to a new file, and record what was captured in the copy
+    pub fn backup_to(&self, path: &str) -> Fallible<CatalogSnapshot> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoiError::Closed);
+        }
+        if std::path::Path::new(path).exists() {
+            return Err(StoiError::InvalidValue(
+                "backups can't overwrite a file that already exists",
+            ));
+        }
+        let taken_at = chrono::Utc::now().to_rfc3339();
+        // This reads the whole database in one read transaction, so it's consistent
+        self.lock()?.execute("VACUUM INTO ?;", &[path])?;
+
+        // Read what the copy has, rather than the original, which may have changed since
+        let mut backup = rusqlite::Connection::open(path)?;
+        backup.execute_batch(include_str!("sqlite_catalog_schema.sql"))?;
+        let txn = backup.transaction()?;
+        let tags = txn
+            .prepare(
+                "SELECT quilt_name, tag_name, comm_id
+                FROM Tag
+                ORDER BY quilt_name, tag_name;",
+            )?
+            .query_map(NO_PARAMS, |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, i64)>>>()?;
+        let snapshot = CatalogSnapshot { taken_at, tags };
+        txn.execute(
+            "INSERT INTO Snapshot(taken_at, tags) VALUES (?, ?);",
+            &[&snapshot.taken_at, &serde_json::to_string(&snapshot.tags)?],
+        )?;
+        txn.commit()?;
+        Ok(snapshot)
+    }
+
+    /// Close the database, so new transactions fail with StoiError::Closed
+    ///
+    /// This waits for a transaction in progress in another thread to end, and it can't finish,
+    /// so it's rolled back. Closing twice is fine.
+    pub fn close(&self) -> Fallible<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        let mut conn = self.lock()?;
+        if !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK;")?;
+        }
+        // Swap in a connection to nothing, so this one can be closed now, releasing the file
+        let old = std::mem::replace(&mut *conn, rusqlite::Connection::open_in_memory()?);
+        old.close().map_err(|(_conn, err)| err)?;
+        Ok(())
+    }
+}
+
+/// How long ago a snapshot was taken, from when it was taken in RFC 3339, if there is one
+pub(crate) fn replica_lag(taken_at: Option<&str>) -> Fallible<Option<Duration>> {
+    let taken_at = match taken_at {
+        Some(taken_at) => chrono::DateTime::parse_from_rfc3339(taken_at)
+            .map_err(|_| StoiError::InvalidValue("the snapshot's time isn't in RFC 3339"))?,
+        None => return Ok(None),
+    };
+    // A clock that went backward is no lag at all, rather than an error
+    Ok(Some(
+        (chrono::Utc::now() - taken_at.with_timezone(&chrono::Utc))
+            .to_std()
+            .unwrap_or_default(),
+    ))
 }
 
 impl<'t> StorageConnection for &'t SQLiteConnection {
@@ -43,22 +334,68 @@ impl<'t> StorageConnection for &'t SQLiteConnection {
     /// Create a new storage transaction on the database
     ///
     /// Most operations can only be done in a transaction, for correctness.
-    fn txn(self) -> Fallible<SQLiteTransaction<'t>> {
-        for i in 0..10 {
-            if let Ok(txn) = self.conn.try_lock() {
-                txn.execute_batch("BEGIN;")?;
-                return Ok(SQLiteTransaction {
-                    txn,
-                    axis_cache: HashMap::new(),
-                    trace: EnumMap::new(),
-                });
-            } else {
-                std::thread::sleep(std::time::Duration::from_millis(1 << i));
-            }
+    ///
+    /// Write transactions take SQLite's write lock immediately (BEGIN IMMEDIATE), waiting for
+    /// other writers, even in other processes, for up to the busy timeout. Read transactions
+    /// only lock when they read, so they never wait for each other, but they can't commit.
+    fn txn(self, write: bool) -> Fallible<SQLiteTransaction<'t>> {
+        let write = write && !self.read_only;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoiError::Closed);
+        }
+        let mut txn = self.lock()?;
+        // It may have closed while we waited
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoiError::Closed);
         }
-        Err(StoiError::RuntimeError(
-            "sqlite mutex could not be acquired",
-        ))
+        self.refresh_replica(&mut txn)?;
+        let patch_compression = *self
+            .patch_compression
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("patch compression setting was poisoned"))?;
+        let write_amplification = *self
+            .write_amplification
+            .lock()
+            .map_err(|_| StoiError::RuntimeError("write amplification setting was poisoned"))?;
+        // Upgrading a read lock can deadlock with another process, so reads can't write at all
+        txn.execute_batch(if write {
+            "BEGIN IMMEDIATE;"
+        } else {
+            "BEGIN; PRAGMA query_only = ON;"
+        })?;
+        Ok(SQLiteTransaction {
+            txn,
+            axis_cache: HashMap::new(),
+            axis_generations: HashMap::new(),
+            label_indices: HashMap::new(),
+            sorted_label_indices: HashMap::new(),
+            savepoints: vec![],
+            trace: EnumMap::new(),
+            trace_quilt: None,
+            trace_by_quilt: HashMap::new(),
+            trace_by_axis: HashMap::new(),
+            totals: &self.counters,
+            closed: &self.closed,
+            access_samples: HashMap::new(),
+            access_backlog: &self.access_backlog,
+            pool: &self.pool,
+            default_target_patch_bytes: self.target_patch_bytes.load(Ordering::Relaxed),
+            target_patch_bytes: self.target_patch_bytes.load(Ordering::Relaxed),
+            patch_compression,
+            write_amplification,
+            recovery: self.recovery,
+            max_read_bytes: self.max_read_bytes,
+            warnings: vec![],
+            skip_unreadable: self.skip_unreadable,
+            skipped: vec![],
+            patchset_bytes: self.patchset_bytes,
+            patchset_cache: None,
+            commit_rules: None,
+            last_id: 0,
+            write,
+            actor: self.actor.clone(),
+            catalog_id: self.catalog_id,
+        })
     }
 }
 
@@ -76,25 +413,141 @@ impl<'t> StorageConnection for &'t SQLiteConnection {
 /// Keep in mind that even if your code is correct, transactions that mutate the database
 /// can fail and may need to be retried. This happens if two transactions enter a race
 /// condition.
+///
+/// Across processes sharing a catalog file, write transactions are serialized by SQLite's
+/// file locks: a write transaction waits up to the busy timeout (5 seconds) to begin, and then
+/// nothing else can write until it ends. If it can't begin in time, the error is
+/// StoiError::Busy. Check StoiError::is_retryable() and retry the whole transaction from
+/// begin() in that case. Read transactions, from begin_read(), can't write anything, and
+/// trying to is StoiError::ReadOnly, which retrying won't help.
 #[derive(Debug)]
 pub struct SQLiteTransaction<'t> {
     txn: MutexGuard<'t, rusqlite::Connection>,
     axis_cache: HashMap<String, Axis>,
+    axis_generations: HashMap<String, u64>,
+    /// Indices of the axes in axis_cache, which are only built when they're needed
+    label_indices: HashMap<String, LabelIndex>,
+    sorted_label_indices: HashMap<String, SortedLabelIndex>,
+    /// Names of the open savepoints, from the oldest to the newest
+    savepoints: Vec<String>,
     trace: EnumMap<Counter, usize>,
+    trace_quilt: Option<String>,
+    trace_by_quilt: CounterBreakdown,
+    trace_by_axis: CounterBreakdown,
+    totals: &'t PerformanceCounters,
+    closed: &'t AtomicBool,
+    /// Reads of patches sampled in this transaction, see get_coldest_patches()
+    access_samples: AccessSamples,
+    access_backlog: &'t Mutex<AccessSamples>,
+    pool: &'t BufferPool,
+    /// The catalog's target_patch_bytes(), for quilts without their own
+    default_target_patch_bytes: usize,
+    /// The target_patch_bytes() of the quilt in trace_quilt
+    target_patch_bytes: usize,
+    patch_compression: PatchCompressionType,
+    write_amplification: WriteAmplificationLimit,
+    recovery: bool,
+    max_read_bytes: Option<u64>,
+    warnings: Vec<String>,
+    skip_unreadable: bool,
+    skipped: Vec<SkippedPatch>,
+    /// Patches smaller than this are packed into patchsets, see CatalogOptions::patchset_bytes
+    patchset_bytes: usize,
+    /// The last patchset read, since fetches usually read several patches from each one
+    patchset_cache: Option<(i64, PatchSet)>,
+    /// The catalog's CommitRules, compiled the first time a message is checked
+    commit_rules: Option<CompiledCommitRules>,
+    /// The last id gen_id() generated
+    last_id: i64,
+    write: bool,
+    actor: Option<String>,
+    catalog_id: i64,
 }
 impl<'t> SQLiteTransaction<'t> {
     /// Put patch is only safe to do inside put_commit, so it's not part of Storage
+    ///
+    /// If `content` is given, it's the patch already serialized with the current compression
+    /// and this quantization, and it's stored as is.
     fn put_patch(
         &mut self,
+        quilt_name: &str,
         comm_id: i64,
         pat: &Patch,
         bounding_box: BoundingBox,
+        quantization: PatchQuantization,
+        content: Option<Vec<u8>>,
     ) -> Fallible<PatchID> {
-        self.trace(Counter::WritePatch, 1);
-        let patch_id = PatchID(self.gen_id());
         // Note - you need to compact here, quite late, because it needs to be after the Axes are updated.
         // That's because
         let pat = pat.compact();
+        let patch_id = self.put_patch_row(comm_id, &pat, bounding_box)?;
+        // TODO: If this serialize fails it will deadlock the connection by not rolling back
+        let content = match content {
+            Some(content) => content,
+            None => {
+                let mut buffer = vec![];
+                pat.serialize_quantized_into(
+                    Some(self.patch_compression),
+                    quantization,
+                    &mut buffer,
+                )?;
+                buffer
+            }
+        };
+        self.txn.execute(
+            "INSERT OR REPLACE INTO PatchContent(patch_id, content) VALUES (?,?);",
+            &[&patch_id as &dyn ToSql, &content],
+        )?;
+        self.add_quilt_usage(quilt_name, content.len() as i64, 1)?;
+        Ok(patch_id)
+    }
+
+    /// Pack small patches into one patchset, sharing a single PatchContent row
+    ///
+    /// Each patch still gets its own Patch row, so searches don't change, and get_patch()
+    /// finds it in the patchset through PatchSetMember.
+    fn put_patchset(
+        &mut self,
+        quilt_name: &str,
+        comm_id: i64,
+        patches: Vec<(Patch, BoundingBox)>,
+        quantization: PatchQuantization,
+    ) -> Fallible<Vec<PatchID>> {
+        let patchset_id = self.gen_id();
+        let compression = self.patch_compression;
+        let mut patchset = PatchSet::default();
+        let mut patch_ids = vec![];
+        for (pat, bounding_box) in patches {
+            self.trace(Counter::PackPatch, 1);
+            let pat = pat.compact();
+            let patch_id = self.put_patch_row(comm_id, &pat, bounding_box)?;
+            patchset.push(patch_id, bounding_box, &pat, compression, quantization)?;
+            self.txn.execute(
+                "INSERT INTO PatchSetMember(patch_id, patchset_id) VALUES (?, ?);",
+                &[&patch_id as &dyn ToSql, &patchset_id],
+            )?;
+            patch_ids.push(patch_id);
+        }
+        let mut buffer = vec![];
+        patchset.serialize_into(compression, &mut buffer)?;
+        self.txn.execute(
+            "INSERT INTO PatchContent(patch_id, content) VALUES (?,?);",
+            &[&patchset_id as &dyn ToSql, &buffer],
+        )?;
+        self.add_quilt_usage(quilt_name, buffer.len() as i64, patch_ids.len() as i64)?;
+        Ok(patch_ids)
+    }
+
+    /// Add the Patch row of a patch, but not its content, see put_patch()
+    fn put_patch_row(
+        &mut self,
+        comm_id: i64,
+        pat: &Patch,
+        bounding_box: BoundingBox,
+    ) -> Fallible<PatchID> {
+        self.trace(Counter::WritePatch, 1);
+        let patch_id = PatchID(self.gen_id());
+        let bounds = bounding_box.to_sql_bounds()?;
         self.txn.execute(
             "INSERT OR REPLACE INTO Patch(
                 patch_id,
@@ -109,185 +562,187 @@ impl<'t> SQLiteTransaction<'t> {
                 &patch_id as &dyn ToSql,
                 &comm_id,
                 &(4 * pat.len() as i64),
-                &(bounding_box[0].0 as i64),
-                &(bounding_box[0].1 as i64),
-                &(bounding_box[1].0 as i64),
-                &(bounding_box[1].1 as i64),
-                &(bounding_box[2].0 as i64),
-                &(bounding_box[2].1 as i64),
-                &(bounding_box[3].0 as i64),
-                &(bounding_box[3].1 as i64),
-            ],
-        )?;
-        // TODO: If this serialize fails it will deadlock the connection by not rolling back
-        self.txn.execute(
-            "INSERT OR REPLACE INTO PatchContent(patch_id, content) VALUES (?,?);",
-            &[
-                &patch_id as &dyn ToSql,
-                &pat.serialize(Some(PatchCompressionType::LZ4 { quality: 0 }))?,
+                &bounds[0],
+                &bounds[1],
+                &bounds[2],
+                &bounds[3],
+                &bounds[4],
+                &bounds[5],
+                &bounds[6],
+                &bounds[7],
             ],
         )?;
+        // Scattered labels get tighter bounds too, but most patches are dense enough without
+        let intervals = self.get_patch_intervals(pat)?;
+        if intervals.is_fragmented() {
+            self.txn.execute(
+                "INSERT OR REPLACE INTO PatchIntervals(patch_id, intervals) VALUES (?, ?);",
+                &[&patch_id as &dyn ToSql, &serde_json::to_string(&intervals)?],
+            )?;
+        }
         Ok(patch_id)
     }
 
     /// Delete a patch
     ///
-    /// This only makes sense untagg(), and for compaction as part of a commit(),
+    /// This only makes sense in collect_garbage(), and for compaction as part of a commit(),
     /// and you can't do this from outside
 
-    fn del_patch(&mut self, patch_id: PatchID) -> Fallible<()> {
+    fn del_patch(&mut self, quilt_name: &str, patch_id: PatchID) -> Fallible<()> {
         self.trace(Counter::DeletePatch, 1);
+        let patchset_id = self.get_patchset_id(patch_id)?;
+        let mut freed_bytes = self.get_content_bytes(patch_id.0)?;
         self.txn
             .execute("DELETE FROM Patch WHERE patch_id = ?;", &[patch_id])?;
         self.txn
             .execute("DELETE FROM PatchContent WHERE patch_id = ?;", &[patch_id])?;
-        Ok(())
-    }
-
-    /// Generate an id using the time plus a small salt
-    fn gen_id(&self) -> i64 {
-        chrono::Utc::now().timestamp_nanos() + rand::random::<i16>() as i64
-    }
-}
-
-impl<'t> StorageTransaction for SQLiteTransaction<'t> {
-    /// Increment an activity counter, used for performance and correctness checking
-    fn trace(&mut self, ctr: Counter, increment: usize) {
-        self.trace[ctr] += increment;
-    }
-
-    /// Retrieve performance counters, useful for debugging performance problems
-    ///
-    /// Returns: a Map containing the counters by name
-    fn get_performance_counters(&self) -> EnumMap<Counter, usize> {
-        self.trace.clone()
-    }
-
-    /// Append labels to an axis, in the order you would expect them to be stored.
-    /// Any duplicate labels will not be appended.
-    ///
-    /// Returns true iff the axis was mutated in the process
-
-    fn union_axis(&mut self, axis: &Axis) -> Fallible<bool> {
-        let existing_labels = self.get_axis(&axis.name)?.labelset();
-
-        let mut changes = 0;
-        let mut trials = 0;
-        changes += self.txn.execute(
-            "INSERT OR IGNORE INTO Axis(axis_name) VALUES (?)",
-            &[&axis.name],
-        )?;
-        let mut stmt = self
-            .txn
-            .prepare("INSERT OR IGNORE INTO AxisContent(axis_name, label) VALUES (?,?);")?;
-        for label in axis.labels() {
-            trials += 1;
-            if !existing_labels.contains(label) {
-                changes += stmt.execute(&[&axis.name as &dyn ToSql, &label])?;
-            }
-        }
-        // Drop an immutable borrow so we can trace
-        std::mem::drop(stmt);
-        if changes > 0 {
-            // Repair the cache
-            self.axis_cache.get_mut(&axis.name).unwrap().union(&axis);
-            self.trace(Counter::WriteAxisLabel, changes);
-            self.trace(Counter::TrialAxisLabel, trials);
-        }
-        Ok(changes > 0)
-    }
-
-    /// Get all the labels of an axis, in the order you would expect them to be stored
-    fn get_axis(&mut self, axis_name: &str) -> Fallible<&Axis> {
-        if !self.axis_cache.contains_key(axis_name) {
-            self.trace(Counter::ReadAxis, 1);
-            let mut stmt = self.txn.prepare(
-                "SELECT label FROM AxisContent WHERE axis_name = ? ORDER BY global_storage_index",
+        if let Some(patchset_id) = patchset_id {
+            // The patchset stays until the last of its patches is gone
+            self.txn.execute(
+                "DELETE FROM PatchSetMember WHERE patch_id = ?;",
+                &[patch_id],
             )?;
-            let rows = stmt.query_map(&[&axis_name], |r| r.get::<_, i64>(0))?;
-            let mut labels = vec![];
-            for label in rows {
-                labels.push(label?);
+            let remaining: i64 = self.txn.query_row(
+                "SELECT count(*) FROM PatchSetMember WHERE patchset_id = ?;",
+                &[patchset_id],
+                |r| r.get(0),
+            )?;
+            if remaining == 0 {
+                freed_bytes += self.get_content_bytes(patchset_id)?;
+                self.txn.execute(
+                    "DELETE FROM PatchContent WHERE patch_id = ?;",
+                    &[patchset_id],
+                )?;
             }
-            self.axis_cache
-                .insert(axis_name.to_string(), Axis::new(axis_name, labels)?);
         }
-        Ok(self.axis_cache.get(axis_name).unwrap())
+        self.add_quilt_usage(quilt_name, -freed_bytes, -1)?;
+        self.txn
+            .execute("DELETE FROM VisibleIndex WHERE patch_id = ?;", &[patch_id])?;
+        self.txn
+            .execute("DELETE FROM PatchAccess WHERE patch_id = ?;", &[patch_id])?;
+        self.txn.execute(
+            "DELETE FROM PatchIntervals WHERE patch_id = ?;",
+            &[patch_id],
+        )?;
+        Ok(())
     }
 
-    /// List the currently available quilts
-    fn list_quilts(&mut self) -> Fallible<HashMap<String, QuiltDetails>> {
-        let mut map = HashMap::new();
-        for row in self
-            .txn
-            .prepare("SELECT quilt_name, axes FROM quilt;")?
-            .query_map(NO_PARAMS, |r| QuiltDetails::try_from(r))?
-        {
-            let row = row?;
-            map.insert(row.name.clone(), row);
-        }
-        Ok(map)
+    /// Get the size of the content in a PatchContent row, or 0 if there is no row
+    fn get_content_bytes(&mut self, content_id: i64) -> Fallible<i64> {
+        Ok(self.txn.query_row(
+            "SELECT coalesce(sum(length(content)), 0) FROM PatchContent WHERE patch_id = ?;",
+            &[content_id],
+            |r| r.get(0),
+        )?)
     }
 
-    /// Create a quilt, and create axes as necessary to make it.
-    fn create_quilt(&mut self, quilt_name: &str, axes_names: &[&str]) -> Fallible<bool> {
-        let changes = self.txn.execute(
-            "INSERT OR IGNORE INTO quilt(quilt_name, axes) VALUES (?, ?);",
-            &[&quilt_name, &serde_json::to_string(axes_names)?.as_ref()],
+    /// Add to the running storage use of a quilt, see get_quilt_usage()
+    ///
+    /// Quilts that haven't been measured yet are left alone, since measuring will count this.
+    fn add_quilt_usage(&mut self, quilt_name: &str, bytes: i64, patches: i64) -> Fallible<()> {
+        self.txn.execute(
+            "UPDATE QuiltUsage SET bytes = bytes + ?, patches = patches + ? WHERE quilt_name = ?;",
+            &[&bytes as &dyn ToSql, &patches, &quilt_name],
         )?;
-        Ok(changes > 0)
+        Ok(())
     }
 
-    /// Get details about a quilt by name
+    /// Measure the storage used by all the commits of a quilt, by reading all of them
     ///
-    /// What details are available may depend on the quilt, and fields are likely to
-    /// be added with time (so be careful with serializing QuiltDetails)
-    fn get_quilt_details(&mut self, quilt_name: &str) -> Fallible<QuiltDetails> {
-        let deets = self
-            .txn
-            .query_row_and_then(
-                "SELECT quilt_name, axes FROM quilt WHERE quilt_name = ?",
-                &[&quilt_name],
-                |r| QuiltDetails::try_from(r),
+    /// This is slow for long histories, so it's only for quilts without a running count.
+    fn measure_quilt_usage(&mut self, quilt_name: &str) -> Fallible<QuiltUsage> {
+        // Commits only belong to a quilt through its tags, so follow them all back
+        let (patches, bytes): (i64, i64) = self.txn.query_row(
+            "
+            WITH RECURSIVE QuiltCommit(comm_id) AS (
+                SELECT comm_id FROM Tag WHERE quilt_name = ?
+                UNION
+                SELECT Comm.parent_comm_id
+                    FROM QuiltCommit
+                    INNER JOIN Comm USING (comm_id)
+                    WHERE Comm.parent_comm_id IS NOT NULL
+            ),
+            -- Packed patches share their patchset's content, so count it only once
+            QuiltPatch(patch_id, content_id) AS (
+                SELECT patch_id, coalesce(patchset_id, patch_id)
+                    FROM QuiltCommit
+                    INNER JOIN Patch USING (comm_id)
+                    LEFT JOIN PatchSetMember USING (patch_id)
             )
-            .optional()?;
-        match deets {
-            None => Err(StoiError::NotFound(
-                "quilt doesn't exist",
-                quilt_name.into(),
-            )),
-            Some(x) => Ok(x),
-        }
+            SELECT
+                (SELECT count(*)
+                    FROM QuiltPatch
+                    INNER JOIN PatchContent ON PatchContent.patch_id = QuiltPatch.content_id),
+                (SELECT coalesce(sum(length(content)), 0)
+                    FROM PatchContent
+                    WHERE patch_id IN (SELECT content_id FROM QuiltPatch));
+            ",
+            &[&quilt_name],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        Ok(QuiltUsage {
+            bytes: bytes as u64,
+            patches: patches as u64,
+        })
     }
 
-    /// Get the Patch IDs that would have to be applied to fill a fetch(), in the order they would
-    /// need to be applied.
-    ///
-    /// You can specify many bounding boxes but the search degrades the more are provided. It's
-    /// probably never a good idea to load more than a hundred.
-    ///
-    /// For fetching, you need deep=true, so you see the state of the tensor at that commit,
-    /// but for compaction, deep=false can avoid filling in void areas.
-    ///
-    /// Accepts:
-    ///     quilt_name: the quilt associated with the tag we're looking for
-    ///     tag: the named commit
-    ///     deep: whether to provide only the patches within this quilt, or all it's ancestors
-    ///     bounding_boxes: returned patches should intersect at least one of these boxes
+    /// Search for patches, like search(), but choosing whether to use a visibility index
     ///
-    /// Returns:
-    ///     A vector of Patch ID's
-    fn search(
+    /// use_index must only be true if the tag has a visibility index, and implies deep.
+    fn search_patches(
         &mut self,
         quilt_name: &str,
         tag: &str,
         deep: bool,
+        use_index: bool,
         bounding_boxes: &[BoundingBox],
     ) -> Fallible<Vec<PatchRef>> {
         self.trace(Counter::SearchPatches, 1);
-        // This is a fairly complex query we need to run so it deserved long-hand
-        let mut stmt = self.txn.prepare(
-            "
+        let bounding_boxes_json = serde_json::to_string(
+            &bounding_boxes
+                .iter()
+                .map(|bx| bx.to_sql_bounds())
+                .collect::<Fallible<Vec<_>>>()?,
+        )?;
+        if use_index {
+            self.trace(Counter::SearchVisibilityIndex, 1);
+        }
+        let mut stmt;
+        let mut rows = if use_index {
+            stmt = self.txn.prepare(
+                "
+                SELECT
+                    Patch.patch_id, decompressed_size,
+                    dim_0_min, dim_0_max,
+                    dim_1_min, dim_1_max,
+                    dim_2_min, dim_2_max,
+                    dim_3_min, dim_3_max,
+                    intervals
+                    FROM VisibleIndex
+                    INNER JOIN Patch USING (patch_id)
+                    LEFT JOIN PatchIntervals Tight ON (Tight.patch_id = Patch.patch_id)
+                    LEFT JOIN CommitSequence Seq ON (Seq.comm_id = Patch.comm_id)
+                    INNER JOIN json_each(?) BoundingBox ON (
+                            dim_0_max >= json_extract(value, '$[0]')
+                        AND dim_0_min <= json_extract(value, '$[1]')
+                        AND dim_1_max >= json_extract(value, '$[2]')
+                        AND dim_1_min <= json_extract(value, '$[3]')
+                        AND dim_2_max >= json_extract(value, '$[4]')
+                        AND dim_2_min <= json_extract(value, '$[5]')
+                        AND dim_3_max >= json_extract(value, '$[6]')
+                        AND dim_3_min <= json_extract(value, '$[7]')
+                    )
+                    WHERE VisibleIndex.quilt_name = ?
+                    AND tag_name = ?
+                    GROUP BY Patch.comm_id, Patch.patch_id
+                    ORDER BY coalesce(sequence, 0) ASC, Patch.comm_id ASC, Patch.patch_id ASC
+            ",
+            )?;
+            stmt.query(&[&bounding_boxes_json as &dyn ToSql, &quilt_name, &tag])?
+        } else {
+            // This is a fairly complex query we need to run so it deserved long-hand
+            stmt = self.txn.prepare(
+                "
                 WITH RECURSIVE CommitAncestry AS (
                     SELECT
                             comm_id parent_comm_id,
@@ -307,9 +762,12 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
                     dim_0_min, dim_0_max,
                     dim_1_min, dim_1_max,
                     dim_2_min, dim_2_max,
-                    dim_3_min, dim_3_max
+                    dim_3_min, dim_3_max,
+                    intervals
                     FROM CommitAncestry
                     INNER JOIN Patch USING (comm_id)
+                    LEFT JOIN PatchIntervals USING (patch_id)
+                    LEFT JOIN CommitSequence USING (comm_id)
                     INNER JOIN json_each(?) BoundingBox ON (
                             dim_0_max >= json_extract(value, '$[0]')
                         AND dim_0_min <= json_extract(value, '$[1]')
@@ -321,87 +779,254 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
                         AND dim_3_min <= json_extract(value, '$[7]')
                     )
                     GROUP BY comm_id, patch_id
-                    ORDER BY comm_id ASC, patch_id ASC
+                    ORDER BY coalesce(sequence, 0) ASC, comm_id ASC, patch_id ASC
             ",
-        )?;
-        let mut rows = stmt.query(&[
-            &quilt_name as &dyn ToSql,
-            &tag,
-            &deep, // This flag will enable/disable ancestor search
-            &serde_json::to_string(
-                &bounding_boxes
-                    .iter()
-                    .map(|bx| {
-                        (0..4)
-                            .into_iter()
-                            .map(|ax_ix| bx.get(ax_ix).copied().unwrap_or((0, 1 << 30)))
-                            .collect_vec()
-                    })
-                    .map(|bx| {
-                        [
-                            bx[0].0, bx[0].1, bx[1].0, bx[1].1, bx[2].0, bx[2].1, bx[3].0, bx[3].1,
-                        ]
-                    })
-                    .collect_vec(),
-            )?,
-        ])?;
+            )?;
+            stmt.query(&[
+                &quilt_name as &dyn ToSql,
+                &tag,
+                &deep, // This flag will enable/disable ancestor search
+                &bounding_boxes_json,
+            ])?
+        };
 
         let mut patch_refs: Vec<PatchRef> = vec![];
+        let mut rejected = 0;
         while let Some(row) = rows.next()? {
+            // The bounding box only says the patch might be there, the intervals are tighter
+            if let Some(intervals) = row.get::<usize, Option<String>>(10)? {
+                let intervals: PatchIntervals = serde_json::from_str(&intervals)?;
+                if !bounding_boxes.iter().any(|bx| intervals.intersects(bx)) {
+                    rejected += 1;
+                    continue;
+                }
+            }
             patch_refs.push(PatchRef {
                 id: row.get(0)?,
                 decompressed_size: row.get::<usize, i64>(1)? as u64,
-                bounding_box: [
-                    (
-                        row.get::<usize, i64>(2)? as usize,
-                        row.get::<usize, i64>(3)? as usize,
-                    ),
-                    (
-                        row.get::<usize, i64>(4)? as usize,
-                        row.get::<usize, i64>(5)? as usize,
-                    ),
-                    (
-                        row.get::<usize, i64>(6)? as usize,
-                        row.get::<usize, i64>(7)? as usize,
-                    ),
-                    (
-                        row.get::<usize, i64>(8)? as usize,
-                        row.get::<usize, i64>(9)? as usize,
-                    ),
-                ],
+                bounding_box: BoundingBox::from_sql_row(row, 2)?,
             });
         }
+        drop(rows);
+        drop(stmt);
+        self.trace(Counter::IntervalRejected, rejected);
         Ok(patch_refs)
     }
 
-    fn get_patch(&mut self, id: PatchID) -> Fallible<Patch> {
-        self.trace(Counter::ReadPatch, 1);
-        let res: Vec<u8> = self.txn.query_row(
-            "SELECT content FROM PatchContent WHERE patch_id = ?",
-            &[&id],
-            |r| r.get(0),
+    /// The position of the newest open savepoint with this name
+    fn find_savepoint(&self, name: &str) -> Fallible<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|open| open == name)
+            .ok_or_else(|| StoiError::NotFound("savepoint", name.into()))
+    }
+
+    /// Whether a tag has a visibility index, see StorageTransaction::enable_visibility_index()
+    fn has_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<bool> {
+        let count: i64 = self.txn.query_row(
+            "SELECT count(*) FROM VisibleIndexTag WHERE quilt_name = ? AND tag_name = ?",
+            &[&quilt_name, &tag],
+            |r| r.get(0),
         )?;
-        self.trace(Counter::ReadBytes, res.len());
-        let p = Patch::deserialize_from(&res[..])?;
-        Ok(p)
+        Ok(count > 0)
     }
 
-    // put_patch is part of Self, not Storage because you can only do it using put_commit()
+    /// Read the generation of an axis from the database, not from what this transaction knows
+    fn read_axis_generation(&mut self, axis_name: &str) -> Fallible<u64> {
+        let generation: Option<i64> = self
+            .txn
+            .query_row(
+                "SELECT generation FROM AxisGeneration WHERE axis_name = ?",
+                &[&axis_name],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(generation.unwrap_or(0) as u64)
+    }
 
-    /// Make changes to a tensor via a commit
+    /// Whether a patch has a value everywhere in its bounding box
     ///
-    /// This is only available together, so that the underlying storage media can do this
-    /// atomically without a complicated API
+    /// A patch like this hides every older patch inside its bounding box.
+    fn covers_bounding_box(patch: &Patch, bounding_box: &BoundingBox) -> bool {
+        patch
+            .axes()
+            .iter()
+            .zip(bounding_box.iter())
+            .all(|(axis, &(start, end))| axis.len() == end + 1 - start)
+            && patch.content().iter().all(|x| !x.is_nan())
+    }
 
-    fn put_commit(
+    /// Bring the visibility index of a tag up to date after a commit to it
+    ///
+    /// `new_patches` are the patches of the new commit, with their bounding boxes and
+    /// whether they cover them. This does nothing if the tag has no visibility index.
+    fn update_visibility_index(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        comm_id: i64,
+        new_patches: &[(PatchID, BoundingBox, bool)],
+    ) -> Fallible<()> {
+        if !self.has_visibility_index(quilt_name, new_tag)? {
+            return Ok(());
+        }
+        if !parent_tag.eq_ignore_ascii_case(new_tag) {
+            if self.has_visibility_index(quilt_name, parent_tag)? {
+                // Start from what the parent could see
+                self.txn.execute(
+                    "DELETE FROM VisibleIndex WHERE quilt_name = ? AND tag_name = ?;",
+                    &[&quilt_name, &new_tag],
+                )?;
+                self.txn.execute(
+                    "INSERT INTO VisibleIndex(quilt_name, tag_name, patch_id, covers)
+                    SELECT quilt_name, ?, patch_id, covers FROM VisibleIndex
+                    WHERE quilt_name = ? AND tag_name = ?;",
+                    &[&new_tag, &quilt_name, &parent_tag],
+                )?;
+            } else {
+                // There is nothing to start from, so walk the ancestry, new commit included
+                self.rebuild_visibility_index(quilt_name, new_tag)?;
+            }
+        }
+        for (patch_id, bbox, covers) in new_patches {
+            self.txn.execute(
+                "INSERT OR REPLACE INTO VisibleIndex(quilt_name, tag_name, patch_id, covers)
+                VALUES (?, ?, ?, ?);",
+                &[&quilt_name as &dyn ToSql, &new_tag, patch_id, covers],
+            )?;
+            if *covers {
+                // Anything older inside this patch's bounding box can't be seen anymore.
+                // Older means applied first, by CommitSequence like fetches, not by comm_id.
+                let bounds = bbox.to_sql_bounds()?;
+                self.txn.execute(
+                    "DELETE FROM VisibleIndex
+                    WHERE quilt_name = ? AND tag_name = ? AND patch_id IN (
+                        SELECT patch_id FROM Patch
+                        LEFT JOIN CommitSequence Seq USING (comm_id)
+                        WHERE (
+                            coalesce(Seq.sequence, 0) < (
+                                SELECT sequence FROM CommitSequence WHERE comm_id = ?
+                            )
+                            OR (Patch.comm_id = ? AND patch_id < ?)
+                        )
+                        AND dim_0_min >= ? AND dim_0_max <= ?
+                        AND dim_1_min >= ? AND dim_1_max <= ?
+                        AND dim_2_min >= ? AND dim_2_max <= ?
+                        AND dim_3_min >= ? AND dim_3_max <= ?
+                    );",
+                    &[
+                        &quilt_name as &dyn ToSql,
+                        &new_tag,
+                        &comm_id,
+                        &comm_id,
+                        patch_id,
+                        &bounds[0],
+                        &bounds[1],
+                        &bounds[2],
+                        &bounds[3],
+                        &bounds[4],
+                        &bounds[5],
+                        &bounds[6],
+                        &bounds[7],
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append an entry to the audit log, as the actor of this transaction
+    fn audit(
+        &mut self,
+        operation: &str,
+        quilt_name: &str,
+        parameters: serde_json::Value,
+        comm_ids: &[i64],
+    ) -> Fallible<()> {
+        self.txn.execute(
+            "INSERT INTO AuditLog(timestamp, actor, operation, quilt_name, parameters, comm_ids)
+            VALUES (?, ?, ?, ?, ?, ?);",
+            &[
+                &chrono::Utc::now().to_rfc3339() as &dyn ToSql,
+                &self.actor,
+                &operation,
+                &quilt_name,
+                &serde_json::to_string(&parameters)?,
+                &serde_json::to_string(comm_ids)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Make a commit, checking the quota, see StorageTransaction::put_commit()
+    ///
+    /// Each patch may come with its serialized content, see put_patch().
+    fn put_commit_checked(
         &mut self,
         quilt_name: &str,
         parent_tag: &str,
         new_tag: &str,
         message: &str,
-        patches: &[&Patch],
+        patches: Vec<(Cow<Patch>, Option<Vec<u8>>)>,
     ) -> Fallible<()> {
+        self.trace_quilt(quilt_name);
         self.trace(Counter::PutCommit, 1);
+        if !self.write {
+            // SQLite would refuse anyway, but only after the patches were split and compressed
+            return Err(StoiError::ReadOnly);
+        }
+        let details = self.get_quilt_details(quilt_name)?;
+        // Bounding boxes are searched in the quilt's axis order, so patches are stored that way
+        let axis_names = details.axes.iter().map(|name| name.as_str()).collect_vec();
+        let patches = patches
+            .into_iter()
+            .map(|(pat, content)| {
+                let names = pat.axes().iter().map(|axis| axis.name.as_str());
+                if names.eq(axis_names.iter().copied()) {
+                    Ok((pat, content))
+                } else {
+                    // Prepared content is in the old order, so it's serialized again
+                    Ok((Cow::Owned(pat.permute_axes(&axis_names)?), None))
+                }
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        let quota = match (details.quota, &details.namespace) {
+            (None, Some(namespace)) => self.get_namespace_config(namespace)?.quota,
+            (quota, _) => quota,
+        };
+        let may_abort = match self.write_amplification {
+            WriteAmplificationLimit::Abort(_) => true,
+            _ => false,
+        };
+        if quota.is_none() && !may_abort {
+            return self.put_commit_patches(quilt_name, parent_tag, new_tag, message, patches);
+        }
+        // Undo only this commit if it goes over quota, or writes too much,
+        // so the transaction is still usable
+        self.txn.execute_batch("SAVEPOINT put_commit;")?;
+        let result = self
+            .put_commit_patches(quilt_name, parent_tag, new_tag, message, patches)
+            .and_then(|_| match quota {
+                Some(quota) => self.check_quilt_quota(quilt_name, quota),
+                None => Ok(()),
+            });
+        if result.is_err() {
+            self.txn.execute_batch("ROLLBACK TO put_commit;")?;
+        }
+        self.txn.execute_batch("RELEASE put_commit;")?;
+        result
+    }
+
+    /// Make a commit, without checking the quota, see put_commit_checked()
+    fn put_commit_patches(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: Vec<(Cow<Patch>, Option<Vec<u8>>)>,
+    ) -> Fallible<()> {
         // The heuristic used for balancing may change in the future, but this is my suggestion:
         //
         //     - Take patches from this commit that overlap this patch
@@ -410,16 +1035,51 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
         //     - If it gets too large, split it by the longest dimension
         //
         let comm_id: i64 = self.gen_id();
+        // Merges can rewrite much more than the new patches, so keep track in case that's limited
+        let input_bytes = patches.iter().map(|(pat, _)| 4 * pat.len()).sum::<usize>();
+        let max_bytes = self
+            .write_amplification
+            .max_bytes(input_bytes, self.target_patch_bytes);
+        let mut written_bytes = 0;
         let mut pending_patches = vec![];
-        for &pat in patches {
+        let quantization = self.get_quilt_details(quilt_name)?.storage_quantization();
+        let compression = self.patch_compression;
+        // Appends can't overlap anything, so lay them out in blocks instead of merging them
+        let appending = self.get_append_axis_of_commit(quilt_name, parent_tag, &patches)?;
+        let patches = match &appending {
+            Some(append_axis) => {
+                self.trace(Counter::AppendCommit, 1);
+                self.align_append(patches, append_axis)?
+            }
+            None => patches,
+        };
+        for (pat, content) in patches {
+            // NAN is never applied over existing data, so a patch of only NAN changes nothing
+            if pat.is_empty_data() {
+                self.trace(Counter::EmptyPatch, 1);
+                continue;
+            }
             let new_bounding_box = self.get_bounding_box(&pat)?;
-            // Find a friend to merge with: choosing the smallest will bring up the tiny patchlets
-            let maybe_friend_patch_ref = self
-                .search(quilt_name, new_tag, false, &[new_bounding_box])?
+            // Find a friend to merge with: the one that overlaps the most, relative to the box
+            // they would make together, so we don't merge disjoint corners into a huge box.
+            // Among equals, choosing the smallest will bring up the tiny patchlets
+            let candidates = match appending {
+                Some(_) => vec![],
+                None => self.search(quilt_name, new_tag, false, &[new_bounding_box])?,
+            };
+            self.trace(Counter::MergeCandidate, candidates.len());
+            let maybe_friend_patch_ref = candidates
                 .into_iter()
-                // TODO: Consider percent overlap
-                .min_by_key(|patch_ref| patch_ref.decompressed_size);
-            pending_patches.extend(match maybe_friend_patch_ref {
+                .map(|patch_ref| (overlap_ratio(&new_bounding_box, &patch_ref.bounding_box), patch_ref))
+                .filter(|(ratio, _patch_ref)| *ratio >= MIN_MERGE_OVERLAP)
+                .max_by(|(ratio_a, ref_a), (ratio_b, ref_b)| {
+                    ratio_a
+                        .partial_cmp(ratio_b)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then(ref_b.decompressed_size.cmp(&ref_a.decompressed_size))
+                })
+                .map(|(_ratio, patch_ref)| patch_ref);
+            let merged = match maybe_friend_patch_ref {
                 Some(friend_patch_ref) => {
                     // Find the visible area, not just the original. If it was occluded by another (larger?) patch
                     // in between, we need to include that occlusion in the new patch because it's what you
@@ -436,23 +1096,95 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
                         .map(|ax| AxisSelection::Labels(ax.labels().to_vec()))
                         .collect_vec();
                     self.trace(Counter::PutCommitFetch, 1);
-                    let friend_visible_area = self.fetch(quilt_name, new_tag, patch_request)?;
-                    // Garbage collect the old patch because now it has been compacted into the new one
-                    self.del_patch(friend_patch_ref.id)?;
+                    let friend_visible_area =
+                        self.fetch_stored(quilt_name, new_tag, patch_request)?;
+
+                    // Data sent again unchanged would only rewrite the friend, so leave it out.
+                    // The visible area is the parent's only when committing to the same tag.
+                    if parent_tag == new_tag {
+                        let stats = friend_visible_area.clone().apply_counted(&pat)?;
+                        if stats.changed_nothing() && stats.outside_cells == 0 {
+                            self.trace(Counter::UnchangedPatch, 1);
+                            continue;
+                        }
+                    }
 
                     // Merge the patch with it's friend
                     let new_large_patch = friend_visible_area.merge(&pat)?;
-                    self.maybe_split(new_large_patch)
+                    let merged_bytes = 4 * new_large_patch.len();
+                    match max_bytes {
+                        Some(max_bytes) if written_bytes + merged_bytes > max_bytes => {
+                            self.trace(Counter::WriteAmplificationLimited, 1);
+                            if let WriteAmplificationLimit::Abort(_) = self.write_amplification {
+                                return Err(StoiError::WriteAmplification(
+                                    quilt_name.into(),
+                                    written_bytes + merged_bytes,
+                                    input_bytes,
+                                ));
+                            }
+                            None
+                        }
+                        _ => {
+                            self.trace(Counter::Merge, 1);
+                            // Garbage collect the old patch because now it has been compacted into the new one
+                            self.del_patch(quilt_name, friend_patch_ref.id)?;
+                            Some((new_large_patch, friend))
+                        }
+                    }
                 }
+                None => None,
+            };
+            let parts = match merged {
+                // The merged patch is new, so any prepared content doesn't apply. It's
+                // quantized like its friend if it can be, so the friend's values stay the same.
+                Some((new_large_patch, friend)) => self
+                    .maybe_split(new_large_patch)?
+                    .into_iter()
+                    .map(|part| {
+                        let mut content = vec![];
+                        part.compact().serialize_merged_into(
+                            Some(compression),
+                            quantization,
+                            &friend,
+                            &mut content,
+                        )?;
+                        Ok((part, Some(content)))
+                    })
+                    .collect::<Fallible<Vec<_>>>()?,
                 // TODO: Look at this clone
-                None => Ok(vec![pat.to_owned()]),
-            }?);
+                None => vec![(pat.into_owned(), content)],
+            };
+            written_bytes += parts.iter().map(|(part, _)| 4 * part.len()).sum::<usize>();
+            pending_patches.extend(parts);
         }
-        for new_patch in pending_patches {
+        let mut new_patches = vec![];
+        let mut small_patches = vec![];
+        for (new_patch, content) in pending_patches {
             if new_patch.len() > 0 {
                 // Add each new patch
                 let bbox = self.get_bounding_box(&new_patch)?;
-                self.put_patch(comm_id, &new_patch, bbox)?;
+                let covers = Self::covers_bounding_box(&new_patch, &bbox);
+                if 4 * new_patch.len() < self.patchset_bytes {
+                    small_patches.push((new_patch, bbox, covers, content));
+                    continue;
+                }
+                let patch_id =
+                    self.put_patch(quilt_name, comm_id, &new_patch, bbox, quantization, content)?;
+                new_patches.push((patch_id, bbox, covers));
+            }
+        }
+        // A patchset of one patch would only be slower to read
+        if small_patches.len() == 1 {
+            let (new_patch, bbox, covers, content) = small_patches.pop().unwrap();
+            let patch_id =
+                self.put_patch(quilt_name, comm_id, &new_patch, bbox, quantization, content)?;
+            new_patches.push((patch_id, bbox, covers));
+        } else if !small_patches.is_empty() {
+            let covers = small_patches.iter().map(|p| (p.1, p.2)).collect_vec();
+            let packed = small_patches.into_iter().map(|p| (p.0, p.1)).collect();
+            let patch_ids = self.put_patchset(quilt_name, comm_id, packed, quantization)?;
+            for (patch_id, (bbox, covers)) in patch_ids.into_iter().zip(covers) {
+                new_patches.push((patch_id, bbox, covers));
             }
         }
         self.txn.execute(
@@ -471,6 +1203,18 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
             LEFT JOIN Tag Parent USING (quilt_name, tag_name);",
             &[&comm_id as &dyn ToSql, &message, &quilt_name, &parent_tag],
         )?;
+        // Commit ids come from the clock, so they say nothing reliable about the order
+        self.txn.execute(
+            "INSERT INTO CommitSequence(comm_id, quilt_name, sequence)
+                SELECT ?1, ?2, coalesce(max(sequence), 0) + 1
+                FROM CommitSequence
+                WHERE quilt_name = ?2;",
+            &[&comm_id as &dyn ToSql, &quilt_name],
+        )?;
+        self.txn.execute(
+            "INSERT INTO CommitTime(comm_id, created_at) VALUES (?, ?);",
+            &[&comm_id as &dyn ToSql, &chrono::Utc::now().to_rfc3339()],
+        )?;
         self.txn.execute(
             "INSERT OR REPLACE INTO Tag(
                 quilt_name,
@@ -479,25 +1223,2123 @@ impl<'t> StorageTransaction for SQLiteTransaction<'t> {
             ) VALUES (?, ?, ?)",
             &[&quilt_name as &dyn ToSql, &new_tag, &comm_id],
         )?;
+        self.update_visibility_index(quilt_name, parent_tag, new_tag, comm_id, &new_patches)?;
+        self.audit(
+            "create_commit",
+            quilt_name,
+            serde_json::json!({
+                "parent_tag": parent_tag,
+                "new_tag": new_tag,
+                "message": message,
+                "patches": new_patches.len(),
+            }),
+            &[comm_id],
+        )?;
+        self.queue_derived_work(quilt_name, new_tag, &new_patches)?;
         Ok(())
     }
 
-    /// Commit the transaction
-    fn finish(self) -> Fallible<()> {
-        println!("Transaction completed with stats {:#?}", self.trace);
-        Ok(self.txn.execute_batch("COMMIT;")?)
+    /// Find the quilt's append axis, if all the patches are past its end in the parent tag
+    ///
+    /// This only looks at where the patches start along the append axis, so it's quick, and
+    /// the only cost of missing an append is merging as usual.
+    fn get_append_axis_of_commit(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        patches: &[(Cow<Patch>, Option<Vec<u8>>)],
+    ) -> Fallible<Option<String>> {
+        let append_axis = match self.get_quilt_append_axis(quilt_name)? {
+            Some(append_axis) => append_axis,
+            None => return Ok(None),
+        };
+        let mut start = None;
+        let mut append_ix = None;
+        for (pat, _) in patches {
+            let ax_ix = match pat.axes().iter().position(|ax| ax.name == append_axis) {
+                Some(ax_ix) if append_ix.unwrap_or(ax_ix) == ax_ix => ax_ix,
+                _ => return Ok(None),
+            };
+            append_ix = Some(ax_ix);
+            let first = self.get_bounding_box(pat)?[ax_ix].0;
+            start = Some(start.map_or(first, |start: usize| start.min(first)));
+        }
+        let (append_ix, start) = match (append_ix, start) {
+            (Some(append_ix), Some(start)) => (append_ix, start),
+            _ => return Ok(None),
+        };
+        let past_end =
+            BoundingBox::everywhere().with_segment(append_ix, (start, BoundingBox::UNBOUNDED))?;
+        let overlapping = self.search(quilt_name, parent_tag, true, &[past_end])?;
+        if overlapping.is_empty() {
+            Ok(Some(append_axis))
+        } else {
+            Ok(None)
+        }
     }
 
-    /// Rollback the transaction
-    fn rollback(self) -> Fallible<()> {
-        println!("Transaction rolled back with stats {:#?}", self.trace);
-        Ok(self.txn.execute_batch("ROLLBACK;")?)
+    /// Split appended patches into blocks aligned on every axis but the append axis
+    ///
+    /// Patches that already fit in one block keep their prepared content.
+    fn align_append<'p>(
+        &mut self,
+        patches: Vec<(Cow<'p, Patch>, Option<Vec<u8>>)>,
+        append_axis: &str,
+    ) -> Fallible<Vec<(Cow<'p, Patch>, Option<Vec<u8>>)>> {
+        let mut aligned = vec![];
+        for (pat, content) in patches {
+            let mut global_indices = vec![];
+            for axis in pat.axes() {
+                let index = self.get_label_index(&axis.name)?;
+                global_indices.push(
+                    axis.labels()
+                        .iter()
+                        .map(|label| {
+                            index
+                                .get(label)
+                                .copied()
+                                .ok_or_else(|| StoiError::LabelNotFound(axis.name.clone(), *label))
+                        })
+                        .collect::<Fallible<Vec<usize>>>()?,
+                );
+            }
+            let parts = split_aligned(&pat, &global_indices, append_axis, APPEND_BLOCK_WIDTH)?;
+            match parts.len() {
+                1 => aligned.push((pat, content)),
+                _ => aligned.extend(parts.into_iter().map(|part| (Cow::Owned(part), None))),
+            }
+        }
+        Ok(aligned)
+    }
+
+    /// Queue work for the quilts derived from this one, over the region of new patches
+    fn queue_derived_work(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        new_patches: &[(PatchID, BoundingBox, bool)],
+    ) -> Fallible<()> {
+        let mut boxes = new_patches.iter().map(|(_id, bbox, _covers)| *bbox);
+        let mut region = match boxes.next() {
+            Some(bbox) => bbox,
+            None => return Ok(()),
+        };
+        for bbox in boxes {
+            region = region.hull(&bbox);
+        }
+        self.txn.execute(
+            "INSERT INTO DerivedWork(
+                src_quilt_name, dst_quilt_name, tag_name, bounding_box, queued_at)
+            SELECT src_quilt_name, dst_quilt_name, ?, ?, ?
+            FROM DerivedQuilt
+            WHERE src_quilt_name = ?;",
+            &[
+                &tag as &dyn ToSql,
+                &serde_json::to_string(&region)?,
+                &chrono::Utc::now().to_rfc3339(),
+                &quilt_name,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The target_patch_bytes() of a quilt: its own, or its namespace's, or the catalog's
+    fn effective_target_patch_bytes(&mut self, quilt_name: &str) -> usize {
+        // A quilt that doesn't exist will fail soon enough in whatever set it
+        let bytes: Option<i64> = self
+            .txn
+            .query_row(
+                "SELECT coalesce(
+                    (SELECT target_patch_bytes FROM QuiltTuning WHERE quilt_name = ?1),
+                    (SELECT json_extract(config, '$.target_patch_bytes')
+                        FROM QuiltNamespace INNER JOIN Namespace USING (namespace)
+                        WHERE quilt_name = ?1)
+                )",
+                &[&quilt_name],
+                |r| r.get(0),
+            )
+            .ok()
+            .flatten();
+        bytes.map_or(self.default_target_patch_bytes, |b| b as usize)
+    }
+
+    /// Fail if a quilt uses more storage than its quota allows
+    fn check_quilt_quota(&mut self, quilt_name: &str, quota: QuiltQuota) -> Fallible<()> {
+        let usage = self.get_quilt_usage(quilt_name)?;
+        match quota {
+            QuiltQuota {
+                max_bytes: Some(max_bytes),
+                ..
+            } if usage.bytes > max_bytes => Err(StoiError::QuotaExceeded(
+                quilt_name.into(),
+                "bytes",
+                max_bytes,
+            )),
+            QuiltQuota {
+                max_patches: Some(max_patches),
+                ..
+            } if usage.patches > max_patches => Err(StoiError::QuotaExceeded(
+                quilt_name.into(),
+                "patches",
+                max_patches,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Increment an axis counter, which is also broken down by axis
+    fn trace_axis(&mut self, axis_name: &str, ctr: Counter, increment: usize) {
+        self.trace(ctr, increment);
+        self.trace_by_axis
+            .entry(axis_name.to_string())
+            .or_insert_with(EnumMap::new)[ctr] += increment;
+    }
+
+    /// Get the patchset a patch was packed into, if it was, see put_patchset()
+    fn get_patchset_id(&mut self, patch_id: PatchID) -> Fallible<Option<i64>> {
+        Ok(self
+            .txn
+            .query_row(
+                "SELECT patchset_id FROM PatchSetMember WHERE patch_id = ?",
+                &[&patch_id],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Read a patch packed into a patchset, reusing the last patchset read if it's the same
+    fn get_patchset_member(&mut self, patchset_id: i64, id: PatchID) -> Fallible<Patch> {
+        let patchset = match self.patchset_cache.take() {
+            Some((cached_id, patchset)) if cached_id == patchset_id => patchset,
+            _ => {
+                let content: Vec<u8> = self
+                    .txn
+                    .query_row(
+                        "SELECT content FROM PatchContent WHERE patch_id = ?",
+                        &[&patchset_id],
+                        |r| r.get(0),
+                    )
+                    .optional()?
+                    .ok_or_else(|| StoiError::NotFound("patch content", id.0.to_string()))?;
+                self.trace(Counter::ReadBytes, content.len());
+                PatchSet::deserialize_from(&content[..])?
+            }
+        };
+        let patch = patchset.get(id);
+        self.patchset_cache = Some((patchset_id, patchset));
+        patch
+    }
+
+    /// Sample a read of a patch, now and then, see get_coldest_patches()
+    fn sample_access(&mut self, id: PatchID) {
+        if rand::random::<u32>() % ACCESS_SAMPLE_RATE == 0 {
+            let sample = (ACCESS_SAMPLE_RATE as u64, chrono::Utc::now().to_rfc3339());
+            merge_access_sample(&mut self.access_samples, id, sample);
+        }
+    }
+
+    /// Save the reads sampled in this transaction, and those left by read transactions
+    fn put_access_samples(&mut self) -> Fallible<()> {
+        let mut samples = std::mem::replace(&mut self.access_samples, HashMap::new());
+        if let Ok(mut backlog) = self.access_backlog.lock() {
+            for (id, sample) in backlog.drain() {
+                merge_access_sample(&mut samples, id, sample);
+            }
+        }
+        for (id, (reads, last_read)) in samples {
+            // Patches deleted since they were read are skipped
+            self.txn.execute(
+                "INSERT INTO PatchAccess(patch_id, reads, last_read)
+                    SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM Patch WHERE patch_id = ?1)
+                ON CONFLICT (patch_id) DO UPDATE SET
+                    reads = reads + excluded.reads,
+                    last_read = max(last_read, excluded.last_read);",
+                &[&id as &dyn ToSql, &(reads as i64), &last_read],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Generate an id using the time plus a small salt
+    ///
+    /// The ids of one transaction always increase, so patches of a commit are applied in the
+    /// order they were written, even when the salt or the clock would reorder them.
+    fn gen_id(&mut self) -> i64 {
+        let id = chrono::Utc::now().timestamp_nanos() + rand::random::<i16>() as i64;
+        self.last_id = id.max(self.last_id + 1);
+        self.last_id
     }
 }
 
-/// Rollback the transaction by default
-impl<'t> Drop for SQLiteTransaction<'t> {
-    fn drop(&mut self) {
-        self.txn.execute_batch("ROLLBACK;").unwrap_or(());
+impl<'t> StorageTransaction for SQLiteTransaction<'t> {
+    /// Increment an activity counter, used for performance and correctness checking
+    fn trace(&mut self, ctr: Counter, increment: usize) {
+        self.trace[ctr] += increment;
+        if let Some(quilt_name) = &self.trace_quilt {
+            self.trace_by_quilt
+                .entry(quilt_name.clone())
+                .or_insert_with(EnumMap::new)[ctr] += increment;
+        }
+    }
+
+    /// Count everything traced from now on against this quilt too
+    ///
+    /// The quilt's own target_patch_bytes() applies until another quilt is set, too.
+    fn trace_quilt(&mut self, quilt_name: &str) {
+        if self.trace_quilt.as_deref() != Some(quilt_name) {
+            self.trace_quilt = Some(quilt_name.to_string());
+            self.target_patch_bytes = self.effective_target_patch_bytes(quilt_name);
+        }
+    }
+
+    /// Retrieve performance counters, useful for debugging performance problems
+    ///
+    /// Returns: a Map containing the counters by name
+    fn get_performance_counters(&self) -> EnumMap<Counter, usize> {
+        self.trace.clone()
+    }
+
+    /// Retrieve performance counters broken down by the quilt that caused them
+    fn get_performance_counters_by_quilt(&self) -> CounterBreakdown {
+        self.trace_by_quilt.clone()
+    }
+
+    /// Retrieve the axis counters broken down by axis
+    fn get_performance_counters_by_axis(&self) -> CounterBreakdown {
+        self.trace_by_axis.clone()
+    }
+
+    /// The size patches should be in storage, after compression
+    fn target_patch_bytes(&self) -> usize {
+        self.target_patch_bytes
+    }
+
+    /// The compression used for patches written in this transaction
+    fn patch_compression(&self) -> PatchCompressionType {
+        self.patch_compression
+    }
+
+    /// Set how much more than they were given commits in this transaction may write
+    fn set_write_amplification_limit(&mut self, limit: WriteAmplificationLimit) {
+        self.write_amplification = limit;
+    }
+
+    /// The most bytes of patches one fetch may read, decompressed
+    fn max_read_bytes(&self) -> Option<u64> {
+        self.max_read_bytes
+    }
+
+    /// Whether reads should tolerate missing patches
+    fn recovery_mode(&self) -> bool {
+        self.recovery
+    }
+
+    /// Record a problem that was tolerated, rather than returning an error
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    /// Take the warnings recorded so far in this transaction
+    fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::replace(&mut self.warnings, vec![])
+    }
+
+    /// Whether reads should skip patches that can't be decoded
+    fn skip_unreadable(&self) -> bool {
+        self.skip_unreadable
+    }
+
+    /// Record a patch that a read skipped, rather than returning an error
+    fn skip_patch(&mut self, skipped: SkippedPatch) {
+        self.skipped.push(skipped);
+    }
+
+    /// Take the patches skipped so far in this transaction
+    fn take_skipped_patches(&mut self) -> Vec<SkippedPatch> {
+        std::mem::replace(&mut self.skipped, vec![])
+    }
+
+    /// Append labels to an axis, in the order you would expect them to be stored.
+    /// Any duplicate labels will not be appended.
+    ///
+    /// Returns true iff the axis was mutated in the process
+
+    fn union_axis(&mut self, axis: &Axis) -> Fallible<bool> {
+        // If our copy is out of date, bounding boxes we computed from it may be wrong too.
+        // This is the one place the database is asked again, since it's about to be written.
+        if let Some(&cached_generation) = self.axis_generations.get(&axis.name) {
+            if cached_generation != self.read_axis_generation(&axis.name)? {
+                return Err(StoiError::StaleAxis(axis.name.clone()));
+            }
+        }
+        let new_labels = {
+            let existing_labels = self.get_label_index(&axis.name)?;
+            axis.labels()
+                .iter()
+                .filter(|label| !existing_labels.contains_key(label))
+                .copied()
+                .collect_vec()
+        };
+
+        let mut changes = 0;
+        let trials = axis.len();
+        changes += self.txn.execute(
+            "INSERT OR IGNORE INTO Axis(axis_name) VALUES (?)",
+            &[&axis.name],
+        )?;
+        let mut stmt = self
+            .txn
+            .prepare("INSERT OR IGNORE INTO AxisContent(axis_name, label) VALUES (?,?);")?;
+        for label in &new_labels {
+            changes += stmt.execute(&[&axis.name as &dyn ToSql, label])?;
+        }
+        // Drop an immutable borrow so we can trace
+        std::mem::drop(stmt);
+        if changes > 0 {
+            // Repair the cache, and its index, since labels are only ever appended
+            let cached = self.axis_cache.get_mut(&axis.name).unwrap();
+            let old_len = cached.len();
+            cached.union(&axis);
+            if let Some(index) = self.label_indices.get_mut(&axis.name) {
+                for (ix, &label) in cached.labels().iter().enumerate().skip(old_len) {
+                    index.insert(label, ix);
+                }
+            }
+            // New labels could go anywhere in the sorted order, so that's rebuilt if needed
+            self.sorted_label_indices.remove(&axis.name);
+            // A counter would be handed out again if this transaction rolls back, and then
+            // patches could keep bounding boxes from labels that were never written
+            let generation = rand::random::<i64>() | 1;
+            self.txn.execute(
+                "INSERT INTO AxisGeneration(axis_name, generation) VALUES (?, ?)
+                ON CONFLICT(axis_name) DO UPDATE SET generation = excluded.generation;",
+                &[&axis.name as &dyn ToSql, &generation],
+            )?;
+            self.axis_generations
+                .insert(axis.name.clone(), generation as u64);
+            self.trace_axis(&axis.name, Counter::WriteAxisLabel, changes);
+            self.trace_axis(&axis.name, Counter::TrialAxisLabel, trials);
+        }
+        Ok(changes > 0)
+    }
+
+    /// Name labels of the MEASURE_AXIS, see StorageTransaction::declare_measures()
+    fn declare_measures(&mut self, names: &[&str]) -> Fallible<Vec<Label>> {
+        let mut labels = vec![];
+        for &name in names {
+            let existing: Option<Label> = self
+                .txn
+                .query_row(
+                    "SELECT label FROM MeasureName WHERE name = ?",
+                    &[&name],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            let label = match existing {
+                Some(label) => label,
+                None => {
+                    let label: Label = self.txn.query_row(
+                        "SELECT coalesce(max(label) + 1, 0) FROM MeasureName",
+                        NO_PARAMS,
+                        |r| r.get(0),
+                    )?;
+                    self.txn.execute(
+                        "INSERT INTO MeasureName(name, label) VALUES (?, ?)",
+                        &[&name as &dyn ToSql, &label],
+                    )?;
+                    label
+                }
+            };
+            labels.push(label);
+        }
+        // The same name can be given twice, but an axis can't repeat labels
+        let axis = Axis::new(MEASURE_AXIS, labels.iter().copied().unique().collect())?;
+        self.union_axis(&axis)?;
+        Ok(labels)
+    }
+
+    /// Get the named labels of the MEASURE_AXIS, see StorageTransaction::get_measures()
+    fn get_measures(&mut self) -> Fallible<Vec<(String, Label)>> {
+        let mut stmt = self
+            .txn
+            .prepare("SELECT name, label FROM MeasureName ORDER BY label")?;
+        let measures = stmt
+            .query_map(NO_PARAMS, |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(measures)
+    }
+
+    /// Get all the labels of an axis, in the order you would expect them to be stored
+    ///
+    /// The transaction sees the same axis throughout, apart from its own changes, which keep
+    /// the cache up to date, so a cached axis is used without asking the database again.
+    fn get_axis(&mut self, axis_name: &str) -> Fallible<&Axis> {
+        if !self.axis_cache.contains_key(axis_name) {
+            // Remember which generation the copy is from, for union_axis() to check
+            self.get_axis_generation(axis_name)?;
+            self.trace_axis(axis_name, Counter::ReadAxis, 1);
+            let mut stmt = self.txn.prepare(
+                "SELECT label FROM AxisContent WHERE axis_name = ? ORDER BY global_storage_index",
+            )?;
+            let rows = stmt.query_map(&[&axis_name], |r| r.get::<_, i64>(0))?;
+            let mut labels = vec![];
+            for label in rows {
+                labels.push(label?);
+            }
+            self.axis_cache
+                .insert(axis_name.to_string(), Axis::new(axis_name, labels)?);
+        }
+        Ok(self.axis_cache.get(axis_name).unwrap())
+    }
+
+    /// Get the storage index of every label of an axis, building it if it's not cached
+    fn get_label_index(&mut self, axis_name: &str) -> Fallible<&LabelIndex> {
+        // Make sure the axis is cached
+        self.get_axis(axis_name)?;
+        if !self.label_indices.contains_key(axis_name) {
+            let index = self.axis_cache[axis_name].label_index();
+            self.label_indices.insert(axis_name.to_string(), index);
+        }
+        Ok(&self.label_indices[axis_name])
+    }
+
+    fn get_sorted_label_index(&mut self, axis_name: &str) -> Fallible<&SortedLabelIndex> {
+        // Make sure the axis is cached
+        self.get_axis(axis_name)?;
+        if !self.sorted_label_indices.contains_key(axis_name) {
+            let index = self.axis_cache[axis_name].sorted_index();
+            self.sorted_label_indices
+                .insert(axis_name.to_string(), index);
+        }
+        Ok(&self.sorted_label_indices[axis_name])
+    }
+
+    /// Get the token of the last change to an axis, see StorageTransaction::get_axis_generation()
+    fn get_axis_generation(&mut self, axis_name: &str) -> Fallible<u64> {
+        // Only this transaction can change it from here on, and it keeps the map up to date
+        if let Some(&generation) = self.axis_generations.get(axis_name) {
+            return Ok(generation);
+        }
+        let generation = self.read_axis_generation(axis_name)?;
+        self.axis_generations
+            .insert(axis_name.to_string(), generation);
+        Ok(generation)
+    }
+
+    fn get_catalog_id(&self) -> i64 {
+        self.catalog_id
+    }
+
+    /// Get a range of the labels of an axis, by storage index
+    fn get_axis_slice(&mut self, axis_name: &str, range: std::ops::Range<usize>) -> Fallible<Axis> {
+        if let Some(axis) = self.axis_cache.get(axis_name) {
+            // It's already here, so there's no reason to hit the database
+            let end = range.end.min(axis.len());
+            let start = range.start.min(end);
+            return Ok(Axis::new_unchecked(
+                axis_name,
+                axis.labels()[start..end].to_vec(),
+            ));
+        }
+        let mut stmt = self.txn.prepare(
+            "SELECT label FROM AxisContent WHERE axis_name = ?
+            ORDER BY global_storage_index
+            LIMIT ? OFFSET ?",
+        )?;
+        let rows = stmt.query_map(
+            &[
+                &axis_name as &dyn ToSql,
+                &(range.end.saturating_sub(range.start) as i64),
+                &(range.start as i64),
+            ],
+            |r| r.get::<_, i64>(0),
+        )?;
+        let mut labels = vec![];
+        for label in rows {
+            labels.push(label?);
+        }
+        Ok(Axis::new_unchecked(axis_name, labels))
+    }
+
+    /// Get the number of labels in an axis, without reading the axis
+    fn get_axis_len(&mut self, axis_name: &str) -> Fallible<usize> {
+        if let Some(axis) = self.axis_cache.get(axis_name) {
+            return Ok(axis.len());
+        }
+        let len: i64 = self.txn.query_row(
+            "SELECT count(*) FROM AxisContent WHERE axis_name = ?",
+            &[&axis_name],
+            |r| r.get(0),
+        )?;
+        Ok(len as usize)
+    }
+
+    /// List the currently available quilts
+    fn list_quilts(&mut self) -> Fallible<HashMap<String, QuiltDetails>> {
+        let mut map = HashMap::new();
+        for row in self
+            .txn
+            .prepare(
+                "SELECT quilt_name, axes, unit_name, unit_scale, unit_offset, quantization,
+                    integers, max_bytes, max_patches, namespace
+                FROM Quilt
+                LEFT JOIN QuiltUnits USING (quilt_name)
+                LEFT JOIN QuiltQuantization USING (quilt_name)
+                LEFT JOIN QuiltIntegers USING (quilt_name)
+                LEFT JOIN QuiltQuota USING (quilt_name)
+                LEFT JOIN QuiltNamespace USING (quilt_name);",
+            )?
+            .query_map(NO_PARAMS, |r| QuiltDetails::try_from(r))?
+        {
+            let row = row?;
+            map.insert(row.name.clone(), row);
+        }
+        Ok(map)
+    }
+
+    /// List the tags of a quilt, sorted by name
+    fn list_tags(&mut self, quilt_name: &str) -> Fallible<Vec<String>> {
+        Ok(self
+            .txn
+            .prepare("SELECT tag_name FROM Tag WHERE quilt_name = ? ORDER BY tag_name;")?
+            .query_map(&[&quilt_name], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    /// Create a namespace for quilts, unless it already exists
+    fn create_namespace(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<bool> {
+        if namespace.is_empty() || namespace.contains('/') {
+            return Err(StoiError::InvalidValue(
+                "namespace names can't be empty or contain a slash",
+            ));
+        }
+        if config.target_patch_bytes == Some(0) {
+            return Err(StoiError::InvalidValue(
+                "target_patch_bytes must be positive",
+            ));
+        }
+        let changes = self.txn.execute(
+            "INSERT OR IGNORE INTO Namespace(namespace, config) VALUES (?, ?);",
+            &[&namespace, &serde_json::to_string(&config)?.as_ref()],
+        )?;
+        Ok(changes > 0)
+    }
+
+    /// Get the configuration of a namespace
+    fn get_namespace_config(&mut self, namespace: &str) -> Fallible<NamespaceConfig> {
+        let config: Option<String> = self
+            .txn
+            .query_row(
+                "SELECT config FROM Namespace WHERE namespace = ?",
+                &[&namespace],
+                |r| r.get(0),
+            )
+            .optional()?;
+        match config {
+            Some(config) => Ok(serde_json::from_str(&config)?),
+            None => Err(StoiError::NotFound("namespace", namespace.into())),
+        }
+    }
+
+    /// Replace the configuration of a namespace
+    fn set_namespace_config(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<()> {
+        if config.target_patch_bytes == Some(0) {
+            return Err(StoiError::InvalidValue(
+                "target_patch_bytes must be positive",
+            ));
+        }
+        let changes = self.txn.execute(
+            "UPDATE Namespace SET config = ? WHERE namespace = ?;",
+            &[&serde_json::to_string(&config)?.as_ref(), &namespace],
+        )?;
+        if changes == 0 {
+            return Err(StoiError::NotFound("namespace", namespace.into()));
+        }
+        // The current quilt may have been using the namespace's patch size
+        if let Some(quilt_name) = self.trace_quilt.clone() {
+            self.target_patch_bytes = self.effective_target_patch_bytes(&quilt_name);
+        }
+        Ok(())
+    }
+
+    /// List the namespaces in the catalog, sorted by name
+    fn list_namespaces(&mut self) -> Fallible<Vec<String>> {
+        Ok(self
+            .txn
+            .prepare("SELECT namespace FROM Namespace ORDER BY namespace;")?
+            .query_map(NO_PARAMS, |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    /// List the quilts in a namespace, by their full names
+    fn list_quilts_in(&mut self, namespace: &str) -> Fallible<HashMap<String, QuiltDetails>> {
+        // Make sure the namespace exists first, rather than listing nothing
+        self.get_namespace_config(namespace)?;
+        let mut map = HashMap::new();
+        for row in self
+            .txn
+            .prepare(
+                "SELECT quilt_name, axes, unit_name, unit_scale, unit_offset, quantization,
+                    integers, max_bytes, max_patches, namespace
+                FROM QuiltNamespace
+                INNER JOIN Quilt USING (quilt_name)
+                LEFT JOIN QuiltUnits USING (quilt_name)
+                LEFT JOIN QuiltQuantization USING (quilt_name)
+                LEFT JOIN QuiltIntegers USING (quilt_name)
+                LEFT JOIN QuiltQuota USING (quilt_name)
+                WHERE namespace = ?;",
+            )?
+            .query_map(&[&namespace], |r| QuiltDetails::try_from(r))?
+        {
+            let row = row?;
+            map.insert(row.name.clone(), row);
+        }
+        Ok(map)
+    }
+
+    /// Create a quilt, and create axes as necessary to make it.
+    fn create_quilt(&mut self, quilt_name: &str, axes_names: &[&str]) -> Fallible<bool> {
+        let namespace = match quilt_name.find('/') {
+            Some(ix) if ix + 1 == quilt_name.len() => {
+                return Err(StoiError::InvalidValue(
+                    "a quilt in a namespace needs a name after the slash",
+                ))
+            }
+            Some(ix) => {
+                // Make sure the namespace exists first, for a better error
+                self.get_namespace_config(&quilt_name[..ix])?;
+                Some(&quilt_name[..ix])
+            }
+            None => None,
+        };
+        let changes = self.txn.execute(
+            "INSERT OR IGNORE INTO quilt(quilt_name, axes) VALUES (?, ?);",
+            &[&quilt_name, &serde_json::to_string(axes_names)?.as_ref()],
+        )?;
+        if let (true, Some(namespace)) = (changes > 0, namespace) {
+            self.txn.execute(
+                "INSERT INTO QuiltNamespace(quilt_name, namespace) VALUES (?, ?);",
+                &[&quilt_name, &namespace],
+            )?;
+        }
+        if changes > 0 {
+            self.audit(
+                "create_quilt",
+                quilt_name,
+                serde_json::json!({ "axes": axes_names }),
+                &[],
+            )?;
+        }
+        Ok(changes > 0)
+    }
+
+    /// Set or clear the units of a quilt
+    fn set_quilt_units(&mut self, quilt_name: &str, units: Option<QuiltUnits>) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        self.get_quilt_details(quilt_name)?;
+        match units {
+            Some(units) => self.txn.execute(
+                "INSERT OR REPLACE INTO QuiltUnits(quilt_name, unit_name, unit_scale, unit_offset)
+                VALUES (?, ?, ?, ?);",
+                &[
+                    &quilt_name as &dyn ToSql,
+                    &units.unit,
+                    &units.scale,
+                    &units.offset,
+                ],
+            )?,
+            None => self.txn.execute(
+                "DELETE FROM QuiltUnits WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Choose how precisely new patches of a quilt are stored
+    fn set_quilt_quantization(
+        &mut self,
+        quilt_name: &str,
+        quantization: PatchQuantization,
+    ) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        let details = self.get_quilt_details(quilt_name)?;
+        if details.integers.is_some() && !quantization.is_lossless() {
+            return Err(StoiError::InvalidValue(
+                "quilts of integers must be stored exactly, or they would lose precision",
+            ));
+        }
+        self.txn.execute(
+            "INSERT OR REPLACE INTO QuiltQuantization(quilt_name, quantization) VALUES (?, ?);",
+            &[&quilt_name, &serde_json::to_string(&quantization)?.as_ref()],
+        )?;
+        Ok(())
+    }
+
+    /// Set or clear the integer storage of a quilt
+    fn set_quilt_integers(
+        &mut self,
+        quilt_name: &str,
+        integers: Option<QuiltIntegers>,
+    ) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        let details = self.get_quilt_details(quilt_name)?;
+        match integers {
+            Some(integers) => {
+                // The fields are public, so check them again
+                QuiltIntegers::new(integers.min, integers.max, integers.rounding)?;
+                if !details.quantization.is_lossless() {
+                    return Err(StoiError::InvalidValue(
+                        "quilts of integers must be stored exactly, or they would lose precision",
+                    ));
+                }
+                self.txn.execute(
+                    "INSERT OR REPLACE INTO QuiltIntegers(quilt_name, integers) VALUES (?, ?);",
+                    &[&quilt_name, &serde_json::to_string(&integers)?.as_ref()],
+                )?
+            }
+            None => self.txn.execute(
+                "DELETE FROM QuiltIntegers WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Set or clear the storage limits of a quilt
+    fn set_quilt_quota(&mut self, quilt_name: &str, quota: Option<QuiltQuota>) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        self.get_quilt_details(quilt_name)?;
+        match quota {
+            Some(quota) => self.txn.execute(
+                "INSERT OR REPLACE INTO QuiltQuota(quilt_name, max_bytes, max_patches)
+                VALUES (?, ?, ?);",
+                &[
+                    &quilt_name as &dyn ToSql,
+                    &quota.max_bytes.map(|x| x as i64),
+                    &quota.max_patches.map(|x| x as i64),
+                ],
+            )?,
+            None => self.txn.execute(
+                "DELETE FROM QuiltQuota WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Get how much history a quilt keeps, if it's limited
+    fn get_quilt_retention(&mut self, quilt_name: &str) -> Fallible<Option<QuiltRetention>> {
+        Ok(self
+            .txn
+            .query_row(
+                "SELECT max_age_days, max_depth FROM QuiltRetention WHERE quilt_name = ?",
+                &[&quilt_name],
+                |r| {
+                    Ok(QuiltRetention {
+                        max_age_days: r.get::<usize, Option<i64>>(0)?.map(|x| x as u32),
+                        max_depth: r.get::<usize, Option<i64>>(1)?.map(|x| x as usize),
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Set or clear how much history a quilt keeps
+    fn set_quilt_retention(
+        &mut self,
+        quilt_name: &str,
+        retention: Option<QuiltRetention>,
+    ) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        self.get_quilt_details(quilt_name)?;
+        match retention {
+            Some(retention) => self.txn.execute(
+                "INSERT OR REPLACE INTO QuiltRetention(quilt_name, max_age_days, max_depth)
+                VALUES (?, ?, ?);",
+                &[
+                    &quilt_name as &dyn ToSql,
+                    &retention.max_age_days,
+                    &retention.max_depth.map(|x| x as i64),
+                ],
+            )?,
+            None => self.txn.execute(
+                "DELETE FROM QuiltRetention WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Merge the expired commits of a quilt into their parents
+    fn collect_expired_commits(&mut self, quilt_name: &str) -> Fallible<usize> {
+        let history = self.get_history(quilt_name)?;
+        let expired: HashSet<i64> = history
+            .iter()
+            .filter(|commit| commit.expired)
+            .map(|commit| commit.comm_id)
+            .collect();
+        let mut parents: HashMap<i64, Option<i64>> = history
+            .iter()
+            .map(|commit| (commit.comm_id, commit.parent_comm_id))
+            .collect();
+        let mut merged = vec![];
+        // Oldest first, so each run of expired commits ends up in the first of them
+        for commit in history.iter().filter(|commit| commit.expired) {
+            let parent = match parents[&commit.comm_id] {
+                Some(parent) if expired.contains(&parent) => parent,
+                _ => continue,
+            };
+            let children: i64 = self.txn.query_row(
+                "SELECT count(*) FROM Comm WHERE parent_comm_id = ?",
+                &[&parent],
+                |r| r.get(0),
+            )?;
+            // Patches of one commit are applied in order of id, which has to stay the same
+            let (parent_last, first): (Option<i64>, Option<i64>) = self.txn.query_row(
+                "SELECT
+                    (SELECT max(patch_id) FROM Patch WHERE comm_id = ?),
+                    (SELECT min(patch_id) FROM Patch WHERE comm_id = ?)",
+                &[&parent, &commit.comm_id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )?;
+            let in_order = match (parent_last, first) {
+                (Some(parent_last), Some(first)) => parent_last < first,
+                _ => true,
+            };
+            if children != 1 || !in_order {
+                continue;
+            }
+            self.txn.execute(
+                "UPDATE Patch SET comm_id = ? WHERE comm_id = ?",
+                &[&parent, &commit.comm_id],
+            )?;
+            self.txn.execute(
+                "UPDATE Comm SET parent_comm_id = ? WHERE parent_comm_id = ?",
+                &[&parent, &commit.comm_id],
+            )?;
+            // Retrying with the key should still find the commit, now that it's part of the parent
+            self.txn.execute(
+                "UPDATE CommitKey SET comm_id = ? WHERE comm_id = ?",
+                &[&parent, &commit.comm_id],
+            )?;
+            for table in &["CommitSequence", "CommitTime", "CommitStats", "Comm"] {
+                self.txn.execute(
+                    &format!("DELETE FROM {} WHERE comm_id = ?", table),
+                    &[&commit.comm_id],
+                )?;
+            }
+            for grandparent in parents.values_mut() {
+                if *grandparent == Some(commit.comm_id) {
+                    *grandparent = Some(parent);
+                }
+            }
+            merged.push(commit.comm_id);
+        }
+        if !merged.is_empty() {
+            self.trace(Counter::ExpiredCommit, merged.len());
+            self.audit(
+                "collect_expired_commits",
+                quilt_name,
+                serde_json::json!({ "merged": merged.len() }),
+                &merged,
+            )?;
+        }
+        Ok(merged.len())
+    }
+
+    /// Delete the commits of a quilt that no tag can reach, and their patches
+    fn collect_garbage(&mut self, quilt_name: &str) -> Fallible<usize> {
+        // Commits only belong to a quilt through its tags, so follow them all back
+        let unreachable: Vec<i64> = self
+            .txn
+            .prepare(
+                "
+                WITH RECURSIVE Reachable(comm_id) AS (
+                    SELECT comm_id FROM Tag WHERE quilt_name = ?1
+                    UNION
+                    SELECT Comm.parent_comm_id
+                        FROM Reachable
+                        INNER JOIN Comm USING (comm_id)
+                        WHERE Comm.parent_comm_id IS NOT NULL
+                )
+                SELECT comm_id FROM CommitSequence
+                WHERE quilt_name = ?1 AND comm_id NOT IN (SELECT comm_id FROM Reachable);
+                ",
+            )?
+            .query_map(&[&quilt_name], |r| r.get(0))?
+            .collect::<Result<_, _>>()?;
+        let mut deleted_patches = 0;
+        for comm_id in &unreachable {
+            let patch_ids: Vec<i64> = self
+                .txn
+                .prepare("SELECT patch_id FROM Patch WHERE comm_id = ?;")?
+                .query_map(&[comm_id], |r| r.get(0))?
+                .collect::<Result<_, _>>()?;
+            for patch_id in patch_ids {
+                self.del_patch(quilt_name, PatchID(patch_id))?;
+                deleted_patches += 1;
+            }
+            // The descendants of an unreachable commit are unreachable too, so they go as well
+            for table in &[
+                "CommitKey",
+                "CommitSequence",
+                "CommitTime",
+                "CommitStats",
+                "Comm",
+            ] {
+                self.txn.execute(
+                    &format!("DELETE FROM {} WHERE comm_id = ?", table),
+                    &[comm_id],
+                )?;
+            }
+        }
+        if !unreachable.is_empty() {
+            self.audit(
+                "collect_garbage",
+                quilt_name,
+                serde_json::json!({
+                    "commits": unreachable.len(),
+                    "patches": deleted_patches,
+                }),
+                &unreachable,
+            )?;
+        }
+        Ok(unreachable.len())
+    }
+
+    /// Untag a commit, and garbage collect whatever no other tag can reach now
+    fn untag(&mut self, quilt_name: &str, tag: &str) -> Fallible<()> {
+        let comm_id = self
+            .get_tag_commit(quilt_name, tag)?
+            .ok_or_else(|| StoiError::NotFound("tag", tag.into()))?;
+        self.txn.execute(
+            "DELETE FROM Tag WHERE quilt_name = ? AND tag_name = ?;",
+            &[&quilt_name, &tag],
+        )?;
+        // A new tag with the same name shouldn't inherit the index
+        self.disable_visibility_index(quilt_name, tag)?;
+        self.audit(
+            "untag",
+            quilt_name,
+            serde_json::json!({ "tag": tag }),
+            &[comm_id],
+        )?;
+        self.collect_garbage(quilt_name)?;
+        Ok(())
+    }
+
+    /// Get the target_patch_bytes() of a quilt, if it has its own
+    fn get_quilt_target_patch_bytes(&mut self, quilt_name: &str) -> Fallible<Option<usize>> {
+        let bytes: Option<i64> = self
+            .txn
+            .query_row(
+                "SELECT target_patch_bytes FROM QuiltTuning WHERE quilt_name = ?",
+                &[&quilt_name],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(bytes.map(|b| b as usize))
+    }
+
+    /// Set or clear the target_patch_bytes() of a quilt
+    fn set_quilt_target_patch_bytes(
+        &mut self,
+        quilt_name: &str,
+        bytes: Option<usize>,
+    ) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        self.get_quilt_details(quilt_name)?;
+        match bytes {
+            Some(0) => {
+                return Err(StoiError::InvalidValue(
+                    "target_patch_bytes must be positive",
+                ))
+            }
+            Some(bytes) => self.txn.execute(
+                "INSERT OR REPLACE INTO QuiltTuning(quilt_name, target_patch_bytes) VALUES (?, ?);",
+                &[&quilt_name as &dyn ToSql, &(bytes as i64)],
+            )?,
+            None => self.txn.execute(
+                "DELETE FROM QuiltTuning WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        if self.trace_quilt.as_deref() == Some(quilt_name) {
+            self.target_patch_bytes = self.effective_target_patch_bytes(quilt_name);
+        }
+        Ok(())
+    }
+
+    /// Get the axis a quilt is appended along, if it has one
+    fn get_quilt_append_axis(&mut self, quilt_name: &str) -> Fallible<Option<String>> {
+        Ok(self
+            .txn
+            .query_row(
+                "SELECT axis_name FROM QuiltAppendAxis WHERE quilt_name = ?",
+                &[&quilt_name],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Set or clear the axis a quilt is appended along
+    fn set_quilt_append_axis(&mut self, quilt_name: &str, axis_name: Option<&str>) -> Fallible<()> {
+        // Make sure the quilt exists first, for a better error
+        let details = self.get_quilt_details(quilt_name)?;
+        match axis_name {
+            Some(axis_name) => {
+                if !details.axes.iter().any(|ax| ax == axis_name) {
+                    return Err(StoiError::InvalidValue(
+                        "the append axis must be one of the quilt's axes",
+                    ));
+                }
+                self.txn.execute(
+                    "INSERT OR REPLACE INTO QuiltAppendAxis(quilt_name, axis_name) VALUES (?, ?);",
+                    &[&quilt_name, &axis_name],
+                )?
+            }
+            None => self.txn.execute(
+                "DELETE FROM QuiltAppendAxis WHERE quilt_name = ?;",
+                &[&quilt_name],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Get the storage used by all the commits of a quilt, measuring it if it's not counted yet
+    fn get_quilt_usage(&mut self, quilt_name: &str) -> Fallible<QuiltUsage> {
+        let counted: Option<(i64, i64)> = self
+            .txn
+            .query_row(
+                "SELECT bytes, patches FROM QuiltUsage WHERE quilt_name = ?;",
+                &[&quilt_name],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?;
+        if let Some((bytes, patches)) = counted {
+            return Ok(QuiltUsage {
+                bytes: bytes as u64,
+                patches: patches as u64,
+            });
+        }
+        let usage = self.measure_quilt_usage(quilt_name)?;
+        if self.write {
+            self.txn.execute(
+                "INSERT INTO QuiltUsage(quilt_name, bytes, patches) VALUES (?, ?, ?);",
+                &[
+                    &quilt_name as &dyn ToSql,
+                    &(usage.bytes as i64),
+                    &(usage.patches as i64),
+                ],
+            )?;
+        }
+        Ok(usage)
+    }
+
+    /// List the coldest patches of a quilt, saving the sampled reads first if possible
+    fn get_coldest_patches(&mut self, quilt_name: &str, bytes: u64) -> Fallible<Vec<PatchAccess>> {
+        if self.write {
+            self.put_access_samples()?;
+        }
+        let mut stmt = self.txn.prepare(
+            "
+            WITH RECURSIVE QuiltCommit(comm_id) AS (
+                SELECT comm_id FROM Tag WHERE quilt_name = ?
+                UNION
+                SELECT Comm.parent_comm_id
+                    FROM QuiltCommit
+                    INNER JOIN Comm USING (comm_id)
+                    WHERE Comm.parent_comm_id IS NOT NULL
+            )
+            SELECT
+                patch_id, length(content), reads, last_read,
+                dim_0_min, dim_0_max,
+                dim_1_min, dim_1_max,
+                dim_2_min, dim_2_max,
+                dim_3_min, dim_3_max
+            FROM QuiltCommit
+            INNER JOIN Patch USING (comm_id)
+            INNER JOIN PatchContent USING (patch_id)
+            LEFT JOIN PatchAccess USING (patch_id)
+            WHERE patch_id NOT IN (SELECT patch_id FROM PatchSetMember)
+            ORDER BY last_read IS NOT NULL, last_read, patch_id;
+            ",
+        )?;
+        let mut rows = stmt.query(&[&quilt_name])?;
+        let mut coldest = vec![];
+        let mut total = 0;
+        while total < bytes {
+            let row = match rows.next()? {
+                Some(row) => row,
+                None => break,
+            };
+            let access = PatchAccess {
+                patch_id: row.get(0)?,
+                bytes: row.get::<usize, i64>(1)? as u64,
+                reads: row.get::<usize, Option<i64>>(2)?.unwrap_or(0) as u64,
+                last_read: row.get(3)?,
+                bounding_box: BoundingBox::from_sql_row(row, 4)?,
+            };
+            total += access.bytes;
+            coldest.push(access);
+        }
+        Ok(coldest)
+    }
+
+    /// Get details about a quilt by name
+    ///
+    /// What details are available may depend on the quilt, and fields are likely to
+    /// be added with time (so be careful with serializing QuiltDetails)
+    fn get_quilt_details(&mut self, quilt_name: &str) -> Fallible<QuiltDetails> {
+        let deets = self
+            .txn
+            .query_row_and_then(
+                "SELECT quilt_name, axes, unit_name, unit_scale, unit_offset, quantization,
+                    integers, max_bytes, max_patches, namespace
+                FROM Quilt
+                LEFT JOIN QuiltUnits USING (quilt_name)
+                LEFT JOIN QuiltQuantization USING (quilt_name)
+                LEFT JOIN QuiltIntegers USING (quilt_name)
+                LEFT JOIN QuiltQuota USING (quilt_name)
+                LEFT JOIN QuiltNamespace USING (quilt_name)
+                WHERE quilt_name = ?",
+                &[&quilt_name],
+                |r| QuiltDetails::try_from(r),
+            )
+            .optional()?;
+        match deets {
+            None => Err(StoiError::NotFound(
+                "quilt doesn't exist",
+                quilt_name.into(),
+            )),
+            Some(x) => Ok(x),
+        }
+    }
+
+    /// Get the Patch IDs that would have to be applied to fill a fetch(), in the order they would
+    /// need to be applied.
+    ///
+    /// You can specify many bounding boxes but the search degrades the more are provided. It's
+    /// probably never a good idea to load more than a hundred.
+    ///
+    /// For fetching, you need deep=true, so you see the state of the tensor at that commit,
+    /// but for compaction, deep=false can avoid filling in void areas.
+    ///
+    /// Accepts:
+    ///     quilt_name: the quilt associated with the tag we're looking for
+    ///     tag: the named commit
+    ///     deep: whether to provide only the patches within this quilt, or all it's ancestors
+    ///     bounding_boxes: returned patches should intersect at least one of these boxes
+    ///
+    /// Returns:
+    ///     A vector of Patch ID's
+    fn search(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        deep: bool,
+        bounding_boxes: &[BoundingBox],
+    ) -> Fallible<Vec<PatchRef>> {
+        self.trace_quilt(quilt_name);
+        // With a visibility index, the patches of the tag are already known
+        let use_index = deep && self.has_visibility_index(quilt_name, tag)?;
+        self.search_patches(quilt_name, tag, deep, use_index, bounding_boxes)
+    }
+
+    fn get_tag_commit(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<i64>> {
+        Ok(self
+            .txn
+            .query_row(
+                "SELECT comm_id FROM Tag WHERE quilt_name = ? AND tag_name = ?",
+                &[&quilt_name, &tag],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    fn get_patch(&mut self, id: PatchID) -> Fallible<Patch> {
+        self.trace(Counter::ReadPatch, 1);
+        self.sample_access(id);
+        if let Some(patchset_id) = self.get_patchset_id(id)? {
+            return self.get_patchset_member(patchset_id, id);
+        }
+        // Copy the content into a spare buffer, rather than a new one each time
+        let mut res = self.pool.take_bytes();
+        self.txn
+            .query_row(
+                "SELECT content FROM PatchContent WHERE patch_id = ?",
+                &[&id],
+                |r| {
+                    if let ValueRef::Blob(content) = r.get_raw(0) {
+                        res.extend_from_slice(content);
+                    }
+                    Ok(())
+                },
+            )
+            .optional()?
+            .ok_or_else(|| StoiError::NotFound("patch content", id.0.to_string()))?;
+        self.trace(Counter::ReadBytes, res.len());
+        let p = Patch::deserialize_stored(&res[..]);
+        self.pool.give_bytes(res);
+        p
+    }
+
+    /// Read the stored content of a patch, to decode it somewhere else
+    fn get_patch_content(&mut self, id: PatchID) -> Fallible<Option<Vec<u8>>> {
+        self.trace(Counter::ReadPatch, 1);
+        if self.get_patchset_id(id)?.is_some() {
+            return Ok(None);
+        }
+        let content: Vec<u8> = self
+            .txn
+            .query_row(
+                "SELECT content FROM PatchContent WHERE patch_id = ?",
+                &[&id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| StoiError::NotFound("patch content", id.0.to_string()))?;
+        self.trace(Counter::ReadBytes, content.len());
+        self.sample_access(id);
+        Ok(Some(content))
+    }
+
+    /// Create the empty patch a fetch assembles its result in, reusing a spare buffer
+    fn new_target_patch(&mut self, axes: Vec<Axis>) -> Fallible<Patch> {
+        if !self.pool.is_enabled() {
+            return Patch::new(axes, None);
+        }
+        let (buffer, reused) = self
+            .pool
+            .take_floats(axes.iter().map(|a| a.len()).product());
+        self.trace(
+            if reused {
+                Counter::PoolReuse
+            } else {
+                Counter::PoolAllocate
+            },
+            1,
+        );
+        Patch::from_buffer(axes, buffer)
+    }
+
+    /// Give the patch's buffer to the pool
+    fn recycle_patch(&mut self, patch: Patch) {
+        if self.pool.is_enabled() {
+            self.pool.give_floats(patch.into_buffer());
+        }
+    }
+
+    // put_patch is part of Self, not Storage because you can only do it using put_commit()
+
+    /// Make changes to a tensor via a commit
+    ///
+    /// This is only available together, so that the underlying storage media can do this
+    /// atomically without a complicated API
+
+    fn put_commit(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+    ) -> Fallible<()> {
+        let patches = patches
+            .iter()
+            .map(|&pat| (Cow::Borrowed(pat), None))
+            .collect();
+        self.put_commit_checked(quilt_name, parent_tag, new_tag, message, patches)
+    }
+
+    /// Make changes to a tensor via a commit, with patches prepared outside the transaction
+    fn put_commit_prepared(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: Vec<PreparedPatch>,
+    ) -> Fallible<()> {
+        self.check_commit_message(message)?;
+        let quantization = self.get_quilt_details(quilt_name)?.storage_quantization();
+        let compression = self.patch_compression;
+        let patches = patches
+            .into_iter()
+            .map(|prepared| {
+                // Settings may have changed since it was prepared
+                let current = prepared.compression == compression
+                    && prepared.quantization == quantization;
+                let content = if current { Some(prepared.content) } else { None };
+                (Cow::Owned(prepared.patch), content)
+            })
+            .collect();
+        self.put_commit_checked(quilt_name, parent_tag, new_tag, message, patches)
+    }
+
+    /// Get the rules every commit message must follow
+    fn get_commit_rules(&mut self) -> Fallible<CommitRules> {
+        let rules: Option<String> = self
+            .txn
+            .query_row("SELECT rules FROM CommitRules", NO_PARAMS, |r| r.get(0))
+            .optional()?;
+        match rules {
+            Some(rules) => Ok(serde_json::from_str(&rules)?),
+            None => Ok(CommitRules::default()),
+        }
+    }
+
+    /// Set the rules every commit message must follow
+    fn set_commit_rules(&mut self, rules: &CommitRules) -> Fallible<()> {
+        let compiled = rules.compiled()?;
+        self.txn.execute(
+            "INSERT OR REPLACE INTO CommitRules(rules_id, rules) VALUES (1, ?);",
+            &[&serde_json::to_string(rules)?],
+        )?;
+        self.commit_rules = Some(compiled);
+        Ok(())
+    }
+
+    /// Check a commit message against the catalog's CommitRules, compiling them only once
+    fn check_commit_message(&mut self, message: &str) -> Fallible<()> {
+        let rules = match self.commit_rules.take() {
+            Some(rules) => rules,
+            None => self.get_commit_rules()?.compiled()?,
+        };
+        let checked = rules.check(message);
+        self.commit_rules = Some(rules);
+        checked
+    }
+
+    /// Rewrite the patches of a tag's commit in a region into as few patches as possible
+    fn compact_region(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        bounding_box: BoundingBox,
+    ) -> Fallible<usize> {
+        let comm_id: i64 = self
+            .txn
+            .query_row(
+                "SELECT comm_id FROM Tag WHERE quilt_name = ? AND tag_name = ?",
+                &[&quilt_name, &tag],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| StoiError::NotFound("tag", tag.into()))?;
+        let in_region = self.search(quilt_name, tag, false, &[bounding_box])?;
+        if in_region.len() < 2 {
+            // There's nothing to merge, but it may still be in an old format
+            let mut upgraded = 0;
+            for patch_ref in &in_region {
+                if self.upgrade_patch(patch_ref.id)? {
+                    upgraded += 1;
+                }
+            }
+            if upgraded > 0 {
+                self.audit(
+                    "compact_region",
+                    quilt_name,
+                    serde_json::json!({
+                        "tag": tag,
+                        "bounding_box": bounding_box,
+                        "upgraded": upgraded,
+                    }),
+                    &[comm_id],
+                )?;
+            }
+            return Ok(upgraded);
+        }
+        let quilt_details = self.get_quilt_details(quilt_name)?;
+
+        // Everything the replaced patches covered has to be rewritten, not just the region,
+        // or we would lose whatever was outside the region. Other patches of the commit that
+        // overlap that hull are replaced too, so none of the rest overlap the new patches.
+        let mut hull = in_region[0].bounding_box;
+        for patch_ref in &in_region[1..] {
+            hull = hull.hull(&patch_ref.bounding_box);
+        }
+        let mut rest = self.search(quilt_name, tag, false, &[BoundingBox::everywhere()])?;
+        let mut patch_refs = vec![];
+        loop {
+            let (overlapping, others): (Vec<PatchRef>, Vec<PatchRef>) = rest
+                .into_iter()
+                .partition(|patch_ref| boxes_overlap(&hull, &patch_ref.bounding_box));
+            rest = others;
+            if overlapping.is_empty() {
+                break;
+            }
+            for patch_ref in overlapping {
+                hull = hull.hull(&patch_ref.bounding_box);
+                patch_refs.push(patch_ref);
+            }
+        }
+        // Apply them in the order fetch() would, but only these, since the ancestors' patches
+        // aren't part of this commit
+        patch_refs.sort_by_key(|patch_ref| patch_ref.id);
+        let mut request = vec![];
+        for (ax_ix, axis_name) in quilt_details.axes.iter().enumerate() {
+            let end = (hull[ax_ix].1 + 1).min(self.get_axis(axis_name)?.len());
+            request.push(AxisSelection::StorageSlice(hull[ax_ix].0, end));
+        }
+        let (axes, _) = self.resolve_request(quilt_name, request)?;
+        let mut visible_area = self.new_target_patch(axes)?;
+        for patch_ref in &patch_refs {
+            let patch = self.get_patch(patch_ref.id)?;
+            visible_area.apply(&patch)?;
+        }
+
+        // Tags that could see the old patches should see the new ones instead
+        let indexed_tags = self
+            .txn
+            .prepare(
+                "SELECT DISTINCT quilt_name, tag_name FROM VisibleIndex
+                WHERE patch_id IN (SELECT value FROM json_each(?));",
+            )?
+            .query_map(
+                &[&serde_json::to_string(&patch_refs.iter().map(|r| r.id.0).collect_vec())?],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        for patch_ref in &patch_refs {
+            self.del_patch(quilt_name, patch_ref.id)?;
+        }
+        let quantization = quilt_details.storage_quantization();
+        for new_patch in self.maybe_split(visible_area.compact().into_owned())? {
+            let bbox = self.get_bounding_box(&new_patch)?;
+            let patch_id =
+                self.put_patch(quilt_name, comm_id, &new_patch, bbox, quantization, None)?;
+            let covers = Self::covers_bounding_box(&new_patch, &bbox);
+            for (indexed_quilt, indexed_tag) in &indexed_tags {
+                self.txn.execute(
+                    "INSERT OR REPLACE INTO VisibleIndex(quilt_name, tag_name, patch_id, covers)
+                    VALUES (?, ?, ?, ?);",
+                    &[indexed_quilt as &dyn ToSql, indexed_tag, &patch_id, &covers],
+                )?;
+            }
+        }
+        self.audit(
+            "compact_region",
+            quilt_name,
+            serde_json::json!({
+                "tag": tag,
+                "bounding_box": bounding_box,
+                "replaced": patch_refs.len(),
+            }),
+            &[comm_id],
+        )?;
+        Ok(patch_refs.len())
+    }
+
+    /// Get the most recent entries of the audit log, newest first
+    fn get_audit_log(
+        &mut self,
+        quilt_name: Option<&str>,
+        limit: usize,
+    ) -> Fallible<Vec<AuditEntry>> {
+        let mut stmt = self.txn.prepare(
+            "SELECT timestamp, actor, operation, quilt_name, parameters, comm_ids
+            FROM AuditLog
+            WHERE coalesce(? = quilt_name, 1)
+            ORDER BY audit_id DESC
+            LIMIT ?;",
+        )?;
+        let mut rows = stmt.query(&[&quilt_name as &dyn ToSql, &(limit as i64)])?;
+        let mut entries = vec![];
+        while let Some(row) = rows.next()? {
+            entries.push(AuditEntry {
+                timestamp: row.get(0)?,
+                actor: row.get(1)?,
+                operation: row.get(2)?,
+                quilt_name: row.get(3)?,
+                parameters: serde_json::from_str(&row.get::<_, String>(4)?)?,
+                comm_ids: serde_json::from_str(&row.get::<_, String>(5)?)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Get what was captured when this catalog was made as a backup, if it was
+    fn get_snapshot(&mut self) -> Fallible<Option<CatalogSnapshot>> {
+        let snapshot: Option<(String, String)> = self
+            .txn
+            .query_row(
+                "SELECT taken_at, tags FROM Snapshot ORDER BY snapshot_id DESC LIMIT 1;",
+                NO_PARAMS,
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?;
+        Ok(match snapshot {
+            Some((taken_at, tags)) => Some(CatalogSnapshot {
+                taken_at,
+                tags: serde_json::from_str(&tags)?,
+            }),
+            None => None,
+        })
+    }
+
+    /// Get up to `limit` commits to a quilt after `last`, with their patches
+    fn get_changes_since(
+        &mut self,
+        quilt_name: &str,
+        last: (i64, i64),
+        limit: usize,
+    ) -> Fallible<Vec<CommitChanges>> {
+        // Commits only belong to a quilt through its tags, so follow them all back
+        let mut stmt = self.txn.prepare(
+            "
+            WITH RECURSIVE QuiltCommit(comm_id) AS (
+                SELECT comm_id FROM Tag WHERE quilt_name = ?1
+                UNION
+                SELECT Comm.parent_comm_id
+                    FROM QuiltCommit
+                    INNER JOIN Comm USING (comm_id)
+                    WHERE Comm.parent_comm_id IS NOT NULL
+            ),
+            Sequenced(comm_id, sequence) AS (
+                SELECT comm_id, coalesce(Seq.sequence, 0)
+                    FROM QuiltCommit
+                    LEFT JOIN CommitSequence Seq USING (comm_id)
+            ),
+            Changed(comm_id, sequence) AS (
+                SELECT comm_id, sequence FROM Sequenced
+                    WHERE sequence > ?2 OR (sequence = ?2 AND comm_id > ?3)
+                    ORDER BY sequence ASC, comm_id ASC
+                    LIMIT ?4
+            )
+            SELECT
+                comm_id, message, patch_id,
+                dim_0_min, dim_0_max,
+                dim_1_min, dim_1_max,
+                dim_2_min, dim_2_max,
+                dim_3_min, dim_3_max,
+                Changed.sequence
+                FROM Changed
+                INNER JOIN Comm USING (comm_id)
+                LEFT JOIN Patch USING (comm_id)
+                ORDER BY Changed.sequence ASC, comm_id ASC, patch_id ASC;
+            ",
+        )?;
+        let mut rows = stmt.query(&[
+            &quilt_name as &dyn ToSql,
+            &last.0,
+            &last.1,
+            &(limit as i64),
+        ])?;
+        let mut changes: Vec<CommitChanges> = vec![];
+        while let Some(row) = rows.next()? {
+            let row_comm_id: i64 = row.get(0)?;
+            if changes.last().map(|change| change.comm_id) != Some(row_comm_id) {
+                changes.push(CommitChanges {
+                    comm_id: row_comm_id,
+                    sequence: row.get(11)?,
+                    message: row.get::<usize, Option<String>>(1)?.unwrap_or_default(),
+                    patch_ids: vec![],
+                    bounding_boxes: vec![],
+                });
+            }
+            // Commits without patches have one row, with no patch
+            if let Some(patch_id) = row.get::<usize, Option<PatchID>>(2)? {
+                let bounding_box = BoundingBox::from_sql_row(row, 3)?;
+                let change = changes.last_mut().unwrap(); // <- Pushed above if it was new
+                change.patch_ids.push(patch_id);
+                change.bounding_boxes.push(bounding_box);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Get the commits of a quilt, see StorageTransaction::get_history()
+    fn get_history(&mut self, quilt_name: &str) -> Fallible<Vec<HistoryCommit>> {
+        let retention = self.get_quilt_retention(quilt_name)?;
+        let mut stmt = self.txn.prepare(
+            "
+            WITH RECURSIVE QuiltCommit(comm_id) AS (
+                SELECT comm_id FROM Tag WHERE quilt_name = ?
+                UNION
+                SELECT Comm.parent_comm_id
+                    FROM QuiltCommit
+                    INNER JOIN Comm USING (comm_id)
+                    WHERE Comm.parent_comm_id IS NOT NULL
+            )
+            SELECT
+                Comm.comm_id, Comm.parent_comm_id, Comm.message,
+                (SELECT count(*) FROM Patch WHERE Patch.comm_id = Comm.comm_id),
+                Time.created_at
+                FROM QuiltCommit
+                INNER JOIN Comm USING (comm_id)
+                LEFT JOIN CommitSequence Seq ON Seq.comm_id = Comm.comm_id
+                LEFT JOIN CommitTime Time ON Time.comm_id = Comm.comm_id
+                ORDER BY coalesce(Seq.sequence, 0), Comm.comm_id;
+            ",
+        )?;
+        let mut history = stmt
+            .query_map(&[&quilt_name], |r| {
+                Ok(HistoryCommit {
+                    comm_id: r.get(0)?,
+                    parent_comm_id: r.get(1)?,
+                    message: r.get::<usize, Option<String>>(2)?.unwrap_or_default(),
+                    patches: r.get::<usize, i64>(3)? as usize,
+                    tags: vec![],
+                    created_at: r.get(4)?,
+                    expired: false,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut stmt = self
+            .txn
+            .prepare("SELECT tag_name, comm_id FROM Tag WHERE quilt_name = ? ORDER BY tag_name")?;
+        let tags = stmt
+            .query_map(&[&quilt_name], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<(String, i64)>, _>>()?;
+        for (tag, comm_id) in tags {
+            if let Some(commit) = history.iter_mut().find(|c| c.comm_id == comm_id) {
+                commit.tags.push(tag);
+            }
+        }
+        if let Some(retention) = retention {
+            retention.mark_expired(&mut history, chrono::Utc::now());
+        }
+        Ok(history)
+    }
+
+    /// Derive a quilt from another, or stop deriving it
+    fn set_derived_quilt(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        derivation: Option<Derivation>,
+    ) -> Fallible<()> {
+        // Make sure the quilts exist first, for a better error
+        self.get_quilt_details(src_quilt)?;
+        self.get_quilt_details(dst_quilt)?;
+        if derivation.is_some() {
+            // Derived commits queue work of their own, so a cycle would never stop
+            if src_quilt == dst_quilt {
+                return Err(StoiError::InvalidValue("a quilt can't be derived from itself"));
+            }
+            let cycle: bool = self.txn.query_row(
+                "WITH RECURSIVE Downstream(quilt_name) AS (
+                    SELECT ?
+                    UNION
+                    SELECT dst_quilt_name
+                    FROM DerivedQuilt
+                    INNER JOIN Downstream ON (src_quilt_name = quilt_name)
+                )
+                SELECT EXISTS(SELECT 1 FROM Downstream WHERE quilt_name = ?);",
+                &[&dst_quilt, &src_quilt],
+                |r| r.get(0),
+            )?;
+            if cycle {
+                return Err(StoiError::InvalidValue(
+                    "the source is derived from the destination, which would make a cycle",
+                ));
+            }
+        }
+        match derivation {
+            Some(derivation) => self.txn.execute(
+                "INSERT OR REPLACE INTO DerivedQuilt(src_quilt_name, dst_quilt_name, derivation)
+                VALUES (?, ?, ?);",
+                &[
+                    &src_quilt,
+                    &dst_quilt,
+                    &serde_json::to_string(&derivation)?.as_str(),
+                ],
+            )?,
+            None => self.txn.execute(
+                "DELETE FROM DerivedQuilt WHERE src_quilt_name = ? AND dst_quilt_name = ?;",
+                &[&src_quilt, &dst_quilt],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Get how one quilt is derived from another, if it is
+    fn get_derivation(&mut self, src_quilt: &str, dst_quilt: &str) -> Fallible<Option<Derivation>> {
+        let derivation: Option<String> = self
+            .txn
+            .query_row(
+                "SELECT derivation FROM DerivedQuilt
+                WHERE src_quilt_name = ? AND dst_quilt_name = ?;",
+                &[&src_quilt, &dst_quilt],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(match derivation {
+            Some(derivation) => Some(serde_json::from_str(&derivation)?),
+            None => None,
+        })
+    }
+
+    /// Get the rules deriving quilts from a quilt, or from any quilt, and their staleness
+    fn get_derived_quilts(&mut self, src_quilt: Option<&str>) -> Fallible<Vec<DerivedQuiltStatus>> {
+        let mut stmt = self.txn.prepare(
+            "SELECT q.src_quilt_name, q.dst_quilt_name, q.derivation,
+                count(w.work_id), min(w.queued_at), q.refreshed_at
+            FROM DerivedQuilt q
+            LEFT JOIN DerivedWork w USING (src_quilt_name, dst_quilt_name)
+            WHERE ?1 IS NULL OR q.src_quilt_name = ?1
+            GROUP BY q.src_quilt_name, q.dst_quilt_name
+            ORDER BY q.src_quilt_name, q.dst_quilt_name;",
+        )?;
+        let mut rows = stmt.query(&[&src_quilt])?;
+        let mut statuses = vec![];
+        while let Some(row) = rows.next()? {
+            statuses.push(DerivedQuiltStatus {
+                src_quilt: row.get(0)?,
+                dst_quilt: row.get(1)?,
+                derivation: serde_json::from_str(&row.get::<_, String>(2)?)?,
+                pending: row.get::<_, i64>(3)? as usize,
+                stale_since: row.get(4)?,
+                refreshed_at: row.get(5)?,
+            });
+        }
+        Ok(statuses)
+    }
+
+    /// Queue work to derive a whole tag of a derived quilt again
+    fn queue_derived_refresh(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        tag: &str,
+    ) -> Fallible<()> {
+        if self.get_derivation(src_quilt, dst_quilt)?.is_none() {
+            return Err(StoiError::NotFound(
+                "derived quilt",
+                format!("{} from {}", dst_quilt, src_quilt),
+            ));
+        }
+        let region = BoundingBox::everywhere();
+        self.txn.execute(
+            "INSERT INTO DerivedWork(
+                src_quilt_name, dst_quilt_name, tag_name, bounding_box, queued_at)
+            VALUES (?, ?, ?, ?, ?);",
+            &[
+                &src_quilt as &dyn ToSql,
+                &dst_quilt,
+                &tag,
+                &serde_json::to_string(&region)?,
+                &chrono::Utc::now().to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get the oldest work queued for derived quilts
+    fn get_derived_work(&mut self, limit: usize) -> Fallible<Vec<DerivedWork>> {
+        let mut stmt = self.txn.prepare(
+            "SELECT work_id, src_quilt_name, dst_quilt_name, tag_name, bounding_box
+            FROM DerivedWork
+            ORDER BY work_id
+            LIMIT ?;",
+        )?;
+        let mut rows = stmt.query(&[limit as i64])?;
+        let mut work = vec![];
+        while let Some(row) = rows.next()? {
+            work.push(DerivedWork {
+                work_id: row.get(0)?,
+                src_quilt: row.get(1)?,
+                dst_quilt: row.get(2)?,
+                tag: row.get(3)?,
+                bounding_box: serde_json::from_str(&row.get::<_, String>(4)?)?,
+            });
+        }
+        Ok(work)
+    }
+
+    /// Remove an item from the queue of work for derived quilts
+    fn finish_derived_work(&mut self, work_id: i64) -> Fallible<()> {
+        self.txn.execute(
+            "UPDATE DerivedQuilt SET refreshed_at = ?
+            WHERE EXISTS (
+                SELECT 1 FROM DerivedWork w
+                WHERE w.work_id = ?
+                AND w.src_quilt_name = DerivedQuilt.src_quilt_name
+                AND w.dst_quilt_name = DerivedQuilt.dst_quilt_name);",
+            &[&chrono::Utc::now().to_rfc3339() as &dyn ToSql, &work_id],
+        )?;
+        self.txn
+            .execute("DELETE FROM DerivedWork WHERE work_id = ?;", &[work_id])?;
+        Ok(())
+    }
+
+    /// Start keeping a visibility index for a tag, and build it
+    fn enable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize> {
+        self.txn.execute(
+            "INSERT OR IGNORE INTO VisibleIndexTag(quilt_name, tag_name) VALUES (?, ?);",
+            &[&quilt_name, &tag],
+        )?;
+        self.rebuild_visibility_index(quilt_name, tag)
+    }
+
+    /// Stop keeping a visibility index for a tag, and delete it
+    fn disable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<()> {
+        self.txn.execute(
+            "DELETE FROM VisibleIndexTag WHERE quilt_name = ? AND tag_name = ?;",
+            &[&quilt_name, &tag],
+        )?;
+        self.txn.execute(
+            "DELETE FROM VisibleIndex WHERE quilt_name = ? AND tag_name = ?;",
+            &[&quilt_name, &tag],
+        )?;
+        Ok(())
+    }
+
+    /// Build the visibility index of a tag again from its ancestry
+    fn rebuild_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize> {
+        if !self.has_visibility_index(quilt_name, tag)? {
+            return Err(StoiError::NotFound("visibility index for tag", tag.into()));
+        }
+        self.txn.execute(
+            "DELETE FROM VisibleIndex WHERE quilt_name = ? AND tag_name = ?;",
+            &[&quilt_name, &tag],
+        )?;
+        let everywhere = BoundingBox::everywhere();
+        let patch_refs = self.search_patches(quilt_name, tag, true, false, &[everywhere])?;
+        // Commits know whether their patches cover their boxes, but here they have to be read
+        let mut covers = Vec::with_capacity(patch_refs.len());
+        for patch_ref in &patch_refs {
+            let patch = self.get_patch(patch_ref.id)?;
+            covers.push(Self::covers_bounding_box(&patch, &patch_ref.bounding_box));
+        }
+        let mut indexed = 0;
+        for (ix, patch_ref) in patch_refs.iter().enumerate() {
+            // Leave out what a later patch hides completely, like the commits would have
+            let bbox = &patch_ref.bounding_box;
+            let hidden = (ix + 1..patch_refs.len())
+                .any(|later| covers[later] && patch_refs[later].bounding_box.contains(bbox));
+            if hidden {
+                continue;
+            }
+            self.txn.execute(
+                "INSERT INTO VisibleIndex(quilt_name, tag_name, patch_id, covers)
+                VALUES (?, ?, ?, ?);",
+                &[&quilt_name as &dyn ToSql, &tag, &patch_ref.id, &covers[ix]],
+            )?;
+            indexed += 1;
+        }
+        Ok(indexed)
+    }
+
+    /// Compare the visibility index of a tag to its ancestry
+    fn check_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<Vec<String>> {
+        if !self.has_visibility_index(quilt_name, tag)? {
+            return Err(StoiError::NotFound("visibility index for tag", tag.into()));
+        }
+        let indexed: HashMap<PatchID, bool> = self
+            .txn
+            .prepare(
+                "SELECT patch_id, covers FROM VisibleIndex WHERE quilt_name = ? AND tag_name = ?;",
+            )?
+            .query_map(&[&quilt_name, &tag], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let everywhere = BoundingBox::everywhere();
+        let patch_refs = self.search_patches(quilt_name, tag, true, false, &[everywhere])?;
+
+        let mut problems = vec![];
+        let visible: HashSet<PatchID> = patch_refs.iter().map(|r| r.id).collect();
+        for id in indexed.keys().filter(|id| !visible.contains(*id)).sorted() {
+            problems.push(format!("patch {} is indexed but isn't part of the tag", id.0));
+        }
+        for (ix, patch_ref) in patch_refs.iter().enumerate() {
+            if indexed.contains_key(&patch_ref.id) {
+                continue;
+            }
+            // It's fine to leave it out if a later patch hides it completely
+            let hidden = patch_refs[ix + 1..].iter().any(|later| {
+                indexed.get(&later.id) == Some(&true)
+                    && later.bounding_box.contains(&patch_ref.bounding_box)
+            });
+            if !hidden {
+                problems.push(format!(
+                    "patch {} is part of the tag but isn't indexed",
+                    patch_ref.id.0
+                ));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Rewrite a patch in place in the current format, if it was written in an older one
+    fn upgrade_patch(&mut self, id: PatchID) -> Fallible<bool> {
+        if self.get_patchset_id(id)?.is_some() {
+            // Patchsets are only written by this version, so their patches are current
+            return Ok(false);
+        }
+        let content: Vec<u8> = self
+            .txn
+            .query_row(
+                "SELECT content FROM PatchContent WHERE patch_id = ?",
+                &[&id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| StoiError::NotFound("patch content", id.0.to_string()))?;
+        if Patch::serialized_version(&content)? >= PATCH_VERSION {
+            return Ok(false);
+        }
+        let mut buffer = vec![];
+        Patch::upgrade_serialized(&content, Some(self.patch_compression), &mut buffer)?;
+        self.txn.execute(
+            "UPDATE PatchContent SET content = ? WHERE patch_id = ?;",
+            &[&buffer as &dyn ToSql, &id],
+        )?;
+        let quilt_name: Option<String> = self
+            .txn
+            .query_row(
+                "SELECT quilt_name FROM Patch INNER JOIN CommitSequence USING (comm_id)
+                WHERE patch_id = ?;",
+                &[&id],
+                |r| r.get(0),
+            )
+            .optional()?;
+        if let Some(quilt_name) = quilt_name {
+            let grown = buffer.len() as i64 - content.len() as i64;
+            self.add_quilt_usage(&quilt_name, grown, 0)?;
+        }
+        Ok(true)
+    }
+
+    /// Save a data quality report for the commit a tag points to
+    fn put_commit_stats(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        stats: &CommitStats,
+    ) -> Fallible<()> {
+        let changes = self.txn.execute(
+            "INSERT OR REPLACE INTO CommitStats(comm_id, stats)
+            SELECT comm_id, ? FROM Tag WHERE quilt_name = ? AND tag_name = ?;",
+            &[&serde_json::to_string(stats)? as &dyn ToSql, &quilt_name, &tag],
+        )?;
+        if changes == 0 {
+            return Err(StoiError::NotFound("tag", tag.into()));
+        }
+        Ok(())
+    }
+
+    /// Get the data quality report for the commit a tag points to
+    fn get_commit_stats(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<CommitStats>> {
+        let stats: Option<String> = self
+            .txn
+            .query_row(
+                "SELECT stats FROM Tag
+                INNER JOIN CommitStats USING (comm_id)
+                WHERE quilt_name = ? AND tag_name = ?;",
+                &[&quilt_name, &tag],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(match stats {
+            Some(stats) => Some(serde_json::from_str(&stats)?),
+            None => None,
+        })
+    }
+
+    /// Record the key of the commit a tag points to, returning the id of that commit
+    fn put_commit_key(&mut self, quilt_name: &str, tag: &str, commit_key: &str) -> Fallible<i64> {
+        let comm_id = self
+            .get_tag_commit(quilt_name, tag)?
+            .ok_or_else(|| StoiError::NotFound("tag", tag.into()))?;
+        self.txn.execute(
+            "INSERT INTO CommitKey(quilt_name, commit_key, comm_id) VALUES (?, ?, ?);",
+            &[&quilt_name as &dyn ToSql, &commit_key, &comm_id],
+        )?;
+        Ok(comm_id)
+    }
+
+    /// Get the id of the commit made on a quilt with this key, if there is one
+    fn get_keyed_commit(&mut self, quilt_name: &str, commit_key: &str) -> Fallible<Option<i64>> {
+        Ok(self
+            .txn
+            .query_row(
+                "SELECT comm_id FROM CommitKey WHERE quilt_name = ? AND commit_key = ?",
+                &[&quilt_name, &commit_key],
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    fn savepoint(&mut self, name: &str) -> Fallible<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(StoiError::InvalidValue(
+                "savepoint names must be letters, digits and underscores",
+            ));
+        }
+        // Prefixed, so they can't be confused with the savepoints commits use internally
+        self.txn
+            .execute_batch(&format!("SAVEPOINT user_{};", name))?;
+        self.savepoints.push(name.to_string());
+        Ok(())
+    }
+
+    fn release(&mut self, name: &str) -> Fallible<()> {
+        let position = self.find_savepoint(name)?;
+        self.txn.execute_batch(&format!("RELEASE user_{};", name))?;
+        self.savepoints.truncate(position);
+        Ok(())
+    }
+
+    fn rollback_to(&mut self, name: &str) -> Fallible<()> {
+        let position = self.find_savepoint(name)?;
+        self.txn
+            .execute_batch(&format!("ROLLBACK TO user_{};", name))?;
+        self.savepoints.truncate(position + 1);
+        // Anything cached could have been changed since the savepoint
+        self.axis_cache.clear();
+        self.axis_generations.clear();
+        self.label_indices.clear();
+        self.sorted_label_indices.clear();
+        self.commit_rules = None;
+        if let Some(quilt_name) = self.trace_quilt.take() {
+            self.trace_quilt(&quilt_name);
+        }
+        Ok(())
+    }
+
+    /// Commit the transaction
+    fn finish(mut self) -> Fallible<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            // Dropping it rolls it back
+            return Err(StoiError::Closed);
+        }
+        if self.write {
+            self.put_access_samples()?;
+        }
+        println!("Transaction completed with stats {:#?}", self.trace);
+        Ok(self.txn.execute_batch("COMMIT;")?)
+    }
+
+    /// Rollback the transaction
+    fn rollback(self) -> Fallible<()> {
+        println!("Transaction rolled back with stats {:#?}", self.trace);
+        Ok(self.txn.execute_batch("ROLLBACK;")?)
+    }
+}
+
+/// Rollback the transaction by default
+///
+/// This is also where the transaction's counters are added to the catalog totals,
+/// since every transaction is dropped exactly once however it ends.
+impl<'t> Drop for SQLiteTransaction<'t> {
+    fn drop(&mut self) {
+        self.totals.accumulate(&self.trace);
+        self.totals
+            .accumulate_breakdown(&self.trace_by_quilt, &self.trace_by_axis);
+        // Reads sampled but not saved wait for the next write transaction
+        if let Ok(mut backlog) = self.access_backlog.lock() {
+            for (id, sample) in self.access_samples.drain() {
+                merge_access_sample(&mut backlog, id, sample);
+            }
+        }
+        self.txn.execute_batch("ROLLBACK;").unwrap_or(());
+        if !self.write {
+            // The connection is shared with later transactions, which may write
+            self.txn
+                .execute_batch("PRAGMA query_only = OFF;")
+                .unwrap_or(());
+        }
     }
 }
@@ -9,6 +9,73 @@ CREATE TABLE IF NOT EXISTS Quilt(
     axes       TEXT                NOT NULL CHECK (json_valid(axes))
 ) WITHOUT ROWID;
 
+-- Optional units and scaling for quilts, which is only present if they have units
+CREATE TABLE IF NOT EXISTS QuiltUnits(
+    quilt_name TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    unit_name   TEXT NOT NULL,
+    unit_scale  REAL NOT NULL,
+    unit_offset REAL NOT NULL
+) WITHOUT ROWID;
+
+-- Optional lossy storage for quilts, which is exact if absent
+CREATE TABLE IF NOT EXISTS QuiltQuantization(
+    quilt_name   TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    quantization TEXT NOT NULL CHECK (json_valid(quantization))
+) WITHOUT ROWID;
+
+-- Optional integer storage for quilts, like counts, which is floats if absent
+CREATE TABLE IF NOT EXISTS QuiltIntegers(
+    quilt_name TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    integers   TEXT NOT NULL CHECK (json_valid(integers))
+) WITHOUT ROWID;
+
+-- Optional limits on the storage a quilt may use, which is unlimited if absent
+CREATE TABLE IF NOT EXISTS QuiltQuota(
+    quilt_name  TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    max_bytes   INTEGER,
+    max_patches INTEGER
+) WITHOUT ROWID;
+
+-- The running storage use of quilts, see StorageTransaction::get_quilt_usage().
+-- Quilts without a row are measured from their commits the next time it's needed.
+CREATE TABLE IF NOT EXISTS QuiltUsage(
+    quilt_name TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    bytes      INTEGER NOT NULL,
+    patches    INTEGER NOT NULL
+) WITHOUT ROWID;
+
+-- Optional limits on the history of quilts, see QuiltRetention
+CREATE TABLE IF NOT EXISTS QuiltRetention(
+    quilt_name   TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    max_age_days INTEGER,
+    max_depth    INTEGER
+) WITHOUT ROWID;
+
+-- Optional patch sizes for quilts, which use the catalog's if absent, see tune_target_patch_bytes()
+CREATE TABLE IF NOT EXISTS QuiltTuning(
+    quilt_name         TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    target_patch_bytes INTEGER NOT NULL
+) WITHOUT ROWID;
+
+-- Optional axis quilts are appended along, which skips merging appends, see set_quilt_append_axis()
+CREATE TABLE IF NOT EXISTS QuiltAppendAxis(
+    quilt_name TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    axis_name  TEXT NOT NULL
+) WITHOUT ROWID;
+
+-- Namespaces quilts can be created in, so several projects can share a catalog
+CREATE TABLE IF NOT EXISTS Namespace(
+    namespace TEXT COLLATE NOCASE PRIMARY KEY,
+    config    TEXT NOT NULL CHECK (json_valid(config))
+) WITHOUT ROWID;
+
+-- The namespace of each quilt created in one. Quilts without a row are in no namespace.
+CREATE TABLE IF NOT EXISTS QuiltNamespace(
+    quilt_name TEXT COLLATE NOCASE PRIMARY KEY REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    namespace  TEXT COLLATE NOCASE NOT NULL REFERENCES Namespace(namespace) DEFERRABLE INITIALLY DEFERRED
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS QuiltNamespace__namespace ON QuiltNamespace(namespace);
+
 -- Later see if an r-tree actually changes performance
 CREATE TABLE IF NOT EXISTS Patch (
     patch_id INTEGER PRIMARY KEY,
@@ -25,6 +92,29 @@ CREATE TABLE IF NOT EXISTS PatchContent(
     content  BLOB
 );
 
+-- Small patches packed together share one PatchContent row, keyed by patchset_id.
+-- Patches without a row have their own PatchContent row.
+CREATE TABLE IF NOT EXISTS PatchSetMember(
+    patch_id    INTEGER PRIMARY KEY REFERENCES Patch(patch_id) DEFERRABLE INITIALLY DEFERRED,
+    patchset_id INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS PatchSetMember__patchset_id ON PatchSetMember(patchset_id);
+
+-- Tighter bounds of patches whose labels are scattered, see PatchIntervals.
+-- Patches without a row are dense enough that their bounding box is all there is.
+CREATE TABLE IF NOT EXISTS PatchIntervals(
+    patch_id  INTEGER PRIMARY KEY REFERENCES Patch(patch_id) DEFERRABLE INITIALLY DEFERRED,
+    intervals TEXT NOT NULL
+);
+
+-- Sampled reads of patches, to find the cold ones, see StorageTransaction::get_coldest_patches()
+-- Patches without a row were never sampled.
+CREATE TABLE IF NOT EXISTS PatchAccess(
+    patch_id  INTEGER PRIMARY KEY REFERENCES Patch(patch_id) DEFERRABLE INITIALLY DEFERRED,
+    reads     INTEGER NOT NULL,
+    last_read TEXT NOT NULL
+);
+
 CREATE TABLE IF NOT EXISTS Axis(
     axis_name TEXT PRIMARY KEY
 ) WITHOUT ROWID;
@@ -36,12 +126,55 @@ CREATE TABLE IF NOT EXISTS AxisContent(
 );
 CREATE UNIQUE INDEX IF NOT EXISTS AxisContent__axis_name__global_storage_index__label ON AxisContent(axis_name, global_storage_index, label);
 
+-- Names of the labels of the measure axis, like "value" or "flag", see MEASURE_AXIS
+CREATE TABLE IF NOT EXISTS MeasureName(
+    name  TEXT PRIMARY KEY,
+    label INTEGER NOT NULL UNIQUE
+) WITHOUT ROWID;
+
+-- A new random token every time labels are added to an axis, so cached copies can be checked.
+-- Axes that have never changed have no row, which means generation 0.
+CREATE TABLE IF NOT EXISTS AxisGeneration(
+    axis_name  TEXT PRIMARY KEY REFERENCES Axis(axis_name) DEFERRABLE INITIALLY DEFERRED,
+    generation INTEGER NOT NULL
+) WITHOUT ROWID;
+
 CREATE TABLE IF NOT EXISTS Comm(
     comm_id        INTEGER PRIMARY KEY,
     parent_comm_id INTEGER                         REFERENCES Comm(comm_id) DEFERRABLE INITIALLY DEFERRED,
     message TEXT
 );
 
+-- The order of the commits to each quilt, which is the order fetches apply their patches in.
+-- Commit ids come from clocks, so they can collide or go backwards. Commits made before this
+-- table existed have no row, and are applied before the rest, by id.
+CREATE TABLE IF NOT EXISTS CommitSequence(
+    comm_id    INTEGER PRIMARY KEY REFERENCES Comm(comm_id) DEFERRABLE INITIALLY DEFERRED,
+    quilt_name TEXT COLLATE NOCASE NOT NULL,
+    sequence   INTEGER NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS CommitSequence__quilt_name__sequence ON CommitSequence(quilt_name, sequence);
+
+-- When each commit was made, for retention. Commits made before this table existed have no
+-- row, and never expire by age.
+CREATE TABLE IF NOT EXISTS CommitTime(
+    comm_id    INTEGER PRIMARY KEY REFERENCES Comm(comm_id) DEFERRABLE INITIALLY DEFERRED,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS CommitStats(
+    comm_id INTEGER PRIMARY KEY REFERENCES Comm(comm_id) DEFERRABLE INITIALLY DEFERRED,
+    stats   TEXT    NOT NULL CHECK (json_valid(stats))
+);
+
+CREATE TABLE IF NOT EXISTS CommitKey(
+    quilt_name TEXT COLLATE NOCASE REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    commit_key TEXT,
+    comm_id INTEGER NOT NULL REFERENCES Comm(comm_id) DEFERRABLE INITIALLY DEFERRED,
+
+    PRIMARY KEY (quilt_name, commit_key)
+) WITHOUT ROWID;
+
 CREATE TABLE IF NOT EXISTS Tag(
     quilt_name TEXT COLLATE NOCASE REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
     tag_name   TEXT COLLATE NOCASE,
@@ -49,4 +182,77 @@ CREATE TABLE IF NOT EXISTS Tag(
 
     PRIMARY KEY (quilt_name, tag_name)
 ) WITHOUT ROWID;
-CREATE INDEX IF NOT EXISTS Tag__comm_id ON Tag(comm_id);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS Tag__comm_id ON Tag(comm_id);
+
+-- Optional materialized sets of the patches visible from a tag, so fetches don't walk ancestry.
+-- Only tags listed in VisibleIndexTag have an index, and it's kept up to date on commit.
+CREATE TABLE IF NOT EXISTS VisibleIndexTag(
+    quilt_name TEXT COLLATE NOCASE,
+    tag_name   TEXT COLLATE NOCASE,
+
+    PRIMARY KEY (quilt_name, tag_name)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS VisibleIndex(
+    quilt_name TEXT COLLATE NOCASE,
+    tag_name   TEXT COLLATE NOCASE,
+    patch_id   INTEGER NOT NULL,
+    -- 1 if the patch has no NANs or gaps, so it hides every patch inside its bounding box
+    covers     INTEGER NOT NULL,
+
+    PRIMARY KEY (quilt_name, tag_name, patch_id)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS VisibleIndex__patch_id ON VisibleIndex(patch_id);
+
+-- Every change to the catalog, for compliance. Nothing may change or delete these rows.
+CREATE TABLE IF NOT EXISTS AuditLog(
+    audit_id   INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp  TEXT NOT NULL,
+    actor      TEXT,
+    operation  TEXT NOT NULL,
+    quilt_name TEXT COLLATE NOCASE NOT NULL,
+    parameters TEXT NOT NULL CHECK (json_valid(parameters)),
+    comm_ids   TEXT NOT NULL CHECK (json_valid(comm_ids))
+);
+CREATE INDEX IF NOT EXISTS AuditLog__quilt_name ON AuditLog(quilt_name);
+CREATE TRIGGER IF NOT EXISTS AuditLog__no_update BEFORE UPDATE ON AuditLog
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append only');
+END;
+CREATE TRIGGER IF NOT EXISTS AuditLog__no_delete BEFORE DELETE ON AuditLog
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append only');
+END;
+
+-- Quilts computed from other quilts, and how, see StorageTransaction::set_derived_quilt()
+CREATE TABLE IF NOT EXISTS DerivedQuilt(
+    src_quilt_name TEXT COLLATE NOCASE REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    dst_quilt_name TEXT COLLATE NOCASE REFERENCES Quilt(quilt_name) DEFERRABLE INITIALLY DEFERRED,
+    derivation     TEXT NOT NULL CHECK (json_valid(derivation)),
+    -- When work for it was last done, to tell how stale it is
+    refreshed_at   TEXT,
+
+    PRIMARY KEY (src_quilt_name, dst_quilt_name)
+) WITHOUT ROWID;
+-- Work owed to derived quilts, queued in the same transaction as the commit that caused it
+CREATE TABLE IF NOT EXISTS DerivedWork(
+    work_id        INTEGER PRIMARY KEY AUTOINCREMENT,
+    src_quilt_name TEXT COLLATE NOCASE NOT NULL,
+    dst_quilt_name TEXT COLLATE NOCASE NOT NULL,
+    tag_name       TEXT COLLATE NOCASE NOT NULL,
+    bounding_box   TEXT NOT NULL CHECK (json_valid(bounding_box)),
+    queued_at      TEXT NOT NULL
+);
+
+-- Rules every commit message must follow, see StorageTransaction::set_commit_rules()
+-- There is at most one row, and without it anything goes.
+CREATE TABLE IF NOT EXISTS CommitRules(
+    rules_id INTEGER PRIMARY KEY CHECK (rules_id = 1),
+    rules    TEXT NOT NULL CHECK (json_valid(rules))
+);
+
+-- What each backup captured, written into the backup itself, see Catalog::backup_to()
+CREATE TABLE IF NOT EXISTS Snapshot(
+    snapshot_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    taken_at    TEXT NOT NULL,
+    tags        TEXT NOT NULL CHECK (json_valid(tags))
+);
@@ -0,0 +1,600 @@
+use crate::sqlite::SQLiteTransaction;
+use crate::{
+    AuditEntry, Axis, BoundingBox, CatalogSnapshot, CommitChanges, CommitRules, CommitStats,
+    Counter, CounterBreakdown, Derivation, DerivedQuiltStatus, DerivedWork, Fallible,
+    HistoryCommit, Label, LabelIndex, NamespaceConfig, Patch, PatchAccess, PatchCompressionType,
+    PatchID, PatchQuantization, PatchRef, PreparedPatch, QuiltDetails, QuiltIntegers, QuiltQuota,
+    QuiltRetention, QuiltUnits, QuiltUsage, SkippedPatch, SortedLabelIndex, StorageTransaction,
+    WriteAmplificationLimit,
+};
+use enum_map::EnumMap;
+use std::collections::HashMap;
+
+/// A transaction on whichever storage a catalog uses
+///
+/// Catalog::begin() and Catalog::begin_read() return this, so code using a catalog doesn't
+/// depend on its backend. Every method passes straight through to the backend's transaction.
+pub enum Transaction<'t> {
+    SQLite(SQLiteTransaction<'t>),
+}
+
+impl<'t> StorageTransaction for Transaction<'t> {
+    fn trace(&mut self, ctr: Counter, increment: usize) {
+        match self {
+            Transaction::SQLite(txn) => txn.trace(ctr, increment),
+        }
+    }
+
+    fn trace_quilt(&mut self, quilt_name: &str) {
+        match self {
+            Transaction::SQLite(txn) => txn.trace_quilt(quilt_name),
+        }
+    }
+
+    fn get_performance_counters(&self) -> EnumMap<Counter, usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_performance_counters(),
+        }
+    }
+
+    fn get_performance_counters_by_quilt(&self) -> CounterBreakdown {
+        match self {
+            Transaction::SQLite(txn) => txn.get_performance_counters_by_quilt(),
+        }
+    }
+
+    fn get_performance_counters_by_axis(&self) -> CounterBreakdown {
+        match self {
+            Transaction::SQLite(txn) => txn.get_performance_counters_by_axis(),
+        }
+    }
+
+    fn target_patch_bytes(&self) -> usize {
+        match self {
+            Transaction::SQLite(txn) => txn.target_patch_bytes(),
+        }
+    }
+
+    fn patch_compression(&self) -> PatchCompressionType {
+        match self {
+            Transaction::SQLite(txn) => txn.patch_compression(),
+        }
+    }
+
+    fn set_write_amplification_limit(&mut self, limit: WriteAmplificationLimit) {
+        match self {
+            Transaction::SQLite(txn) => txn.set_write_amplification_limit(limit),
+        }
+    }
+
+    fn max_read_bytes(&self) -> Option<u64> {
+        match self {
+            Transaction::SQLite(txn) => txn.max_read_bytes(),
+        }
+    }
+
+    fn recovery_mode(&self) -> bool {
+        match self {
+            Transaction::SQLite(txn) => txn.recovery_mode(),
+        }
+    }
+
+    fn warn(&mut self, message: String) {
+        match self {
+            Transaction::SQLite(txn) => txn.warn(message),
+        }
+    }
+
+    fn take_warnings(&mut self) -> Vec<String> {
+        match self {
+            Transaction::SQLite(txn) => txn.take_warnings(),
+        }
+    }
+
+    fn skip_unreadable(&self) -> bool {
+        match self {
+            Transaction::SQLite(txn) => txn.skip_unreadable(),
+        }
+    }
+
+    fn skip_patch(&mut self, skipped: SkippedPatch) {
+        match self {
+            Transaction::SQLite(txn) => txn.skip_patch(skipped),
+        }
+    }
+
+    fn take_skipped_patches(&mut self) -> Vec<SkippedPatch> {
+        match self {
+            Transaction::SQLite(txn) => txn.take_skipped_patches(),
+        }
+    }
+
+    fn union_axis(&mut self, axis: &Axis) -> Fallible<bool> {
+        match self {
+            Transaction::SQLite(txn) => txn.union_axis(axis),
+        }
+    }
+
+    fn declare_measures(&mut self, names: &[&str]) -> Fallible<Vec<Label>> {
+        match self {
+            Transaction::SQLite(txn) => txn.declare_measures(names),
+        }
+    }
+
+    fn get_measures(&mut self) -> Fallible<Vec<(String, Label)>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_measures(),
+        }
+    }
+
+    fn get_axis(&mut self, axis_name: &str) -> Fallible<&Axis> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_axis(axis_name),
+        }
+    }
+
+    fn get_label_index(&mut self, axis_name: &str) -> Fallible<&LabelIndex> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_label_index(axis_name),
+        }
+    }
+
+    fn get_sorted_label_index(&mut self, axis_name: &str) -> Fallible<&SortedLabelIndex> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_sorted_label_index(axis_name),
+        }
+    }
+
+    fn get_axis_generation(&mut self, axis_name: &str) -> Fallible<u64> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_axis_generation(axis_name),
+        }
+    }
+
+    fn get_catalog_id(&self) -> i64 {
+        match self {
+            Transaction::SQLite(txn) => txn.get_catalog_id(),
+        }
+    }
+
+    fn get_axis_slice(&mut self, axis_name: &str, range: std::ops::Range<usize>) -> Fallible<Axis> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_axis_slice(axis_name, range),
+        }
+    }
+
+    fn get_axis_len(&mut self, axis_name: &str) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_axis_len(axis_name),
+        }
+    }
+
+    fn list_quilts(&mut self) -> Fallible<HashMap<String, QuiltDetails>> {
+        match self {
+            Transaction::SQLite(txn) => txn.list_quilts(),
+        }
+    }
+
+    fn list_tags(&mut self, quilt_name: &str) -> Fallible<Vec<String>> {
+        match self {
+            Transaction::SQLite(txn) => txn.list_tags(quilt_name),
+        }
+    }
+
+    fn create_namespace(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<bool> {
+        match self {
+            Transaction::SQLite(txn) => txn.create_namespace(namespace, config),
+        }
+    }
+
+    fn get_namespace_config(&mut self, namespace: &str) -> Fallible<NamespaceConfig> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_namespace_config(namespace),
+        }
+    }
+
+    fn set_namespace_config(&mut self, namespace: &str, config: NamespaceConfig) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_namespace_config(namespace, config),
+        }
+    }
+
+    fn list_namespaces(&mut self) -> Fallible<Vec<String>> {
+        match self {
+            Transaction::SQLite(txn) => txn.list_namespaces(),
+        }
+    }
+
+    fn list_quilts_in(&mut self, namespace: &str) -> Fallible<HashMap<String, QuiltDetails>> {
+        match self {
+            Transaction::SQLite(txn) => txn.list_quilts_in(namespace),
+        }
+    }
+
+    fn create_quilt(&mut self, quilt_name: &str, axes_names: &[&str]) -> Fallible<bool> {
+        match self {
+            Transaction::SQLite(txn) => txn.create_quilt(quilt_name, axes_names),
+        }
+    }
+
+    fn set_quilt_units(&mut self, quilt_name: &str, units: Option<QuiltUnits>) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_units(quilt_name, units),
+        }
+    }
+
+    fn set_quilt_quantization(
+        &mut self,
+        quilt_name: &str,
+        quantization: PatchQuantization,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_quantization(quilt_name, quantization),
+        }
+    }
+
+    fn set_quilt_integers(
+        &mut self,
+        quilt_name: &str,
+        integers: Option<QuiltIntegers>,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_integers(quilt_name, integers),
+        }
+    }
+
+    fn set_quilt_quota(&mut self, quilt_name: &str, quota: Option<QuiltQuota>) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_quota(quilt_name, quota),
+        }
+    }
+
+    fn get_quilt_retention(&mut self, quilt_name: &str) -> Fallible<Option<QuiltRetention>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_quilt_retention(quilt_name),
+        }
+    }
+
+    fn set_quilt_retention(
+        &mut self,
+        quilt_name: &str,
+        retention: Option<QuiltRetention>,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_retention(quilt_name, retention),
+        }
+    }
+
+    fn collect_expired_commits(&mut self, quilt_name: &str) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.collect_expired_commits(quilt_name),
+        }
+    }
+
+    fn collect_garbage(&mut self, quilt_name: &str) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.collect_garbage(quilt_name),
+        }
+    }
+
+    fn untag(&mut self, quilt_name: &str, tag: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.untag(quilt_name, tag),
+        }
+    }
+
+    fn get_quilt_target_patch_bytes(&mut self, quilt_name: &str) -> Fallible<Option<usize>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_quilt_target_patch_bytes(quilt_name),
+        }
+    }
+
+    fn set_quilt_target_patch_bytes(
+        &mut self,
+        quilt_name: &str,
+        bytes: Option<usize>,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_target_patch_bytes(quilt_name, bytes),
+        }
+    }
+
+    fn get_quilt_append_axis(&mut self, quilt_name: &str) -> Fallible<Option<String>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_quilt_append_axis(quilt_name),
+        }
+    }
+
+    fn set_quilt_append_axis(&mut self, quilt_name: &str, axis_name: Option<&str>) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_quilt_append_axis(quilt_name, axis_name),
+        }
+    }
+
+    fn get_quilt_usage(&mut self, quilt_name: &str) -> Fallible<QuiltUsage> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_quilt_usage(quilt_name),
+        }
+    }
+
+    fn get_coldest_patches(&mut self, quilt_name: &str, bytes: u64) -> Fallible<Vec<PatchAccess>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_coldest_patches(quilt_name, bytes),
+        }
+    }
+
+    fn get_quilt_details(&mut self, quilt_name: &str) -> Fallible<QuiltDetails> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_quilt_details(quilt_name),
+        }
+    }
+
+    fn search(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        deep: bool,
+        bounding_boxes: &[BoundingBox],
+    ) -> Fallible<Vec<PatchRef>> {
+        match self {
+            Transaction::SQLite(txn) => txn.search(quilt_name, tag, deep, bounding_boxes),
+        }
+    }
+
+    fn get_tag_commit(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<i64>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_tag_commit(quilt_name, tag),
+        }
+    }
+
+    fn get_patch(&mut self, id: PatchID) -> Fallible<Patch> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_patch(id),
+        }
+    }
+
+    fn get_patch_content(&mut self, id: PatchID) -> Fallible<Option<Vec<u8>>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_patch_content(id),
+        }
+    }
+
+    fn new_target_patch(&mut self, axes: Vec<Axis>) -> Fallible<Patch> {
+        match self {
+            Transaction::SQLite(txn) => txn.new_target_patch(axes),
+        }
+    }
+
+    fn recycle_patch(&mut self, patch: Patch) {
+        match self {
+            Transaction::SQLite(txn) => txn.recycle_patch(patch),
+        }
+    }
+
+    fn put_commit(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: &[&Patch],
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => {
+                txn.put_commit(quilt_name, parent_tag, new_tag, message, patches)
+            }
+        }
+    }
+
+    fn put_commit_prepared(
+        &mut self,
+        quilt_name: &str,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        patches: Vec<PreparedPatch>,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => {
+                txn.put_commit_prepared(quilt_name, parent_tag, new_tag, message, patches)
+            }
+        }
+    }
+
+    fn get_commit_rules(&mut self) -> Fallible<CommitRules> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_commit_rules(),
+        }
+    }
+
+    fn set_commit_rules(&mut self, rules: &CommitRules) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_commit_rules(rules),
+        }
+    }
+
+    fn check_commit_message(&mut self, message: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.check_commit_message(message),
+        }
+    }
+
+    fn compact_region(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        bounding_box: BoundingBox,
+    ) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.compact_region(quilt_name, tag, bounding_box),
+        }
+    }
+
+    fn upgrade_patch(&mut self, id: PatchID) -> Fallible<bool> {
+        match self {
+            Transaction::SQLite(txn) => txn.upgrade_patch(id),
+        }
+    }
+
+    fn get_audit_log(
+        &mut self,
+        quilt_name: Option<&str>,
+        limit: usize,
+    ) -> Fallible<Vec<AuditEntry>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_audit_log(quilt_name, limit),
+        }
+    }
+
+    fn get_snapshot(&mut self) -> Fallible<Option<CatalogSnapshot>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_snapshot(),
+        }
+    }
+
+    fn get_changes_since(
+        &mut self,
+        quilt_name: &str,
+        last: (i64, i64),
+        limit: usize,
+    ) -> Fallible<Vec<CommitChanges>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_changes_since(quilt_name, last, limit),
+        }
+    }
+
+    fn get_history(&mut self, quilt_name: &str) -> Fallible<Vec<HistoryCommit>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_history(quilt_name),
+        }
+    }
+
+    fn set_derived_quilt(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        derivation: Option<Derivation>,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.set_derived_quilt(src_quilt, dst_quilt, derivation),
+        }
+    }
+
+    fn get_derivation(&mut self, src_quilt: &str, dst_quilt: &str) -> Fallible<Option<Derivation>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_derivation(src_quilt, dst_quilt),
+        }
+    }
+
+    fn get_derived_quilts(&mut self, src_quilt: Option<&str>) -> Fallible<Vec<DerivedQuiltStatus>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_derived_quilts(src_quilt),
+        }
+    }
+
+    fn queue_derived_refresh(
+        &mut self,
+        src_quilt: &str,
+        dst_quilt: &str,
+        tag: &str,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.queue_derived_refresh(src_quilt, dst_quilt, tag),
+        }
+    }
+
+    fn get_derived_work(&mut self, limit: usize) -> Fallible<Vec<DerivedWork>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_derived_work(limit),
+        }
+    }
+
+    fn finish_derived_work(&mut self, work_id: i64) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.finish_derived_work(work_id),
+        }
+    }
+
+    fn enable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.enable_visibility_index(quilt_name, tag),
+        }
+    }
+
+    fn disable_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.disable_visibility_index(quilt_name, tag),
+        }
+    }
+
+    fn rebuild_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<usize> {
+        match self {
+            Transaction::SQLite(txn) => txn.rebuild_visibility_index(quilt_name, tag),
+        }
+    }
+
+    fn check_visibility_index(&mut self, quilt_name: &str, tag: &str) -> Fallible<Vec<String>> {
+        match self {
+            Transaction::SQLite(txn) => txn.check_visibility_index(quilt_name, tag),
+        }
+    }
+
+    fn put_commit_stats(
+        &mut self,
+        quilt_name: &str,
+        tag: &str,
+        stats: &CommitStats,
+    ) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.put_commit_stats(quilt_name, tag, stats),
+        }
+    }
+
+    fn get_commit_stats(&mut self, quilt_name: &str, tag: &str) -> Fallible<Option<CommitStats>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_commit_stats(quilt_name, tag),
+        }
+    }
+
+    fn put_commit_key(&mut self, quilt_name: &str, tag: &str, commit_key: &str) -> Fallible<i64> {
+        match self {
+            Transaction::SQLite(txn) => txn.put_commit_key(quilt_name, tag, commit_key),
+        }
+    }
+
+    fn get_keyed_commit(&mut self, quilt_name: &str, commit_key: &str) -> Fallible<Option<i64>> {
+        match self {
+            Transaction::SQLite(txn) => txn.get_keyed_commit(quilt_name, commit_key),
+        }
+    }
+
+    fn savepoint(&mut self, name: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.savepoint(name),
+        }
+    }
+
+    fn release(&mut self, name: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.release(name),
+        }
+    }
+
+    fn rollback_to(&mut self, name: &str) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.rollback_to(name),
+        }
+    }
+
+    fn finish(self) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.finish(),
+        }
+    }
+
+    fn rollback(self) -> Fallible<()> {
+        match self {
+            Transaction::SQLite(txn) => txn.rollback(),
+        }
+    }
+}
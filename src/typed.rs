@@ -0,0 +1,253 @@
+use crate::{Axis, AxisSelection, Catalog, Fallible, Label, Patch, StoiError, StorageTransaction};
+use itertools::Itertools;
+use ndarray as nd;
+
+/// The names of a quilt's axes as a tuple, which fixes how many there are at compile time
+///
+/// This is implemented for tuples of one to four `&str`, like `("itm", "lct", "day")`.
+/// The associated types are tuples of the same arity, so passing the wrong number of
+/// selections or labels to a TypedQuilt doesn't compile.
+pub trait AxisNames: Copy {
+    /// One selection for each axis, such as `(AxisSelection, AxisSelection)`
+    type Selections;
+    /// The labels of each axis, such as `(Vec<Label>, Vec<Label>)`
+    type Labels;
+    /// The dimension of the content, such as `Ix2`
+    type Dim: nd::Dimension;
+
+    /// The names, in order
+    fn names(self) -> Vec<&'static str>;
+    /// Split the selections into a Vec, in the same order
+    fn selections(selections: Self::Selections) -> Vec<AxisSelection>;
+    /// Split the labels into a Vec, in the same order
+    fn split_labels(labels: Self::Labels) -> Vec<Vec<Label>>;
+    /// Join the labels of each axis into a tuple
+    fn join_labels(labels: Vec<Vec<Label>>) -> Self::Labels;
+}
+
+macro_rules! impl_axis_names {
+    (@str $name:ident) => { &'static str };
+    (@selection $name:ident) => { AxisSelection };
+    (@labels $name:ident) => { Vec<Label> };
+    (@next $labels:ident $name:ident) => { $labels.next().unwrap_or_default() };
+    ($dim:ty; $($ix:tt $name:ident),+) => {
+        impl AxisNames for ($(impl_axis_names!(@str $name),)+) {
+            type Selections = ($(impl_axis_names!(@selection $name),)+);
+            type Labels = ($(impl_axis_names!(@labels $name),)+);
+            type Dim = $dim;
+
+            fn names(self) -> Vec<&'static str> {
+                vec![$(self.$ix),+]
+            }
+            fn selections(selections: Self::Selections) -> Vec<AxisSelection> {
+                vec![$(selections.$ix),+]
+            }
+            fn split_labels(labels: Self::Labels) -> Vec<Vec<Label>> {
+                vec![$(labels.$ix),+]
+            }
+            fn join_labels(labels: Vec<Vec<Label>>) -> Self::Labels {
+                let mut labels = labels.into_iter();
+                ($(impl_axis_names!(@next labels $name),)+)
+            }
+        }
+    };
+}
+impl_axis_names!(nd::Ix1; 0 a);
+impl_axis_names!(nd::Ix2; 0 a, 1 b);
+impl_axis_names!(nd::Ix3; 0 a, 1 b, 2 c);
+impl_axis_names!(nd::Ix4; 0 a, 1 b, 2 c, 3 d);
+
+/// A quilt with its axes named in the type, so their number and order can't be mixed up
+///
+/// The quilt's axes are checked once, when the handle is made with Catalog::typed_quilt().
+/// After that, selections, labels, and content are always in the order of the names you gave,
+/// whatever order the quilt stores them in.
+///
+///     use stoicheia::{AxisSelection, Catalog, StorageTransaction};
+///     use ndarray::arr2;
+///     let cat = Catalog::connect("").unwrap();
+///     let mut txn = cat.begin().unwrap();
+///     txn.create_quilt("sales", &["itm", "lct"]).unwrap();
+///     txn.finish().unwrap();
+///
+///     // Name them in any order, but all of them
+///     let sales = cat.typed_quilt("sales", ("lct", "itm")).unwrap();
+///     let mut txn = cat.begin().unwrap();
+///     let labels = (vec![10], vec![1, 2]);
+///     sales.commit(&mut txn, "latest", "latest", "message", labels, arr2(&[[1., 2.]])).unwrap();
+///     let (labels, content) = sales
+///         .fetch(&mut txn, "latest", (AxisSelection::All, AxisSelection::Labels(vec![2])))
+///         .unwrap();
+///     assert_eq!(labels, (vec![10], vec![2]));
+///     assert_eq!(content, arr2(&[[2.]]));
+#[derive(Debug, Clone)]
+pub struct TypedQuilt<A: AxisNames> {
+    quilt_name: String,
+    names: A,
+    /// For each axis of the quilt, the position of its name in `names`
+    positions: Vec<usize>,
+}
+impl<A: AxisNames> TypedQuilt<A> {
+    /// Check the names against the quilt's axes, see Catalog::typed_quilt()
+    pub fn new<T: StorageTransaction>(txn: &mut T, quilt_name: &str, names: A) -> Fallible<Self> {
+        let quilt_axes = txn.get_quilt_details(quilt_name)?.axes;
+        let given = names.names();
+        if given
+            .iter()
+            .copied()
+            .sorted()
+            .ne(quilt_axes.iter().map(|a| a.as_str()).sorted())
+        {
+            return Err(StoiError::MisalignedAxes(format!(
+                "the quilt \"{}\" has axes [{}] but the handle names [{}]",
+                quilt_name,
+                quilt_axes.iter().join(", "),
+                given.iter().join(", ")
+            )));
+        }
+        Ok(TypedQuilt {
+            quilt_name: quilt_name.to_string(),
+            names,
+            positions: quilt_axes
+                .iter()
+                .map(|axis| given.iter().position(|&name| name == axis).unwrap_or(0))
+                .collect(),
+        })
+    }
+
+    /// The name of the quilt
+    pub fn quilt_name(&self) -> &str {
+        &self.quilt_name
+    }
+
+    /// The names of the axes, in the handle's order
+    pub fn names(&self) -> A {
+        self.names
+    }
+
+    /// Fetch a slice, with one selection for each axis, in the handle's order
+    ///
+    /// Returns the labels and content in the handle's order too.
+    pub fn fetch<T: StorageTransaction>(
+        &self,
+        txn: &mut T,
+        tag: &str,
+        selections: A::Selections,
+    ) -> Fallible<(A::Labels, nd::Array<f32, A::Dim>)> {
+        let mut selections = A::selections(selections)
+            .into_iter()
+            .map(Some)
+            .collect_vec();
+        let request = self
+            .positions
+            .iter()
+            .map(|&pos| selections[pos].take().unwrap_or(AxisSelection::All))
+            .collect_vec();
+        let patch = txn.fetch(&self.quilt_name, tag, request)?;
+
+        // The patch is in the quilt's order, so put it back in the handle's order
+        let order = (0..self.positions.len())
+            .map(|pos| self.positions.iter().position(|&p| p == pos).unwrap_or(0))
+            .collect_vec();
+        let labels = order
+            .iter()
+            .map(|&ax_ix| patch.axes()[ax_ix].labels().to_vec())
+            .collect_vec();
+        let content = patch.content().permuted_axes(&order[..]);
+        let content = content.as_standard_layout().into_owned();
+        Ok((
+            A::join_labels(labels),
+            content.into_dimensionality::<A::Dim>()?,
+        ))
+    }
+
+    /// Commit content with the labels of each axis, in the handle's order
+    pub fn commit<T: StorageTransaction>(
+        &self,
+        txn: &mut T,
+        parent_tag: &str,
+        new_tag: &str,
+        message: &str,
+        labels: A::Labels,
+        content: nd::Array<f32, A::Dim>,
+    ) -> Fallible<()> {
+        let axes = self
+            .names
+            .names()
+            .into_iter()
+            .zip(A::split_labels(labels))
+            .map(|(name, labels)| Axis::new(name, labels))
+            .collect::<Fallible<Vec<Axis>>>()?;
+        let patch = Patch::new(axes, Some(content.into_dyn()))?;
+        txn.create_commit(&self.quilt_name, parent_tag, new_tag, message, &[&patch])
+    }
+}
+
+impl Catalog {
+    /// Get a handle to a quilt that checks the number and names of its axes
+    ///
+    /// See TypedQuilt. It's an error if `names` aren't exactly the quilt's axes.
+    pub fn typed_quilt<A: AxisNames>(&self, quilt_name: &str, names: A) -> Fallible<TypedQuilt<A>> {
+        TypedQuilt::new(&mut self.begin_read()?, quilt_name, names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use ndarray::{arr1, arr3};
+
+    #[test]
+    fn typed_quilt_round_trip() {
+        let cat = Catalog::connect("").unwrap();
+        let mut txn = cat.begin().unwrap();
+        txn.create_quilt("sales", &["itm", "lct", "day"]).unwrap();
+        txn.finish().unwrap();
+
+        // The names have to be the quilt's, but the order is up to you
+        assert!(cat.typed_quilt("sales", ("itm", "lct")).is_err());
+        assert!(cat.typed_quilt("sales", ("itm", "lct", "wk")).is_err());
+        let sales = cat.typed_quilt("sales", ("day", "itm", "lct")).unwrap();
+        let one_item = cat.typed_quilt("sales", ("itm", "day", "lct")).unwrap();
+
+        let mut txn = cat.begin().unwrap();
+        let content = arr3(&[[[1., 2.], [3., 4.], [5., 6.]]]);
+        let labels = (vec![7], vec![1, 2, 3], vec![10, 11]);
+        sales
+            .commit(
+                &mut txn,
+                "latest",
+                "latest",
+                "message",
+                labels.clone(),
+                content.clone(),
+            )
+            .unwrap();
+        let (fetched_labels, fetched) = sales
+            .fetch(
+                &mut txn,
+                "latest",
+                (AxisSelection::All, AxisSelection::All, AxisSelection::All),
+            )
+            .unwrap();
+        assert_eq!(fetched_labels, labels);
+        assert_eq!(fetched, content);
+
+        // It's stored in the quilt's order underneath
+        let patch = txn.fetch("sales", "latest", vec![]).unwrap();
+        assert_eq!(patch.content().shape(), &[3, 2, 1]);
+
+        let (_, fetched) = one_item
+            .fetch(
+                &mut txn,
+                "latest",
+                (
+                    AxisSelection::Labels(vec![2]),
+                    AxisSelection::All,
+                    AxisSelection::All,
+                ),
+            )
+            .unwrap();
+        assert_eq!(fetched.into_shape(2).unwrap(), arr1(&[3., 4.]));
+    }
+}
@@ -1 +1 @@
-from .stoicheia import Catalog, Axis, Patch
\ No newline at end of file
+from .stoicheia import Catalog, Axis, Patch, Transaction, ChunkGrid
\ No newline at end of file
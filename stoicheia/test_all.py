@@ -11,6 +11,14 @@ def test_create_quilt():
     cat = Catalog()
     cat.create_quilt("sales", ["itm", "lct", "day"])
 
+def test_create_quilts_from_template():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct", "day"])
+    assert cat.create_quilts_from_template("sales", ["returns", "orders"]) == 2
+    # They have the same axes, so they can be fetched the same way
+    pat = cat.fetch("returns", "latest", itm=1, lct=[2,3,4])
+    assert pat.axis_names == ["itm", "lct", "day"]
+
 def test_fetch_empty():
     cat = Catalog()
     try:
@@ -39,11 +47,34 @@ def test_fetch_empty():
     assert np.all(np.isnan(content))
 
 
+def test_fetch_unknown_axis():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct", "day"])
+    try:
+        cat.fetch("sales", "latest", itm=1, color=[2,3,4])
+        assert False, "Should have failed because the quilt has no axis named color"
+    except ValueError as err:
+        # The message should list the axes it does have
+        assert "itm, lct, day" in str(err)
+
+    # Unless it's lenient, then it's like the selection wasn't there
+    pat = cat.fetch("sales", "latest", _strict=False, itm=1, color=[2,3,4])
+    axes, content = pat.export()
+    assert np.array_equal(axes[0], np.array([1]))
+
+    # An axis can be named strict, since the option has an underscore
+    cat.create_quilt("checks", ["strict"])
+    pat = cat.fetch("checks", "latest", strict=[1, 2])
+    assert pat.axis_names == ["strict"]
+    assert cat.estimate("checks", "latest", strict=[1, 2])["shape"] == [2]
+
+
 def test_init_an_axis():
     # Should work fine with a numpy array
     a = Axis("thing", np.array([1,2,3]))
     assert np.array_equal(a.labels(), np.array([1,2,3]))
     assert a.name() == "thing"
+    assert repr(a) == "Axis(thing: 3 labels, 1 to 3)"
     # TODO: Should work fine with a list too
     # a = Axis("thing", [1,2,3])
     # assert np.array_equal(a.labels(), np.array([1,2,3]))
@@ -62,6 +93,70 @@ def test_create_patch():
     assert np.array_equal(axes[1], np.array([2,3,4]))
     assert np.array_equal(content, np.array([[1,2,6]]))
 
+def test_patch_accessors():
+    pat = Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("lct", np.array([2,3,4]))
+        ],
+        content = np.array([[1, np.nan, 6]], dtype=np.float32)
+    )
+    assert pat.shape == [1, 3]
+    assert pat.ndim == 2
+    assert pat.axis_names == ["itm", "lct"]
+    assert np.array_equal(pat.labels("lct"), np.array([2,3,4]))
+    assert repr(pat) == "Patch(itm: 1, lct: 3; 33.3% NaN; values 1 to 6)"
+    assert pat.get([1, 4]) == 6
+    assert pat.get([1, 5]) is None
+    pat.set([1, 3], 2)
+    assert pat.get([1, 3]) == 2
+
+def test_patch_from_coo():
+    pat = Patch.from_coo(
+        axes = {"itm": np.array([5, 3, 5]), "lct": np.array([1, 1, 2])},
+        values = np.array([1, 2, 3], dtype=np.float32)
+    )
+    axes, content = pat.export()
+    assert np.array_equal(axes[0], np.array([5, 3]))
+    assert np.array_equal(axes[1], np.array([1, 2]))
+    assert np.array_equal(content, np.array([[1, 3], [2, np.nan]]), equal_nan=True)
+
+def test_patch_fill_gaps():
+    pat = Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("day", np.array([1,2,4,5]))
+        ],
+        content = np.array([[np.nan, 1, np.nan, 7]], dtype=np.float32)
+    )
+    pat.interpolate("day")
+    assert np.array_equal(pat.export()[1], np.array([[np.nan, 1, 5, 7]]), equal_nan=True)
+    pat.fill_nan(0)
+    assert np.array_equal(pat.export()[1], np.array([[0, 1, 5, 7]]))
+
+def test_patch_export_missing():
+    pat = Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("day", np.array([1,2,3]))
+        ],
+        content = np.array([[np.nan, 1, 2]], dtype=np.float32)
+    )
+    _, content = pat.export(fill=-1)
+    assert np.array_equal(content, np.array([[-1, 1, 2]]))
+    _, content = pat.export(mask="masked")
+    assert isinstance(content, np.ma.MaskedArray)
+    assert np.array_equal(content.mask, np.array([[True, False, False]]))
+    assert content.sum() == 3
+    _, content, valid = pat.export(fill=0, mask="separate")
+    assert np.array_equal(content, np.array([[0, 1, 2]]))
+    assert np.array_equal(valid, np.array([[False, True, True]]))
+    try:
+        pat.export(mask="zero")
+        assert False, "unknown masks should be rejected"
+    except ValueError:
+        pass
+
 def test_commit_patch():
     cat = Catalog()
     cat.create_quilt("sales", ["itm", "lct", "day"])
@@ -126,3 +221,192 @@ def test_commit_patch():
     # Test untag, to make sure it doesn't throw an error
     cat.untag("sales", "latest")
     pat = cat.fetch("sales", "latest", itm=1, lct=[2,3,4])
+
+def test_transaction_reads_its_writes():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct"])
+    pat = Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("lct", np.array([2,3]))
+        ],
+        content = np.array([[1, 2]], dtype=np.float32)
+    )
+
+    # Fetches in the transaction see its commits, and they're written when it's done
+    with cat.transaction() as txn:
+        txn.commit("sales", message="example commit", patches=[pat])
+        _, content = txn.fetch("sales", "latest", itm=1).export()
+        assert np.array_equal(content, np.array([[1, 2]]))
+    _, content = cat.fetch("sales", "latest", itm=1, lct=[2,3]).export()
+    assert np.array_equal(content, np.array([[1, 2]]))
+
+    # An exception rolls everything back
+    pat = Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("lct", np.array([2]))
+        ],
+        content = np.array([[100]], dtype=np.float32)
+    )
+    try:
+        with cat.transaction() as txn:
+            txn.commit("sales", message="never mind", patches=[pat])
+            _, content = txn.fetch("sales", "latest", itm=1).export()
+            assert np.array_equal(content, np.array([[100, 2]]))
+            raise KeyError("on second thought")
+    except KeyError:
+        pass
+    _, content = cat.fetch("sales", "latest", itm=1).export()
+    assert np.array_equal(content, np.array([[1, 2]]))
+
+    # A commit that fails is left out, and the transaction goes on without it
+    txn = cat.transaction()
+    try:
+        txn.commit("no such quilt", message="oops", patches=[pat])
+        assert False, "Should have failed because the quilt doesn't exist"
+    except ValueError:
+        pass
+    txn.commit("sales", message="never mind", patches=[pat])
+    _, content = txn.fetch("sales", "latest", itm=1).export()
+    assert np.array_equal(content, np.array([[100, 2]]))
+
+    # So does rollback(), and then the transaction can't be used anymore
+    txn.rollback()
+    try:
+        txn.fetch("sales", "latest")
+        assert False, "Should have failed because the transaction is over"
+    except ValueError:
+        pass
+    _, content = cat.fetch("sales", "latest", itm=1).export()
+    assert np.array_equal(content, np.array([[1, 2]]))
+
+def test_catalog_axis_pages():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "day"])
+    cat.commit("sales", None, None, "message", [Patch(
+        axes = [
+            Axis("itm", np.array([1])),
+            Axis("day", np.array([700, 701, 702, 703]))
+        ],
+        content = np.array([[1, 2, 3, 4]], dtype=np.float32)
+    )])
+    days = cat.axis("day")
+    assert len(days) == 4
+    assert np.array_equal(days[1:3], np.array([701, 702]))
+    assert np.array_equal(days[::-2], np.array([703, 701]))
+    assert days[-1] == 703
+
+def test_fetch_chunks():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct"])
+    cat.commit("sales", None, None, "message", [Patch(
+        axes = [
+            Axis("itm", np.array([1, 2, 3])),
+            Axis("lct", np.array([4, 5]))
+        ],
+        content = np.array([[1, 2], [3, 4], [5, 6]], dtype=np.float32)
+    )])
+    grid = cat.plan_chunks("sales", "latest", [2, 2])
+    assert grid.grid_shape == [2, 1]
+    assert grid.keys == [[0, 0], [1, 0]]
+    axes, content = cat.fetch_chunk(grid, [1, 0]).export()
+    assert np.array_equal(axes[0], np.array([3]))
+    assert np.array_equal(content, np.array([[5, 6]]))
+
+def test_fetch_label_range():
+    cat = Catalog()
+    cat.create_quilt("sales", ["day"])
+    cat.commit("sales", None, None, "message", [Patch(
+        axes = [Axis("day", np.array([20200302, 20200228, 20200401, 20200301]))],
+        content = np.array([1, 2, 3, 4], dtype=np.float32)
+    )])
+    axes, content = cat.fetch("sales", "latest", day=slice(20200301, 20200331)).export()
+    assert np.array_equal(axes[0], np.array([20200302, 20200301]))
+    assert np.array_equal(content, np.array([1, 4]))
+    axes, content = cat.fetch("sales", "latest", day=slice(None, 20200301)).export()
+    assert np.array_equal(axes[0], np.array([20200228, 20200301]))
+
+def test_fetch_many_selections():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct"])
+    cat.commit("sales", None, None, "message", [Patch(
+        axes = [
+            Axis("itm", np.array([1, 2, 3])),
+            Axis("lct", np.array([4, 5]))
+        ],
+        content = np.array([[1, 2], [3, 4], [5, 6]], dtype=np.float32)
+    )])
+    left, right = cat.fetch_many_selections("sales", "latest", [{"itm": [1, 2]}, {"itm": 3, "lct": 5}])
+    assert np.array_equal(left.export()[1], np.array([[1, 2], [3, 4]]))
+    assert np.array_equal(right.export()[1], np.array([[6]]))
+    # Axes the quilt doesn't have are ignored when it's lenient, like in fetch()
+    [both] = cat.fetch_many_selections("sales", "latest", [{"itm": 1, "color": 2}], _strict=False)
+    assert np.array_equal(both.export()[1], np.array([[1, 2]]))
+
+def test_measures():
+    cat = Catalog()
+    assert cat.declare_measures(["value", "flag"]) == [0, 1]
+    assert cat.measures() == {"value": 0, "flag": 1}
+    cat.create_quilt("readings", ["measure", "sensor"])
+    cat.commit("readings", None, None, "message", [Patch(
+        axes = [
+            Axis("measure", np.array([0, 1])),
+            Axis("sensor", np.array([4, 5]))
+        ],
+        content = np.array([[1.5, 2.5], [0, 1]], dtype=np.float32)
+    )])
+    pat = cat.fetch("readings", "latest")
+    assert np.array_equal(pat["flag"], np.array([0, 1]))
+    assert np.array_equal(pat[0], np.array([1.5, 2.5]))
+    try:
+        pat["stamp"]
+        assert False, "Should have failed because no measure is named stamp"
+    except KeyError:
+        pass
+
+def test_fetch_ordered():
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm", "lct", "day"])
+    pat = cat.fetch("sales", "latest", day=[720], itm=[1])
+    assert pat.axis_names == ["itm", "lct", "day"]
+    pat = cat.fetch("sales", "latest", _ordered=True, day=[720], itm=[1])
+    assert pat.axis_names == ["day", "itm", "lct"]
+    with cat.transaction() as txn:
+        pat = txn.fetch("sales", "latest", _ordered=True, lct=[5])
+        assert pat.axis_names == ["lct", "itm", "day"]
+
+    # An axis can be named ordered, since the option has an underscore
+    cat.create_quilt("checks", ["strict", "ordered"])
+    pat = cat.fetch("checks", "latest", _ordered=True, ordered=[1], strict=[2, 3])
+    assert pat.axis_names == ["ordered", "strict"]
+
+def test_export_history():
+    import json
+    cat = Catalog()
+    cat.create_quilt("sales", ["itm"])
+    cat.commit("sales", None, None, "first", [Patch(
+        axes = [Axis("itm", np.array([1, 2]))],
+        content = np.array([1, 2], dtype=np.float32)
+    )])
+    assert cat.export_history("sales").startswith('digraph "sales"')
+    history = json.loads(cat.export_history("sales", "json"))
+    assert history["commits"][0]["tags"] == ["latest"]
+
+def test_fetch_joined():
+    cat = Catalog()
+    cat.create_quilt("price", ["itm"])
+    cat.create_quilt("volume", ["itm", "lct"])
+    cat.commit("price", "latest", "latest", "price", [Patch(
+        axes = [Axis("itm", np.array([2, 1]))],
+        content = np.array([3, 4], dtype=np.float32)
+    )])
+    cat.commit("volume", "latest", "latest", "volume", [Patch(
+        axes = [Axis("itm", np.array([1])), Axis("lct", np.array([5, 6]))],
+        content = np.array([[1, 2]], dtype=np.float32)
+    )])
+    price, volume = cat.fetch_joined([("price", "latest"), ("volume", "latest")], itm=[1, 2])
+    assert np.array_equal(price.labels("itm"), volume.labels("itm"))
+    _, prices = price.export()
+    _, volumes = volume.export()
+    assert np.array_equal(prices[:, None] * volumes, np.array([[4, 8], [np.nan, np.nan]]), equal_nan=True)
@@ -1,4 +1,5 @@
 #!/bin/sh
 set -e
 maturin develop --cargo-extra-args="--features python"
-py.test stoicheia
\ No newline at end of file
+py.test stoicheia
+cargo test --features interop --test interop
@@ -0,0 +1,131 @@
+//! Serialized patches from every version of the format, checked into tests/data
+//!
+//! Catalogs keep patches for years, so every patch ever written has to stay readable. Each
+//! file is the same patch, serialized with one format version, compression and quantization,
+//! and named like `v1_lz4_exact.stoi`. Every file has to decode to that patch, and patches
+//! serialized the current way without compression have to match their files byte for byte.
+//! Compressed files only have to decode, since compressors are free to change their output.
+//!
+//! The format is little endian with 64 bit lengths everywhere, so these files are the same on
+//! big endian and 32 bit targets, and running this test there checks that too.
+//!
+//! When the format changes, keep the old files and write the new ones with
+//! `STOI_BLESS_GOLDEN=1 cargo test --test compatibility`.
+use std::path::PathBuf;
+use stoicheia::{Patch, PatchCompressionType, PatchQuantization};
+
+/// Every way a patch is serialized in the current version, named like the files
+const CASES: &[(&str, PatchCompressionType, PatchQuantization)] = &[
+    (
+        "off_exact",
+        PatchCompressionType::Off,
+        PatchQuantization::Exact,
+    ),
+    ("off_f16", PatchCompressionType::Off, PatchQuantization::F16),
+    ("off_u8", PatchCompressionType::Off, PatchQuantization::U8),
+    (
+        "lz4_exact",
+        PatchCompressionType::LZ4 { quality: 0 },
+        PatchQuantization::Exact,
+    ),
+    (
+        "brotli_exact",
+        PatchCompressionType::Brotli { quality: 9 },
+        PatchQuantization::Exact,
+    ),
+];
+
+/// The patch every file holds, with a NAN and values that need some care to quantize
+fn golden_patch() -> Patch {
+    Patch::build()
+        .axis("itm", &[1, 2, 3])
+        .axis("day", &[20210101, 20210102])
+        .content_2d(&[[0.5, 1.0], [-2.0, std::f32::NAN], [4.25, 8.0]])
+        .unwrap()
+}
+
+fn data_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data")
+}
+
+fn serialize(compression: PatchCompressionType, quantization: PatchQuantization) -> Vec<u8> {
+    let mut buffer = vec![];
+    golden_patch()
+        .serialize_quantized_into(Some(compression), quantization, &mut buffer)
+        .unwrap();
+    buffer
+}
+
+/// The version the current format writes
+fn current_version() -> u8 {
+    Patch::serialized_version(&serialize(
+        PatchCompressionType::Off,
+        PatchQuantization::Exact,
+    ))
+    .unwrap()
+}
+
+#[test]
+fn golden_patches_decode() {
+    let expected = golden_patch();
+    let mut files = 0;
+    for entry in std::fs::read_dir(data_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("stoi") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let parts: Vec<&str> = name.split('_').collect();
+        assert_eq!(
+            parts.len(),
+            3,
+            "golden file {} should be version_compression_quantization",
+            name
+        );
+        let version: u8 = parts[0].trim_start_matches('v').parse().unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(
+            Patch::serialized_version(&content).unwrap(),
+            version,
+            "{}",
+            name
+        );
+
+        let patch = Patch::deserialize_from(&content[..]).unwrap();
+        assert_eq!(patch.axes(), expected.axes(), "{}", name);
+        // U8 has 254 levels over the range of the patch, which is 10 wide
+        let tolerance = if parts[2] == "u8" { 10. / 254. } else { 0. };
+        for (actual, expected) in patch.content().iter().zip(expected.content().iter()) {
+            if expected.is_nan() {
+                assert!(actual.is_nan(), "{}: {} should be NAN", name, actual);
+            } else {
+                assert!(
+                    (actual - expected).abs() <= tolerance,
+                    "{}: {} != {}",
+                    name,
+                    actual,
+                    expected
+                );
+            }
+        }
+        files += 1;
+    }
+    assert!(files >= CASES.len());
+}
+
+#[test]
+fn golden_patches_are_byte_compatible() {
+    let version = current_version();
+    let bless = std::env::var("STOI_BLESS_GOLDEN").is_ok();
+    for &(case, compression, quantization) in CASES {
+        let path = data_dir().join(format!("v{}_{}.stoi", version, case));
+        let serialized = serialize(compression, quantization);
+        if bless {
+            std::fs::write(&path, &serialized).unwrap();
+        } else if compression == PatchCompressionType::Off {
+            let golden = std::fs::read(&path)
+                .unwrap_or_else(|_| panic!("{} is missing, see the module docs", path.display()));
+            assert_eq!(serialized, golden, "{} changed", path.display());
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Scenarios that cross from one component to another, sharing one catalog file
+//!
+//! Unit tests check each component alone, so these catch the mistakes between them, like the
+//! Python bindings writing something Rust reads differently. They need the Python module
+//! installed (see test.sh) so they only run with the "interop" feature:
+//!
+//!     maturin develop --cargo-extra-args="--features python"
+//!     cargo test --features interop --test interop
+//!
+//! Set STOI_TEST_PYTHON to use another Python interpreter than python3.
+#![cfg(feature = "interop")]
+use stoicheia::{BoundingBox, Catalog, Patch, StorageTransaction};
+
+/// Run a Python script with the catalog's path as its only argument, and return its output
+fn run_python(script: &str, path: &str) -> String {
+    let python = std::env::var("STOI_TEST_PYTHON").unwrap_or_else(|_| "python3".into());
+    let output = std::process::Command::new(python)
+        .args(&["-c", script, path])
+        .output()
+        .expect("Python should be installed to run the interop tests");
+    assert!(
+        output.status.success(),
+        "the Python script failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Commit from Python, change and compact from Rust, and read it all back from Python
+#[test]
+fn python_and_rust_share_a_catalog() {
+    let path = std::env::temp_dir().join(format!("stoi-interop-{}.db", rand::random::<u32>()));
+    let path = path.to_str().unwrap();
+    run_python(
+        r#"
+import sys
+import numpy as np
+from stoicheia import Catalog, Axis, Patch
+cat = Catalog(sys.argv[1])
+cat.create_quilt("sales", ["itm", "day"])
+cat.commit("sales", "latest", "latest", "from python", [Patch(
+    axes = [Axis("itm", np.array([1, 2])), Axis("day", np.array([10, 11]))],
+    content = np.array([[1, 2], [3, 4]], dtype=np.float32)
+)])
+"#,
+        path,
+    );
+
+    // Rust sees exactly what Python wrote
+    let cat = Catalog::connect(path).unwrap();
+    let mut txn = cat.begin().unwrap();
+    let patch = txn.fetch("sales", "latest", vec![]).unwrap();
+    assert_eq!(patch.get(&[2, 10]), Some(3.));
+    assert_eq!(patch.get(&[1, 11]), Some(2.));
+
+    // Then overwrites some of it, and compacts everything into fewer patches
+    let update = Patch::build()
+        .axis("itm", &[2, 3])
+        .axis("day", &[11])
+        .content_2d(&[[40.], [50.]])
+        .unwrap();
+    txn.create_commit("sales", "latest", "latest", "from rust", &[&update])
+        .unwrap();
+    txn.compact_region("sales", "latest", BoundingBox::everywhere())
+        .unwrap();
+    txn.finish().unwrap();
+    drop(cat);
+
+    // Python sees the update and the compaction the same way
+    let output = run_python(
+        r#"
+import sys, json
+import numpy as np
+from stoicheia import Catalog
+pat = Catalog(sys.argv[1]).fetch("sales", "latest", itm=[1, 2, 3], day=[10, 11])
+_, content = pat.export(fill=-1)
+print(json.dumps(content.tolist()))
+"#,
+        path,
+    );
+    let content: Vec<Vec<f32>> = serde_json::from_str(output.trim()).unwrap();
+    assert_eq!(content, vec![vec![1., 2.], vec![3., 40.], vec![-1., 50.]]);
+    std::fs::remove_file(path).unwrap();
+}
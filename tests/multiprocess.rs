@@ -0,0 +1,111 @@
+//! Several processes sharing one catalog file
+//!
+//! The test binary runs itself again as the worker processes, selecting the worker "test"
+//! and passing the catalog through the environment. Run normally, the worker does nothing.
+use stoicheia::{Catalog, Fallible, Patch, StoiError, StorageTransaction};
+
+const WORKERS: i64 = 4;
+const COMMITS_PER_WORKER: i64 = 10;
+
+/// Commit one new label, retrying for as long as other processes are in the way
+fn commit_label(cat: &Catalog, label: i64) -> Fallible<()> {
+    let patch = Patch::build()
+        .axis("itm", &[label])
+        .content_1d(&[label as f32])?;
+    loop {
+        let result = cat.begin().and_then(|mut txn| {
+            txn.create_commit("sales", "latest", "latest", "message", &[&patch])?;
+            txn.finish()
+        });
+        match result {
+            Err(err) if err.is_retryable() => continue,
+            result => return result,
+        }
+    }
+}
+
+#[test]
+fn multiprocess_worker() {
+    let (path, worker) = match (
+        std::env::var("STOI_TEST_CATALOG"),
+        std::env::var("STOI_TEST_WORKER"),
+    ) {
+        (Ok(path), Ok(worker)) => (path, worker.parse::<i64>().unwrap()),
+        _ => return,
+    };
+    let cat = Catalog::connect(&path).unwrap();
+    for i in 0..COMMITS_PER_WORKER {
+        commit_label(&cat, worker * 1000 + i).unwrap();
+    }
+}
+
+/// Commits from many processes at once should all land, without losing any
+#[test]
+fn multiprocess_commits() {
+    let path =
+        std::env::temp_dir().join(format!("stoi-multiprocess-{}.db", rand::random::<u32>()));
+    let path = path.to_str().unwrap();
+    let cat = Catalog::connect(path).unwrap();
+    let mut txn = cat.begin().unwrap();
+    txn.create_quilt("sales", &["itm"]).unwrap();
+    txn.finish().unwrap();
+
+    let workers = (0..WORKERS)
+        .map(|worker| {
+            std::process::Command::new(std::env::current_exe().unwrap())
+                .args(&["--exact", "multiprocess_worker", "--nocapture"])
+                .env("STOI_TEST_CATALOG", path)
+                .env("STOI_TEST_WORKER", worker.to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    // Commit from this process too, in the thick of it
+    commit_label(&cat, -1).unwrap();
+    for mut worker in workers {
+        assert!(worker.wait().unwrap().success());
+    }
+
+    // Every label arrived, with its own value
+    let mut txn = cat.begin_read().unwrap();
+    let patch = txn.fetch("sales", "latest", vec![]).unwrap();
+    let mut labels = patch.axes()[0].labels().to_vec();
+    labels.sort();
+    let mut expected = vec![-1];
+    for worker in 0..WORKERS {
+        expected.extend((0..COMMITS_PER_WORKER).map(|i| worker * 1000 + i));
+    }
+    assert_eq!(labels, expected);
+    for (label, value) in patch.axes()[0].labels().iter().zip(patch.content().iter()) {
+        assert_eq!(*label as f32, *value);
+    }
+    drop(txn);
+    drop(cat);
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Read transactions never take the write lock, so they can't commit
+#[test]
+fn read_transactions_cannot_commit() {
+    let cat = Catalog::connect("").unwrap();
+    let mut txn = cat.begin().unwrap();
+    txn.create_quilt("sales", &["itm"]).unwrap();
+    txn.finish().unwrap();
+
+    let patch = Patch::build().axis("itm", &[1]).content_1d(&[1.]).unwrap();
+    let mut txn = cat.begin_read().unwrap();
+    match txn.create_commit("sales", "latest", "latest", "message", &[&patch]) {
+        Err(StoiError::ReadOnly) => (),
+        other => panic!("expected a read only error, got {:?}", other),
+    }
+    match txn.create_quilt("returns", &["itm"]) {
+        Err(StoiError::ReadOnly) => (),
+        other => panic!("expected a read only error, got {:?}", other),
+    }
+    drop(txn);
+
+    // The connection writes again once the read transaction is over
+    let mut txn = cat.begin().unwrap();
+    txn.create_quilt("returns", &["itm"]).unwrap();
+    txn.finish().unwrap();
+}